@@ -1,8 +1,35 @@
 use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use crate::ircore::token::sgmt::Shingles;
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct Cfg {
     file_type: String,
     fields: Vec<String>,
+    // json field used as display title, "title" if not set
+    #[serde(default)]
+    title_field: Option<String>,
+    // pdf: one document per page instead of per file
+    #[serde(default)]
+    split_pages: bool,
+    // file type by file extension, e.g. md: markdown, others are file_type
+    #[serde(default)]
+    extensions: HashMap<String, String>,
+    // encoding label (e.g. gbk, latin1) of text files not in UTF-8,
+    // "auto" to guess it per file
+    #[serde(default)]
+    fallback_encoding: Option<String>,
+    // files larger than this are skipped, None for no limit
+    #[serde(default)]
+    max_file_bytes: Option<u64>,
+    // words the Chinese segmenter keeps whole, "word" or "word frequency"
+    #[serde(default)]
+    user_dict: Vec<String>,
+    // index word n-grams, e.g. {n: 2, unigrams: true}
+    #[serde(default)]
+    shingles: Option<Shingles>,
+    // lines of the .rirignore file of the corpus root
+    #[serde(skip)]
+    ignore_patterns: Vec<String>,
 }
 
 impl Cfg {
@@ -10,6 +37,14 @@ impl Cfg {
         Cfg {
             file_type: String::from("text"),
             fields: vec![],
+            title_field: None,
+            split_pages: false,
+            extensions: HashMap::new(),
+            fallback_encoding: None,
+            max_file_bytes: None,
+            user_dict: vec![],
+            shingles: None,
+            ignore_patterns: vec![],
         }
     }
     pub fn from_str(repo_cfg: &str) -> Self {
@@ -32,6 +67,60 @@ impl Cfg {
     pub fn get_fields(&self) -> &Vec<String> {
         &self.fields
     }
+
+    pub fn is_split_pages(&self) -> bool {
+        self.split_pages
+    }
+
+    pub fn get_title_field(&self) -> &str {
+        self.title_field.as_deref().unwrap_or("title")
+    }
+
+    pub fn get_extensions(&self) -> &HashMap<String, String> {
+        &self.extensions
+    }
+
+    pub fn get_fallback_encoding(&self) -> Option<&str> {
+        self.fallback_encoding.as_deref()
+    }
+
+    pub fn get_max_file_bytes(&self) -> Option<u64> {
+        self.max_file_bytes
+    }
+
+    pub fn set_max_file_bytes(&mut self, max_file_bytes: Option<u64>) {
+        self.max_file_bytes = max_file_bytes;
+    }
+
+    // words of user_dict with their frequency if given, malformed entries are skipped
+    pub fn get_user_words(&self) -> Vec<(&str, Option<usize>)> {
+        let mut words = vec![];
+        for entry in &self.user_dict {
+            let mut parts = entry.split_whitespace();
+            let word = match parts.next() {
+                Some(word) => word,
+                None => continue,
+            };
+            match parts.next().map(str::parse::<usize>) {
+                None => words.push((word, None)),
+                Some(Ok(freq)) => words.push((word, Some(freq))),
+                Some(Err(_)) => log::warn!("user_dict entry {:?} has no valid frequency, skipped", entry),
+            }
+        }
+        words
+    }
+
+    pub fn get_shingles(&self) -> Option<Shingles> {
+        self.shingles
+    }
+
+    pub fn get_ignore_patterns(&self) -> &Vec<String> {
+        &self.ignore_patterns
+    }
+
+    pub fn set_ignore_patterns(&mut self, patterns: Vec<String>) {
+        self.ignore_patterns = patterns;
+    }
 }
 
 #[cfg(test)]
@@ -51,10 +140,23 @@ fields:
         let cfg:Cfg = Cfg::from_str(cfg_str);
         assert_eq!(cfg, Cfg { file_type: "json".to_string(), 
                 fields: vec!["id".to_string(), "title".to_string(), 
-                            "url".to_string(), "content".to_string()] });
+                            "url".to_string(), "content".to_string()],
+                title_field: None, split_pages: false, extensions: HashMap::new(), fallback_encoding: None,
+                max_file_bytes: None, user_dict: vec![], shingles: None,
+                ignore_patterns: vec![] });
         assert!(cfg.is_json());
         assert_eq!(cfg.get_fields(), &vec![
             "id".to_string(), "title".to_string(), 
             "url".to_string(), "content".to_string()]);
+        assert_eq!(cfg.get_title_field(), "title");
+        let cfg = Cfg::from_str("file_type: json\nfields:\n  - text\ntitle_field: name\n");
+        assert_eq!(cfg.get_title_field(), "name");
+        let cfg = Cfg::from_str("file_type: text\nfields: []\nextensions:\n  json: json\n  md: markdown\n");
+        assert_eq!(cfg.get_extensions().get("json").map(String::as_str), Some("json"));
+        assert_eq!(cfg.get_extensions().len(), 2);
+        let cfg = Cfg::from_str("file_type: text\nfields: []\nuser_dict:\n  - 东逝水\n  - 长江大桥 20\n  - 浪花 x\n");
+        assert_eq!(cfg.get_user_words(), vec![("东逝水", None), ("长江大桥", Some(20))]);
+        let cfg = Cfg::from_str("file_type: text\nfields: []\nshingles:\n  n: 2\n");
+        assert_eq!(cfg.get_shingles(), Some(Shingles { n: 2, unigrams: false }));
     }
 }
\ No newline at end of file
@@ -1,28 +1,135 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::path::{Path, PathBuf};
 use super::Document;
 use crate::ircore::doc::cfg::Cfg;
-use std::io;
-use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::fs::{self, File};
+use rayon::prelude::*;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use encoding_rs::Encoding;
+use chardetng::EncodingDetector;
 
 pub type FnParseString = fn(&Path, &str, &Cfg) -> io::Result<Vec<Document>>;
+pub type FnParseBytes = fn(&Path, &[u8], &Cfg) -> io::Result<Vec<Document>>;
+// document of the line of the given (1-based) number, None to skip the line
+pub type FnParseLine = fn(&Path, usize, &str, &Cfg) -> Option<Document>;
+
+// handlers of utf-8 text files get the content as string,
+// handlers of binary formats get the raw bytes,
+// handlers of one document per line formats get the file line by line
+#[derive(Clone, Copy)]
+pub enum FnParse {
+    String(FnParseString),
+    Bytes(FnParseBytes),
+    Lines(FnParseLine),
+}
+
+// file read line by line by a FnParse::Lines handler
+struct LineStream {
+    path: PathBuf,
+    reader: BufReader<File>,
+    handler: FnParseLine,
+    // lines read so far
+    lines: usize,
+}
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
 
 pub struct DirIter<'a> {
+    path: PathBuf,
     path_queue: VecDeque<PathBuf>,
-    fn_parsestring: FnParseString,
+    fn_parse: FnParse,
+    // handlers by lowercase file extension, fn_parse for the others
+    extension_parse: HashMap<String, FnParse>,
     cfg: &'a Cfg,
+    // compiled ignore patterns of the config, relative to path
+    rules: Option<Gitignore>,
+    // files tried so far, parsed or not
+    visited: usize,
+    // file of a FnParse::Lines handler being read
+    stream: Option<LineStream>,
 }
 
 impl<'a> DirIter<'a> {
     pub fn new(path: &str, 
-            fn_parsestring: FnParseString,
+            fn_parse: FnParse,
             cfg: &'a Cfg) -> Self{
         DirIter {
+            path: PathBuf::from(path),
             path_queue: VecDeque::from(vec!(PathBuf::from(path))),
-            fn_parsestring: fn_parsestring,
+            fn_parse,
+            extension_parse: HashMap::new(),
             cfg: cfg,
+            rules: Self::compile_rules(Path::new(path), cfg.get_ignore_patterns()),
+            visited: 0,
+            stream: None,
+        }
+    }
+
+    // documents of a streamed file are yielded in chunks of at most this many,
+    // so memory is bounded by the chunk instead of the file
+    const STREAM_CHUNK_DOCS: usize = 1000;
+
+    // patterns of a single file are relative to its directory,
+    // invalid ones are logged and skipped
+    fn compile_rules(path: &Path, patterns: &[String]) -> Option<Gitignore> {
+        if patterns.is_empty() {
+            return None;
+        }
+        let root = match path.is_file() {
+            true => path.parent().unwrap_or(path),
+            false => path,
+        };
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in patterns {
+            if let Err(e) = builder.add_line(None, pattern) {
+                log::warn!("skip ignore pattern {}: {}", pattern, e);
+            }
+        }
+        match builder.build() {
+            Ok(rules) => Some(rules),
+            Err(e) => {
+                log::warn!("ignore patterns not used: {}", e);
+                None
+            }
         }
     }
+
+    pub fn with_extensions(mut self, extension_parse: HashMap<String, FnParse>) -> Self {
+        self.extension_parse = extension_parse;
+        self
+    }
+
+    pub fn visited(&self) -> usize {
+        self.visited
+    }
+
+    // number of files the iterator will visit under path
+    pub fn count_files(&self) -> usize {
+        let mut count = 0;
+        let mut path_queue = VecDeque::from(vec!(self.path.clone()));
+        while let Some(path) = path_queue.pop_front() {
+            if self.excluded(&path) {
+                continue;
+            }else if path.is_file() {
+                count += 1;
+            }else if path.is_dir() {
+                path_queue.extend(Self::read_dir_sorted(&path));
+            }
+        }
+        count
+    }
+    // hidden or matching an ignore pattern
+    fn excluded(&self, path: &Path) -> bool {
+        if Self::ignore(path) {
+            return true;
+        }
+        match &self.rules {
+            Some(rules) => rules.matched(path, path.is_dir()).is_ignore(),
+            None => false,
+        }
+    }
+
     fn ignore(path: &Path) -> bool {
         if let Some(filename) = path.file_name(){
             //by default, ignore hidden files on unix like platforms
@@ -32,42 +139,224 @@ impl<'a> DirIter<'a> {
         }
         return false;
     }
-    fn load_content(path: &Path) -> io::Result<String> {
-        fs::read_to_string(path)
+    // entries in name order so builds assign the same doc ids on every platform,
+    // an unreadable directory is logged and skipped
+    fn read_dir_sorted(path: &Path) -> Vec<PathBuf> {
+        let mut entries: Vec<PathBuf> = match path.read_dir() {
+            Ok(entries) => entries.flatten().map(|entry| entry.path()).collect(),
+            Err(e) => {
+                log::warn!("skip directory {}: {}", path.display(), e);
+                return vec![];
+            }
+        };
+        if entries.is_empty() {
+            log::debug!("{} is empty", path.display());
+        }
+        entries.sort();
+        entries
+    }
+    // next file to parse in walk order, None when all are visited
+    fn next_file(&mut self) -> Option<PathBuf> {
+        while let Some(path) = self.path_queue.pop_front(){
+            if self.excluded(&path){
+                log::info!("doc parse ignore: {}", path.display());
+            }else if path.is_file() {
+                self.visited += 1;
+                return Some(path);
+            }else if path.is_dir(){
+                log::debug!("{}...", path.display());
+                self.path_queue.extend(Self::read_dir_sorted(&path));
+            }
+        }
+        None
+    }
+
+    // Documents of the next max_files files parsed in parallel, in walk order,
+    // files failed to parse are logged and left out. A streamed file ends the
+    // batch, its chunks are batches of their own. None when all are visited.
+    pub fn next_batch(&mut self, max_files: usize) -> Option<Vec<Vec<Document>>> {
+        loop {
+            if let Some(chunk) = self.next_chunk() {
+                return Some(vec![chunk]);
+            }
+            let mut paths = vec![];
+            let mut streamed = false;
+            while paths.len() < max_files.max(1) {
+                let Some(path) = self.next_file() else {
+                    break;
+                };
+                if self.open_stream(&path) {
+                    streamed = true;
+                    break;
+                }
+                paths.push(path);
+            }
+            if !paths.is_empty() {
+                let batch = paths.par_iter()
+                    .filter_map(|path| self.parse_logged(path))
+                    .collect();
+                return Some(batch);
+            }
+            if !streamed {
+                return None;
+            }
+        }
+    }
+
+    // true if path is to be streamed, its chunks are then read by next_chunk
+    fn open_stream(&mut self, path: &Path) -> bool {
+        let FnParse::Lines(handler) = self.handler(path) else {
+            return false;
+        };
+        if self.too_large(path).unwrap_or(false) {
+            return true;
+        }
+        match File::open(path) {
+            Ok(f) => self.stream = Some(LineStream{
+                path: path.to_path_buf(),
+                reader: BufReader::new(f),
+                handler,
+                lines: 0,
+            }),
+            Err(e) => log::error!("{}: {}", path.display(), e),
+        }
+        true
+    }
+
+    // next documents of the streamed file, None once it is read
+    fn next_chunk(&mut self) -> Option<Vec<Document>> {
+        let mut stream = self.stream.take()?;
+        let mut docs = vec![];
+        let mut line = vec![];
+        let mut done = false;
+        while docs.len() < Self::STREAM_CHUNK_DOCS {
+            line.clear();
+            match stream.reader.read_until(b'\n', &mut line) {
+                Ok(0) => {
+                    done = true;
+                    break;
+                },
+                Ok(_) => stream.lines += 1,
+                Err(e) => {
+                    log::error!("{}: {}", stream.path.display(), e);
+                    done = true;
+                    break;
+                },
+            }
+            while line.last().is_some_and(|&b| b == b'\n' || b == b'\r') {
+                line.pop();
+            }
+            if stream.lines == 1 && line.starts_with(UTF8_BOM) {
+                line.drain(..UTF8_BOM.len());
+            }
+            match self.decode(&stream.path, std::mem::take(&mut line)) {
+                Ok(text) => docs.extend((stream.handler)(&stream.path, stream.lines, &text, self.cfg)),
+                Err(e) => log::warn!("{}:{}: {}", stream.path.display(), stream.lines, e),
+            }
+        }
+        if !done {
+            self.stream = Some(stream);
+        }
+        match docs.is_empty() {
+            true if done => None,
+            _ => Some(docs),
+        }
+    }
+
+    fn parse_logged(&self, path: &Path) -> Option<Vec<Document>> {
+        match self.parse_file(path){
+            Ok(docs) => Some(docs),
+            Err(e) => {
+                log::error!("{}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn handler(&self, path: &Path) -> FnParse {
+        path.extension()
+            .and_then(|ext| self.extension_parse.get(&ext.to_string_lossy().to_lowercase()))
+            .copied()
+            .unwrap_or(self.fn_parse)
+    }
+
+    // Text of a file, decoded as the encoding of its BOM if any, else UTF-8,
+    // else the fallback encoding of the config. Not UTF-8 without a fallback
+    // is an error.
+    fn load_content(&self, path: &Path) -> io::Result<String> {
+        let bytes = fs::read(path)?;
+        if let Some((encoding, bom_length)) = Encoding::for_bom(&bytes) {
+            return Ok(encoding.decode_without_bom_handling(&bytes[bom_length..]).0.into_owned());
+        }
+        self.decode(path, bytes)
+    }
+
+    // bytes as UTF-8, else as the fallback encoding
+    fn decode(&self, path: &Path, bytes: Vec<u8>) -> io::Result<String> {
+        let bytes = match String::from_utf8(bytes) {
+            Ok(text) => return Ok(text),
+            Err(e) => e.into_bytes(),
+        };
+        let encoding = match self.cfg.get_fallback_encoding() {
+            Some(label) if label.eq_ignore_ascii_case("auto") => {
+                let mut detector = EncodingDetector::new();
+                detector.feed(&bytes, true);
+                detector.guess(None, true)
+            },
+            Some(label) => Encoding::for_label(label.as_bytes()).ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidInput, format!("unknown encoding {}", label)))?,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                "stream did not contain valid UTF-8, set fallback_encoding in the config")),
+        };
+        let (text, had_errors) = encoding.decode_without_bom_handling(&bytes);
+        if had_errors {
+            log::warn!("{}: invalid {} sequences replaced", path.display(), encoding.name());
+        }
+        Ok(text.into_owned())
+    }
+
+    // over max_file_bytes of the config, logged
+    fn too_large(&self, path: &Path) -> io::Result<bool> {
+        if let Some(max_file_bytes) = self.cfg.get_max_file_bytes() {
+            let file_bytes = fs::metadata(path)?.len();
+            if file_bytes > max_file_bytes {
+                log::warn!("skip {}: {} bytes, over the limit of {}", path.display(), file_bytes, max_file_bytes);
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn parse_file(&self, path: &Path) -> io::Result<Vec<Document>> {
+        if self.too_large(path)? {
+            return Ok(vec![]);
+        }
+        match self.handler(path) {
+            FnParse::String(fn_parsestring) => fn_parsestring(path, &self.load_content(path)?, self.cfg),
+            FnParse::Bytes(fn_parsebytes) => fn_parsebytes(path, &fs::read(path)?, self.cfg),
+            FnParse::Lines(fn_parseline) => Ok(self.load_content(path)?.lines()
+                .enumerate()
+                .filter_map(|(n, line)| fn_parseline(path, n + 1, line, self.cfg))
+                .collect()),
+        }
     }
 }
 
 impl<'a> Iterator for DirIter<'a> {
     type Item = Vec<Document>;
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(path) = self.path_queue.pop_front(){
-            let path_string = path.to_string_lossy().to_string();
-            if Self::ignore(&path){
-                log::info!("doc parse ignore: {}", path_string);
-            }else if path.is_file() {
-                match Self::load_content(&path){
-                    Ok(c) => match (self.fn_parsestring)(&path, &c, &self.cfg){
-                        Ok(docs) => return Some(docs),
-                        Err(e) => {
-                            log::error!("{}: {}", path_string, e);
-                        }
-                    },
-                    Err(e) => {
-                        log::error!("{}: {}", path_string, e);
-                    }
-                }
-            }else if path.is_dir(){
-                log::debug!("{}...", path.display());
-                for entry_result in path.read_dir().expect(&format!("read dir {} failed", path.display())) {
-                    if let Ok(entry) = entry_result{
-                        let entry_path = entry.path();
-                        self.path_queue.push_back(entry_path)   
-                    }
-                }    
+        loop {
+            if let Some(chunk) = self.next_chunk() {
+                return Some(chunk);
+            }
+            let path = self.next_file()?;
+            if self.open_stream(&path) {
+                continue;
+            }
+            if let Some(docs) = self.parse_logged(&path) {
+                return Some(docs);
             }
-            return self.next();    
         }
-        return None;
     }
 
 }
@@ -75,6 +364,8 @@ impl<'a> Iterator for DirIter<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ircore::doc::doc_parser::DocParser;
+    use crate::ircore::doc::text::parse_text;
 
     #[test]
     fn test_ignore() {
@@ -84,4 +375,124 @@ mod tests {
         assert!(DirIter::ignore(Path::new("./.rir")));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_count_files() {
+        // binary_file is visited even though it cannot be parsed
+        assert_eq!(DocParser::new("./sample_corpus/romeo_juliet").count_files(), 6);
+        // .rircfg is ignored
+        assert_eq!(DocParser::new("./sample_corpus/wiki_zh").count_files(), 2);
+    }
+
+    #[test]
+    fn test_read_dir_sorted() {
+        let entries = DirIter::read_dir_sorted(Path::new("./sample_corpus/romeo_juliet"));
+        let mut sorted = entries.clone();
+        sorted.sort();
+        assert_eq!(entries, sorted);
+        assert_eq!(entries.len(), 4);
+        assert!(DirIter::read_dir_sorted(Path::new("./sample_corpus/not_exist")).is_empty());
+    }
+
+    #[test]
+    fn test_next_batch() {
+        DocParser::init();
+        let dp = DocParser::new("./sample_corpus/romeo_juliet");
+        let one_by_one: Vec<String> = dp.docs().flatten()
+            .map(|doc| doc.get_path().to_owned())
+            .collect();
+        let mut docs_iter = dp.docs();
+        let mut batched = vec![];
+        while let Some(batch) = docs_iter.next_batch(4) {
+            batched.extend(batch.into_iter().flatten().map(|doc| doc.get_path().to_owned()));
+        }
+        // same documents in the same order, binary_file fails to parse
+        assert_eq!(batched, one_by_one);
+        assert_eq!(docs_iter.visited(), 6);
+    }
+
+    fn visited_paths(dir: &str) -> Vec<String> {
+        let dp = DocParser::new(dir);
+        assert_eq!(dp.count_files(), dp.docs().flatten().count());
+        let mut docs_iter = dp.docs();
+        std::iter::from_fn(|| docs_iter.next_file())
+            .map(|path| path.strip_prefix(dir).unwrap().to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_rirignore() {
+        let dir = "./.rir/rirignore_corpus";
+        let _ = fs::remove_dir_all(dir);
+        for sub in ["tmp", "a/tmp", "b"] {
+            fs::create_dir_all(format!("{}/{}", dir, sub)).unwrap();
+        }
+        for file in ["1.txt", "big.bin", "keep.bin", "tmp/2.txt", "a/3.txt", "a/tmp/4.txt", "b/tmp"] {
+            fs::write(format!("{}/{}", dir, file), "Quarrel sir!").unwrap();
+        }
+        assert_eq!(visited_paths(dir).len(), 7);
+        // tmp/ matches directories only, the file b/tmp is kept
+        fs::write(format!("{}/.rirignore", dir), "*.bin\n!keep.bin\n# comment\ntmp/\n").unwrap();
+        assert_eq!(visited_paths(dir), vec!["1.txt", "keep.bin", "a/3.txt", "b/tmp"]);
+        // anchored to the corpus root
+        fs::write(format!("{}/.rirignore", dir), "/tmp/\n").unwrap();
+        assert_eq!(visited_paths(dir), vec!["1.txt", "big.bin", "keep.bin", "a/3.txt", "b/tmp", "a/tmp/4.txt"]);
+    }
+
+    #[test]
+    fn test_load_content() {
+        let dir = Path::new("./.rir/encoding_corpus");
+        fs::create_dir_all(dir).unwrap();
+        let text = "数学是利用符号语言研究数量、结构、变化以及空间等概念的一门学科";
+        let utf8_bom = dir.join("bom.txt");
+        fs::write(&utf8_bom, [&[0xEF, 0xBB, 0xBF], text.as_bytes()].concat()).unwrap();
+        let gbk = dir.join("gbk.txt");
+        fs::write(&gbk, encoding_rs::GBK.encode(text).0).unwrap();
+
+        let mut cfg = Cfg::new();
+        let dir_iter = DirIter::new("./.rir/encoding_corpus", FnParse::String(parse_text), &cfg);
+        assert_eq!(dir_iter.load_content(&utf8_bom).unwrap(), text);
+        assert_eq!(dir_iter.load_content(&gbk).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        for encoding in ["gbk", "auto"] {
+            cfg = Cfg::from_str(&format!("file_type: text\nfields: []\nfallback_encoding: {}\n", encoding));
+            let dir_iter = DirIter::new("./.rir/encoding_corpus", FnParse::String(parse_text), &cfg);
+            assert_eq!(dir_iter.load_content(&gbk).unwrap(), text);
+            assert_eq!(dir_iter.load_content(&utf8_bom).unwrap(), text);
+        }
+        cfg = Cfg::from_str("file_type: text\nfields: []\nfallback_encoding: nonsense\n");
+        let dir_iter = DirIter::new("./.rir/encoding_corpus", FnParse::String(parse_text), &cfg);
+        assert!(dir_iter.load_content(&gbk).is_err());
+    }
+
+    #[test]
+    fn test_stream_lines() {
+        DocParser::init();
+        let dir = "./.rir/stream_corpus";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        fs::write(format!("{}/.rircfg", dir), "file_type: jsonlines\nfields:\n  - text\n").unwrap();
+        let lines = 2 * DirIter::STREAM_CHUNK_DOCS + 500;
+        let line = r#"{"title": "Quarrel", "text": "Do you quarrel, sir? Quarrel sir! no, sir!"}"#;
+        let text: String = (0..lines).map(|_| format!("{}\n", line)).collect();
+        fs::write(format!("{}/large", dir), [UTF8_BOM, text.as_bytes()].concat()).unwrap();
+        fs::write(format!("{}/small", dir), format!("{}\nnot json\n{}", line, line)).unwrap();
+
+        let dp = DocParser::new(dir);
+        // large is yielded in chunks, never held whole
+        let chunks: Vec<Vec<Document>> = dp.docs().collect();
+        let sizes: Vec<usize> = chunks.iter().map(|chunk| chunk.len()).collect();
+        assert_eq!(sizes, vec![DirIter::STREAM_CHUNK_DOCS, DirIter::STREAM_CHUNK_DOCS, 500, 2]);
+        let largest_chunk: usize = chunks[0].iter().map(|doc| doc.get_content().len()).sum();
+        assert!(largest_chunk * 2 < text.len());
+        assert_eq!(chunks[0][0].get_path(), format!("{}/large:1", dir));
+        assert_eq!(chunks[2][499].get_path(), format!("{}/large:{}", dir, lines));
+        assert_eq!(chunks[3][1].get_path(), format!("{}/small:3", dir));
+        // batches stop at a streamed file
+        let mut docs_iter = dp.docs();
+        let mut batch_sizes = vec![];
+        while let Some(batch) = docs_iter.next_batch(64) {
+            batch_sizes.push(batch.iter().map(|docs| docs.len()).collect::<Vec<usize>>());
+        }
+        assert_eq!(batch_sizes, vec![vec![DirIter::STREAM_CHUNK_DOCS], vec![DirIter::STREAM_CHUNK_DOCS],
+            vec![500], vec![2]]);
+    }
+}
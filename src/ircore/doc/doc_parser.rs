@@ -4,10 +4,11 @@ use crate::ircore::doc::dir::DirIter;
 use crate::ircore::doc::json::{self};
 use crate::ircore::doc::text::{self};
 use crate::ircore::doc::jsonlines::{self};
-use crate::ircore::CFG_NAME;
+use crate::ircore::doc::pdf::{self};
+use crate::ircore::{CFG_NAME, IGNORE_NAME};
 use crate::ircore::doc::cfg::Cfg;
 use std::collections::HashMap;
-use crate::ircore::doc::dir::FnParseString;
+use crate::ircore::doc::dir::{FnParse, FnParseString, FnParseBytes, FnParseLine};
 use once_cell::sync::Lazy;
 use std::sync::RwLock;
 use std::sync::Once;
@@ -17,7 +18,7 @@ pub struct DocParser {
     cfg: Cfg,
 }
 
-static HANDLERS: Lazy<RwLock<HashMap<String, FnParseString>>> = Lazy::new(||{
+static HANDLERS: Lazy<RwLock<HashMap<String, FnParse>>> = Lazy::new(||{
         let m = HashMap::new();
         RwLock::new(m)
     }
@@ -26,24 +27,29 @@ static HANDLERS: Lazy<RwLock<HashMap<String, FnParseString>>> = Lazy::new(||{
 static INIT: Once = Once::new();
 
 impl DocParser {
+    // the config of a single file is the one of its directory,
+    // so is its .rirignore
     pub fn new(path: &str) -> Self {
         Self::init();
-        if Path::new(path).is_dir() {
-            let dir_path = Path::new(path);
+        let dir_path = match Path::new(path) {
+            file_path if file_path.is_file() => file_path.parent(),
+            dir_path => Some(dir_path),
+        };
+        let mut cfg = Cfg::new();
+        if let Some(dir_path) = dir_path.filter(|dir_path| dir_path.is_dir()) {
             let cfg_path = dir_path.join(Path::new(CFG_NAME));
             if let Ok(cfg_str) = fs::read_to_string(cfg_path){
-                match serde_yaml::from_str(&cfg_str){
-                    Ok(cfg) => return DocParser {
-                        path: String::from(path),
-                        cfg: cfg,
-                    },
-                    _ => (),
-                }    
+                if let Ok(dir_cfg) = serde_yaml::from_str(&cfg_str){
+                    cfg = dir_cfg;
+                }
+            }
+            if let Ok(ignore_str) = fs::read_to_string(dir_path.join(Path::new(IGNORE_NAME))) {
+                cfg.set_ignore_patterns(ignore_str.lines().map(String::from).collect());
             }
         }
         return DocParser{
             path: String::from(path),
-            cfg: Cfg::new(),
+            cfg,
         };
 
     }
@@ -51,18 +57,42 @@ impl DocParser {
         INIT.call_once(||{
             Self::register(text::FILETYPE, text::parse_text);
             Self::register(json::FILETYPE, json::parse_json);
-            Self::register(jsonlines::FILETYPE, jsonlines::parse_jsonlines);    
+            Self::register_lines(jsonlines::FILETYPE, jsonlines::parse_jsonline);
+            Self::register_bytes(pdf::FILETYPE, pdf::parse_pdf);
         });
     }
     pub fn get_config(&self) -> &Cfg {
         &self.cfg
     }
 
+    // overrides max_file_bytes of the config
+    pub fn set_max_file_bytes(&mut self, max_file_bytes: Option<u64>) {
+        self.cfg.set_max_file_bytes(max_file_bytes);
+    }
+
     pub fn register(filetype: &str, handler: FnParseString) {
         let mut handlers = HANDLERS.write().unwrap();
-        handlers.insert(filetype.to_string(), handler);
+        handlers.insert(filetype.to_string(), FnParse::String(handler));
     }
 
+    // handler of a binary file type
+    pub fn register_bytes(filetype: &str, handler: FnParseBytes) {
+        let mut handlers = HANDLERS.write().unwrap();
+        handlers.insert(filetype.to_string(), FnParse::Bytes(handler));
+    }
+
+    // handler of a file type with one document per line, files are streamed
+    pub fn register_lines(filetype: &str, handler: FnParseLine) {
+        let mut handlers = HANDLERS.write().unwrap();
+        handlers.insert(filetype.to_string(), FnParse::Lines(handler));
+    }
+
+    pub fn count_files(&self) -> usize {
+        self.docs().count_files()
+    }
+
+    // files are parsed by the handler of their extension in the config,
+    // else by the one of its file_type, else as text
     pub fn docs(&self) -> DirIter {
         let handler;
         let handlers = HANDLERS.read().unwrap();
@@ -71,7 +101,16 @@ impl DocParser {
             Some(h) => handler = *h,
             None => handler = *handlers.get("text").unwrap(),
         }
-        return DirIter::new(&self.path, handler, &self.cfg);
+        let mut extension_handlers = HashMap::new();
+        for (ext, ext_filetype) in self.cfg.get_extensions() {
+            match handlers.get(ext_filetype) {
+                Some(h) => {
+                    extension_handlers.insert(ext.trim_start_matches('.').to_lowercase(), *h);
+                },
+                None => log::warn!("no handler of file type {} for .{} files, using {}", ext_filetype, ext, filetype),
+            }
+        }
+        DirIter::new(&self.path, handler, &self.cfg).with_extensions(extension_handlers)
     }
 }
 
@@ -87,6 +126,32 @@ mod tests {
         assert_eq!(dp.cfg.is_json(), false);
         dp = DocParser::new("./sample_corpus/wiki_lines");
         assert_eq!(dp.cfg.is_jsonlines(), true);
+        dp = DocParser::new("./sample_corpus/pdf");
+        assert_eq!(dp.cfg.get_file_type(), "pdf");
+        // a single file takes the config of its directory
+        dp = DocParser::new("./sample_corpus/wiki_lines/lines1");
+        assert!(dp.cfg.is_jsonlines());
+        dp = DocParser::new("./sample_corpus/romeo_juliet/5.txt");
+        assert_eq!(dp.cfg.get_file_type(), "text");
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_docs_by_extension() {
+        let dir = "./.rir/extensions_corpus";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        fs::write(format!("{}/{}", dir, CFG_NAME),
+            "file_type: text\nfields:\n  - text\nextensions:\n  json: json\n  md: markdown\n").unwrap();
+        fs::write(format!("{}/a.json", dir), r#"{"title": "Quarrel", "text": "Quarrel sir!"}"#).unwrap();
+        fs::write(format!("{}/b.txt", dir), r#"{"text": "taken as text"}"#).unwrap();
+        // no markdown handler, parsed as file_type
+        fs::write(format!("{}/c.md", dir), "# No, sir").unwrap();
+        let dp = DocParser::new(dir);
+        let docs: Vec<_> = dp.docs().flatten().collect();
+        assert_eq!(docs.len(), 3);
+        assert_eq!(docs[0].get_content(), "Quarrel sir!");
+        assert_eq!(docs[0].get_title(), Some("Quarrel"));
+        assert_eq!(docs[1].get_content(), r#"{"text": "taken as text"}"#);
+        assert_eq!(docs[2].get_content(), "# No, sir");
+    }
+}
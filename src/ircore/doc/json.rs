@@ -18,7 +18,11 @@ pub fn parse_json(path: &Path, text: &str, cfg: &Cfg) -> io::Result<Vec<Document
             _ => (),
         }
     }
-    Ok(vec![Document::new(content, path_string.to_string())])
+    let title = match &value[cfg.get_title_field()] {
+        Value::String(s) => Some(s.to_string()),
+        _ => None,
+    };
+    Ok(vec![Document::new(content, path_string.to_string()).with_title(title)])
 }
 
 
@@ -56,6 +60,7 @@ fields:
             let c = doc[0].get_content();
             assert!(c.len() > 0);
             assert_eq!(doc[0].get_path(), "some path");
+            assert_eq!(doc[0].get_title(), Some("印卡王室述评"));
         }
     }
 
@@ -6,28 +6,43 @@ use std::path::Path;
 
 pub const FILETYPE:&str = "jsonlines";
 pub fn parse_jsonlines(path: &Path, text: &str, cfg: &Cfg) -> io::Result<Vec<Document>> {
-    let mut docs = vec![];
-    for (n,line) in text.lines().enumerate(){
-        let path_string = path.to_string_lossy().to_string();
-        match serde_json::from_str::<Value>(line){
-            Ok(value) => {
-                let mut content = String::new();
-                for f in cfg.get_fields() {
-                    let field_name = f.to_lowercase();
-                    match &value[field_name] {
-                        Value::String(s) => {
-                            content.push_str(s);
-                        },
-                        _ => (),
+    Ok(text.lines()
+        .enumerate()
+        .filter_map(|(n, line)| parse_jsonline(path, n + 1, line, cfg))
+        .collect())
+}
+
+// document of line number n, streamed by DirIter so that large files are
+// never read as a whole, None for lines that are not json
+pub fn parse_jsonline(path: &Path, n: usize, line: &str, cfg: &Cfg) -> Option<Document> {
+    let path_string = path.to_string_lossy().to_string();
+    match serde_json::from_str::<Value>(line){
+        Ok(value) => {
+            let mut content = String::new();
+            let mut fields = vec![];
+            for f in cfg.get_fields() {
+                let field_name = f.to_lowercase();
+                if let Value::String(s) = &value[&field_name] {
+                    // separated so that content tokens line up with the fields
+                    if !content.is_empty() {
+                        content.push('\n');
                     }
+                    content.push_str(s);
+                    fields.push((field_name, s.to_string()));
                 }
-                docs.push(Document::new(content.clone(), format!("{}:{}",path_string,n+1)));    
-            },
-            Err(e) => log::warn!("{}:{}",path_string, e),
-
-        }    
+            }
+            let title = match &value[cfg.get_title_field()] {
+                Value::String(s) => Some(s.to_string()),
+                _ => None,
+            };
+            Some(Document::new(content, format!("{}:{}",path_string,n))
+                .with_title(title).with_fields(fields))
+        },
+        Err(e) => {
+            log::warn!("{}:{}",path_string, e);
+            None
+        },
     }
-    Ok(docs)
 }
 
 
@@ -56,11 +71,15 @@ fields:
         if let Ok(docs) = parse_jsonlines(Path::new("some path"), text, &cfg){
             assert_eq!(docs.len(), 2);
             let c0 = docs[0].get_content();
-            assert_eq!(c0, "1https://someurl/1line1line1 content");
+            assert_eq!(c0, "1\nhttps://someurl/1\nline1\nline1 content");
             let c1 = docs[1].get_content();
-            assert_eq!(c1, "2https://someurl/2line2line2 content");
+            assert_eq!(c1, "2\nhttps://someurl/2\nline2\nline2 content");
             assert_eq!(docs[0].get_path(), "some path:3");
             assert_eq!(docs[1].get_path(), "some path:4");
+            let fields = docs[0].get_fields();
+            assert_eq!(fields.len(), 4);
+            assert_eq!(fields[2], ("title".to_string(), "line1".to_string()));
+            assert_eq!(fields[3], ("text".to_string(), "line1 content".to_string()));
         }
     }
 
@@ -4,12 +4,17 @@ pub mod json;
 pub mod doc_parser;
 pub mod cfg;
 pub mod jsonlines;
+pub mod pdf;
 
 
 #[derive(PartialEq, Debug)]
 pub struct Document {
     content: String,
     path: String,
+    // human readable title for display
+    title: Option<String>,
+    // (name, text) of fields indexed separately, empty to index content as a whole
+    fields: Vec<(String, String)>,
 }
 
 impl Document {
@@ -17,14 +22,30 @@ impl Document {
         Document {
             content: content.to_string(),
             path: path.to_string(),
+            title: None,
+            fields: vec![],
         }
     }
+    pub fn with_title(mut self, title: Option<String>) -> Self {
+        self.title = title;
+        self
+    }
+    pub fn with_fields(mut self, fields: Vec<(String, String)>) -> Self {
+        self.fields = fields;
+        self
+    }
     pub fn get_content(&self) -> &str {
         &self.content
     }
     pub fn get_path(&self) -> &str {
         &self.path
     }
+    pub fn get_title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+    pub fn get_fields(&self) -> &[(String, String)] {
+        &self.fields
+    }
 }
 
 #[cfg(test)]
@@ -36,5 +57,11 @@ mod tests {
         let doc = Document::new("content: String".to_string(), "path: String".to_string());
         assert_eq!(doc.get_content(), "content: String");
         assert_eq!(doc.get_path(), "path: String");
+        assert_eq!(doc.get_title(), None);
+        let doc = doc.with_title(Some("title".to_string()));
+        assert_eq!(doc.get_title(), Some("title"));
+        assert!(doc.get_fields().is_empty());
+        let doc = doc.with_fields(vec![("title".to_string(), "title".to_string())]);
+        assert_eq!(doc.get_fields(), &[("title".to_string(), "title".to_string())]);
     }
 }
\ No newline at end of file
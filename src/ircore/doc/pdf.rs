@@ -0,0 +1,66 @@
+use std::io;
+use crate::ircore::doc::Document;
+use crate::ircore::doc::cfg::Cfg;
+use std::path::Path;
+
+pub const FILETYPE:&str = "pdf";
+pub fn parse_pdf(path: &Path, bytes: &[u8], cfg: &Cfg) -> io::Result<Vec<Document>> {
+    let path_string = path.to_string_lossy().to_string();
+    let pages = pdf_extract::extract_text_from_mem_by_pages(bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    // scanned pdfs have no text layer
+    if pages.iter().all(|page| page.trim().is_empty()) {
+        log::warn!("{}: no text extracted, image only pdf skipped", path_string);
+        return Ok(vec![]);
+    }
+    let mut docs = vec![];
+    if cfg.is_split_pages() {
+        for (n, page) in pages.iter().enumerate() {
+            if !page.trim().is_empty() {
+                docs.push(Document::new(page.to_string(), format!("{}:{}", path_string, n+1)));
+            }
+        }
+    }else{
+        docs.push(Document::new(pages.join("\n"), path_string));
+    }
+    Ok(docs)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ircore::doc::doc_parser::DocParser;
+    use std::fs;
+
+    #[test]
+    fn test_parse_pdf() {
+        let path = Path::new("./sample_corpus/pdf/romeo.pdf");
+        let bytes = fs::read(path).unwrap();
+        let docs = parse_pdf(path, &bytes, &Cfg::new()).unwrap();
+        assert_eq!(docs.len(), 1);
+        assert!(docs[0].get_content().contains("Do you quarrel, sir?"));
+        assert!(docs[0].get_content().contains("Quarrel sir! no, sir!"));
+        let cfg = Cfg::from_str("file_type: pdf\nfields: []\nsplit_pages: true\n");
+        let docs = parse_pdf(path, &bytes, &cfg).unwrap();
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[1].get_path(), "./sample_corpus/pdf/romeo.pdf:2");
+        assert!(docs[1].get_content().contains("Quarrel sir! no, sir!"));
+    }
+
+    #[test]
+    fn test_skip_image_only_pdf() {
+        let path = Path::new("./sample_corpus/pdf/scanned.pdf");
+        let bytes = fs::read(path).unwrap();
+        assert_eq!(parse_pdf(path, &bytes, &Cfg::new()).unwrap(), vec![]);
+        assert!(parse_pdf(path, b"not a pdf", &Cfg::new()).is_err());
+    }
+
+    #[test]
+    fn test_load_pdf() {
+        let dp = DocParser::new("./sample_corpus/pdf");
+        let docs: Vec<Document> = dp.docs().flatten().collect();
+        assert_eq!(docs.len(), 1);
+        assert_eq!(docs[0].get_path(), "./sample_corpus/pdf/romeo.pdf");
+    }
+}
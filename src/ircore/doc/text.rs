@@ -6,7 +6,12 @@ use crate::ircore::doc::cfg::Cfg;
 pub const FILETYPE:&str = "text";
 pub fn parse_text(path: &Path, text: &str, _cfg: &Cfg) -> io::Result<Vec<Document>> {    
     let path_string = path.to_string_lossy().to_string();
-    Ok(vec![Document::new(text.to_string(), path_string)])
+    // first non-empty line is the title
+    let title = text.lines()
+        .map(|line| line.trim())
+        .find(|line| !line.is_empty())
+        .map(|line| line.to_string());
+    Ok(vec![Document::new(text.to_string(), path_string).with_title(title)])
 }
 
 
@@ -23,6 +28,7 @@ mod tests {
                 &Cfg::new()){
             assert_eq!(doc[0].get_content(), "Do you quarrel, sir?");
             assert_eq!(doc[0].get_path(), "./sample_corpus/romeo_juliet/a/1.txt");
+            assert_eq!(doc[0].get_title(), Some("Do you quarrel, sir?"));
         }else{
             assert!(false);
         }
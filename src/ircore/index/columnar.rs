@@ -0,0 +1,487 @@
+use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use std::mem::size_of;
+use crate::ircore::index::pl::{SchemaDependIndex, IndexStats, type_token_ratio, distinct_terms, intersect_sorted};
+use crate::ircore::utils::sparse_vector::{SparseVector, SparseVectorOp};
+use crate::ircore::token::dictionary::Dictionary;
+use crate::ircore::ranking::{WeightedTerm, unit_weights, query_term_weights};
+use crate::ircore::{DocId, TermId, TermOffset, FieldId, STOPWORD_GAP};
+
+// postings of one term stored as parallel arrays, sorted by doc id
+#[derive(Debug)]
+struct TermPostings {
+    doc_ids: Vec<DocId>,
+    term_frequencies: Vec<u32>,
+    // positions of doc_ids[i] are positions[offsets[i]..offsets[i+1]]
+    offsets: Vec<u32>,
+    positions: Vec<TermOffset>,
+    // equals doc_ids.len(), kept to hand out a reference
+    document_frequency: u32,
+}
+
+impl TermPostings {
+    fn new() -> Self {
+        TermPostings {
+            doc_ids: vec![],
+            term_frequencies: vec![],
+            offsets: vec![0],
+            positions: vec![],
+            document_frequency: 0,
+        }
+    }
+
+    fn find(&self, doc: DocId) -> Option<usize> {
+        self.doc_ids.binary_search(&doc).ok()
+    }
+
+    fn positions_at(&self, i: usize) -> &[TermOffset] {
+        &self.positions[self.offsets[i] as usize..self.offsets[i + 1] as usize]
+    }
+
+    // doc ids are appended in increasing order
+    fn push(&mut self, doc: DocId, offset: TermOffset) {
+        if self.doc_ids.last() != Some(&doc) {
+            self.doc_ids.push(doc);
+            self.term_frequencies.push(0);
+            self.offsets.push(self.positions.len() as u32);
+            self.document_frequency += 1;
+        }
+        *self.term_frequencies.last_mut().unwrap() += 1;
+        self.positions.push(offset);
+        *self.offsets.last_mut().unwrap() = self.positions.len() as u32;
+    }
+
+    fn remove(&mut self, i: usize) {
+        let (start, end) = (self.offsets[i] as usize, self.offsets[i + 1] as usize);
+        self.positions.drain(start..end);
+        self.offsets.remove(i + 1);
+        for offset in &mut self.offsets[i + 1..] {
+            *offset -= (end - start) as u32;
+        }
+        self.doc_ids.remove(i);
+        self.term_frequencies.remove(i);
+        self.document_frequency -= 1;
+    }
+
+    fn heap_size(&self) -> usize {
+        self.doc_ids.capacity() * size_of::<DocId>()
+            + self.term_frequencies.capacity() * size_of::<u32>()
+            + self.offsets.capacity() * size_of::<u32>()
+            + self.positions.capacity() * size_of::<TermOffset>()
+    }
+}
+
+// Columnar postings layout compared against PositionList: one allocation
+// per array and term instead of one positions Vec per (term, document).
+// Term frequencies are read from the postings directly, so no
+// (term, doc) -> frequency map is kept. In memory only and without fields,
+// the engine always indexes into a PositionList.
+#[derive(Debug)]
+pub struct ColumnarPositionList {
+    postings_lists: HashMap<TermId, TermPostings>,
+    next_doc_id: DocId,
+    // value doc_id - 1 is used as vector index
+    document_length: Vec<u32>,
+    total_document_length: u64,
+    average_document_length: f32,
+    document_count: usize,
+    // doc-term list, for TF-IDF computing
+    doc_terms: HashMap<DocId, Vec<TermId>>,
+    // tombstones of deleted documents, their ids are never reused
+    deleted: HashSet<DocId>,
+}
+
+impl Default for ColumnarPositionList {
+    fn default() -> Self {
+        ColumnarPositionList::new()
+    }
+}
+
+impl ColumnarPositionList {
+    pub fn new() -> Self {
+        ColumnarPositionList {
+            postings_lists: HashMap::new(),
+            next_doc_id: 0,
+            document_length: vec![],
+            total_document_length: 0,
+            average_document_length: 0.0,
+            document_count: 0,
+            doc_terms: HashMap::new(),
+            deleted: HashSet::new(),
+        }
+    }
+
+    // approximate heap bytes used by the postings
+    pub fn postings_heap_size(&self) -> usize {
+        self.postings_lists.values().map(|postings| postings.heap_size()).sum()
+    }
+
+    fn index_document(&mut self, term_ids: &[TermId]) -> DocId {
+        let doc_id = self.next_doc_id();
+        let document_length = term_ids.len() as u32;
+        self.document_length.push(document_length);
+        self.total_document_length += document_length as u64;
+        self.document_count += 1;
+        let mut doc_terms = vec![];
+        for (seq, tid) in term_ids.iter().enumerate() {
+            let term_offset = seq as TermOffset + 1;
+            if *tid == STOPWORD_GAP {
+                continue;
+            }
+            let postings = self.postings_lists.entry(*tid).or_insert_with(TermPostings::new);
+            if postings.doc_ids.last() != Some(&doc_id) {
+                doc_terms.push(*tid);
+            }
+            postings.push(doc_id, term_offset);
+        }
+        self.doc_terms.insert(doc_id, doc_terms);
+        doc_id
+    }
+
+    fn update_average_document_length(&mut self) {
+        self.average_document_length = match self.document_count {
+            0 => 0.0,
+            count => self.total_document_length as f32 / count as f32,
+        };
+    }
+
+    // None once the last document containing the term is deleted
+    fn tfidf(&self, freq: f32, term: TermId) -> Option<f32> {
+        let df = self.postings_lists.get(&term)?.document_frequency as f32;
+        Some((freq.log2() + 1f32) * (self.document_count as f32 / df).log2())
+    }
+}
+
+impl SchemaDependIndex for ColumnarPositionList {
+    fn add_document(&mut self, term_ids: &Vec<TermId>) -> DocId {
+        let doc_id = self.index_document(term_ids);
+        self.update_average_document_length();
+        doc_id
+    }
+
+    fn add_documents(&mut self, docs: &[Vec<TermId>]) -> Vec<DocId> {
+        let doc_ids = docs.iter().map(|term_ids| self.index_document(term_ids)).collect();
+        if !docs.is_empty() {
+            self.update_average_document_length();
+        }
+        doc_ids
+    }
+
+    fn delete_document(&mut self, doc: DocId) -> bool {
+        if !self.is_valid_doc_id(doc) {
+            return false;
+        }
+        for term in self.doc_terms.remove(&doc).unwrap_or_default() {
+            if let Some(postings) = self.postings_lists.get_mut(&term) {
+                if let Some(i) = postings.find(doc) {
+                    postings.remove(i);
+                }
+                if postings.doc_ids.is_empty() {
+                    self.postings_lists.remove(&term);
+                }
+            }
+        }
+        let length = std::mem::take(&mut self.document_length[doc as usize - 1]);
+        self.total_document_length -= length as u64;
+        self.document_count -= 1;
+        self.update_average_document_length();
+        self.deleted.insert(doc);
+        true
+    }
+
+    fn next_doc_id(&mut self) -> DocId {
+        self.next_doc_id += 1;
+        self.next_doc_id
+    }
+
+    fn get_positions(&self, term: TermId, doc: DocId) -> Option<&[TermOffset]> {
+        let postings = self.postings_lists.get(&term)?;
+        let i = postings.find(doc)?;
+        Some(postings.positions_at(i))
+    }
+
+    // fields are not supported, documents are indexed as a whole
+    fn get_field_names(&self) -> &[String] {
+        &[]
+    }
+
+    fn get_field_lengths(&self, _doc: DocId) -> Option<&[u32]> {
+        None
+    }
+
+    fn get_total_field_lengths(&self) -> &[u64] {
+        &[]
+    }
+
+    fn get_position_fields(&self, _term: TermId, _doc: DocId) -> Option<&[FieldId]> {
+        None
+    }
+
+    fn get_term_occurences_num(&self, term: TermId) -> u32 {
+        match self.postings_lists.get(&term) {
+            Some(postings) => postings.positions.len() as u32,
+            None => 0,
+        }
+    }
+
+    fn get_document_count(&self) -> usize {
+        self.document_count
+    }
+
+    fn get_average_document_length(&self) -> f32 {
+        self.average_document_length
+    }
+
+    fn get_document_length(&self, doc: DocId) -> u32 {
+        self.document_length[doc as usize - 1]
+    }
+
+    fn get_term_frequency(&self, term: TermId, doc: DocId) -> Option<&u32> {
+        let postings = self.postings_lists.get(&term)?;
+        let i = postings.find(doc)?;
+        Some(&postings.term_frequencies[i])
+    }
+
+    fn get_document_frequency(&self, term: TermId) -> Option<&u32> {
+        self.postings_lists.get(&term).map(|postings| &postings.document_frequency)
+    }
+
+    fn docs(&self, term_id: TermId) -> Option<HashSet<DocId>> {
+        self.postings_lists.get(&term_id)
+            .map(|postings| postings.doc_ids.iter().copied().collect())
+    }
+
+    fn docs_contain_all(&self, term_list: &[TermId]) -> Option<HashSet<DocId>> {
+        let lists: Vec<&[DocId]> = distinct_terms(term_list).iter()
+            .filter_map(|term| self.postings_lists.get(term).map(|postings| postings.doc_ids.as_slice()))
+            .collect();
+        if lists.is_empty() {
+            return None;
+        }
+        Some(intersect_sorted(&lists, |doc| *doc).into_iter().collect())
+    }
+
+    fn docs_contain_any(&self, term_list: &[TermId]) -> HashSet<DocId> {
+        let mut doc_set = HashSet::new();
+        for term in distinct_terms(term_list) {
+            if let Some(postings) = self.postings_lists.get(&term) {
+                doc_set.extend(postings.doc_ids.iter().copied());
+            }
+        }
+        doc_set
+    }
+
+    fn docs_not_containing(&self, term: TermId) -> HashSet<DocId> {
+        let mut doc_set: HashSet<DocId> = (1..=self.document_length.len() as DocId)
+            .filter(|doc_id| !self.deleted.contains(doc_id))
+            .collect();
+        if let Some(postings) = self.postings_lists.get(&term) {
+            for doc_id in &postings.doc_ids {
+                doc_set.remove(doc_id);
+            }
+        }
+        doc_set
+    }
+
+    fn is_valid_doc_id(&self, doc_id: DocId) -> bool {
+        doc_id >= 1 && doc_id as usize <= self.document_length.len() && !self.deleted.contains(&doc_id)
+    }
+
+    fn get_doc_tfidf_vector(&self, doc: DocId) -> SparseVector {
+        assert!(self.is_valid_doc_id(doc));
+        let mut tfidf_vec = SparseVector::new();
+        if let Some(terms) = self.doc_terms.get(&doc) {
+            for term in terms {
+                let freq = *self.get_term_frequency(*term, doc).unwrap() as f32;
+                tfidf_vec.vec_set(*term, self.tfidf(freq, *term).unwrap());
+            }
+        }
+        tfidf_vec.vec_normalize();
+        tfidf_vec
+    }
+
+    fn doc_tfidf_dot(&self, doc: DocId, other: &SparseVector) -> f32 {
+        self.get_doc_tfidf_vector(doc).vec_dot(other)
+    }
+
+    fn get_phrase_tfidf_vector(&self, terms: &Vec<TermId>) -> Box<SparseVector> {
+        self.get_weighted_tfidf_vector(&unit_weights(terms))
+    }
+
+    fn get_weighted_tfidf_vector(&self, terms: &[WeightedTerm]) -> Box<SparseVector> {
+        let query_term_freq = query_term_weights(terms);
+        let mut query_tfidf = SparseVector::new();
+        for (&tid, &(count, weight)) in &query_term_freq {
+            let freq = count as f32;
+            if let Some(term_tfidf) = self.tfidf(freq, tid) {
+                query_tfidf.vec_set(tid, term_tfidf * weight / freq);
+            }
+        }
+        query_tfidf.vec_normalize();
+        Box::new(query_tfidf)
+    }
+
+    fn stats(&self, dict: &Dictionary) -> IndexStats {
+        let mut term_freq: Vec<(TermId, String, u32)> = self.postings_lists.iter()
+            .map(|(tid, postings)| (*tid, dict.get_term_by_id(*tid), postings.positions.len() as u32))
+            .collect();
+        term_freq.sort_by_key(|itm| Reverse(itm.2));
+        let doc_type_token_ratio = self.document_length.iter().enumerate()
+            .map(|(i, &length)| (i as DocId + 1, length))
+            .filter(|(doc_id, _)| !self.deleted.contains(doc_id))
+            .map(|(doc_id, length)| {
+                let distinct = self.doc_terms.get(&doc_id).map_or(0, |terms| terms.len());
+                (doc_id, type_token_ratio(distinct, length as u64))
+            })
+            .collect();
+        IndexStats {
+            total_document_length: self.total_document_length,
+            average_document_length: self.average_document_length,
+            document_count: self.document_count,
+            term_freq,
+            type_token_ratio: type_token_ratio(self.postings_lists.len(), self.total_document_length),
+            doc_type_token_ratio,
+            unique_term_count: self.postings_lists.len(),
+            total_posting_count: self.postings_lists.values().map(|postings| postings.doc_ids.len()).sum(),
+            max_posting_list_len: self.postings_lists.values().map(|postings| postings.doc_ids.len()).max().unwrap_or(0),
+        }
+    }
+
+    fn preload(&self) -> usize {
+        let mut visited = 0;
+        for postings in self.postings_lists.values() {
+            std::hint::black_box(&postings.doc_ids);
+            std::hint::black_box(&postings.term_frequencies);
+            std::hint::black_box(&postings.offsets);
+            visited += std::hint::black_box(postings.positions.iter()).count();
+        }
+        std::hint::black_box(&self.document_length);
+        visited
+    }
+
+    fn validate(&self) -> bool {
+        self.postings_lists.values().all(|postings|
+            postings.offsets.len() == postings.doc_ids.len() + 1
+            && postings.term_frequencies.len() == postings.doc_ids.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ircore::index::pl::PositionList;
+    use crate::ircore::token::analyzer::Analyzer;
+    use crate::ircore::ranking::Scorer;
+    use crate::ircore::RankingAlgorithm;
+    use std::time::Instant;
+
+    fn build_both(texts: &[&str]) -> (PositionList, ColumnarPositionList, Analyzer) {
+        let mut analyzer = Analyzer::new();
+        let mut idx = PositionList::new();
+        let mut columnar = ColumnarPositionList::new();
+        for text in texts {
+            let term_ids = analyzer.analyze(text);
+            assert_eq!(idx.add_document(&term_ids), columnar.add_document(&term_ids));
+        }
+        (idx, columnar, analyzer)
+    }
+
+    #[test]
+    fn test_columnar_same_results() {
+        let (idx, columnar, analyzer) = build_both(&[
+            "Do you quarrel, sir?",
+            "Quarrel sir! no, sir!",
+            "If you do, sir, I am for you: I serve as good a man as you.",
+            "No better.",
+            "Well, sir.",
+        ]);
+        assert!(columnar.validate());
+        assert_eq!(columnar.get_document_count(), idx.get_document_count());
+        assert_eq!(columnar.get_average_document_length(), idx.get_average_document_length());
+        assert_eq!(columnar.get_positions(4, 2), idx.get_positions(4, 2));
+        let (stats, expected) = (columnar.stats(analyzer.get_dictionary()), idx.stats(analyzer.get_dictionary()));
+        assert_eq!(stats.unique_term_count, expected.unique_term_count);
+        assert_eq!(stats.total_posting_count, expected.total_posting_count);
+        assert_eq!(stats.max_posting_list_len, expected.max_posting_list_len);
+        for query in ["quarrel sir", "sir", "you do", "no better", "as you"] {
+            let (term_ids, _) = analyzer.parse(query);
+            for ranking in [RankingAlgorithm::ExactMatch, RankingAlgorithm::VectorSpaceModel,
+                RankingAlgorithm::OkapiBM25, RankingAlgorithm::LMD, RankingAlgorithm::Bim] {
+                let mut expected: Vec<(DocId, f32)> = idx.score(&term_ids, ranking)
+                    .into_iter().map(|doc| (doc.docid, doc.score)).collect();
+                let mut actual: Vec<(DocId, f32)> = columnar.score(&term_ids, ranking)
+                    .into_iter().map(|doc| (doc.docid, doc.score)).collect();
+                expected.sort_by_key(|itm| itm.0);
+                actual.sort_by_key(|itm| itm.0);
+                assert_eq!(actual.len(), expected.len());
+                for (a, e) in actual.iter().zip(expected.iter()) {
+                    assert_eq!(a.0, e.0);
+                    assert!((a.1 - e.1).abs() <= f32::EPSILON);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_columnar_delete_document() {
+        let (mut idx, mut columnar, analyzer) = build_both(&[
+            "Do you quarrel, sir?",
+            "Quarrel sir! no, sir!",
+            "Well, sir.",
+        ]);
+        assert!(idx.delete_document(2));
+        assert!(columnar.delete_document(2));
+        assert!(!columnar.delete_document(2));
+        assert!(columnar.validate());
+        assert_eq!(columnar.get_document_count(), idx.get_document_count());
+        assert_eq!(columnar.get_average_document_length(), idx.get_average_document_length());
+        let (term_ids, _) = analyzer.parse("quarrel sir no");
+        for &term_id in &term_ids {
+            assert_eq!(columnar.docs(term_id), idx.docs(term_id));
+            assert_eq!(columnar.get_document_frequency(term_id), idx.get_document_frequency(term_id));
+            assert_eq!(columnar.get_term_occurences_num(term_id), idx.get_term_occurences_num(term_id));
+            assert_eq!(columnar.get_positions(term_id, 3), idx.get_positions(term_id, 3));
+        }
+        assert_eq!(columnar.score(&term_ids, RankingAlgorithm::OkapiBM25), idx.score(&term_ids, RankingAlgorithm::OkapiBM25));
+        // "no" only occurred in the deleted document
+        let (no, _) = analyzer.parse("no");
+        assert!(columnar.score(&no, RankingAlgorithm::VectorSpaceModel).is_empty());
+        let actual = columnar.score(&term_ids, RankingAlgorithm::VectorSpaceModel);
+        let expected = idx.score(&term_ids, RankingAlgorithm::VectorSpaceModel);
+        assert_eq!(actual.iter().map(|doc| doc.docid).collect::<Vec<_>>(), expected.iter().map(|doc| doc.docid).collect::<Vec<_>>());
+    }
+
+    // cargo test bench_columnar_layout -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_columnar_layout() {
+        let words = ["quarrel", "sir", "no", "better", "well", "you", "do", "good", "man", "serve"];
+        let mut texts = vec![];
+        for i in 0..5000usize {
+            let text: Vec<&str> = (0..200).map(|j| words[(i * 7 + j * j) % words.len()]).collect();
+            texts.push(text.join(" "));
+        }
+        let mut analyzer = Analyzer::new();
+        let docs: Vec<Vec<TermId>> = texts.iter().map(|text| analyzer.analyze(text)).collect();
+        let mut idx = PositionList::new();
+        let mut columnar = ColumnarPositionList::new();
+        let start = Instant::now();
+        idx.add_documents(&docs);
+        println!("nested build: {:?}, postings {} bytes", start.elapsed(), idx.postings_heap_size());
+        let start = Instant::now();
+        columnar.add_documents(&docs);
+        println!("columnar build: {:?}, postings {} bytes", start.elapsed(), columnar.postings_heap_size());
+        assert!(columnar.validate());
+        let (term_ids, _) = analyzer.parse("quarrel sir");
+        for ranking in [RankingAlgorithm::ExactMatch, RankingAlgorithm::OkapiBM25] {
+            let start = Instant::now();
+            let expected = idx.score(&term_ids, ranking);
+            let nested_time = start.elapsed();
+            let start = Instant::now();
+            let actual = columnar.score(&term_ids, ranking);
+            println!("{:?} query nested: {:?}, columnar: {:?}", ranking, nested_time, start.elapsed());
+            assert_eq!(actual.iter().map(|doc| doc.docid).collect::<Vec<_>>(),
+                expected.iter().map(|doc| doc.docid).collect::<Vec<_>>());
+        }
+    }
+}
@@ -1,297 +1,2946 @@
 use crate::ircore::index::pl::{SchemaDependIndex, PositionList, IndexStats};
-use crate::ircore::{DocId, RankingAlgorithm};
+use crate::ircore::{DocId, TermId, TermOffset, RankingAlgorithm};
 use crate::ircore::token::analyzer::{Analyzer, AnalyzerStats};
+use crate::ircore::token::sgmt::{Language, UnicodeForm};
+use crate::ircore::token::filter::FilterSpec;
 use std::path::Path;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 use crate::ircore::doc::Document;
-use crate::ircore::query::Query;
-use crate::ircore::ranking::Scorer;
+use crate::ircore::query::{Query, QueryNode, QueryType, SearchRequest, SearchHit, RankedHit, TieRanks, Highlight, JsonHit};
+use crate::ircore::query::cache::QueryCache;
+use crate::ircore::ranking::{Scorer, DocScore, RankingParams, WeightedTerm, RRF_K, fuse_rankings};
+use crate::ircore::ranking::lmd::DocPrior;
+use crate::ircore::ranking::bm25f::FieldWeights;
+use crate::ircore::ranking::ps::PhraseMatch;
 use crate::ircore::doc::doc_parser::DocParser;
-use crate::ircore::utils::serialize;
-use std::io;
+use crate::ircore::utils::serialize::{self, Encoding};
+use crate::ircore::utils::sparse_vector::{SparseVector, SparseVectorOp};
+use std::io::{self, Write};
+use std::fmt;
+use std::fs::{self, File};
+use rayon::prelude::*;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Engine {
     index: PositionList,
     analyzer: Analyzer,
+    #[serde(serialize_with = "serialize::sorted_map")]
     doc_meta: HashMap<DocId, String>,
+    #[serde(serialize_with = "serialize::sorted_map")]
+    doc_titles: HashMap<DocId, String>,
+    // language each document was analyzed as
+    #[serde(serialize_with = "serialize::sorted_map")]
+    doc_lang: HashMap<DocId, Language>,
+    // original text of documents, kept only with store_content
+    #[serde(serialize_with = "serialize::sorted_map")]
+    doc_content: HashMap<DocId, String>,
+    // first document id of each path in doc_meta, rebuilt on load
+    #[serde(skip)]
+    path_ids: HashMap<String, DocId>,
+    #[serde(skip)]
+    store_content: bool,
+    // save the partial index to checkpoint_path every n documents while building
+    #[serde(skip)]
+    checkpoint_every: Option<usize>,
+    #[serde(skip)]
+    checkpoint_path: String,
+    // skip larger corpus files while building, overrides the corpus config
+    #[serde(skip)]
+    max_file_bytes: Option<u64>,
+    // bumped on every mutation of the index
+    #[serde(skip)]
+    epoch: u64,
+    #[serde(skip)]
+    cache: Option<QueryCache>,
+    #[serde(skip)]
+    ranking_params: RankingParams,
+    // query terms in more than this fraction of documents are not scored
+    #[serde(skip)]
+    stopword_df_ratio: Option<f32>,
+    // unknown query terms match terms within this many edits
+    #[serde(skip)]
+    fuzzy_distance: Option<u8>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatrixFormat {
+    // Matrix Market coordinate format, 1-based
+    MatrixMarket,
+    // "rows cols nnz" line, then lines of row offsets, column indices and values,
+    // 0-based
+    Csr,
+}
+
+// why a build left the engine without documents
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildError {
+    // the corpus path does not exist
+    NotFound(String),
+    // nothing in the corpus could be indexed
+    NoDocuments(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::NotFound(path) => write!(f, "corpus {} does not exist", path),
+            BuildError::NoDocuments(path) => write!(f, "no documents found in {}", path),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
 pub struct Stats {
     pub index: IndexStats,
     pub analyzer: AnalyzerStats,
 }
 
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct TermStats {
+    pub term: String,
+    // number of documents containing the term
+    pub document_frequency: u32,
+    // number of times the term occurs in the whole collection
+    pub collection_frequency: u32,
+    // log2(N/df)
+    pub idf: f32,
+}
+
 impl Engine {
     const SERIALIZE_NAME_ANALYZER:&'static str = "idx.al";
     const SERIALIZE_NAME_DOCMETA: &'static str = "idx.dm";
+    const SERIALIZE_NAME_DOCTITLES: &'static str = "idx.dt";
+    const SERIALIZE_NAME_DOCCONTENT: &'static str = "idx.dc";
+    const SERIALIZE_NAME_DOCLANG: &'static str = "idx.lg";
+    // files parsed in parallel at a time while building
+    const BUILD_BATCH_FILES: usize = 64;
+
+    pub fn new() -> Self {
+        Engine{
+            index: PositionList::new(),
+            analyzer: Analyzer::new(),
+            doc_meta: HashMap::new(),
+            doc_titles: HashMap::new(),
+            doc_lang: HashMap::new(),
+            doc_content: HashMap::new(),
+            path_ids: HashMap::new(),
+            store_content: false,
+            checkpoint_every: None,
+            checkpoint_path: String::new(),
+            max_file_bytes: None,
+            epoch: 0,
+            cache: None,
+            ranking_params: RankingParams::default(),
+            stopword_df_ratio: None,
+            fuzzy_distance: None,
+        }
+    }
+
+    pub fn doc_count(&self) -> usize {
+        self.doc_meta.len()
+    }
+
+    // The index saved to path. A missing directory or part is ErrorKind::NotFound,
+    // a corrupt part ErrorKind::InvalidData. Doc titles, languages and content are
+    // optional, absent from indexes saved by older versions.
+    pub fn load_from(path: &str) -> io::Result<Self> {
+        let mut engine = Self::new();
+        engine.index = PositionList::load_from(path)?;
+        engine.analyzer = Self::load_part(path, Self::SERIALIZE_NAME_ANALYZER)?;
+        engine.analyzer.rebuild();
+        engine.doc_meta = Self::load_part(path, Self::SERIALIZE_NAME_DOCMETA)?;
+        for (&doc_id, doc_path) in &engine.doc_meta {
+            let first_id = engine.path_ids.entry(doc_path.clone()).or_insert(doc_id);
+            *first_id = (*first_id).min(doc_id);
+        }
+        engine.doc_titles = Self::load_optional_part(path, Self::SERIALIZE_NAME_DOCTITLES)?.unwrap_or_default();
+        engine.doc_lang = Self::load_optional_part(path, Self::SERIALIZE_NAME_DOCLANG)?.unwrap_or_default();
+        if let Some(doc_content) = Self::load_optional_part(path, Self::SERIALIZE_NAME_DOCCONTENT)? {
+            engine.doc_content = doc_content;
+            engine.store_content = true;
+        }
+        Ok(engine)
+    }
+
+    // load_from, or an empty engine if the index cannot be loaded
+    pub fn load_from_or_default(path: &str) -> Self {
+        Self::load_from(path).unwrap_or_else(|e| {
+            log::warn!("cannot load index from {}: {}", path, e);
+            Self::new()
+        })
+    }
+
+    // touch all postings to avoid latency spike on first queries,
+    // returns number of positions visited
+    pub fn preload(&self) -> usize {
+        self.index.preload()
+    }
+
+    fn load_part<T: DeserializeOwned>(path_str: &str, name: &str) -> io::Result<T> {
+        let path = Path::new(path_str).join(Path::new(name));
+        serialize::read_file_owned(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.to_string_lossy(), e)))
+    }
+
+    fn load_optional_part<T: DeserializeOwned>(path_str: &str, name: &str) -> io::Result<Option<T>> {
+        match Self::load_part(path_str, name) {
+            Ok(part) => Ok(Some(part)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // detect the language of each document, for indexes mixing languages
+    pub fn set_per_document_language(&mut self, per_document_language: bool) {
+        self.analyzer.set_per_document_language(per_document_language);
+    }
+
+    // stem English tokens at index and query time, set before building the index,
+    // saved with the index so queries keep matching after load
+    pub fn set_stemming(&mut self, stemming: bool) {
+        self.analyzer.set_stemming(stemming);
+    }
+
+    // words removed at index and query time, set before building the index,
+    // see Analyzer::set_stopwords for their effect on phrases
+    pub fn set_stopwords(&mut self, words: &[&str]) {
+        self.analyzer.set_stopwords(words);
+    }
+
+    // Unicode case folding instead of lowercasing, set before building the index
+    pub fn set_case_folding(&mut self, case_folding: bool) {
+        self.analyzer.set_case_folding(case_folding);
+    }
+
+    // keep the case of terms, set before building the index,
+    // queries then match the case of the indexed words
+    pub fn set_case_sensitive(&mut self, case_sensitive: bool) {
+        self.analyzer.set_case_sensitive(case_sensitive);
+    }
+
+    // NFC by default, NFKC also maps full-width and other compatibility forms,
+    // set before building the index
+    pub fn set_unicode_form(&mut self, unicode_form: UnicodeForm) {
+        self.analyzer.set_unicode_form(unicode_form);
+    }
+
+    // English tokens shorter than min_token_len are not indexed, set before building the index
+    pub fn set_min_token_len(&mut self, min_token_len: usize) {
+        self.analyzer.set_min_token_len(min_token_len);
+    }
+
+    // token filters run in order after segmentation, set before building the index
+    pub fn set_token_filters(&mut self, filters: Vec<FilterSpec>) {
+        self.analyzer.set_filters(filters);
+    }
+
+    // analyze documents larger than chunk_bytes in chunks to bound memory,
+    // None to analyze each document at once
+    pub fn set_analyze_chunk_bytes(&mut self, chunk_bytes: Option<usize>) {
+        self.analyzer.set_chunk_bytes(chunk_bytes);
+    }
+
+    // keep the original text of documents indexed from now on,
+    // needed by passages
+    pub fn set_store_content(&mut self, store_content: bool) {
+        self.store_content = store_content;
+    }
+
+    // checkpoint the partial index to path every checkpoint_every documents
+    // during builds, None to disable
+    pub fn set_checkpoint(&mut self, path: &str, checkpoint_every: Option<usize>) {
+        self.checkpoint_path = path.to_string();
+        self.checkpoint_every = checkpoint_every;
+    }
+
+    // skip corpus files larger than max_file_bytes while building,
+    // None to use the max_file_bytes of the corpus config, unlimited by default
+    pub fn set_max_file_bytes(&mut self, max_file_bytes: Option<u64>) {
+        self.max_file_bytes = max_file_bytes;
+    }
+
+    // number of documents added by this build, the engine may hold others
+    pub fn build_index_from(&mut self, path: &str) -> Result<usize, BuildError> {
+        self.build_index_with_progress(path, &mut |_, _| ())
+    }
+
+    // progress is called with (files done, total files) after each batch of files
+    pub fn build_index_with_progress(&mut self, path: &str, progress: &mut dyn FnMut(usize, usize))
+        -> Result<usize, BuildError> {
+        self.build(path, false, progress)
+    }
+
+    // continue a build interrupted after a checkpoint, on an engine loaded from it,
+    // documents already indexed are skipped
+    pub fn resume_index_from(&mut self, path: &str) -> Result<usize, BuildError> {
+        self.build(path, true, &mut |_, _| ())
+    }
+
+    fn build(&mut self, path: &str, skip_indexed: bool, progress: &mut dyn FnMut(usize, usize))
+        -> Result<usize, BuildError> {
+        if !Path::new(path).exists() {
+            return Err(BuildError::NotFound(path.to_string()));
+        }
+        let indexed: HashSet<String> = match skip_indexed {
+            true => self.doc_meta.values().cloned().collect(),
+            false => HashSet::new(),
+        };
+        let mut doc_parser = DocParser::new(path);
+        if self.max_file_bytes.is_some() {
+            doc_parser.set_max_file_bytes(self.max_file_bytes);
+        }
+        for (word, freq) in doc_parser.get_config().get_user_words() {
+            self.analyzer.add_user_word(word, freq);
+        }
+        if let Some(shingles) = doc_parser.get_config().get_shingles() {
+            self.analyzer.set_shingles(Some(shingles));
+        }
+        let total = doc_parser.count_files();
+        let mut docs_iter = doc_parser.docs();
+        let mut since_checkpoint = 0;
+        let mut added = 0;
+        let mut skipped = 0;
+        // files are parsed and documents tokenized in parallel, term and doc ids
+        // are assigned in walk order, so the index does not depend on thread count
+        while let Some(mut batch) = docs_iter.next_batch(Self::BUILD_BATCH_FILES) {
+            for docs in batch.iter_mut() {
+                let len = docs.len();
+                docs.retain(|doc| !indexed.contains(doc.get_path()));
+                skipped += len - docs.len();
+            }
+            let mut tokenized = self.tokenize_documents(&batch.iter().flatten().collect::<Vec<_>>())
+                .into_iter();
+            for docs in &batch {
+                let docs: Vec<&Document> = docs.iter().collect();
+                self.merge_documents(&docs, tokenized.by_ref().take(docs.len()).collect());
+                log::debug!("{}", self.index.get_document_count());
+                added += docs.len();
+                since_checkpoint += docs.len();
+                if let Some(every) = self.checkpoint_every {
+                    if since_checkpoint >= every {
+                        since_checkpoint = 0;
+                        let checkpoint_path = self.checkpoint_path.clone();
+                        if let Err(e) = self.save_to(&checkpoint_path) {
+                            log::warn!("cannot save checkpoint to {}: {}", checkpoint_path, e);
+                        }
+                    }
+                }
+            }
+            progress(docs_iter.visited(), total);
+        }
+        // files failed to parse at the end are not reported by the loop
+        progress(docs_iter.visited(), total);
+        log::debug!("build index completed, number of doc: {}", self.doc_count());
+        // nothing to index under path, unless a resumed build finds it all indexed
+        if added == 0 && skipped == 0 {
+            return Err(BuildError::NoDocuments(path.to_string()));
+        }
+        Ok(added)
+    }
+
+    // an engine caching the ranked results of up to capacity queries,
+    // least recently used first out, invalidated on index mutation
+    pub fn with_cache(capacity: usize) -> Self {
+        Engine { cache: Some(QueryCache::with_capacity(capacity)), ..Engine::new() }
+    }
+
+    // cache ranked results of queries, invalidated on index mutation
+    pub fn enable_cache(&mut self) {
+        if self.cache.is_none() {
+            self.cache = Some(QueryCache::new());
+        }
+    }
+
+    pub fn get_cache(&self) -> Option<&QueryCache> {
+        self.cache.as_ref()
+    }
+
+    // round scores to precision decimal places when ordering results,
+    // keeps page boundaries stable across requests
+    pub fn set_score_precision(&mut self, precision: Option<u32>) {
+        self.ranking_params.score_precision = precision;
+        self.bump_epoch();
+    }
+
+    // terms in more than df_ratio (0.0 - 1.0) of all documents
+    pub fn auto_stopwords(&self, df_ratio: f32) -> HashSet<String> {
+        let document_count = self.index.get_document_count();
+        (1..=self.analyzer.get_dictionary().get_term_count() as TermId)
+            .filter(|term_id| self.is_stopword(*term_id, df_ratio, document_count))
+            .map(|term_id| self.analyzer.get_term_by_id(term_id))
+            .collect()
+    }
+
+    // ignore auto_stopwords(df_ratio) in ranked queries, None to score all terms,
+    // phrase matching still uses them
+    pub fn set_auto_stopwords(&mut self, df_ratio: Option<f32>) {
+        self.stopword_df_ratio = df_ratio;
+        self.bump_epoch();
+    }
+
+    fn is_stopword(&self, term_id: TermId, df_ratio: f32, document_count: usize) -> bool {
+        match self.index.get_document_frequency(term_id) {
+            Some(df) => *df as f32 > df_ratio * document_count as f32,
+            None => false,
+        }
+    }
+
+    // rank by term frequency instead of IDF based rankings while the index
+    // has less than min_docs documents, None to disable
+    pub fn set_min_idf_docs(&mut self, min_docs: Option<usize>) {
+        self.ranking_params.min_idf_docs = min_docs;
+        self.bump_epoch();
+    }
+
+    // ExactMatch scores phrase occurrences per 1000 tokens instead of raw counts
+    pub fn set_phrase_per_length(&mut self, phrase_per_length: bool) {
+        self.ranking_params.phrase_per_length = phrase_per_length;
+        self.bump_epoch();
+    }
+
+    // count at most cap occurrences of a term per document in BM25 and LMD,
+    // None for no cap
+    pub fn set_tf_cap(&mut self, cap: Option<u32>) {
+        self.ranking_params.bm25.tf_cap = cap;
+        self.ranking_params.lmd.tf_cap = cap;
+        self.bump_epoch();
+    }
+
+    // BM25 weight saturation k1 (1.2 by default, at least 0) and
+    // document length normalization b (0.75 by default, within [0, 1])
+    pub fn set_bm25_k1_b(&mut self, k1: f32, b: f32) {
+        self.ranking_params.bm25.set_k1_b(k1, b);
+        self.bump_epoch();
+    }
+
+    // weigh matches by field with BM25F in Default and OkapiBM25 ranking,
+    // None for plain BM25
+    pub fn set_field_weights(&mut self, weights: Option<FieldWeights>) {
+        self.ranking_params.field_weights = weights;
+        self.bump_epoch();
+    }
+
+    // log per term score contributions of ranked queries at trace level,
+    // scores stay the same, so cached queries are not scored, nor traced, again
+    pub fn set_trace_scores(&mut self, enabled: bool) {
+        self.ranking_params.trace_scores = enabled;
+    }
+
+    // Dirichlet smoothing mass of LMD ranking, None for the average document length
+    pub fn set_lmd_mu(&mut self, mu: Option<f32>) {
+        self.ranking_params.lmd.mu = match mu {
+            Some(mu) if mu <= 0.0 => {
+                log::warn!("lmd mu {} is not positive, using average document length", mu);
+                None
+            },
+            _ => mu,
+        };
+        self.bump_epoch();
+    }
+
+    // document prior of LMD ranking, uniform by default
+    pub fn set_lmd_prior(&mut self, prior: DocPrior) {
+        self.ranking_params.lmd.prior = prior;
+        self.bump_epoch();
+    }
+
+    // expand query terms not in the dictionary into terms within max_distance
+    // edits (1 or 2 are sensible), None to disable
+    pub fn set_fuzzy(&mut self, max_distance: Option<u8>) {
+        self.fuzzy_distance = max_distance;
+        self.bump_epoch();
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    fn bump_epoch(&mut self) {
+        self.epoch += 1;
+        if let Some(cache) = &self.cache {
+            cache.evict_before(self.epoch);
+        }
+    }
+
+    // drop all documents and terms
+    pub fn clear(&mut self) {
+        self.index = PositionList::new();
+        self.analyzer.clear_dictionary();
+        self.doc_meta = HashMap::new();
+        self.path_ids = HashMap::new();
+        self.doc_titles = HashMap::new();
+        self.doc_lang = HashMap::new();
+        self.doc_content = HashMap::new();
+        self.bump_epoch();
+    }
+
+    // drop one document, its id is not reused, false if it is not indexed
+    pub fn remove_document(&mut self, doc_id: DocId) -> bool {
+        if !self.index.delete_document(doc_id) {
+            return false;
+        }
+        if let Some(doc_path) = self.doc_meta.remove(&doc_id) {
+            // the path moves on to its next document, if any
+            if self.path_ids.get(&doc_path) == Some(&doc_id) {
+                let next_id = self.doc_meta.iter()
+                    .filter(|(_, path)| **path == doc_path)
+                    .map(|(&id, _)| id)
+                    .min();
+                match next_id {
+                    Some(next_id) => self.path_ids.insert(doc_path, next_id),
+                    None => self.path_ids.remove(&doc_path),
+                };
+            }
+        }
+        self.doc_titles.remove(&doc_id);
+        self.doc_lang.remove(&doc_id);
+        self.doc_content.remove(&doc_id);
+        self.bump_epoch();
+        true
+    }
+
+    // index the documents parsed from one file, with the config of its directory,
+    // returns the id of the first one (files like jsonlines hold several documents)
+    pub fn add_file(&mut self, path: &str) -> io::Result<DocId> {
+        if !Path::new(path).is_file() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not a file", path)));
+        }
+        let doc_parser = DocParser::new(path);
+        let docs: Vec<Document> = doc_parser.docs().flatten().collect();
+        let ids = self.add_documents(&docs);
+        log::debug!("{} documents added from {}", ids.len(), path);
+        match ids.first() {
+            Some(&doc_id) => Ok(doc_id),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, format!("no document parsed from {}", path))),
+        }
+    }
+
+    // documents with fields are indexed field by field, the others as a whole
+    fn add_documents(&mut self, docs: &[Document]) -> Vec<DocId> {
+        let docs: Vec<&Document> = docs.iter().collect();
+        let tokenized = self.tokenize_documents(&docs);
+        self.merge_documents(&docs, tokenized)
+    }
+
+    // Read-only and parallel first phase of add_documents, the tokens of each
+    // field of each document. Documents analyzed in chunks are None and left to
+    // merge_documents, to bound memory.
+    fn tokenize_documents(&mut self, docs: &[&Document]) -> Vec<Option<(Vec<Vec<String>>, Language)>> {
+        if let Some(doc) = docs.first() {
+            self.analyzer.settle_language(&Self::field_texts(doc).join("\n"));
+        }
+        let analyzer = &self.analyzer;
+        docs.par_iter()
+            .map(|doc| match doc.get_fields().is_empty() {
+                true if analyzer.is_chunked(doc.get_content()) => None,
+                true => {
+                    let (tokens, lang) = analyzer.tokenize(doc.get_content());
+                    Some((vec![tokens], lang))
+                },
+                false => Some(analyzer.tokenize_fields(&Self::field_texts(doc))),
+            })
+            .collect()
+    }
+
+    // serial second phase, term and doc ids are assigned in the order of docs
+    fn merge_documents(&mut self, docs: &[&Document], tokenized: Vec<Option<(Vec<Vec<String>>, Language)>>)
+        -> Vec<DocId> {
+        let mut ids = vec![];
+        let mut langs = vec![];
+        for (doc, tokens) in docs.iter().zip(tokenized) {
+            let (id, lang) = match tokens {
+                None => {
+                    let (term_ids, lang) = self.analyzer.analyze_with_language(doc.get_content());
+                    (self.index.add_document(&term_ids), lang)
+                },
+                Some((tokens, lang)) if doc.get_fields().is_empty() => {
+                    let term_ids = self.analyzer.merge_tokens(&tokens[0]);
+                    (self.index.add_document(&term_ids), lang)
+                },
+                Some((tokens, lang)) => {
+                    let fields: Vec<(&str, Vec<TermId>)> = doc.get_fields().iter()
+                        .zip(&tokens)
+                        .map(|((name, _), field_tokens)| (name.as_str(), self.analyzer.merge_tokens(field_tokens)))
+                        .collect();
+                    (self.index.add_document_fields(&fields), lang)
+                },
+            };
+            ids.push(id);
+            langs.push(lang);
+        }
+        for ((&id, doc), lang) in ids.iter().zip(docs).zip(langs) {
+            self.doc_meta.insert(id, doc.get_path().to_owned());
+            // ids only grow, an earlier document of the path keeps its place
+            self.path_ids.entry(doc.get_path().to_owned()).or_insert(id);
+            self.doc_lang.insert(id, lang);
+            if let Some(title) = doc.get_title() {
+                self.doc_titles.insert(id, title.to_owned());
+            }
+            if self.store_content {
+                self.doc_content.insert(id, doc.get_content().to_owned());
+            }
+        }
+        self.bump_epoch();
+        ids
+    }
+
+    // the content of a document without fields is its only field
+    fn field_texts(doc: &Document) -> Vec<&str> {
+        match doc.get_fields().is_empty() {
+            true => vec![doc.get_content()],
+            false => doc.get_fields().iter().map(|(_, text)| text.as_str()).collect(),
+        }
+    }
+
+    pub fn save_to(&mut self, path_str: &str) -> io::Result<()> {
+        self.save_to_with(path_str, Encoding::default())
+    }
+
+    // Encoding::Raw for faster loading at the cost of disk space, a gzip level
+    // trades save time for size, load_from reads any of them
+    pub fn save_to_with(&mut self, path_str: &str, encoding: Encoding) -> io::Result<()> {
+        self.index.save_to_with(path_str, encoding)?;
+        Self::save_part(path_str, Self::SERIALIZE_NAME_ANALYZER, &self.analyzer, encoding, "analyzer")?;
+        Self::save_part(path_str, Self::SERIALIZE_NAME_DOCMETA, &serialize::sorted(&self.doc_meta), encoding, "docmeta")?;
+        Self::save_part(path_str, Self::SERIALIZE_NAME_DOCTITLES, &serialize::sorted(&self.doc_titles), encoding, "doc titles")?;
+        Self::save_part(path_str, Self::SERIALIZE_NAME_DOCLANG, &serialize::sorted(&self.doc_lang), encoding, "doc languages")?;
+        if self.store_content {
+            Self::save_part(path_str, Self::SERIALIZE_NAME_DOCCONTENT, &serialize::sorted(&self.doc_content), encoding, "doc content")?;
+        }
+        Ok(())
+    }
+
+    // query time subset of save_to, loaded by load_from as well: no stored content
+    // (passages are not available) and no data that can be rebuilt on load
+    pub fn save_query_index(&mut self, path_str: &str) -> io::Result<()> {
+        self.index.save_query_to(path_str)?;
+        let terms = self.analyzer.take_reverse_terms();
+        let res = Self::save_part(path_str, Self::SERIALIZE_NAME_ANALYZER, &self.analyzer, Encoding::default(), "analyzer");
+        self.analyzer.restore_reverse_terms(terms);
+        res?;
+        self.save_docmeta(path_str)?;
+        self.save_doctitles(path_str)?;
+        self.save_doclang(path_str)?;
+        // left by an earlier full save
+        let content_path = Path::new(path_str).join(Self::SERIALIZE_NAME_DOCCONTENT);
+        if content_path.exists() {
+            fs::remove_file(content_path)?;
+        }
+        Ok(())
+    }
+
+    fn save_part<T: Serialize>(path_str: &str, name: &str, obj: &T, encoding: Encoding, what: &str)
+        -> io::Result<()> {
+        let path = Path::new(path_str).join(Path::new(name));
+        serialize::write_file_with(&path, obj, encoding)?;
+        log::debug!("{} save to {}", what, path.to_string_lossy());
+        Ok(())
+    }
+
+    pub fn save_analyzer(&mut self, path_str: &str) -> io::Result<()> {
+        Self::save_part(path_str, Self::SERIALIZE_NAME_ANALYZER, &self.analyzer, Encoding::default(), "analyzer")
+    }
+
+    pub fn save_docmeta(&mut self, path_str: &str) -> io::Result<()> {
+        Self::save_part(path_str, Self::SERIALIZE_NAME_DOCMETA, &serialize::sorted(&self.doc_meta), Encoding::default(), "docmeta")
+    }
+
+    pub fn save_doctitles(&mut self, path_str: &str) -> io::Result<()> {
+        Self::save_part(path_str, Self::SERIALIZE_NAME_DOCTITLES, &serialize::sorted(&self.doc_titles), Encoding::default(), "doc titles")
+    }
+
+    pub fn save_doclang(&mut self, path_str: &str) -> io::Result<()> {
+        Self::save_part(path_str, Self::SERIALIZE_NAME_DOCLANG, &serialize::sorted(&self.doc_lang), Encoding::default(), "doc languages")
+    }
+
+    pub fn get_language(&self, doc_id: DocId) -> Option<Language> {
+        self.doc_lang.get(&doc_id).copied()
+    }
+
+    pub fn save_doccontent(&mut self, path_str: &str) -> io::Result<()> {
+        Self::save_part(path_str, Self::SERIALIZE_NAME_DOCCONTENT, &serialize::sorted(&self.doc_content), Encoding::default(), "doc content")
+    }
+
+    pub fn get_title(&self, doc_id: DocId) -> Option<&String> {
+        self.doc_titles.get(&doc_id)
+    }
+
+    // id of the first document indexed from the path
+    pub fn get_doc_id(&self, doc_path: &str) -> Option<DocId> {
+        self.path_ids.get(doc_path).copied()
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats{
+            index: self.index.stats(self.analyzer.get_dictionary()),
+            analyzer: self.analyzer.stats(),
+        }
+    }
+
+    // stats of the term word analyzes to, None unless it is a single indexed term
+    pub fn term_stats(&self, word: &str) -> Option<TermStats> {
+        let (term_ids, unknown) = self.analyzer.parse(word);
+        if term_ids.len() != 1 || !unknown.is_empty() {
+            return None;
+        }
+        let term_id = term_ids[0];
+        let document_frequency = *self.index.get_document_frequency(term_id)?;
+        Some(TermStats{
+            term: self.analyzer.get_term_by_id(term_id),
+            document_frequency,
+            collection_frequency: self.index.get_term_occurences_num(term_id),
+            idf: (self.index.get_document_count() as f32 / document_frequency as f32).log2(),
+        })
+    }
+
+    // snapshot stats of the given words as json, unknown words are skipped
+    pub fn save_term_stats(&self, words: &[&str], path_str: &str) -> io::Result<()> {
+        let path = Path::new(path_str);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let snapshot: Vec<TermStats> = words.iter()
+            .filter_map(|word| self.term_stats(word))
+            .collect();
+        serde_json::to_writer_pretty(File::create(path)?, &snapshot)?;
+        log::debug!("term stats save to {}", path.to_string_lossy());
+        Ok(())
+    }
+
+    pub fn load_term_stats(path_str: &str) -> io::Result<Vec<TermStats>> {
+        let snapshot = serde_json::from_reader(File::open(path_str)?)?;
+        Ok(snapshot)
+    }
+
+    // export TF-IDF weights as a documents x vocabulary sparse matrix,
+    // row of document id d is d (d - 1 when 0-based), column of term id t likewise
+    pub fn export_matrix(&self, path_str: &str, format: MatrixFormat) -> io::Result<()> {
+        let rows = self.doc_meta.keys().max().copied().unwrap_or(0);
+        let cols = self.analyzer.get_dictionary().get_term_count();
+        let mut entries: Vec<(DocId, TermId, f32)> = vec![];
+        for doc_id in 1..=rows {
+            if !self.doc_meta.contains_key(&doc_id) {
+                continue;
+            }
+            // terms in every document weigh 0
+            let mut weights: Vec<(TermId, f32)> = self.index.get_doc_tfidf_vector(doc_id).into_iter()
+                .filter(|(_, weight)| *weight != 0.0 && !weight.is_nan())
+                .collect();
+            weights.sort_by_key(|(term_id, _)| *term_id);
+            entries.extend(weights.into_iter().map(|(term_id, weight)| (doc_id, term_id, weight)));
+        }
+
+        if let Some(dir) = Path::new(path_str).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut writer = io::BufWriter::new(File::create(path_str)?);
+        match format {
+            MatrixFormat::MatrixMarket => {
+                writeln!(writer, "%%MatrixMarket matrix coordinate real general")?;
+                writeln!(writer, "{} {} {}", rows, cols, entries.len())?;
+                for (doc_id, term_id, weight) in &entries {
+                    writeln!(writer, "{} {} {}", doc_id, term_id, weight)?;
+                }
+            },
+            MatrixFormat::Csr => {
+                let mut offsets = vec![0usize; rows as usize + 1];
+                for (doc_id, _, _) in &entries {
+                    offsets[*doc_id as usize] += 1;
+                }
+                for i in 1..offsets.len() {
+                    offsets[i] += offsets[i - 1];
+                }
+                let join = |values: Vec<String>| values.join(" ");
+                writeln!(writer, "{} {} {}", rows, cols, entries.len())?;
+                writeln!(writer, "{}", join(offsets.iter().map(|o| o.to_string()).collect()))?;
+                writeln!(writer, "{}", join(entries.iter().map(|(_, t, _)| (t - 1).to_string()).collect()))?;
+                writeln!(writer, "{}", join(entries.iter().map(|(_, _, w)| w.to_string()).collect()))?;
+            },
+        }
+        writer.flush()
+    }
+
+    // split the query into terms known by the index and unknown ones (OOV)
+    pub fn analyze_query(&self, phrase_str: &str) -> (Vec<String>, Vec<String>) {
+        let (term_ids, unknown_terms) = self.analyzer.parse(phrase_str);
+        let known_terms = term_ids.into_iter()
+            .map(|term_id| self.analyzer.get_term_by_id(term_id))
+            .collect();
+        (known_terms, unknown_terms)
+    }
+
+    // ids of the query terms known by the index
+    pub fn query_term_ids(&self, phrase_str: &str) -> Vec<TermId> {
+        if Query::has_boosts(phrase_str) {
+            return Query::parse_weighted(phrase_str, true, &self.analyzer, self.fuzzy_distance)
+                .into_iter().map(|(term_id, _)| term_id).collect();
+        }
+        Query::parse_fuzzy(phrase_str, true, &self.analyzer, self.fuzzy_distance)
+    }
+
+    // window tokens either side of the first query term in the document, re-read from disk,
+    // with matched terms wrapped in **, None if the file is gone or no term matches
+    pub fn snippet(&self, doc: DocId, terms: &[TermId], window: usize) -> Option<String> {
+        let content = &self.reread_content(doc)?;
+        let spans = self.analyzer.word_spans(content);
+        // a matched shingle highlights the words it joins
+        let hits: Vec<(usize, usize)> = self.analyzer.token_spans(content).into_iter()
+            .filter(|&(start, end)| self.analyzer.parse(&content[start..end]).0.iter().any(|id| terms.contains(id)))
+            .collect();
+        let matched: Vec<bool> = spans.iter()
+            .map(|&(start, end)| hits.iter().any(|&(hit_start, hit_end)| hit_start <= start && end <= hit_end))
+            .collect();
+        let first = matched.iter().position(|&m| m)?;
+        let start = first.saturating_sub(window);
+        let end = (first + window + 1).min(spans.len());
+        let mut snippet = String::new();
+        for i in start..end {
+            if i > start {
+                snippet.push_str(&content[spans[i - 1].1..spans[i].0]);
+            }
+            let token = &content[spans[i].0..spans[i].1];
+            match matched[i] {
+                true => snippet.push_str(&format!("**{}**", token)),
+                false => snippet.push_str(token),
+            }
+        }
+        Some(snippet.split_whitespace().collect::<Vec<&str>>().join(" "))
+    }
+
+    // content of the document parsed again from its file, None if the file is gone
+    fn reread_content(&self, doc: DocId) -> Option<String> {
+        let path = self.doc_meta.get(&doc)?;
+        if !Path::new(path).is_file() {
+            log::debug!("{} no longer exists", path);
+            return None;
+        }
+        // files like jsonlines hold several documents with consecutive ids
+        let first_id = self.get_doc_id(path)?;
+        let document = DocParser::new(path).docs().flatten().nth((doc - first_id) as usize)?;
+        Some(document.get_content().to_owned())
+    }
+
+    // (start, end) token positions of every occurrence of the phrase in the document,
+    // positions count from 1, empty if a word of the phrase is not in the index
+    pub fn match_positions(&self, doc: DocId, phrase: &str) -> Vec<(TermOffset, TermOffset)> {
+        let term_ids = Query::parse(phrase, false, &self.analyzer);
+        if term_ids.is_empty() {
+            return vec![];
+        }
+        self.index.phrase_positions(doc, &term_ids)
+    }
+
+    // match_positions as byte ranges of the document content, re-read from disk,
+    // for a file holding several documents (jsonlines) the ranges are within
+    // the content of this one, None if the file is gone
+    pub fn match_byte_ranges(&self, doc: DocId, phrase: &str) -> Option<Vec<(usize, usize)>> {
+        let content = self.reread_content(doc)?;
+        let spans = self.analyzer.token_spans(&content);
+        let ranges = self.match_positions(doc, phrase).into_iter()
+            .filter_map(|(start, end)| {
+                let first = spans.get(start.checked_sub(1)? as usize)?;
+                let last = spans.get(end.checked_sub(1)? as usize)?;
+                Some((first.0, last.1))
+            })
+            .collect();
+        Some(ranges)
+    }
+
+    // paths of documents containing none of the terms in the phrase
+    pub fn docs_not_containing(&self, phrase_str: &str) -> Vec<&String> {
+        let (term_ids, _) = self.analyzer.parse(phrase_str);
+        let mut doc_set: HashSet<DocId> = self.doc_meta.keys().copied().collect();
+        for term_id in term_ids {
+            doc_set = &doc_set & &self.index.docs_not_containing(term_id);
+        }
+        let mut doc_ids: Vec<DocId> = doc_set.into_iter().collect();
+        doc_ids.sort();
+        doc_ids.iter().filter_map(|doc_id| self.doc_meta.get(doc_id)).collect()
+    }
+
+    // top_n passages of passage_len tokens covering most distinct query terms,
+    // densest first, requires store_content
+    pub fn passages(&self, doc_path: &str, terms: &str, passage_len: usize, top_n: usize) -> Vec<String> {
+        let mut passages = vec![];
+        let doc_id = match self.get_doc_id(doc_path) {
+            Some(doc_id) => doc_id,
+            None => return passages,
+        };
+        let content = match self.doc_content.get(&doc_id) {
+            Some(content) => content,
+            None => {
+                log::warn!("content of {} is not stored", doc_path);
+                return passages;
+            }
+        };
+        if passage_len == 0 {
+            return passages;
+        }
+        let spans = self.analyzer.token_spans(content);
+        let (mut term_ids, _) = self.analyzer.parse(terms);
+        term_ids.sort();
+        term_ids.dedup();
+        // (position, term) of all query term occurrences
+        let mut hits: Vec<(usize, TermId)> = vec![];
+        for &term_id in &term_ids {
+            if self.index.get_term_frequency(term_id, doc_id).is_some() {
+                if let Some(positions) = self.index.get_positions(term_id, doc_id) {
+                    hits.extend(positions.iter().map(|&pos| (pos as usize, term_id)));
+                }
+            }
+        }
+        hits.sort();
+        // score windows [start, start + passage_len) by distinct terms, then hits
+        let last_start = spans.len().saturating_sub(passage_len) + 1;
+        let mut windows: Vec<(usize, usize, usize)> = vec![];
+        for start in 1..=last_start {
+            let in_window: Vec<TermId> = hits.iter()
+                .filter(|(pos, _)| *pos >= start && *pos < start + passage_len)
+                .map(|(_, term_id)| *term_id)
+                .collect();
+            if in_window.is_empty() {
+                continue;
+            }
+            let distinct: HashSet<&TermId> = in_window.iter().collect();
+            windows.push((distinct.len(), in_window.len(), start));
+        }
+        windows.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.cmp(&a.1)).then(a.2.cmp(&b.2)));
+        // greedily take the best windows not overlapping each other
+        let mut taken: Vec<usize> = vec![];
+        for (_, _, start) in windows {
+            if taken.len() == top_n {
+                break;
+            }
+            if taken.iter().all(|&other| start + passage_len <= other || other + passage_len <= start) {
+                taken.push(start);
+                let end = (start + passage_len - 1).min(spans.len());
+                passages.push(content[spans[start - 1].0..spans[end - 1].1].to_string());
+            }
+        }
+        passages
+    }
+
+    // reasons why the document does not match the phrase,
+    // empty if it matches as an exact phrase
+    pub fn explain_miss(&self, phrase_str: &str, doc_path: &str) -> Vec<String> {
+        let mut reasons = vec![];
+        let doc_id = match self.get_doc_id(doc_path) {
+            Some(doc_id) => doc_id,
+            None => {
+                reasons.push(format!("document {} is not indexed", doc_path));
+                return reasons;
+            }
+        };
+        let (term_ids, unknown_terms) = self.analyzer.parse(phrase_str);
+        for term in unknown_terms {
+            reasons.push(format!("term \"{}\" is not in the index", term));
+        }
+        for &term_id in &term_ids {
+            if self.index.get_term_frequency(term_id, doc_id).is_none() {
+                reasons.push(format!("term \"{}\" is absent from the document",
+                    self.analyzer.get_term_by_id(term_id)));
+            }
+        }
+        if !reasons.is_empty() {
+            reasons.push("not all query terms are present, required by exact match".to_string());
+        }else if !term_ids.is_empty() && self.index.phrase_positions(doc_id, &term_ids).is_empty() {
+            reasons.push("all terms are present but not adjacent as a phrase".to_string());
+        }
+        reasons
+    }
+
+    // precompute document vectors for VSM, dropped again by the next index update
+    pub fn build_tfidf_cache(&mut self, quantize_tfidf: bool) {
+        self.index.build_tfidf_cache(quantize_tfidf);
+        self.bump_epoch();
+    }
+
+    pub fn exec_query(&self, 
+        phrase_str: &str,
+        ranking: RankingAlgorithm,
+        ) -> Vec<&String>{
+        
+        self.exec_query_scored(phrase_str, ranking).into_iter()
+            .map(|(doc_path, _)| doc_path)
+            .collect()
+    }
+
+    // one page of exec_query results, limit results after skipping offset
+    pub fn exec_query_paged(&self,
+        phrase_str: &str,
+        ranking: RankingAlgorithm,
+        offset: usize,
+        limit: usize,
+        ) -> Vec<&String>{
+
+        let (_, hits) = self.exec_query_page(phrase_str, ranking, None, offset, limit);
+        hits.into_iter().map(|(_, doc_path, _)| doc_path).collect()
+    }
+
+    // one page of exec_query_hits, offset and limit apply after min_score,
+    // along with the number of results over all pages,
+    // paths are only resolved for the page
+    pub fn exec_query_page(&self,
+        phrase_str: &str,
+        ranking: RankingAlgorithm,
+        min_score: Option<f32>,
+        offset: usize,
+        limit: usize,
+        ) -> (usize, Vec<(DocId, &String, f32)>){
+
+        let doc_scores: Vec<DocScore> = self.query(phrase_str, ranking).into_iter()
+            .filter(|doc| min_score.is_none_or(|min_score| doc.score >= min_score))
+            .collect();
+        let total = doc_scores.len();
+        let hits = doc_scores.into_iter()
+            .skip(offset)
+            .take(limit)
+            .filter_map(|doc| self.doc_meta.get(&doc.docid).map(|doc_path| (doc.docid, doc_path, doc.score)))
+            .collect();
+        (total, hits)
+    }
+
+    // same as exec_query, each path comes with its score,
+    // descending by score, ties in doc id order
+    pub fn exec_query_scored(&self,
+        phrase_str: &str,
+        ranking: RankingAlgorithm,
+        ) -> Vec<(&String, f32)>{
+
+        let mut docs = vec![];
+        let doc_scores = self.query(phrase_str, ranking);
+        for doc in doc_scores {
+            if let Some(doc_path) = self.doc_meta.get(&doc.docid){
+                docs.push((doc_path, doc.score));
+            }
+        }
+        docs
+    }
+
+    // exec_query_scored without results scoring below min_score, filtered after ranking
+    // so the order is kept. Scores are on the scale of the ranking:
+    //   Default, OkapiBM25: sum of IDF weighted terms, 0 or more
+    //   VectorSpaceModel: cosine similarity within [0, 1]
+    //   LMD: log likelihood ratio, may well be negative for matching documents
+    //   Bim: sum of IDF of the terms the document contains, 0 or more
+    //   ExactMatch: number of phrase occurrences (or per 1000 tokens)
+    pub fn exec_query_scored_filtered(&self,
+        phrase_str: &str,
+        ranking: RankingAlgorithm,
+        min_score: Option<f32>,
+        ) -> Vec<(&String, f32)>{
+
+        self.exec_query_hits(phrase_str, ranking, min_score).into_iter()
+            .map(|(_, doc_path, score)| (doc_path, score))
+            .collect()
+    }
+
+    // exec_query_scored_filtered keeping the doc id of each result,
+    // to look up its title or snippet without going through the path
+    pub fn exec_query_hits(&self,
+        phrase_str: &str,
+        ranking: RankingAlgorithm,
+        min_score: Option<f32>,
+        ) -> Vec<(DocId, &String, f32)>{
+
+        self.query(phrase_str, ranking).into_iter()
+            .filter(|doc| min_score.is_none_or(|min_score| doc.score >= min_score))
+            .filter_map(|doc| self.doc_meta.get(&doc.docid).map(|doc_path| (doc.docid, doc_path, doc.score)))
+            .collect()
+    }
+
+    // same as exec_query, only documents analyzed as lang
+    pub fn exec_query_lang(&self,
+        phrase_str: &str,
+        ranking: RankingAlgorithm,
+        lang: Language,
+        ) -> Vec<&String>{
+
+        let mut docs = vec![];
+        let doc_scores = self.query(phrase_str, ranking);
+        for doc in doc_scores {
+            if self.get_language(doc.docid) != Some(lang) {
+                continue;
+            }
+            if let Some(doc_path) = self.doc_meta.get(&doc.docid){
+                docs.push(doc_path);
+            }
+        }
+        docs
+    }
+
+    // same as exec_query, each path comes with the document title if there is one
+    pub fn exec_query_with_titles(&self,
+        phrase_str: &str,
+        ranking: RankingAlgorithm,
+        ) -> Vec<(&String, Option<&String>)>{
+
+        let mut docs = vec![];
+        let doc_scores = self.query(phrase_str, ranking);
+        for doc in doc_scores {
+            if let Some(doc_path) = self.doc_meta.get(&doc.docid){
+                docs.push((doc_path, self.get_title(doc.docid)));
+            }
+        }
+        docs
+    }
+
+    // same as exec_query, with pinned documents first in the given order whether they
+    // match or not, organic results follow without the pinned ones
+    pub fn exec_query_with_pins(&self,
+        phrase_str: &str,
+        ranking: RankingAlgorithm,
+        pinned_paths: &[&str],
+        ) -> Vec<&String>{
+
+        let mut docs: Vec<&String> = vec![];
+        for &pinned in pinned_paths {
+            match self.path_ids.get_key_value(pinned) {
+                Some((doc_path, _)) if !docs.contains(&doc_path) => docs.push(doc_path),
+                Some(_) => {},
+                None => log::warn!("pinned document {} is not indexed", pinned),
+            }
+        }
+        let pinned_count = docs.len();
+        for doc_path in self.exec_query(phrase_str, ranking) {
+            if !docs[..pinned_count].contains(&doc_path) {
+                docs.push(doc_path);
+            }
+        }
+        docs
+    }
+
+    // same as exec_query, each path comes with its score and 1-based rank
+    pub fn exec_query_ranked(&self,
+        phrase_str: &str,
+        ranking: RankingAlgorithm,
+        ties: TieRanks,
+        ) -> Vec<RankedHit<'_>>{
+
+        let mut hits: Vec<RankedHit> = vec![];
+        let doc_scores = self.query(phrase_str, ranking);
+        for doc in doc_scores {
+            if let Some(path) = self.doc_meta.get(&doc.docid){
+                let rank = match hits.last() {
+                    Some(prev) if ties == TieRanks::Shared && prev.score == doc.score => prev.rank,
+                    _ => hits.len() + 1,
+                };
+                hits.push(RankedHit{ rank, path, score: doc.score });
+            }
+        }
+        hits
+    }
+
+    // Maximal Marginal Relevance: top_k results picked one at a time by
+    // lambda * relevance - (1 - lambda) * max cosine similarity to those already picked,
+    // relevance is the ranking score scaled to [0, 1], lambda 1.0 keeps the ranking order
+    pub fn exec_query_mmr(&self,
+        phrase_str: &str,
+        ranking: RankingAlgorithm,
+        lambda: f32,
+        top_k: usize,
+        ) -> Vec<&String>{
+
+        let doc_scores = self.query(phrase_str, ranking);
+        let (min, max) = doc_scores.iter().fold((f32::MAX, f32::MIN),
+            |(min, max), doc| (min.min(doc.score), max.max(doc.score)));
+        let relevance = |score: f32| if max > min { (score - min) / (max - min) } else { 1.0 };
+        let mut candidates: Vec<(DocId, f32, SparseVector)> = doc_scores.iter()
+            .map(|doc| (doc.docid, relevance(doc.score), self.index.get_doc_tfidf_vector(doc.docid)))
+            .collect();
+        let mut selected: Vec<(DocId, f32, SparseVector)> = vec![];
+        while selected.len() < top_k && !candidates.is_empty() {
+            let mmr = |(_, rel, vector): &(DocId, f32, SparseVector)| {
+                let similarity = selected.iter()
+                    .map(|(_, _, other)| vector.vec_dot(other))
+                    .filter(|sim| !sim.is_nan())
+                    .fold(0f32, f32::max);
+                lambda * rel - (1.0 - lambda) * similarity
+            };
+            // first best wins, candidates are in ranking order
+            let mut best = 0;
+            let mut best_mmr = mmr(&candidates[0]);
+            for (i, candidate) in candidates.iter().enumerate().skip(1) {
+                let candidate_mmr = mmr(candidate);
+                if candidate_mmr > best_mmr {
+                    best = i;
+                    best_mmr = candidate_mmr;
+                }
+            }
+            selected.push(candidates.remove(best));
+        }
+        selected.iter()
+            .filter_map(|(doc_id, _, _)| self.doc_meta.get(doc_id))
+            .collect()
+    }
+
+    // run several rankings of the same phrase in parallel
+    pub fn exec_query_multi(&self,
+        phrase_str: &str,
+        rankings: &[RankingAlgorithm],
+        ) -> HashMap<RankingAlgorithm, Vec<(&String, f32)>>{
+
+        rankings.par_iter()
+            .map(|&ranking| {
+                let docs = self.query(phrase_str, ranking).into_iter()
+                    .filter_map(|doc| self.doc_meta.get(&doc.docid).map(|path| (path, doc.score)))
+                    .collect();
+                (ranking, docs)
+            })
+            .collect()
+    }
+
+    // reciprocal rank fusion of the rankings of the phrase, k below 0 falls back
+    // to RRF_K (60), fused score descending, ties in doc id order
+    pub fn exec_query_fused(&self,
+        phrase_str: &str,
+        algorithms: &[RankingAlgorithm],
+        k: f32,
+        ) -> Vec<(&String, f32)>{
+
+        let k = match k {
+            k if k < 0.0 => {
+                log::warn!("rrf k {} is negative, using {}", k, RRF_K);
+                RRF_K
+            },
+            k => k,
+        };
+        let rankings: Vec<Vec<DocScore>> = algorithms.iter()
+            .map(|&ranking| self.query(phrase_str, ranking))
+            .collect();
+        fuse_rankings(&rankings, k).into_iter()
+            .filter_map(|doc| self.doc_meta.get(&doc.docid).map(|path| (path, doc.score)))
+            .collect()
+    }
+
+    // results as TREC run lines: query_id Q0 doc_id rank score run_tag,
+    // document paths are used as doc_id
+    pub fn exec_query_trec(&self,
+        query_id: &str,
+        phrase_str: &str,
+        ranking: RankingAlgorithm,
+        run_tag: &str,
+        ) -> Vec<String>{
+
+        let mut lines = vec![];
+        let doc_scores = self.query(phrase_str, ranking);
+        for doc in doc_scores {
+            if let Some(doc_path) = self.doc_meta.get(&doc.docid){
+                lines.push(format!("{} Q0 {} {} {:.6} {}", query_id, doc_path, lines.len() + 1, doc.score, run_tag));
+            }
+        }
+        lines
+    }
+
+    // one page of results as a json array of rank, path and score, with highlight
+    // each hit also lists the positions of the query terms it contains,
+    // paged as exec_query_page, ranks count from the first result of all pages
+    pub fn exec_query_json(&self,
+        phrase_str: &str,
+        ranking: RankingAlgorithm,
+        highlight: bool,
+        min_score: Option<f32>,
+        offset: usize,
+        limit: usize,
+        ) -> serde_json::Result<String>{
+
+        let term_ids = Query::parse(phrase_str, true, &self.analyzer);
+        let (_, page) = self.exec_query_page(phrase_str, ranking, min_score, offset, limit);
+        let hits: Vec<JsonHit> = page.into_iter().enumerate()
+            .map(|(i, (doc_id, path, score))| JsonHit {
+                rank: offset + i + 1,
+                path,
+                score,
+                highlights: highlight.then(|| self.highlights(doc_id, &term_ids)),
+            })
+            .collect();
+        serde_json::to_string(&hits)
+    }
+
+    // positions of the distinct query terms found in the document, in query order
+    fn highlights(&self, doc_id: DocId, term_ids: &[TermId]) -> Vec<Highlight> {
+        let mut highlights: Vec<Highlight> = vec![];
+        let mut seen = HashSet::new();
+        for &term_id in term_ids {
+            if !seen.insert(term_id) {
+                continue;
+            }
+            if let Some(positions) = self.index.get_positions(term_id, doc_id) {
+                highlights.push(Highlight {
+                    term: self.analyzer.get_term_by_id(term_id),
+                    positions: positions.to_vec(),
+                });
+            }
+        }
+        highlights
+    }
+
+    // single entry point: query type selects the matching documents, ranking scores them,
+    // then filters, offset and limit are applied in that order
+    pub fn search(&self, request: &SearchRequest) -> Vec<SearchHit<'_>> {
+        let ranking = request.ranking;
+        let doc_scores = match &request.query {
+            QueryType::Terms(terms) => {
+                let term_ids = Query::parse(terms, true, &self.analyzer);
+                self.rank(&term_ids, Engine::without_exact_match(ranking))
+            },
+            QueryType::Phrase(phrase) => {
+                let term_ids = Query::parse(phrase, false, &self.analyzer);
+                let matches = self.rank(&term_ids, RankingAlgorithm::ExactMatch);
+                self.rank_matches(&term_ids, matches, ranking)
+            },
+            QueryType::Near { terms, max_gap, ordered } => {
+                self.query_near(terms, *max_gap, *ordered, ranking)
+            },
+            QueryType::Boolean { must, should, must_not } => {
+                self.search_boolean(must, should, must_not, Engine::without_exact_match(ranking))
+            },
+        };
+
+        let filter = &request.filter;
+        doc_scores.into_iter()
+            .filter(|doc| filter.language.is_none() || self.get_language(doc.docid) == filter.language)
+            .filter_map(|doc| self.doc_meta.get(&doc.docid).map(|path| (doc, path)))
+            .filter(|(_, path)| match &filter.path_prefix {
+                Some(prefix) => path.starts_with(prefix.as_str()),
+                None => true,
+            })
+            .skip(request.offset)
+            .take(request.limit.unwrap_or(usize::MAX))
+            .map(|(doc, path)| SearchHit{
+                path,
+                score: doc.score,
+                doc_length: request.doc_length.then(|| self.index.get_document_length(doc.docid)),
+            })
+            .collect()
+    }
+
+    fn without_exact_match(ranking: RankingAlgorithm) -> RankingAlgorithm {
+        match ranking {
+            RankingAlgorithm::ExactMatch => RankingAlgorithm::Default,
+            _ => ranking,
+        }
+    }
+
+    // documents with all terms within max_gap positions, ExactMatch orders them by
+    // number of such windows
+    fn query_near(&self, terms: &str, max_gap: TermOffset, ordered: bool, ranking: RankingAlgorithm) -> Vec<DocScore> {
+        let term_ids = Query::parse(terms, false, &self.analyzer);
+        let matches = self.index.search_near(&term_ids, max_gap, ordered);
+        self.rank_matches(&term_ids, matches, ranking)
+    }
+
+    // documents with any of the query terms in the field, None if no document
+    // was indexed with the field
+    pub fn search_field(&self, field: &str, phrase_str: &str, ranking: RankingAlgorithm) -> Option<Vec<&String>> {
+        let field_id = self.index.get_field_id(field)?;
+        let term_ids = Query::parse(phrase_str, true, &self.analyzer);
+        let matches: Vec<DocScore> = term_ids.iter()
+            .flat_map(|&term_id| self.index.docs_in_field(term_id, field_id))
+            .collect::<HashSet<DocId>>()
+            .into_iter()
+            .map(|docid| DocScore{ docid, score: 0.0 })
+            .collect();
+        let ranked = self.rank_matches(&term_ids, matches, Engine::without_exact_match(ranking));
+        Some(ranked.iter().filter_map(|doc| self.doc_meta.get(&doc.docid)).collect())
+    }
+
+    // rank the matched documents with ranking, keep the match order for ExactMatch
+    fn rank_matches(&self, term_ids: &Vec<TermId>, matches: Vec<DocScore>, ranking: RankingAlgorithm) -> Vec<DocScore> {
+        if ranking == RankingAlgorithm::ExactMatch {
+            return matches;
+        }
+        let matched: HashSet<DocId> = matches.iter().map(|doc| doc.docid).collect();
+        self.rank(term_ids, ranking).into_iter()
+            .filter(|doc| matched.contains(&doc.docid))
+            .collect()
+    }
+
+    fn search_boolean(&self, must: &str, should: &str, must_not: &str, ranking: RankingAlgorithm) -> Vec<DocScore> {
+        let (must_ids, unknown_terms) = self.analyzer.parse(must);
+        if !unknown_terms.is_empty() {
+            return vec![];
+        }
+        let (should_ids, _) = self.analyzer.parse(should);
+        let (must_not_ids, _) = self.analyzer.parse(must_not);
+        let candidates = if must_ids.is_empty() {
+            self.index.docs_contain_any(&should_ids)
+        } else {
+            self.index.docs_contain_all(&must_ids).unwrap_or_default()
+        };
+        let excluded = self.index.docs_contain_any(&must_not_ids);
+        let matched: HashSet<DocId> = &candidates - &excluded;
+        let mut term_ids = must_ids;
+        term_ids.extend(should_ids);
+        self.rank(&term_ids, ranking).into_iter()
+            .filter(|doc| matched.contains(&doc.docid))
+            .collect()
+    }
+
+    fn query(&self, phrase_str: &str, ranking: RankingAlgorithm) -> Vec<DocScore> {
+        let ignore_non_exist_term = ranking != RankingAlgorithm::ExactMatch;
+        let (phrases, terms) = Query::split_phrases(phrase_str);
+        if !phrases.is_empty() {
+            return self.query_phrases(&phrases, &terms, ranking);
+        }
+        if let Some((terms, max_gap)) = Query::parse_near(phrase_str) {
+            return self.query_near(&terms, max_gap, false, ranking);
+        }
+        if let Some(node) = Query::parse_boolean(phrase_str, &self.analyzer) {
+            return self.query_boolean(&node, ranking);
+        }
+        if Query::has_boosts(phrase_str) {
+            let terms = Query::parse_weighted(phrase_str, ignore_non_exist_term, &self.analyzer, self.fuzzy_distance);
+            return self.rank_weighted(&terms, ranking);
+        }
+        // the terms a prefix or an unknown word expands to are alternatives at its position
+        if ranking == RankingAlgorithm::ExactMatch && (Query::has_prefixes(phrase_str) || self.fuzzy_distance.is_some()) {
+            return match Query::parse_slots(phrase_str, &self.analyzer, self.fuzzy_distance) {
+                Some(slots) => self.index.search_phrase_alternatives(&slots),
+                None => vec![],
+            };
+        }
+        let term_ids = Query::parse_fuzzy(phrase_str, ignore_non_exist_term, &self.analyzer, self.fuzzy_distance);
+        self.rank(&term_ids, ranking)
+    }
+
+    // documents containing every quoted phrase, ranked by the phrase terms and loose terms,
+    // ExactMatch falls back to the default ranking
+    fn query_phrases(&self, phrases: &[String], terms: &str, ranking: RankingAlgorithm) -> Vec<DocScore> {
+        let mut matched: Option<HashSet<DocId>> = None;
+        let mut term_ids = vec![];
+        for phrase in phrases {
+            let phrase_ids = Query::parse(phrase, false, &self.analyzer);
+            let docs: HashSet<DocId> = if phrase_ids.is_empty() {
+                HashSet::new()
+            } else {
+                self.index.search_phrase(&phrase_ids).iter().map(|doc| doc.docid).collect()
+            };
+            matched = Some(match matched {
+                Some(matched) => &matched & &docs,
+                None => docs,
+            });
+            term_ids.extend(phrase_ids);
+        }
+        let matched = matched.unwrap_or_default();
+        if matched.is_empty() {
+            return vec![];
+        }
+        term_ids.extend(Query::parse(terms, true, &self.analyzer));
+        self.rank(&term_ids, Engine::without_exact_match(ranking)).into_iter()
+            .filter(|doc| matched.contains(&doc.docid))
+            .collect()
+    }
+
+    // matching documents ranked by the terms not under a NOT, documents matched
+    // only through a NOT have no score and follow in doc id order
+    fn query_boolean(&self, node: &QueryNode, ranking: RankingAlgorithm) -> Vec<DocScore> {
+        let mut matched = self.eval_query_node(node);
+        let term_ids = node.positive_terms();
+        let mut doc_scores: Vec<DocScore> = self.rank(&term_ids, Engine::without_exact_match(ranking))
+            .into_iter()
+            .filter(|doc| matched.remove(&doc.docid))
+            .collect();
+        let mut unscored: Vec<DocId> = matched.into_iter().collect();
+        unscored.sort_unstable();
+        doc_scores.extend(unscored.into_iter().map(|docid| DocScore{docid, score: 0.0}));
+        doc_scores
+    }
+
+    fn eval_query_node(&self, node: &QueryNode) -> HashSet<DocId> {
+        match node {
+            QueryNode::Term(term_id) => self.index.docs(*term_id).unwrap_or_default(),
+            QueryNode::Unknown(_) => HashSet::new(),
+            QueryNode::And(left, right) => &self.eval_query_node(left) & &self.eval_query_node(right),
+            QueryNode::Or(left, right) => &self.eval_query_node(left) | &self.eval_query_node(right),
+            QueryNode::Not(node) => {
+                let all_docs: HashSet<DocId> = self.doc_meta.keys().copied().collect();
+                &all_docs - &self.eval_query_node(node)
+            },
+        }
+    }
+
+    fn rank(&self, term_ids: &Vec<TermId>, ranking: RankingAlgorithm) -> Vec<DocScore> {
+        let filtered: Vec<TermId>;
+        let term_ids = match self.stopword_df_ratio {
+            Some(df_ratio) if ranking != RankingAlgorithm::ExactMatch => {
+                let document_count = self.index.get_document_count();
+                filtered = term_ids.iter().copied()
+                    .filter(|term_id| !self.is_stopword(*term_id, df_ratio, document_count))
+                    .collect();
+                &filtered
+            },
+            _ => term_ids,
+        };
+        match &self.cache {
+            Some(cache) => cache.get_or_insert_with(self.epoch, term_ids, ranking,
+                || self.index.score_with(term_ids, ranking, &self.ranking_params)),
+            None => self.index.score_with(term_ids, ranking, &self.ranking_params),
+        }
+    }
+
+    // like rank for boosted terms, not cached as the cache is keyed by term ids only
+    fn rank_weighted(&self, terms: &[WeightedTerm], ranking: RankingAlgorithm) -> Vec<DocScore> {
+        let terms: Vec<WeightedTerm> = match self.stopword_df_ratio {
+            Some(df_ratio) if ranking != RankingAlgorithm::ExactMatch => {
+                let document_count = self.index.get_document_count();
+                terms.iter().copied()
+                    .filter(|(term_id, _)| !self.is_stopword(*term_id, df_ratio, document_count))
+                    .collect()
+            },
+            _ => terms.to_vec(),
+        };
+        self.index.score_weighted(&terms, ranking, &self.ranking_params)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ircore::query::SearchFilter;
+    use crate::ircore::utils::serialize::CompressionLevel;
+
+    #[test]
+    fn test_build_index() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+    }
+
+    #[test]
+    fn test_save_and_load_index() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        assert_eq!(engine.doc_count(), 5);
+        let index_path = ".rir/romeo_juliet1.idx";
+        let _ = engine.save_to(index_path);
+        let loaded_engine = Engine::load_from(index_path).unwrap();
+        assert_eq!(loaded_engine.doc_count(), 5);
+        let docs = loaded_engine.exec_query("Quarrel sir", RankingAlgorithm::ExactMatch);
+        use std::collections::HashSet;
+        let doc_set: HashSet<&String> = HashSet::from_iter(docs);
+        assert_eq!(doc_set, HashSet::from([&"./sample_corpus/romeo_juliet/a/1.txt".to_string(), &"./sample_corpus/romeo_juliet/a/2.txt".to_string()]));
+    }
+
+    #[test]
+    fn test_preload() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let index_path = ".rir/romeo_juliet_preload.idx";
+        let _ = engine.save_to(index_path);
+        let loaded_engine = Engine::load_from(index_path).unwrap();
+        assert_eq!(loaded_engine.preload(), loaded_engine.stats().index.total_document_length as usize);
+        let docs = loaded_engine.exec_query("Quarrel sir", RankingAlgorithm::ExactMatch);
+        assert_eq!(docs.len(), 2);
+    }
+
+    #[test]
+    fn test_load_without_buffer() {
+        let mut engine = Engine::new();
+        let _ = engine.build_index_from("./sample_corpus/romeo_juliet");
+        let index_path = ".rir/romeo_juliet_owned.idx";
+        let _ = engine.save_to(index_path);
+        // index saved before titles were stored
+        let _ = fs::remove_file(Path::new(index_path).join(Engine::SERIALIZE_NAME_DOCTITLES));
+        let loaded_engine = Engine::load_from(index_path).unwrap();
+        assert_eq!(loaded_engine.doc_count(), 5);
+        assert_eq!(loaded_engine.get_title(1), None);
+        assert_eq!(loaded_engine.exec_query("Quarrel sir", RankingAlgorithm::ExactMatch).len(), 2);
+    }
+
+    #[test]
+    fn test_search_phrase() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let mut docs = engine.exec_query("Quarrel sir", RankingAlgorithm::ExactMatch);
+        use std::collections::HashSet;
+        let doc_set: HashSet<&String> = HashSet::from_iter(docs);
+        assert_eq!(doc_set, HashSet::from([&"./sample_corpus/romeo_juliet/a/1.txt".to_string(), &"./sample_corpus/romeo_juliet/a/2.txt".to_string()]));
+        docs = engine.exec_query("sir", RankingAlgorithm::ExactMatch);
+        assert_eq!(docs.len(), 4);
+
+        docs = engine.exec_query("non-exist", RankingAlgorithm::ExactMatch);
+        assert_eq!(docs.len(), 0);
+
+        docs = engine.exec_query("Sir non-exist", RankingAlgorithm::ExactMatch);
+        assert_eq!(docs.len(), 0);
+
+        docs = engine.exec_query("Sir", RankingAlgorithm::ExactMatch);
+        assert_eq!(docs.len(), 4);
+
+    }
+
+    #[test]
+    fn test_vector_space_model() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        assert_eq!(engine.doc_count(), 5);
+        let index_path = ".rir/romeo_juliet2.idx";
+        let _ = engine.save_to(index_path);
+        let loaded_engine = Engine::load_from(index_path).unwrap();
+        assert_eq!(loaded_engine.doc_count(), 5);
+        let docs = loaded_engine.exec_query("Quarrel sir", RankingAlgorithm::VectorSpaceModel);
+        use std::collections::HashSet;
+        let doc_set: HashSet<&String> = HashSet::from_iter(docs);
+        assert_eq!(doc_set, HashSet::from([
+                &"./sample_corpus/romeo_juliet/a/2.txt".to_string(), 
+                &"./sample_corpus/romeo_juliet/a/1.txt".to_string(),
+                &"./sample_corpus/romeo_juliet/5.txt".to_string(), 
+                &"./sample_corpus/romeo_juliet/b/3.txt".to_string(),
+                ]));
+    }
+
+    #[test]
+    fn test_rank_bm25() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        assert_eq!(engine.doc_count(), 5);
+        let index_path = ".rir/romeo_juliet3.idx";
+        let _ = engine.save_to(index_path);
+        let loaded_engine = Engine::load_from(index_path).unwrap();
+        assert_eq!(loaded_engine.doc_count(), 5);
+        let docs = loaded_engine.exec_query("Quarrel sir", RankingAlgorithm::OkapiBM25);
+        use std::collections::HashSet;
+        let doc_set: HashSet<&String> = HashSet::from_iter(docs);
+        assert_eq!(doc_set, HashSet::from([
+                &"./sample_corpus/romeo_juliet/a/2.txt".to_string(), 
+                &"./sample_corpus/romeo_juliet/a/1.txt".to_string(),
+                &"./sample_corpus/romeo_juliet/5.txt".to_string(), 
+                &"./sample_corpus/romeo_juliet/b/3.txt".to_string(),
+                ]));
+    }
+
+    #[test]
+    fn test_rank_default() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        assert_eq!(engine.doc_count(), 5);
+        let index_path = ".rir/romeo_juliet4.idx";
+        let _ = engine.save_to(index_path);
+        let loaded_engine = Engine::load_from(index_path).unwrap();
+        assert_eq!(loaded_engine.doc_count(), 5);
+        let docs = loaded_engine.exec_query("Quarrel sir", RankingAlgorithm::Default);
+        use std::collections::HashSet;
+        let doc_set: HashSet<&String> = HashSet::from_iter(docs);
+        assert_eq!(doc_set, HashSet::from([
+                &"./sample_corpus/romeo_juliet/a/2.txt".to_string(), 
+                &"./sample_corpus/romeo_juliet/a/1.txt".to_string(),
+                &"./sample_corpus/romeo_juliet/5.txt".to_string(), 
+                &"./sample_corpus/romeo_juliet/b/3.txt".to_string(),
+                ]));
+
+    }
+
+    #[test]
+    fn test_chinese_text_index() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/sanguo");
+        assert_eq!(res, Ok(19));
+        assert_eq!(engine.doc_count(), 19);
+        let index_path = ".rir/sanguo.idx";
+        let _ = engine.save_to(index_path);
+        let loaded_engine = Engine::load_from(index_path).unwrap();
+        assert_eq!(loaded_engine.doc_count(), 19);
+        let docs = loaded_engine.exec_query("刘备", RankingAlgorithm::ExactMatch);
+        use std::collections::HashSet;
+        let doc_set: HashSet<&String> = HashSet::from_iter(docs);
+        assert_eq!(doc_set, HashSet::from([&"./sample_corpus/sanguo/9.txt".to_string()]));
+        let docs = loaded_engine.exec_query("桃园结义", RankingAlgorithm::Default);
+        let doc_set: HashSet<&String> = HashSet::from_iter(docs);
+        assert_eq!(doc_set, HashSet::from([
+            &"./sample_corpus/sanguo/1.txt".to_string(),
+            &"./sample_corpus/sanguo/9.txt".to_string(),
+            &"./sample_corpus/sanguo/8.txt".to_string(),
+            ]));
+
+    }
+
+    #[test]
+    fn test_term_stats() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let stats = engine.term_stats("sir").unwrap();
+        assert_eq!(stats.term, "sir");
+        assert_eq!(stats.document_frequency, 4);
+        assert_eq!(stats.collection_frequency, 5);
+        assert!((stats.idf - (5f32/4f32).log2()).abs() <= f32::EPSILON);
+        assert_eq!(engine.term_stats("Quarrel").unwrap().document_frequency, 2);
+        assert_eq!(engine.term_stats("non-exist"), None);
+        // more than one term is not a term
+        assert_eq!(engine.term_stats("quarrel sir"), None);
+        assert_eq!(engine.term_stats("bogus sir"), None);
+        assert_eq!(engine.term_stats(""), None);
+
+        let snapshot_path = ".rir/romeo_juliet_term_stats.json";
+        engine.save_term_stats(&["sir", "quarrel", "non-exist"], snapshot_path).unwrap();
+        let snapshot = Engine::load_term_stats(snapshot_path).unwrap();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0], stats);
+    }
+
+    #[test]
+    fn test_analyze_query() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let (known, unknown) = engine.analyze_query("quarrel bogus sir");
+        assert_eq!(known, vec!["quarrel", "sir"]);
+        assert_eq!(unknown, vec!["bogus"]);
+        let (known, unknown) = engine.analyze_query("Quarrel Sir");
+        assert_eq!(known, vec!["quarrel", "sir"]);
+        assert!(unknown.is_empty());
+    }
+
+    #[test]
+    fn test_cache_epoch() {
+        let mut engine = Engine::new();
+        engine.enable_cache();
+        let epoch = engine.epoch();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        assert!(engine.epoch() > epoch);
+        let docs = engine.exec_query("quarrel sir", RankingAlgorithm::OkapiBM25);
+        assert_eq!(docs.len(), 4);
+        let docs = engine.exec_query("quarrel sir", RankingAlgorithm::OkapiBM25);
+        assert_eq!(docs.len(), 4);
+        let cache = engine.get_cache().unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+
+        // mutation bumps the epoch, the query is recomputed
+        let epoch = engine.epoch();
+        engine.clear();
+        assert!(engine.epoch() > epoch);
+        assert!(engine.get_cache().unwrap().is_empty());
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet/a");
+        assert_eq!(res, Ok(2));
+        let docs = engine.exec_query("quarrel sir", RankingAlgorithm::OkapiBM25);
+        assert_eq!(docs.len(), 2);
+        let cache = engine.get_cache().unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (1, 2));
+
+        // so does a removal
+        let doc_id = engine.get_doc_id(docs[0]).unwrap();
+        let epoch = engine.epoch();
+        assert!(engine.remove_document(doc_id));
+        assert!(engine.epoch() > epoch);
+        assert!(!engine.remove_document(doc_id));
+        let docs = engine.exec_query("quarrel sir", RankingAlgorithm::OkapiBM25);
+        assert_eq!(docs.len(), 1);
+        assert_ne!(engine.get_doc_id(docs[0]), Some(doc_id));
+        let cache = engine.get_cache().unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (1, 3));
+    }
+
+    #[test]
+    fn test_with_cache() {
+        let mut engine = Engine::with_cache(2);
+        assert_eq!(engine.get_cache().unwrap().capacity(), Some(2));
+        assert!(Engine::new().get_cache().is_none());
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let first = engine.exec_query_scored("quarrel sir", RankingAlgorithm::OkapiBM25);
+        let second = engine.exec_query_scored("quarrel sir", RankingAlgorithm::OkapiBM25);
+        assert_eq!(first, second);
+        let cache = engine.get_cache().unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+        // same terms in another order, BM25 scores them the same
+        assert_eq!(engine.exec_query_scored("sir quarrel", RankingAlgorithm::OkapiBM25), first);
+        assert_eq!(engine.get_cache().unwrap().hits(), 2);
+        // two more queries push the first one out
+        engine.exec_query("no", RankingAlgorithm::OkapiBM25);
+        engine.exec_query("you", RankingAlgorithm::OkapiBM25);
+        let cache = engine.get_cache().unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(engine.exec_query_scored("quarrel sir", RankingAlgorithm::OkapiBM25), first);
+        assert_eq!((cache.hits(), cache.misses()), (2, 4));
+    }
+
+    #[test]
+    fn test_docs_not_containing() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let docs = engine.docs_not_containing("quarrel");
+        let doc_set: HashSet<&String> = HashSet::from_iter(docs);
+        assert_eq!(doc_set, HashSet::from([
+            &"./sample_corpus/romeo_juliet/5.txt".to_string(),
+            &"./sample_corpus/romeo_juliet/b/3.txt".to_string(),
+            &"./sample_corpus/romeo_juliet/b/4.txt".to_string(),
+            ]));
+        assert_eq!(engine.docs_not_containing("non-exist").len(), 5);
+    }
+
+    #[test]
+    fn test_build_index_from_pdf_files() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/pdf");
+        // image only pdf is skipped
+        assert_eq!(res, Ok(1));
+        let docs = engine.exec_query("Quarrel sir", RankingAlgorithm::ExactMatch);
+        assert_eq!(docs, vec!["./sample_corpus/pdf/romeo.pdf"]);
+    }
+
+    #[test]
+    fn test_exec_query_multi() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let rankings = [RankingAlgorithm::ExactMatch, RankingAlgorithm::VectorSpaceModel,
+            RankingAlgorithm::OkapiBM25, RankingAlgorithm::LMD, RankingAlgorithm::Bim];
+        let results = engine.exec_query_multi("quarrel sir", &rankings);
+        assert_eq!(results.len(), rankings.len());
+        for ranking in rankings {
+            let expected: Vec<&String> = engine.exec_query("quarrel sir", ranking);
+            let actual: Vec<&String> = results[&ranking].iter().map(|(path, _)| *path).collect();
+            assert!(!expected.is_empty());
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_explain_miss() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let doc = "./sample_corpus/romeo_juliet/b/3.txt";
+        // "If you do, sir, I am for you: I serve as good a man as you."
+        assert_eq!(engine.explain_miss("quarrel sir", doc), vec![
+            "term \"quarrel\" is absent from the document".to_string(),
+            "not all query terms are present, required by exact match".to_string()]);
+        assert_eq!(engine.explain_miss("sir you", doc), vec![
+            "all terms are present but not adjacent as a phrase".to_string()]);
+        assert!(engine.explain_miss("good a man", doc).is_empty());
+        assert_eq!(engine.explain_miss("sir nonexist", doc)[0], "term \"nonexist\" is not in the index");
+        assert_eq!(engine.explain_miss("sir", "missing.txt"), vec!["document missing.txt is not indexed".to_string()]);
+    }
+
+    #[test]
+    fn test_passages() {
+        let mut engine = Engine::new();
+        engine.set_store_content(true);
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let doc = "./sample_corpus/romeo_juliet/b/3.txt";
+        // "If you do, sir, I am for you: I serve as good a man as you."
+        let passages = engine.passages(doc, "serve good man", 5, 2);
+        assert_eq!(passages, vec!["serve as good a man"]);
+        let passages = engine.passages(doc, "serve good man", 4, 2);
+        assert_eq!(passages, vec!["I serve as good", "a man as you"]);
+        let passages = engine.passages(doc, "sir man", 3, 3);
+        assert_eq!(passages, vec!["you do, sir", "good a man"]);
+        // content survives save and load
+        let index_path = ".rir/romeo_juliet_content.idx";
+        let _ = engine.save_to(index_path);
+        let loaded_engine = Engine::load_from(index_path).unwrap();
+        assert_eq!(loaded_engine.passages(doc, "serve good man", 5, 1), vec!["serve as good a man"]);
+        // without stored content there are no passages
+        let mut engine = Engine::new();
+        let _ = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert!(engine.passages(doc, "serve good man", 4, 1).is_empty());
+    }
+
+    #[test]
+    fn test_passages_jsonlines() {
+        let mut engine = Engine::new();
+        engine.set_store_content(true);
+        assert!(engine.build_index_from("./sample_corpus/wiki_lines").unwrap() > 1);
+        // the first line of the file, the article on mathematics
+        let passages = engine.passages("./sample_corpus/wiki_lines/lines1:1", "数学", 5, 1);
+        assert_eq!(passages.len(), 1);
+        assert!(passages[0].contains("数学"));
+    }
+
+    #[test]
+    fn test_build_index_progress() {
+        let mut engine = Engine::new();
+        let mut counts = vec![];
+        let res = engine.build_index_with_progress("./sample_corpus/romeo_juliet",
+            &mut |done, total| counts.push((done, total)));
+        assert_eq!(res, Ok(5));
+        assert!(counts.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        assert!(counts.iter().all(|&(_, total)| total == 6));
+        assert_eq!(counts.last(), Some(&(6, 6)));
+    }
+
+    #[test]
+    fn test_exec_query_lang() {
+        let mut engine = Engine::new();
+        engine.set_per_document_language(true);
+        assert_eq!(engine.build_index_from("./sample_corpus/romeo_juliet"), Ok(5));
+        // the documents of this build only
+        assert_eq!(engine.build_index_from("./sample_corpus/wiki_zh"), Ok(2));
+        assert_eq!(engine.doc_count(), 7);
+        let docs = engine.exec_query("sir 数学", RankingAlgorithm::OkapiBM25);
+        assert_eq!(docs.len(), 6);
+        let docs = engine.exec_query_lang("sir 数学", RankingAlgorithm::OkapiBM25, Language::English);
+        assert_eq!(docs.len(), 4);
+        assert!(docs.iter().all(|path| path.starts_with("./sample_corpus/romeo_juliet")));
+        let docs = engine.exec_query_lang("sir 数学", RankingAlgorithm::OkapiBM25, Language::Chinese);
+        assert_eq!(HashSet::<&String>::from_iter(docs), HashSet::from([
+            &"./sample_corpus/wiki_zh/wiki_1".to_string(),
+            &"./sample_corpus/wiki_zh/wiki_2".to_string()]));
+        // languages survive save and load
+        let index_path = ".rir/mixed_lang.idx";
+        let _ = engine.save_to(index_path);
+        let loaded_engine = Engine::load_from(index_path).unwrap();
+        let docs = loaded_engine.exec_query_lang("sir 数学", RankingAlgorithm::OkapiBM25, Language::English);
+        assert_eq!(docs.len(), 4);
+    }
+
+    #[test]
+    fn test_exec_query_trec() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let lines = engine.exec_query_trec("101", "quarrel sir", RankingAlgorithm::OkapiBM25, "rir-bm25");
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("101 Q0 ./sample_corpus/romeo_juliet/a/2.txt 1 1.9"));
+        let mut last_score = f32::MAX;
+        for (i, line) in lines.iter().enumerate() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            assert_eq!(fields.len(), 6);
+            assert_eq!(fields[0], "101");
+            assert_eq!(fields[1], "Q0");
+            assert_eq!(fields[3], (i + 1).to_string());
+            let score: f32 = fields[4].parse().unwrap();
+            assert!(score <= last_score);
+            last_score = score;
+            assert_eq!(fields[5], "rir-bm25");
+        }
+    }
+
+    #[test]
+    fn test_doc_titles() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/wiki_zh");
+        assert_eq!(res, Ok(2));
+        let index_path = ".rir/wiki_zh_titles.idx";
+        let _ = engine.save_to(index_path);
+        let loaded_engine = Engine::load_from(index_path).unwrap();
+        let docs: HashSet<(&String, Option<&String>)> = HashSet::from_iter(
+            loaded_engine.exec_query_with_titles("哲学", RankingAlgorithm::OkapiBM25));
+        assert!(docs.contains(&(&"./sample_corpus/wiki_zh/wiki_2".to_string(), Some(&"哲学".to_string()))));
+        let mut engine = Engine::new();
+        let _ = engine.build_index_from("./sample_corpus/romeo_juliet");
+        let docs = engine.exec_query_with_titles("Quarrel sir no", RankingAlgorithm::OkapiBM25);
+        assert_eq!(docs[0].1, Some(&"Quarrel sir! no, sir!".to_string()));
+    }
+
+    #[test]
+    fn test_build_index_from_json_files() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/wiki_zh");
+        assert_eq!(res, Ok(2));
+        assert_eq!(engine.doc_count(), 2);
+        let index_path = ".rir/wiki_zh.idx";
+        let _ = engine.save_to(index_path);
+        let loaded_engine = Engine::load_from(index_path).unwrap();
+        assert_eq!(loaded_engine.doc_count(), 2);
+        let docs = loaded_engine.exec_query("数学", RankingAlgorithm::ExactMatch);
+        use std::collections::HashSet;
+        let doc_set: HashSet<&String> = HashSet::from_iter(docs);
+        assert_eq!(doc_set, HashSet::from(
+            [&"./sample_corpus/wiki_zh/wiki_1".to_string(),
+            &"./sample_corpus/wiki_zh/wiki_2".to_string()]));
+    }
+
+
+    #[test]
+    fn test_quantized_tfidf() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert!(res.is_ok());
+        let queries = ["quarrel sir", "you do", "well sir", "no better"];
+        let ranked = |engine: &Engine| -> Vec<Vec<DocScore>> {
+            queries.iter()
+                .map(|q| engine.rank(&Query::parse(q, true, &engine.analyzer), RankingAlgorithm::VectorSpaceModel))
+                .collect()
+        };
+        let exact = ranked(&engine);
+        engine.build_tfidf_cache(false);
+        assert_eq!(ranked(&engine), exact);
+        engine.build_tfidf_cache(true);
+        let quantized = ranked(&engine);
+        for (e, q) in exact.iter().zip(quantized.iter()) {
+            assert!(!e.is_empty());
+            assert_eq!(e.iter().map(|d| d.docid).collect::<Vec<_>>(), q.iter().map(|d| d.docid).collect::<Vec<_>>());
+            for (ed, qd) in e.iter().zip(q.iter()) {
+                assert!((ed.score - qd.score).abs() <= 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn test_search() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let paths = |hits: Vec<SearchHit>| {
+            let mut paths: Vec<String> = hits.into_iter().map(|hit| hit.path.clone()).collect();
+            paths.sort();
+            paths
+        };
+        let path = |name: &str| format!("./sample_corpus/romeo_juliet/{}", name);
+
+        let request = SearchRequest::new(QueryType::Terms("quarrel sir".to_string()));
+        assert_eq!(paths(engine.search(&request)), vec![path("5.txt"), path("a/1.txt"), path("a/2.txt"), path("b/3.txt")]);
+        let hits = engine.search(&request.clone().with_offset(1).with_limit(2));
+        assert_eq!(hits, engine.search(&request)[1..3].to_vec());
+
+        let request = SearchRequest::new(QueryType::Phrase("sir quarrel".to_string()));
+        assert!(engine.search(&request).is_empty());
+        let request = SearchRequest::new(QueryType::Phrase("quarrel sir".to_string()));
+        assert_eq!(paths(engine.search(&request)), vec![path("a/1.txt"), path("a/2.txt")]);
+        let hits = engine.search(&request.with_ranking(RankingAlgorithm::ExactMatch));
+        assert_eq!(hits[0].score, 1.0);
+
+        let request = SearchRequest::new(QueryType::Near { terms: "sir quarrel".to_string(), max_gap: 1, ordered: false });
+        assert_eq!(paths(engine.search(&request)), vec![path("a/1.txt"), path("a/2.txt")]);
+        let request = SearchRequest::new(QueryType::Near { terms: "sir quarrel".to_string(), max_gap: 1, ordered: true });
+        assert!(engine.search(&request).is_empty());
+
+        let request = SearchRequest::new(QueryType::Boolean {
+            must: "sir".to_string(),
+            should: "".to_string(),
+            must_not: "quarrel".to_string(),
+        });
+        assert_eq!(paths(engine.search(&request)), vec![path("5.txt"), path("b/3.txt")]);
+
+        let request = SearchRequest::new(QueryType::Terms("sir".to_string()))
+            .with_filter(SearchFilter{ path_prefix: Some(path("a/")), ..SearchFilter::default() });
+        assert_eq!(paths(engine.search(&request)), vec![path("a/1.txt"), path("a/2.txt")]);
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let checkpoint_path = ".rir/romeo_juliet_checkpoint.idx";
+        let _ = fs::remove_dir_all(checkpoint_path);
+        let mut engine = Engine::new();
+        engine.set_checkpoint(checkpoint_path, Some(2));
+        assert_eq!(engine.build_index_from("./sample_corpus/romeo_juliet"), Ok(5));
+        // the last checkpoint is what an interrupted build leaves behind
+        let mut resumed = Engine::load_from(checkpoint_path).unwrap();
+        assert_eq!(resumed.doc_count(), 4);
+        // only the document left out of the checkpoint is added
+        assert_eq!(resumed.resume_index_from("./sample_corpus/romeo_juliet"), Ok(1));
+        assert_eq!(resumed.doc_count(), 5);
+        // all indexed already, nothing left to add
+        assert_eq!(resumed.resume_index_from("./sample_corpus/romeo_juliet"), Ok(0));
+        let paths: HashSet<&String> = resumed.doc_meta.values().collect();
+        assert_eq!(paths, engine.doc_meta.values().collect());
+        assert_eq!(resumed.stats().index.total_document_length, engine.stats().index.total_document_length);
+        assert_eq!(resumed.exec_query("Quarrel sir", RankingAlgorithm::ExactMatch).len(), 2);
+    }
+
+    #[test]
+    fn test_search_doc_length() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let request = SearchRequest::new(QueryType::Phrase("quarrel sir no".to_string()));
+        let hits = engine.search(&request);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].doc_length, None);
+        let hits = engine.search(&request.with_doc_length(true));
+        let (doc_id, _) = engine.doc_meta.iter().find(|(_, path)| *path == hits[0].path).unwrap();
+        // "Quarrel sir! no, sir!"
+        assert_eq!(hits[0].doc_length, Some(4));
+        assert_eq!(hits[0].doc_length, Some(engine.index.get_document_length(*doc_id)));
+    }
+
+    #[test]
+    fn test_save_raw_index() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let gzip_path = ".rir/romeo_juliet_gzip.idx";
+        let raw_path = ".rir/romeo_juliet_raw.idx";
+        engine.save_to_with(gzip_path, Encoding::default()).unwrap();
+        engine.save_to_with(raw_path, Encoding::Raw).unwrap();
+        let expected = engine.exec_query("Quarrel sir", RankingAlgorithm::OkapiBM25);
+        for path in [gzip_path, raw_path] {
+            let loaded_engine = Engine::load_from(path).unwrap();
+            assert_eq!(loaded_engine.doc_count(), 5);
+            assert_eq!(loaded_engine.exec_query("Quarrel sir", RankingAlgorithm::OkapiBM25), expected);
+        }
+    }
+
+    #[test]
+    fn test_save_compression_level() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/sanguo");
+        assert!(res.unwrap() > 0);
+        let index_size = |path: &str| fs::read_dir(path).unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum::<u64>();
+        let fast_path = ".rir/sanguo_fast.idx";
+        let best_path = ".rir/sanguo_best.idx";
+        engine.save_to_with(fast_path, Encoding::Gzip(CompressionLevel::Fast)).unwrap();
+        engine.save_to_with(best_path, Encoding::Gzip(CompressionLevel::Best)).unwrap();
+        assert!(index_size(best_path) < index_size(fast_path));
+        let expected = engine.exec_query("刘备", RankingAlgorithm::OkapiBM25);
+        assert!(!expected.is_empty());
+        for path in [fast_path, best_path] {
+            let loaded_engine = Engine::load_from(path).unwrap();
+            assert_eq!(loaded_engine.doc_count(), engine.doc_count());
+            assert_eq!(loaded_engine.exec_query("刘备", RankingAlgorithm::OkapiBM25), expected);
+        }
+    }
+
+    #[test]
+    fn test_export_matrix() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let vocab = engine.analyzer.get_dictionary().get_term_count();
+        let (doc_id, _) = engine.doc_meta.iter().find(|(_, path)| path.ends_with("a/2.txt")).unwrap();
+        let (term_ids, _) = engine.analyzer.parse("quarrel");
+        let weight = engine.index.get_doc_tfidf_vector(*doc_id)[&term_ids[0]];
+
+        let mtx_path = ".rir/matrix/romeo_juliet.mtx";
+        engine.export_matrix(mtx_path, MatrixFormat::MatrixMarket).unwrap();
+        let content = fs::read_to_string(mtx_path).unwrap();
+        let mut lines = content.lines().skip(1);
+        let dims: Vec<usize> = lines.next().unwrap().split(' ').map(|v| v.parse().unwrap()).collect();
+        assert_eq!(dims[..2], [5, vocab]);
+        let entries: Vec<&str> = lines.collect();
+        assert_eq!(entries.len(), dims[2]);
+        let entry = format!("{} {} ", doc_id, term_ids[0]);
+        let line = entries.iter().find(|line| line.starts_with(&entry)).unwrap();
+        assert_eq!(line[entry.len()..].parse::<f32>().unwrap(), weight);
+
+        let csr_path = ".rir/matrix/romeo_juliet.csr";
+        engine.export_matrix(csr_path, MatrixFormat::Csr).unwrap();
+        let content = fs::read_to_string(csr_path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines[0], format!("5 {} {}", vocab, dims[2]));
+        let offsets: Vec<usize> = lines[1].split(' ').map(|v| v.parse().unwrap()).collect();
+        assert_eq!(offsets.len(), 6);
+        assert_eq!(offsets[5], dims[2]);
+    }
+
+    #[test]
+    fn test_exec_query_ranked() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let hits = engine.exec_query_ranked("quarrel sir", RankingAlgorithm::Bim, TieRanks::Sequential);
+        assert_eq!(hits.len(), 4);
+        assert_eq!(hits.iter().map(|hit| hit.rank).collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+        assert!(hits.windows(2).all(|pair| pair[0].score >= pair[1].score));
+        let paths: Vec<&String> = hits.iter().map(|hit| hit.path).collect();
+        assert_eq!(paths, engine.exec_query("quarrel sir", RankingAlgorithm::Bim));
+        // BIM ignores term frequency: two docs with both terms, two with sir only
+        let hits = engine.exec_query_ranked("quarrel sir", RankingAlgorithm::Bim, TieRanks::Shared);
+        assert_eq!(hits.iter().map(|hit| hit.rank).collect::<Vec<_>>(), vec![1, 1, 3, 3]);
+    }
+
+    #[test]
+    fn test_exec_query_mmr() {
+        let mut engine = Engine::new();
+        let docs: Vec<Document> = [
+            ("quarrel sir, quarrel sir", "a.txt"),
+            ("quarrel sir, quarrel sir", "copy_of_a.txt"),
+            ("do you quarrel, sir? if you do, sir, I am for you", "b.txt"),
+            ("no better", "c.txt"),
+            ("well met", "d.txt"),
+        ].iter().map(|(content, path)| Document::new(content.to_string(), path.to_string())).collect();
+        engine.add_documents(&docs);
+        let ranked = engine.exec_query("quarrel sir", RankingAlgorithm::VectorSpaceModel);
+        assert_eq!(ranked[..2], ["a.txt", "copy_of_a.txt"]);
+        // relevance only
+        let mmr = engine.exec_query_mmr("quarrel sir", RankingAlgorithm::VectorSpaceModel, 1.0, 3);
+        assert_eq!(mmr, ranked[..3]);
+        // the duplicate is demoted below the less relevant but different document
+        let mmr = engine.exec_query_mmr("quarrel sir", RankingAlgorithm::VectorSpaceModel, 0.3, 3);
+        assert_eq!(mmr, ["a.txt", "b.txt", "copy_of_a.txt"]);
+        assert!(engine.exec_query_mmr("quarrel sir", RankingAlgorithm::VectorSpaceModel, 0.3, 0).is_empty());
+    }
+
+    #[test]
+    fn test_save_query_index() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let full_path = ".rir/romeo_juliet_full.idx";
+        let query_path = ".rir/romeo_juliet_query.idx";
+        engine.save_to(full_path).unwrap();
+        engine.save_query_index(query_path).unwrap();
+        let file_size = |path: &str, name: &str| fs::metadata(Path::new(path).join(name)).unwrap().len();
+        // terms of the analyzer and document_frequency of the index are left out
+        assert!(file_size(query_path, Engine::SERIALIZE_NAME_ANALYZER) < file_size(full_path, Engine::SERIALIZE_NAME_ANALYZER));
+        assert!(file_size(query_path, "idx.pl") < file_size(full_path, "idx.pl"));
 
-    pub fn new() -> Self {
-        Engine{
-            index: PositionList::new(),
-            analyzer: Analyzer::new(),
-            doc_meta: HashMap::new(),
+        let full = Engine::load_from(full_path).unwrap();
+        let query = Engine::load_from(query_path).unwrap();
+        assert_eq!(query.doc_count(), full.doc_count());
+        for ranking in [RankingAlgorithm::ExactMatch, RankingAlgorithm::OkapiBM25,
+            RankingAlgorithm::VectorSpaceModel, RankingAlgorithm::LMD] {
+            assert_eq!(query.exec_query("quarrel sir", ranking), full.exec_query("quarrel sir", ranking));
         }
+        assert_eq!(query.term_stats("sir"), full.term_stats("sir"));
+        assert_eq!(query.analyze_query("quarrel sir"), full.analyze_query("quarrel sir"));
+        assert!(query.index.validate());
     }
 
-    pub fn doc_count(&self) -> usize {
-        self.doc_meta.len()
-    }
+    #[test]
+    fn test_auto_stopwords() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        // sir is in 4 of 5 documents
+        assert_eq!(engine.auto_stopwords(0.5), HashSet::from(["sir".to_string()]));
+        assert!(engine.auto_stopwords(0.8).is_empty());
+        assert_eq!(engine.exec_query("quarrel sir", RankingAlgorithm::OkapiBM25).len(), 4);
 
-    pub fn load_from(path: &str) -> Self {
-        let mut engine = Self::new();
-        engine.index = PositionList::load_from(path);
-        engine.load_analyzer(path);
-        engine.load_docmeta(path);
-        return engine;
+        engine.set_auto_stopwords(Some(0.5));
+        let mut docs = engine.exec_query("quarrel sir", RankingAlgorithm::OkapiBM25);
+        docs.sort();
+        assert_eq!(docs, ["./sample_corpus/romeo_juliet/a/1.txt", "./sample_corpus/romeo_juliet/a/2.txt"]);
+        assert!(engine.exec_query("sir", RankingAlgorithm::OkapiBM25).is_empty());
+        // phrases keep their stopwords
+        assert_eq!(engine.exec_query("quarrel sir", RankingAlgorithm::ExactMatch).len(), 2);
+        engine.set_auto_stopwords(None);
+        assert_eq!(engine.exec_query("sir", RankingAlgorithm::OkapiBM25).len(), 4);
     }
 
-    fn load_analyzer(&mut self, path_str: &str){
-        let path = Path::new(path_str).join(Path::new(Self::SERIALIZE_NAME_ANALYZER));
-        let mut encoded:Vec<u8> = vec![];
-        if let Ok(reloaded_al) = serialize::read_file(&path, &mut encoded) {
-            self.analyzer = reloaded_al;
-        }else{
-            self.analyzer = Analyzer::new();
-        }
+    #[test]
+    fn test_phrase_per_length() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let scores = |engine: &Engine| -> HashMap<String, f32> {
+            engine.query("sir", RankingAlgorithm::ExactMatch).into_iter()
+                .map(|doc| (engine.doc_meta[&doc.docid].replace("./sample_corpus/romeo_juliet/", ""), doc.score))
+                .collect()
+        };
+        // "Quarrel sir! no, sir!" has the most occurrences
+        let raw = scores(&engine);
+        assert_eq!(raw["a/2.txt"], 2.0);
+        assert!(raw["5.txt"] < raw["a/2.txt"]);
+        assert_eq!(raw["5.txt"], raw["b/3.txt"]);
+
+        // "Well, sir." is short, one occurrence per two tokens
+        engine.set_phrase_per_length(true);
+        let normalized = scores(&engine);
+        assert_eq!(normalized["5.txt"], 500.0);
+        assert_eq!(normalized["a/2.txt"], 500.0);
+        assert_eq!(normalized["a/1.txt"], 250.0);
+        assert_eq!(normalized["b/3.txt"], 62.5);
+        let ranked = engine.exec_query("sir", RankingAlgorithm::ExactMatch);
+        assert!(ranked[3].ends_with("b/3.txt"));
     }
 
-    fn load_docmeta(&mut self, path_str: &str){
-        let path = Path::new(path_str).join(Path::new(Self::SERIALIZE_NAME_DOCMETA));
-        let mut encoded:Vec<u8> = vec![];
-        if let Ok(reloaded_dm) = serialize::read_file(&path, &mut encoded) {
-            self.doc_meta = reloaded_dm;
-        }else{
-            self.doc_meta = HashMap::new();
-        }
+    #[test]
+    fn test_exec_query_boolean() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let path = |name: &str| format!("./sample_corpus/romeo_juliet/{}", name);
+        let sorted = |docs: Vec<&String>| {
+            let mut docs: Vec<String> = docs.into_iter().cloned().collect();
+            docs.sort();
+            docs
+        };
+
+        let docs = engine.exec_query("sir AND NOT quarrel", RankingAlgorithm::Default);
+        assert_eq!(sorted(docs), vec![path("5.txt"), path("b/3.txt")]);
+        let docs = engine.exec_query("quarrel OR well", RankingAlgorithm::OkapiBM25);
+        assert_eq!(sorted(docs), vec![path("5.txt"), path("a/1.txt"), path("a/2.txt")]);
+        let docs = engine.exec_query("NOT sir", RankingAlgorithm::Default);
+        assert_eq!(docs, vec![&path("b/4.txt")]);
+        assert!(engine.exec_query("sir AND nonexist", RankingAlgorithm::Default).is_empty());
+        // no operators, same as before
+        let docs = engine.exec_query("sir quarrel", RankingAlgorithm::Default);
+        assert_eq!(docs.len(), 4);
+        assert_eq!(engine.exec_query("sir and quarrel", RankingAlgorithm::ExactMatch), Vec::<&String>::new());
     }
 
-    pub fn build_index_from(&mut self, path: &str) -> Result<usize, ()> {
-        for docs in DocParser::new(path).docs(){
-            for doc in docs {
-                self.add_document(&doc).unwrap();
-                if self.index.get_document_count() % 1000 == 0 {
-                    log::debug!("{}", self.index.get_document_count());
-                }    
-            }
-        }
-        log::debug!("build index completed, number of doc: {}", self.doc_count());
-        return Ok(self.doc_count());
+    #[test]
+    fn test_exec_query_with_pins() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let path = |name: &str| format!("./sample_corpus/romeo_juliet/{}", name);
+        let organic = engine.exec_query("sir", RankingAlgorithm::OkapiBM25);
+        assert_eq!(organic.len(), 4);
+        // pin the lowest scoring match and a document not matching at all
+        let low = organic[3].clone();
+        let b4 = path("b/4.txt");
+        let docs = engine.exec_query_with_pins("sir", RankingAlgorithm::OkapiBM25,
+            &[low.as_str(), b4.as_str(), "./not/indexed.txt", low.as_str()]);
+        assert_eq!(docs.len(), 5);
+        assert_eq!(docs[..2], [&low, &b4]);
+        assert_eq!(docs[2..], organic[..3]);
+        assert_eq!(engine.exec_query_with_pins("sir", RankingAlgorithm::OkapiBM25, &[]), organic);
     }
 
-    fn add_document(&mut self, doc: &Document) -> Result<(),()> {
-        let term_ids = self.analyzer.analyze(doc.get_content());
-        let id = self.index.add_document(&term_ids);
-        self.doc_meta.insert(id, doc.get_path().to_owned());
-        Ok(())
+    #[test]
+    fn test_exec_query_quoted_phrase() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let path = |name: &str| format!("./sample_corpus/romeo_juliet/{}", name);
+        let mut docs = engine.exec_query("\"quarrel sir\" well", RankingAlgorithm::OkapiBM25);
+        docs.sort();
+        assert_eq!(docs, vec![&path("a/1.txt"), &path("a/2.txt")]);
+        // loose terms add to the score of phrase matches
+        let docs = engine.exec_query("\"quarrel sir\" no", RankingAlgorithm::OkapiBM25);
+        assert_eq!(docs, vec![&path("a/2.txt"), &path("a/1.txt")]);
+        assert!(engine.exec_query("\"quarrel sir\" \"well sir\"", RankingAlgorithm::Default).is_empty());
+        assert!(engine.exec_query("\"sir quarrel\"", RankingAlgorithm::Default).is_empty());
+        assert!(engine.exec_query("\"quarrel nonexist\" sir", RankingAlgorithm::Default).is_empty());
+        // dangling quote is a literal, the analyzer drops it
+        assert_eq!(engine.exec_query("\"well", RankingAlgorithm::Default), vec![&path("5.txt")]);
     }
 
-    pub fn save_to(&mut self, path_str: &str) -> io::Result<()> {
-        self.index.save_to(path_str)?;
-        self.save_analyzer(path_str)?;
-        self.save_docmeta(path_str)?;
-        Ok(())
+    #[test]
+    #[cfg(unix)]
+    fn test_build_index_skips_unreadable_dir() {
+        use std::os::unix::fs::PermissionsExt;
+        let corpus = "./.rir/unreadable_corpus";
+        let locked = format!("{}/locked", corpus);
+        let _ = fs::remove_dir_all(corpus);
+        fs::create_dir_all(&locked).unwrap();
+        fs::write(format!("{}/readable.txt", corpus), "Well, sir.").unwrap();
+        fs::write(format!("{}/hidden.txt", locked), "Do you quarrel, sir?").unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        // root may still read the locked directory
+        let locked_readable = fs::read_dir(&locked).is_ok();
+        let mut engine = Engine::new();
+        let res = engine.build_index_from(corpus);
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(res, Ok(if locked_readable { 2 } else { 1 }));
+        assert_eq!(engine.exec_query("well", RankingAlgorithm::Default),
+            vec![&format!("{}/readable.txt", corpus)]);
     }
 
-    pub fn save_analyzer(&mut self, path_str: &str) -> io::Result<()> {
-        let path = Path::new(path_str).join(Path::new(Self::SERIALIZE_NAME_ANALYZER));
-        serialize::write_file(&path, &self.analyzer)?;
-        log::debug!("analyzer save to {}", path.to_string_lossy());
-        Ok(())
+    #[test]
+    fn test_set_bm25_k1_b() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let path = |name: &str| format!("./sample_corpus/romeo_juliet/{}", name);
+        // 5.txt, a/1.txt and b/3.txt mention sir once, shorter documents first
+        let docs = engine.exec_query("sir", RankingAlgorithm::OkapiBM25);
+        assert_eq!(docs[1..], [&path("5.txt"), &path("a/1.txt"), &path("b/3.txt")]);
+        // without length normalization they tie
+        engine.set_bm25_k1_b(1.2, 0.0);
+        let hits = engine.exec_query_ranked("sir", RankingAlgorithm::OkapiBM25, TieRanks::Shared);
+        assert_eq!(hits.iter().map(|hit| hit.rank).collect::<Vec<_>>(), vec![1, 2, 2, 2]);
+        // out of range values are clamped
+        engine.set_bm25_k1_b(1.2, 2.0);
+        let clamped: Vec<String> = engine.exec_query("sir", RankingAlgorithm::OkapiBM25).into_iter().cloned().collect();
+        engine.set_bm25_k1_b(1.2, 1.0);
+        assert_eq!(engine.exec_query("sir", RankingAlgorithm::OkapiBM25), clamped.iter().collect::<Vec<_>>());
     }
 
-    pub fn save_docmeta(&mut self, path_str: &str) -> io::Result<()> {
-        let path = Path::new(path_str).join(Path::new(Self::SERIALIZE_NAME_DOCMETA));
-        serialize::write_file(&path, &self.doc_meta)?;        
-        log::debug!("docmeta save to {}", path.to_string_lossy());
-        Ok(())
+    #[test]
+    fn test_exec_query_json() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let json = engine.exec_query_json("quarrel sir", RankingAlgorithm::ExactMatch, false, None, 0, 10).unwrap();
+        let hits: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(hits.as_array().unwrap().len(), 2);
+        assert!(hits[0].get("highlights").is_none());
+
+        let json = engine.exec_query_json("quarrel sir", RankingAlgorithm::ExactMatch, true, None, 0, 10).unwrap();
+        let hits: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let hit = hits.as_array().unwrap().iter()
+            .find(|hit| hit["path"] == "./sample_corpus/romeo_juliet/a/2.txt")
+            .unwrap();
+        // Quarrel sir! no, sir!
+        assert_eq!(hit["highlights"], serde_json::json!([
+            {"term": "quarrel", "positions": [1]},
+            {"term": "sir", "positions": [2, 4]},
+        ]));
+        assert!(hit["rank"].as_u64().unwrap() >= 1);
+
+        // a page keeps the ranks of the full list
+        let json = engine.exec_query_json("quarrel sir", RankingAlgorithm::OkapiBM25, false, None, 1, 2).unwrap();
+        let hits: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let (total, page) = engine.exec_query_page("quarrel sir", RankingAlgorithm::OkapiBM25, None, 1, 2);
+        assert_eq!(total, 4);
+        assert_eq!(hits.as_array().unwrap().len(), 2);
+        assert_eq!((&hits[0]["rank"], &hits[1]["rank"]), (&serde_json::json!(2), &serde_json::json!(3)));
+        assert_eq!(hits[0]["path"], *page[0].1);
+        let json = engine.exec_query_json("quarrel sir", RankingAlgorithm::OkapiBM25, false, None, 4, 2).unwrap();
+        assert_eq!(json, "[]");
     }
 
-    pub fn stats(&self) -> Stats {
-        Stats{
-            index: self.index.stats(self.analyzer.get_dictionary()),
-            analyzer: self.analyzer.stats(),
-        }
+    #[test]
+    fn test_exec_query_scored() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let docs = engine.exec_query_scored("quarrel sir", RankingAlgorithm::OkapiBM25);
+        assert_eq!(docs.len(), 4);
+        assert_eq!(docs[0].0, "./sample_corpus/romeo_juliet/a/2.txt");
+        assert!((docs[0].1 - 1.98).abs() < 0.005);
+        assert!(docs.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+        let paths: Vec<&String> = docs.iter().map(|(path, _)| *path).collect();
+        assert_eq!(engine.exec_query("quarrel sir", RankingAlgorithm::OkapiBM25), paths);
+        // ties keep the same order on every run
+        let tied = engine.exec_query_scored("sir", RankingAlgorithm::Bim);
+        assert_eq!(engine.exec_query_scored("sir", RankingAlgorithm::Bim), tied);
     }
 
-    pub fn exec_query(&self, 
-        phrase_str: &str,
-        ranking: RankingAlgorithm,
-        ) -> Vec<&String>{
-        
-        let ignore_non_exist_term: bool;
-        match ranking {
-            RankingAlgorithm::ExactMatch => ignore_non_exist_term = false,
-            _ => ignore_non_exist_term = true,
-        }
-        let term_ids = Query::parse(phrase_str, ignore_non_exist_term, &self.analyzer);
-        let mut docs = vec![];
-        let doc_scores = self.index.score(&term_ids, ranking);
-        for doc in doc_scores {
-            if let Some(doc_path) = self.doc_meta.get(&doc.docid){
-                docs.push(doc_path);
-            }
-        }    
-        docs
+    #[test]
+    fn test_exec_query_paged() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let all = engine.exec_query("quarrel sir", RankingAlgorithm::OkapiBM25);
+        assert_eq!(all.len(), 4);
+        assert_eq!(engine.exec_query_paged("quarrel sir", RankingAlgorithm::OkapiBM25, 0, 10), all);
+        assert_eq!(engine.exec_query_paged("quarrel sir", RankingAlgorithm::OkapiBM25, 1, 2), all[1..3]);
+        assert_eq!(engine.exec_query_paged("quarrel sir", RankingAlgorithm::OkapiBM25, 3, 2), all[3..]);
+        assert!(engine.exec_query_paged("quarrel sir", RankingAlgorithm::OkapiBM25, 4, 2).is_empty());
+        assert!(engine.exec_query_paged("quarrel sir", RankingAlgorithm::OkapiBM25, 0, 0).is_empty());
     }
 
-}
+    #[test]
+    fn test_add_file() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let stats = engine.stats().index;
+        let new_file = "./.rir/add_file/6.txt";
+        fs::create_dir_all("./.rir/add_file").unwrap();
+        fs::write(new_file, "Part, fools! Put up your swords, sir.").unwrap();
+        let doc_id = engine.add_file(new_file).unwrap();
+        assert_eq!(doc_id, 6);
+        assert_eq!(engine.doc_count(), 6);
+        let updated = engine.stats().index;
+        assert_eq!(updated.document_count, 6);
+        assert_eq!(updated.total_document_length, stats.total_document_length + 7);
+        assert!(engine.add_file("./.rir/add_file/not_exist.txt").is_err());
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let index_path = "./.rir/add_file.idx";
+        engine.save_to(index_path).unwrap();
+        let engine = Engine::load_from(index_path).unwrap();
+        assert_eq!(engine.doc_count(), 6);
+        assert_eq!(engine.exec_query("swords", RankingAlgorithm::Default), vec![&new_file.to_string()]);
+        assert_eq!(engine.exec_query("sir", RankingAlgorithm::Default).len(), 5);
+    }
 
     #[test]
-    fn test_build_index() {
+    fn test_stemming() {
         let mut engine = Engine::new();
+        engine.set_stemming(true);
         let res = engine.build_index_from("./sample_corpus/romeo_juliet");
         assert_eq!(res, Ok(5));
+        let index_path = "./.rir/stemming.idx";
+        engine.save_to(index_path).unwrap();
+        let engine = Engine::load_from(index_path).unwrap();
+        let mut docs = engine.exec_query("quarrels", RankingAlgorithm::Default);
+        docs.sort();
+        assert_eq!(docs, vec!["./sample_corpus/romeo_juliet/a/1.txt", "./sample_corpus/romeo_juliet/a/2.txt"]);
     }
 
     #[test]
-    fn test_save_and_load_index() {
+    fn test_stopwords() {
         let mut engine = Engine::new();
+        engine.set_stopwords(&["you", "no"]);
         let res = engine.build_index_from("./sample_corpus/romeo_juliet");
         assert_eq!(res, Ok(5));
-        assert_eq!(engine.doc_count(), 5);
-        let index_path = ".rir/romeo_juliet1.idx";
-        let _ = engine.save_to(index_path);
-        let loaded_engine = Engine::load_from(index_path);
-        assert_eq!(loaded_engine.doc_count(), 5);
-        let docs = loaded_engine.exec_query("Quarrel sir", RankingAlgorithm::ExactMatch);
-        use std::collections::HashSet;
-        let doc_set: HashSet<&String> = HashSet::from_iter(docs);
-        assert_eq!(doc_set, HashSet::from([&"./sample_corpus/romeo_juliet/a/1.txt".to_string(), &"./sample_corpus/romeo_juliet/a/2.txt".to_string()]));
+        assert!(engine.exec_query("you", RankingAlgorithm::Default).is_empty());
+        assert!(engine.exec_query("no", RankingAlgorithm::ExactMatch).is_empty());
+        assert_eq!(engine.exec_query("you quarrel", RankingAlgorithm::Default).len(), 2);
+        // "Quarrel sir! no, sir!": sir and sir are not adjacent
+        assert!(engine.exec_query("sir sir", RankingAlgorithm::ExactMatch).is_empty());
+        assert_eq!(engine.exec_query("quarrel sir", RankingAlgorithm::ExactMatch).len(), 2);
     }
 
     #[test]
-    fn test_search_phrase() {
+    fn test_exec_query_prefix() {
         let mut engine = Engine::new();
         let res = engine.build_index_from("./sample_corpus/romeo_juliet");
         assert_eq!(res, Ok(5));
-        let mut docs = engine.exec_query("Quarrel sir", RankingAlgorithm::ExactMatch);
-        use std::collections::HashSet;
-        let doc_set: HashSet<&String> = HashSet::from_iter(docs);
-        assert_eq!(doc_set, HashSet::from([&"./sample_corpus/romeo_juliet/a/1.txt".to_string(), &"./sample_corpus/romeo_juliet/a/2.txt".to_string()]));
-        docs = engine.exec_query("sir", RankingAlgorithm::ExactMatch);
+        let mut docs = engine.exec_query("si*", RankingAlgorithm::Default);
+        docs.sort();
+        let mut sir = engine.exec_query("sir", RankingAlgorithm::Default);
+        sir.sort();
         assert_eq!(docs.len(), 4);
+        assert_eq!(docs, sir);
+        assert_eq!(engine.exec_query("quar* well", RankingAlgorithm::Default).len(), 3);
+        assert!(engine.exec_query("zz*", RankingAlgorithm::Default).is_empty());
+        // an exact match takes any of the expansions at the prefix position,
+        // s* expands to sir and serve
+        let mut docs = engine.exec_query("quarrel s*", RankingAlgorithm::ExactMatch);
+        docs.sort();
+        let mut phrase = engine.exec_query("quarrel sir", RankingAlgorithm::ExactMatch);
+        phrase.sort();
+        assert!(!phrase.is_empty());
+        assert_eq!(docs, phrase);
+        assert!(engine.exec_query("zz* sir", RankingAlgorithm::ExactMatch).is_empty());
+    }
 
-        docs = engine.exec_query("non-exist", RankingAlgorithm::ExactMatch);
-        assert_eq!(docs.len(), 0);
+    #[test]
+    fn test_exec_query_fuzzy() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        assert!(engine.exec_query("quarrle", RankingAlgorithm::Default).is_empty());
+        let mut quarrel = engine.exec_query("quarrel", RankingAlgorithm::Default);
+        quarrel.sort();
+        let quarrel: Vec<String> = quarrel.into_iter().cloned().collect();
+        engine.set_fuzzy(Some(1));
+        let mut docs = engine.exec_query("quarrle", RankingAlgorithm::Default);
+        docs.sort();
+        assert!(!docs.is_empty());
+        assert_eq!(docs, quarrel.iter().collect::<Vec<&String>>());
+        // known terms are not expanded
+        assert_eq!(engine.exec_query("sir", RankingAlgorithm::Default).len(), 4);
+        assert!(engine.exec_query("zzzzzz", RankingAlgorithm::Default).is_empty());
+        // an exact match takes any of the close terms at the position of the word
+        let mut phrase = engine.exec_query("quarrel sir", RankingAlgorithm::ExactMatch);
+        phrase.sort();
+        assert!(!phrase.is_empty());
+        let mut docs = engine.exec_query("quarrle sir", RankingAlgorithm::ExactMatch);
+        docs.sort();
+        assert_eq!(docs, phrase);
+        // ao is within one edit of a, am, as, do and no
+        let mut docs = engine.exec_query("sir ao", RankingAlgorithm::ExactMatch);
+        docs.sort();
+        let mut sir_no = engine.exec_query("sir no", RankingAlgorithm::ExactMatch);
+        sir_no.sort();
+        assert!(!sir_no.is_empty());
+        assert_eq!(docs, sir_no);
+        assert!(engine.exec_query("quarrel zzzzzz", RankingAlgorithm::ExactMatch).is_empty());
+        // one word of several tokens, the misspelled one keeps its position
+        let mut docs = engine.exec_query("quarrle,sir", RankingAlgorithm::ExactMatch);
+        docs.sort();
+        assert_eq!(docs, phrase);
+        assert!(engine.exec_query("sir,quarrle", RankingAlgorithm::ExactMatch).is_empty());
+        engine.set_fuzzy(None);
+        assert!(engine.exec_query("quarrle", RankingAlgorithm::Default).is_empty());
+    }
 
-        docs = engine.exec_query("Sir non-exist", RankingAlgorithm::ExactMatch);
-        assert_eq!(docs.len(), 0);
+    #[test]
+    fn test_get_doc_id() {
+        let mut engine = Engine::new();
+        assert!(engine.build_index_from("./sample_corpus/wiki_lines").unwrap() > 1);
+        // the first of the documents of a jsonlines file
+        let path = engine.doc_meta.values().next().unwrap().clone();
+        let first_id = engine.doc_meta.iter().filter(|(_, p)| **p == path).map(|(id, _)| *id).min();
+        assert_eq!(engine.get_doc_id(&path), first_id);
+        assert_eq!(engine.get_doc_id("./sample_corpus/none.txt"), None);
+        let index_path = ".rir/wiki_lines_doc_id.idx";
+        engine.save_to(index_path).unwrap();
+        let loaded_engine = Engine::load_from(index_path).unwrap();
+        assert_eq!(loaded_engine.get_doc_id(&path), first_id);
+        engine.clear();
+        assert_eq!(engine.get_doc_id(&path), None);
+    }
 
-        docs = engine.exec_query("Sir", RankingAlgorithm::ExactMatch);
-        assert_eq!(docs.len(), 4);
+    #[test]
+    fn test_clear_keeps_analyzer_settings() {
+        let mut engine = Engine::new();
+        engine.set_stemming(true);
+        engine.set_stopwords(&["sir"]);
+        assert_eq!(engine.build_index_from("./sample_corpus/romeo_juliet"), Ok(5));
+        let analyzed = engine.analyze_query("quarrelling sir");
+        assert_eq!(analyzed.0, ["quarrel"]);
+        engine.clear();
+        assert!(engine.analyze_query("quarrelling sir").0.is_empty());
+        assert_eq!(engine.build_index_from("./sample_corpus/romeo_juliet"), Ok(5));
+        assert_eq!(engine.analyze_query("quarrelling sir"), analyzed);
+        assert!(engine.exec_query("sir", RankingAlgorithm::OkapiBM25).is_empty());
+    }
 
+    #[test]
+    fn test_snippet() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let path = "./sample_corpus/romeo_juliet/b/3.txt";
+        let doc = engine.get_doc_id(path).unwrap();
+        // "If you do, sir, I am for you: I serve as good a man as you."
+        let terms = engine.query_term_ids("serve man");
+        assert_eq!(engine.snippet(doc, &terms, 2), Some(String::from("you: I **serve** as good")));
+        assert_eq!(engine.snippet(doc, &terms, 4), Some(String::from("am for you: I **serve** as good a **man**")));
+        assert_eq!(engine.snippet(doc, &engine.query_term_ids("sir"), 0), Some(String::from("**sir**")));
+        assert_eq!(engine.snippet(doc, &engine.query_term_ids("romeo"), 2), None);
+        // the file is gone since indexing
+        let dir = ".rir/snippet_corpus";
+        let _ = fs::create_dir_all(dir);
+        let file = format!("{}/gone.txt", dir);
+        fs::write(&file, "a plague on both your houses").unwrap();
+        let doc = engine.add_file(&file).unwrap();
+        let terms = engine.query_term_ids("plague");
+        assert_eq!(engine.snippet(doc, &terms, 1), Some(String::from("a **plague** on")));
+        fs::remove_file(&file).unwrap();
+        assert_eq!(engine.snippet(doc, &terms, 1), None);
     }
 
     #[test]
-    fn test_vector_space_model() {
+    fn test_match_positions() {
         let mut engine = Engine::new();
         let res = engine.build_index_from("./sample_corpus/romeo_juliet");
         assert_eq!(res, Ok(5));
-        assert_eq!(engine.doc_count(), 5);
-        let index_path = ".rir/romeo_juliet2.idx";
-        let _ = engine.save_to(index_path);
-        let loaded_engine = Engine::load_from(index_path);
-        assert_eq!(loaded_engine.doc_count(), 5);
-        let docs = loaded_engine.exec_query("Quarrel sir", RankingAlgorithm::VectorSpaceModel);
-        use std::collections::HashSet;
-        let doc_set: HashSet<&String> = HashSet::from_iter(docs);
-        assert_eq!(doc_set, HashSet::from([
-                &"./sample_corpus/romeo_juliet/a/2.txt".to_string(), 
-                &"./sample_corpus/romeo_juliet/a/1.txt".to_string(),
-                &"./sample_corpus/romeo_juliet/5.txt".to_string(), 
-                &"./sample_corpus/romeo_juliet/b/3.txt".to_string(),
-                ]));
+        let path = "./sample_corpus/romeo_juliet/a/2.txt";
+        let doc = engine.get_doc_id(path).unwrap();
+        // "Quarrel sir! no, sir!"
+        assert_eq!(engine.match_positions(doc, "quarrel sir"), vec![(1, 2)]);
+        assert_eq!(engine.match_positions(doc, "sir"), vec![(2, 2), (4, 4)]);
+        assert_eq!(engine.match_positions(doc, "sir quarrel"), vec![]);
+        assert_eq!(engine.match_positions(doc, "quarrel romeo"), vec![]);
+        let content = fs::read_to_string(path).unwrap();
+        let ranges = engine.match_byte_ranges(doc, "quarrel sir").unwrap();
+        assert_eq!(ranges.iter().map(|&(start, end)| &content[start..end]).collect::<Vec<_>>(), ["Quarrel sir"]);
+        let ranges = engine.match_byte_ranges(doc, "Sir").unwrap();
+        assert_eq!(ranges, vec![(8, 11), (17, 20)]);
+        assert!(ranges.iter().all(|&(start, end)| &content[start..end] == "sir"));
+
+        // "If you do, sir, I am for you: I serve as good a man as you."
+        let path = "./sample_corpus/romeo_juliet/b/3.txt";
+        let doc = engine.get_doc_id(path).unwrap();
+        let content = fs::read_to_string(path).unwrap();
+        let ranges = engine.match_byte_ranges(doc, "serve as good").unwrap();
+        assert_eq!(ranges.iter().map(|&(start, end)| &content[start..end]).collect::<Vec<_>>(), ["serve as good"]);
+        assert_eq!(engine.match_byte_ranges(doc, "romeo"), Some(vec![]));
     }
 
     #[test]
-    fn test_rank_bm25() {
+    fn test_exec_query_near() {
         let mut engine = Engine::new();
         let res = engine.build_index_from("./sample_corpus/romeo_juliet");
         assert_eq!(res, Ok(5));
-        assert_eq!(engine.doc_count(), 5);
-        let index_path = ".rir/romeo_juliet3.idx";
+        // "Do you quarrel, sir?" in a/1.txt, in either order
+        let mut docs = engine.exec_query("sir NEAR/1 quarrel", RankingAlgorithm::Default);
+        docs.sort();
+        assert_eq!(docs, vec!["./sample_corpus/romeo_juliet/a/1.txt", "./sample_corpus/romeo_juliet/a/2.txt"]);
+        assert_eq!(engine.exec_query("quarrel NEAR/1 sir", RankingAlgorithm::ExactMatch).len(), 2);
+        assert!(engine.exec_query("quarrel NEAR/1 do", RankingAlgorithm::Default).is_empty());
+        assert_eq!(engine.exec_query("quarrel NEAR/2 do", RankingAlgorithm::Default),
+            vec!["./sample_corpus/romeo_juliet/a/1.txt"]);
+        assert!(engine.exec_query("quarrel NEAR/5 nobody", RankingAlgorithm::Default).is_empty());
+    }
+
+    #[test]
+    fn test_search_field() {
+        let dir = ".rir/fields_corpus";
+        let _ = fs::create_dir_all(dir);
+        fs::write(format!("{}/.rircfg", dir), "file_type: jsonlines\nfields:\n  - title\n  - text\n").unwrap();
+        fs::write(format!("{}/lines", dir), concat!(
+            r#"{"title": "Quarrel", "text": "Do you bite your thumb at us, sir?"}"#, "\n",
+            r#"{"title": "Thumb", "text": "Do you quarrel, sir?"}"#, "\n")).unwrap();
+        let mut engine = Engine::new();
+        assert_eq!(engine.build_index_from(dir), Ok(2));
+        let line = |n: usize| format!("{}/lines:{}", dir, n);
+        let mut docs = engine.exec_query("quarrel", RankingAlgorithm::Default);
+        docs.sort();
+        assert_eq!(docs, vec![&line(1), &line(2)]);
+        // a term in the title is not matched by a text restricted query
+        assert_eq!(engine.search_field("text", "quarrel", RankingAlgorithm::Default), Some(vec![&line(2)]));
+        assert_eq!(engine.search_field("title", "quarrel", RankingAlgorithm::Default), Some(vec![&line(1)]));
+        assert_eq!(engine.search_field("title", "sir", RankingAlgorithm::Default), Some(vec![]));
+        assert_eq!(engine.search_field("body", "quarrel", RankingAlgorithm::Default), None);
+        // fields survive save and load
+        let index_path = ".rir/fields_corpus.idx";
         let _ = engine.save_to(index_path);
-        let loaded_engine = Engine::load_from(index_path);
-        assert_eq!(loaded_engine.doc_count(), 5);
-        let docs = loaded_engine.exec_query("Quarrel sir", RankingAlgorithm::OkapiBM25);
-        use std::collections::HashSet;
-        let doc_set: HashSet<&String> = HashSet::from_iter(docs);
-        assert_eq!(doc_set, HashSet::from([
-                &"./sample_corpus/romeo_juliet/a/2.txt".to_string(), 
-                &"./sample_corpus/romeo_juliet/a/1.txt".to_string(),
-                &"./sample_corpus/romeo_juliet/5.txt".to_string(), 
-                &"./sample_corpus/romeo_juliet/b/3.txt".to_string(),
-                ]));
+        let loaded_engine = Engine::load_from(index_path).unwrap();
+        assert_eq!(loaded_engine.search_field("text", "thumb", RankingAlgorithm::Default), Some(vec![&line(1)]));
     }
 
     #[test]
-    fn test_rank_default() {
+    fn test_set_field_weights() {
+        let dir = ".rir/field_weights_corpus";
+        let _ = fs::create_dir_all(dir);
+        fs::write(format!("{}/.rircfg", dir), "file_type: jsonlines\nfields:\n  - title\n  - text\n").unwrap();
+        fs::write(format!("{}/lines", dir), concat!(
+            r#"{"title": "Thumb", "text": "Do you quarrel, sir?"}"#, "\n",
+            r#"{"title": "Quarrel", "text": "Do you thumb, sir?"}"#, "\n",
+            r#"{"title": "Well", "text": "No better, sir."}"#, "\n")).unwrap();
+        let mut engine = Engine::new();
+        assert_eq!(engine.build_index_from(dir), Ok(3));
+        let line = |n: usize| format!("{}/lines:{}", dir, n);
+        let scored = engine.exec_query_scored("quarrel", RankingAlgorithm::Default);
+        assert_eq!(scored[0].1, scored[1].1);
+        let mut weights = FieldWeights::new();
+        weights.set("title", 2.0);
+        engine.set_field_weights(Some(weights));
+        assert_eq!(engine.exec_query("quarrel", RankingAlgorithm::OkapiBM25), vec![&line(2), &line(1)]);
+        engine.set_field_weights(None);
+        let scored = engine.exec_query_scored("quarrel", RankingAlgorithm::Default);
+        assert_eq!(scored[0].1, scored[1].1);
+    }
+
+    #[test]
+    fn test_exec_query_fused() {
         let mut engine = Engine::new();
         let res = engine.build_index_from("./sample_corpus/romeo_juliet");
         assert_eq!(res, Ok(5));
-        assert_eq!(engine.doc_count(), 5);
-        let index_path = ".rir/romeo_juliet4.idx";
-        let _ = engine.save_to(index_path);
-        let loaded_engine = Engine::load_from(index_path);
-        assert_eq!(loaded_engine.doc_count(), 5);
-        let docs = loaded_engine.exec_query("Quarrel sir", RankingAlgorithm::Default);
-        use std::collections::HashSet;
-        let doc_set: HashSet<&String> = HashSet::from_iter(docs);
-        assert_eq!(doc_set, HashSet::from([
-                &"./sample_corpus/romeo_juliet/a/2.txt".to_string(), 
-                &"./sample_corpus/romeo_juliet/a/1.txt".to_string(),
-                &"./sample_corpus/romeo_juliet/5.txt".to_string(), 
-                &"./sample_corpus/romeo_juliet/b/3.txt".to_string(),
-                ]));
+        let path = |p: &str| format!("./sample_corpus/romeo_juliet/{}", p);
+        let both = [RankingAlgorithm::OkapiBM25, RankingAlgorithm::VectorSpaceModel];
+        // BM25 and VSM swap 5.txt and a/1.txt, fused they tie and keep doc id order
+        assert_eq!(engine.exec_query("no sir", RankingAlgorithm::OkapiBM25)[2..4], [&path("5.txt"), &path("a/1.txt")]);
+        assert_eq!(engine.exec_query("no sir", RankingAlgorithm::VectorSpaceModel)[2..4], [&path("a/1.txt"), &path("5.txt")]);
+        let fused = engine.exec_query_fused("no sir", &both, RRF_K);
+        let paths: Vec<&String> = fused.iter().map(|(path, _)| *path).collect();
+        assert_eq!(paths, vec![&path("a/2.txt"), &path("b/4.txt"), &path("5.txt"), &path("a/1.txt"), &path("b/3.txt")]);
+        assert_eq!(fused[0].1, 2.0 / 61.0);
+        assert_eq!(fused[2].1, fused[3].1);
+        assert_eq!(engine.exec_query_fused("no sir", &[both[1], both[0]], RRF_K), fused);
+        // a single ranking keeps its order
+        let bm25: Vec<&String> = engine.exec_query_fused("no sir", &both[..1], RRF_K).into_iter().map(|(path, _)| path).collect();
+        assert_eq!(bm25, engine.exec_query("no sir", RankingAlgorithm::OkapiBM25));
+        assert_eq!(engine.exec_query_fused("no sir", &both, -1.0), fused);
+        assert!(engine.exec_query_fused("no sir", &[], RRF_K).is_empty());
+    }
 
+    #[test]
+    fn test_exec_query_scored_filtered() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let all = engine.exec_query_scored("quarrel sir", RankingAlgorithm::OkapiBM25);
+        assert_eq!(all.len(), 4);
+        assert_eq!(engine.exec_query_scored_filtered("quarrel sir", RankingAlgorithm::OkapiBM25, None), all);
+        let mut last_len = all.len();
+        for min_score in [0.3, 1.0, 1.9, 5.0] {
+            let filtered = engine.exec_query_scored_filtered("quarrel sir", RankingAlgorithm::OkapiBM25, Some(min_score));
+            assert!(filtered.len() < last_len);
+            assert!(filtered.iter().all(|(_, score)| *score >= min_score));
+            // the top of the same order
+            assert_eq!(filtered, all[..filtered.len()]);
+            last_len = filtered.len();
+        }
+        assert_eq!(last_len, 0);
+        // LMD scores can be negative, a negative threshold keeps some of them
+        let lmd = engine.exec_query_scored("quarrel sir", RankingAlgorithm::LMD);
+        assert!(lmd.iter().any(|(_, score)| *score < 0.0));
+        assert!(engine.exec_query_scored_filtered("quarrel sir", RankingAlgorithm::LMD, Some(0.0)).len() < lmd.len());
+        // the same results with their doc ids
+        let hits = engine.exec_query_hits("quarrel sir", RankingAlgorithm::OkapiBM25, Some(1.0));
+        assert_eq!(hits.iter().map(|&(_, path, score)| (path, score)).collect::<Vec<_>>(),
+            engine.exec_query_scored_filtered("quarrel sir", RankingAlgorithm::OkapiBM25, Some(1.0)));
+        assert!(hits.iter().all(|&(doc_id, path, _)| engine.doc_meta[&doc_id] == *path));
+        let json = engine.exec_query_json("quarrel sir", RankingAlgorithm::OkapiBM25, false, Some(1.0), 0, 10).unwrap();
+        assert_eq!(json.matches("\"rank\"").count(), 2);
     }
 
     #[test]
-    fn test_chinese_text_index() {
+    fn test_build_index_thread_count() {
+        // the saved index is the same bytes whatever the number of threads
+        let build_with = |threads: usize, corpus: &str, index_path: &str| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            pool.install(|| {
+                let mut engine = Engine::new();
+                engine.set_store_content(true);
+                assert!(engine.build_index_from(corpus).is_ok());
+                assert!(engine.save_to(index_path).is_ok());
+            });
+        };
+        for (corpus, name) in [("./sample_corpus/romeo_juliet", "romeo"), ("./sample_corpus/wiki_lines", "lines")] {
+            let single = format!(".rir/threads_{}_1.idx", name);
+            let multi = format!(".rir/threads_{}_4.idx", name);
+            build_with(1, corpus, &single);
+            build_with(4, corpus, &multi);
+            let mut files: Vec<_> = fs::read_dir(&single).unwrap().flatten().map(|entry| entry.file_name()).collect();
+            files.sort();
+            assert_eq!(files.len(), 6);
+            for file in files {
+                assert_eq!(fs::read(Path::new(&single).join(&file)).unwrap(),
+                    fs::read(Path::new(&multi).join(&file)).unwrap(), "{:?}", file);
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_from_errors() {
+        let missing = Engine::load_from("./.rir/not_exist.idx");
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(Engine::load_from_or_default("./.rir/not_exist.idx").doc_count(), 0);
+
         let mut engine = Engine::new();
-        let res = engine.build_index_from("./sample_corpus/sanguo");
-        assert_eq!(res, Ok(19));
-        assert_eq!(engine.doc_count(), 19);
-        let index_path = ".rir/sanguo.idx";
-        let _ = engine.save_to(index_path);
-        let loaded_engine = Engine::load_from(index_path);
-        assert_eq!(loaded_engine.doc_count(), 19);
-        let docs = loaded_engine.exec_query("刘备", RankingAlgorithm::ExactMatch);
-        use std::collections::HashSet;
-        let doc_set: HashSet<&String> = HashSet::from_iter(docs);
-        assert_eq!(doc_set, HashSet::from([&"./sample_corpus/sanguo/9.txt".to_string()]));
-        let docs = loaded_engine.exec_query("桃园结义", RankingAlgorithm::Default);
-        let doc_set: HashSet<&String> = HashSet::from_iter(docs);
-        assert_eq!(doc_set, HashSet::from([
-            &"./sample_corpus/sanguo/1.txt".to_string(),
-            &"./sample_corpus/sanguo/9.txt".to_string(),
-            &"./sample_corpus/sanguo/8.txt".to_string(),
-            ]));
+        assert_eq!(engine.build_index_from("./sample_corpus/romeo_juliet"), Ok(5));
+        let index_path = "./.rir/load_errors.idx";
+        let _ = fs::remove_dir_all(index_path);
+        engine.save_to(index_path).unwrap();
+        // every part carries the schema version, a mismatch is a clear error
+        for name in ["idx.pl", Engine::SERIALIZE_NAME_ANALYZER, Engine::SERIALIZE_NAME_DOCMETA] {
+            assert!(fs::read(Path::new(index_path).join(name)).unwrap().starts_with(b"RIR1"));
+        }
+        let analyzer_path = Path::new(index_path).join(Engine::SERIALIZE_NAME_ANALYZER);
+        let analyzer_bytes = fs::read(&analyzer_path).unwrap();
+        let mut bytes = analyzer_bytes.clone();
+        bytes[4] = 0;
+        fs::write(&analyzer_path, &bytes).unwrap();
+        let e = Engine::load_from(index_path).unwrap_err();
+        assert!(e.to_string().contains("idx.al: index schema version 0"), "{}", e);
+        fs::write(&analyzer_path, &analyzer_bytes).unwrap();
+        // optional parts may be missing
+        fs::remove_file(Path::new(index_path).join(Engine::SERIALIZE_NAME_DOCLANG)).unwrap();
+        assert_eq!(Engine::load_from(index_path).unwrap().doc_count(), 5);
+        // a corrupt part is an error, not a panic
+        fs::write(Path::new(index_path).join(Engine::SERIALIZE_NAME_DOCMETA), b"corrupt").unwrap();
+        assert!(Engine::load_from(index_path).is_err());
+        fs::remove_file(Path::new(index_path).join(Engine::SERIALIZE_NAME_DOCMETA)).unwrap();
+        assert_eq!(Engine::load_from(index_path).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
 
+    #[test]
+    fn test_build_index_gbk() {
+        let dir = "./.rir/gbk_corpus";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        fs::write(format!("{}/.rircfg", dir), "file_type: text\nfields: []\nfallback_encoding: gbk\n").unwrap();
+        let text = "数学是利用符号语言研究数量、结构、变化以及空间等概念的一门学科";
+        fs::write(format!("{}/math.txt", dir), encoding_rs::GBK.encode(text).0).unwrap();
+        fs::write(format!("{}/philosophy.txt", dir), "哲学是研究普遍的、根本的问题的学科").unwrap();
+        let mut engine = Engine::new();
+        assert_eq!(engine.build_index_from(dir), Ok(2));
+        assert_eq!(engine.exec_query("数学", RankingAlgorithm::Default), vec![&format!("{}/math.txt", dir)]);
     }
 
     #[test]
-    fn test_build_index_from_json_files() {
+    fn test_build_empty_corpus() {
+        let dir = "./.rir/empty_corpus";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
         let mut engine = Engine::new();
-        let res = engine.build_index_from("./sample_corpus/wiki_zh");
-        assert_eq!(res, Ok(2));
-        assert_eq!(engine.doc_count(), 2);
-        let index_path = ".rir/wiki_zh.idx";
-        let _ = engine.save_to(index_path);
-        let loaded_engine = Engine::load_from(index_path);
-        assert_eq!(loaded_engine.doc_count(), 2);
-        let docs = loaded_engine.exec_query("数学", RankingAlgorithm::ExactMatch);
-        use std::collections::HashSet;
-        let doc_set: HashSet<&String> = HashSet::from_iter(docs);
-        assert_eq!(doc_set, HashSet::from(
-            [&"./sample_corpus/wiki_zh/wiki_1".to_string(),
-            &"./sample_corpus/wiki_zh/wiki_2".to_string()]));
+        assert_eq!(engine.build_index_from(dir), Err(BuildError::NoDocuments(dir.to_string())));
+        // only files too large to index
+        fs::write(format!("{}/large.txt", dir), "Quarrel sir! ".repeat(100)).unwrap();
+        engine.set_max_file_bytes(Some(100));
+        assert_eq!(engine.build_index_from(dir), Err(BuildError::NoDocuments(dir.to_string())));
+        let missing = "./.rir/no_such_corpus";
+        let e = engine.build_index_from(missing).unwrap_err();
+        assert_eq!(e, BuildError::NotFound(missing.to_string()));
+        assert!(e.to_string().contains("does not exist"));
+        assert_eq!(engine.doc_count(), 0);
+        // nor into an engine that already has documents
+        let _ = fs::remove_file(format!("{}/large.txt", dir));
+        engine.set_max_file_bytes(None);
+        assert!(engine.build_index_from("./sample_corpus/romeo_juliet").unwrap() > 0);
+        let doc_count = engine.doc_count();
+        assert_eq!(engine.build_index_from(dir), Err(BuildError::NoDocuments(dir.to_string())));
+        assert_eq!(engine.doc_count(), doc_count);
+    }
+
+    #[test]
+    fn test_query_empty_engine() {
+        let engine = Engine::new();
+        for ranking in [RankingAlgorithm::Default, RankingAlgorithm::ExactMatch, RankingAlgorithm::VectorSpaceModel,
+            RankingAlgorithm::OkapiBM25, RankingAlgorithm::LMD, RankingAlgorithm::Bim] {
+            assert!(engine.exec_query("quarrel sir", ranking).is_empty());
+            assert!(engine.query("quarrel^2 sir", ranking).is_empty());
+        }
+        let request = SearchRequest::new(QueryType::Terms("quarrel sir".to_string()));
+        assert!(engine.search(&request).is_empty());
+    }
+
+    #[test]
+    fn test_max_file_bytes() {
+        let dir = "./.rir/max_file_corpus";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        fs::write(format!("{}/small.txt", dir), "Quarrel sir!").unwrap();
+        fs::write(format!("{}/large.txt", dir), "Quarrel sir! ".repeat(100)).unwrap();
+        let mut engine = Engine::new();
+        engine.set_max_file_bytes(Some(100));
+        assert_eq!(engine.build_index_from(dir), Ok(1));
+        assert_eq!(engine.exec_query("quarrel", RankingAlgorithm::Default), vec![&format!("{}/small.txt", dir)]);
+        // unlimited by default
+        let mut engine = Engine::new();
+        assert_eq!(engine.build_index_from(dir), Ok(2));
+        // or limited by the corpus config, streamed files too
+        fs::write(format!("{}/.rircfg", dir), "file_type: text\nfields: []\nmax_file_bytes: 100\n").unwrap();
+        let mut engine = Engine::new();
+        assert_eq!(engine.build_index_from(dir), Ok(1));
+        fs::write(format!("{}/.rircfg", dir), "file_type: jsonlines\nfields:\n  - text\nmax_file_bytes: 100\n").unwrap();
+        fs::write(format!("{}/small.txt", dir), r#"{"text": "Quarrel sir!"}"#).unwrap();
+        fs::write(format!("{}/large.txt", dir), format!("{}\n", r#"{"text": "Quarrel sir!"}"#).repeat(10)).unwrap();
+        let mut engine = Engine::new();
+        assert_eq!(engine.build_index_from(dir), Ok(1));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_exec_query_boosted() {
+        let mut engine = Engine::new();
+        let res = engine.build_index_from("./sample_corpus/romeo_juliet");
+        assert_eq!(res, Ok(5));
+        let plain = engine.exec_query("quarrel sir", RankingAlgorithm::OkapiBM25);
+        assert_eq!(plain[1..3], ["./sample_corpus/romeo_juliet/a/1.txt", "./sample_corpus/romeo_juliet/5.txt"]);
+        assert_eq!(engine.exec_query_scored("quarrel^1 sir", RankingAlgorithm::OkapiBM25),
+            engine.exec_query_scored("quarrel sir", RankingAlgorithm::OkapiBM25));
+        // without the weight of quarrel, 5.txt with more sir goes before a/1.txt
+        for ranking in [RankingAlgorithm::OkapiBM25, RankingAlgorithm::LMD] {
+            let boosted = engine.exec_query("quarrel^0 sir", ranking);
+            assert_eq!(boosted[1..3], ["./sample_corpus/romeo_juliet/5.txt", "./sample_corpus/romeo_juliet/a/1.txt"]);
+        }
+        let boosted = engine.exec_query_scored("quarrel^3 sir", RankingAlgorithm::OkapiBM25);
+        let plain = engine.exec_query_scored("quarrel sir", RankingAlgorithm::OkapiBM25);
+        assert!(boosted[0].1 > plain[0].1);
+    }
+
+    #[test]
+    fn test_shingles() {
+        let dir = ".rir/shingles_corpus";
+        let _ = fs::create_dir_all(dir);
+        fs::write(format!("{}/.rircfg", dir), "file_type: text\nfields: []\nshingles:\n  n: 2\n  unigrams: true\n").unwrap();
+        fs::write(format!("{}/adjacent.txt", dir), "Do you quarrel, sir?").unwrap();
+        fs::write(format!("{}/apart.txt", dir), "Quarrel? No, sir.").unwrap();
+        let mut engine = Engine::new();
+        assert_eq!(engine.build_index_from(dir), Ok(2));
+        // only the document with the adjacent words has the bigram
+        let adjacent = format!("{}/adjacent.txt", dir);
+        let (term_ids, _) = engine.analyzer.parse("quarrel sir");
+        let bigram = term_ids[1];
+        assert_eq!(engine.analyzer.get_term_by_id(bigram), "quarrel_sir");
+        let docs = engine.index.docs(bigram).unwrap();
+        assert_eq!(docs.iter().map(|doc| &engine.doc_meta[doc]).collect::<Vec<_>>(), vec![&adjacent]);
+        assert_eq!(engine.exec_query("quarrel sir", RankingAlgorithm::Default)[0], &adjacent);
+        assert_eq!(engine.exec_query("quarrel sir", RankingAlgorithm::ExactMatch), vec![&adjacent]);
+        assert_eq!(engine.snippet(engine.get_doc_id(&adjacent).unwrap(), &[bigram], 1),
+            Some(String::from("you **quarrel**, **sir**")));
+    }
+
+    #[test]
+    fn test_shingles_boost_fuzzy_prefix() {
+        for unigrams in [true, false] {
+            let dir = format!(".rir/shingles_query_corpus_{}", unigrams);
+            let _ = fs::create_dir_all(&dir);
+            fs::write(format!("{}/.rircfg", dir),
+                format!("file_type: text\nfields: []\nshingles:\n  n: 2\n  unigrams: {}\n", unigrams)).unwrap();
+            fs::write(format!("{}/adjacent.txt", dir), "Do you quarrel, sir?").unwrap();
+            fs::write(format!("{}/apart.txt", dir), "Quarrel? No, sir.").unwrap();
+            let mut engine = Engine::new();
+            assert_eq!(engine.build_index_from(&dir), Ok(2));
+            let adjacent = format!("{}/adjacent.txt", dir);
+            // the bigram of a boosted word carries its weight
+            let (term_ids, _) = engine.analyzer.parse("quarrel sir");
+            let bigram = *term_ids.iter().find(|&&term_id| engine.analyzer.get_term_by_id(term_id) == "quarrel_sir").unwrap();
+            assert!(Query::parse_weighted("quarrel sir^2", false, &engine.analyzer, None).contains(&(bigram, 2.0)));
+            for ranking in [RankingAlgorithm::Default, RankingAlgorithm::ExactMatch] {
+                assert_eq!(engine.exec_query("quarrel sir^2", ranking)[0], &adjacent);
+                engine.set_fuzzy(Some(1));
+                assert_eq!(engine.exec_query("quarrel sir^2", ranking)[0], &adjacent);
+                assert_eq!(engine.exec_query("quarrle sir", ranking)[0], &adjacent);
+                engine.set_fuzzy(None);
+                assert_eq!(engine.exec_query("quarrel s*", ranking)[0], &adjacent);
+                assert_eq!(engine.exec_query("quar* sir", ranking)[0], &adjacent);
+            }
+            assert_eq!(engine.exec_query("quarrel s*", RankingAlgorithm::ExactMatch), vec![&adjacent]);
+            assert_eq!(engine.exec_query("quar* sir", RankingAlgorithm::ExactMatch), vec![&adjacent]);
+            engine.set_fuzzy(Some(1));
+            assert_eq!(engine.exec_query("quarrle sir", RankingAlgorithm::ExactMatch), vec![&adjacent]);
+            engine.set_fuzzy(None);
+            let _ = fs::remove_dir_all(&dir);
+        }
+    }
+}
@@ -1,2 +1,3 @@
 pub mod pl;
-pub mod engine;
\ No newline at end of file
+pub mod engine;
+pub mod columnar;
\ No newline at end of file
@@ -1,19 +1,26 @@
 use std::collections::{HashMap, HashSet};
 use std::cmp::Reverse;
+use std::mem::size_of;
 use serde::{Serialize, Deserialize};
-use crate::ircore::utils::sparse_vector::{SparseVector, SparseVectorOp};
+use crate::ircore::utils::sparse_vector::{SparseVector, SparseVectorOp, QuantizedVector};
 use crate::ircore::token::dictionary::Dictionary;
-use crate::ircore::{DocId, TermId, TermOffset};
+use crate::ircore::{DocId, TermId, TermOffset, FieldId, STOPWORD_GAP};
 use std::io;
 use std::path::Path;
-use crate::ircore::utils::serialize;
+use std::sync::OnceLock;
+use crate::ircore::utils::serialize::{self, Encoding};
+use crate::ircore::ranking::{WeightedTerm, unit_weights, query_term_weights};
 
 type Positions = Vec<TermOffset>;
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct Posting {
     doc_id: DocId,
     term_frequency: u32,
+    // absolute in memory, gaps on disk
+    #[serde(serialize_with = "serialize::deltas", deserialize_with = "serialize::from_deltas")]
     positions: Positions,
+    // field of each position, empty for documents indexed without fields
+    fields: Vec<FieldId>,
 }
 
 impl Posting {
@@ -23,23 +30,35 @@ impl Posting {
     pub fn get_positions(&self) -> &Positions {
         &self.positions
     }
+    pub fn get_fields(&self) -> &[FieldId] {
+        &self.fields
+    }
 }
 
 
 type PositingList = HashMap<TermId, Vec<Posting>>;
 
+// precomputed normalized TF-IDF vectors, value doc_id - 1 is used as vector index
+#[derive(Debug)]
+pub enum TfIdfCache {
+    Exact(Vec<SparseVector>),
+    Quantized(Vec<QuantizedVector>),
+}
+
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PositionList {
     // document list,  termid -> positions
+    #[serde(serialize_with = "serialize::sorted_map")]
     postings_lists: PositingList,
     next_doc_id: DocId,
     // the number of documents in the collection containing the term (id)
+    #[serde(serialize_with = "serialize::sorted_map")]
     document_frequency: HashMap<TermId, u32>,
     // the number of times term(termid) appears in document(doc_id)
     #[serde(skip)]
     term_frequency: HashMap<(TermId, DocId), u32>,
-    // number of tokens of a document measured in tokens
+    // number of tokens of a document measured in tokens, removed stopwords included
     // value doc_id - 1 is used as vector index
     document_length: Vec<u32>,
     total_document_length: u64,
@@ -50,11 +69,28 @@ pub struct PositionList {
     // doc-term list, for TF-IDF computing
     #[serde(skip)]
     doc_terms: HashMap<DocId, HashSet<TermId>>,
+    // built on the first VSM query unless built before with build_tfidf_cache,
+    // dropped whenever a document is added or deleted
+    #[serde(skip)]
+    tfidf_cache: OnceLock<TfIdfCache>,
+    // tombstones of deleted documents, their ids are never reused
+    #[serde(default, serialize_with = "serialize::sorted_set")]
+    deleted: HashSet<DocId>,
+    // names of the fields documents are indexed with, FieldId is the index
+    #[serde(default)]
+    field_names: Vec<String>,
+    // tokens in each field (FieldId is the index) of documents indexed with fields
+    #[serde(default, serialize_with = "serialize::sorted_map")]
+    doc_field_lengths: HashMap<DocId, Vec<u32>>,
+    // tokens in each field over all documents
+    #[serde(default)]
+    total_field_lengths: Vec<u64>,
 }
 
 
 impl PositionList {
     const SERIALIZE_NAME: &'static str = "idx.pl";
+    const MAX_FIELDS: usize = 16;
     pub fn new() -> Self {
         PositionList{
             // dict: Dictionary::new(),
@@ -67,27 +103,48 @@ impl PositionList {
             average_document_length: 0.0,
             document_count: 0,
             doc_terms: HashMap::new(),
+            tfidf_cache: OnceLock::new(),
+            deleted: HashSet::new(),
+            field_names: vec![],
+            doc_field_lengths: HashMap::new(),
+            total_field_lengths: vec![],
         }
     }
     pub fn save_to(&self, path_str: &str) -> io::Result<()>{
+        self.save_to_with(path_str, Encoding::default())
+    }
+    pub fn save_to_with(&self, path_str: &str, encoding: Encoding) -> io::Result<()>{
         let path = &Path::new(path_str).join(Path::new(Self::SERIALIZE_NAME));
-        serialize::write_file(&path, self)?;
+        serialize::write_file_with(path, self, encoding)?;
         log::debug!("index save to {}", path.to_string_lossy());
         Ok(())
     }
-    pub fn load_from(path_prefix: &str) -> Self{
+    // save without the data rebuilt on load
+    pub fn save_query_to(&mut self, path_str: &str) -> io::Result<()>{
+        let document_frequency = std::mem::take(&mut self.document_frequency);
+        let res = self.save_to(path_str);
+        self.document_frequency = document_frequency;
+        res
+    }
+    pub fn load_from(path_prefix: &str) -> io::Result<Self>{
         let path = Path::new(path_prefix).join(Path::new(Self::SERIALIZE_NAME));
-        let mut encoded:Vec<u8> = vec![];
-        if let Ok(mut reloaded_pl) = serialize::read_file::<PositionList>(&path, &mut encoded) {
-            reloaded_pl.rebuild();
-            return reloaded_pl;
-        }else{
-            return Self::new();
+        let mut reloaded_pl = serialize::read_file_owned::<PositionList>(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.to_string_lossy(), e)))?;
+        reloaded_pl.rebuild();
+        if !reloaded_pl.validate() {
+            log::warn!("index {} is inconsistent", path.to_string_lossy());
         }
+        Ok(reloaded_pl)
     }
 
     // Rebuild index after load from index file
     fn rebuild(&mut self) -> bool {
+        // left out by save_query_to, one posting per document containing the term
+        if self.document_frequency.is_empty() {
+            for (term_id, postings) in &self.postings_lists {
+                self.document_frequency.insert(*term_id, postings.len() as u32);
+            }
+        }
         let rebuild_doc_terms = self.doc_terms.len() == 0 ;
         let rebuild_term_frequency = self.term_frequency.len() == 0;
         if rebuild_doc_terms || rebuild_term_frequency {
@@ -113,7 +170,157 @@ impl PositionList {
     
         true
     }
-    
+
+    // approximate heap bytes used by the postings and the (term, doc)
+    // frequencies, compare ColumnarPositionList::postings_heap_size
+    pub fn postings_heap_size(&self) -> usize {
+        let postings: usize = self.postings_lists.values()
+            .map(|postings| postings.capacity() * size_of::<Posting>()
+                + postings.iter().map(|posting| posting.positions.capacity() * size_of::<TermOffset>()
+                    + posting.fields.capacity() * size_of::<FieldId>()).sum::<usize>())
+            .sum();
+        postings + self.term_frequency.capacity() * size_of::<((TermId, DocId), u32)>()
+    }
+
+    // precompute TF-IDF vectors of all documents for VSM,
+    // quantize_tfidf trades precision (see QuantizedVector) for memory
+    pub fn build_tfidf_cache(&mut self, quantize_tfidf: bool) {
+        self.tfidf_cache = OnceLock::from(self.compute_tfidf_cache(quantize_tfidf));
+    }
+
+    fn compute_tfidf_cache(&self, quantize_tfidf: bool) -> TfIdfCache {
+        let vectors = (1..=self.document_length.len() as DocId)
+            .map(|doc| match self.deleted.contains(&doc) {
+                true => SparseVector::new(),
+                false => self.get_doc_tfidf_vector(doc),
+            });
+        if quantize_tfidf {
+            TfIdfCache::Quantized(vectors.map(|v| QuantizedVector::from_sparse(&v)).collect())
+        }else{
+            TfIdfCache::Exact(vectors.collect())
+        }
+    }
+
+    pub fn get_field_id(&self, name: &str) -> Option<FieldId> {
+        self.field_names.iter().position(|field| field == name).map(|id| id as FieldId)
+    }
+
+    // id of the field, registered on first use, None once MAX_FIELDS are taken
+    fn register_field(&mut self, name: &str) -> Option<FieldId> {
+        if let Some(field_id) = self.get_field_id(name) {
+            return Some(field_id);
+        }
+        if self.field_names.len() == Self::MAX_FIELDS {
+            log::warn!("field {} is indexed without field, at most {} fields are supported", name, Self::MAX_FIELDS);
+            return None;
+        }
+        self.field_names.push(name.to_string());
+        self.total_field_lengths.push(0);
+        Some((self.field_names.len() - 1) as FieldId)
+    }
+
+    // index one document from the term ids of its named fields, positions run on
+    // from one field to the next. If no more fields can be registered the whole
+    // document is indexed without fields.
+    pub fn add_document_fields(&mut self, fields: &[(&str, Vec<TermId>)]) -> DocId {
+        let field_ids: Option<Vec<FieldId>> = fields.iter()
+            .map(|(name, _)| self.register_field(name))
+            .collect();
+        let term_ids: Vec<TermId> = fields.iter().flat_map(|(_, ids)| ids.iter().copied()).collect();
+        let position_fields: Vec<FieldId> = match &field_ids {
+            Some(field_ids) => fields.iter().zip(field_ids)
+                .flat_map(|((_, ids), field_id)| std::iter::repeat_n(*field_id, ids.len()))
+                .collect(),
+            None => vec![],
+        };
+        let doc_id = self.index_document_in(&term_ids, &position_fields);
+        if let Some(field_ids) = field_ids {
+            let mut lengths = vec![0; self.field_names.len()];
+            for ((_, ids), field_id) in fields.iter().zip(field_ids) {
+                lengths[field_id as usize] += ids.len() as u32;
+                self.total_field_lengths[field_id as usize] += ids.len() as u64;
+            }
+            self.doc_field_lengths.insert(doc_id, lengths);
+        }
+        self.update_average_document_length();
+        doc_id
+    }
+
+    // docs with the term in the field
+    pub fn docs_in_field(&self, term_id: TermId, field_id: FieldId) -> HashSet<DocId> {
+        match self.postings_lists.get(&term_id) {
+            Some(postings) => postings.iter()
+                .filter(|posting| posting.fields.contains(&field_id))
+                .map(|posting| posting.doc_id)
+                .filter(|doc_id| !self.deleted.contains(doc_id))
+                .collect(),
+            None => HashSet::new(),
+        }
+    }
+
+    // build position index of one document without touching aggregates
+    fn index_document(&mut self, term_ids: &[TermId]) -> DocId {
+        self.index_document_in(term_ids, &[])
+    }
+
+    // fields is empty or holds the field of each term
+    fn index_document_in(&mut self, term_ids: &[TermId], fields: &[FieldId]) -> DocId {
+        let doc_id = self.next_doc_id();
+        self.tfidf_cache = OnceLock::new();
+        let mut cached_term_id: HashSet<TermId> = HashSet::new();
+        // update document length
+        let document_length = term_ids.len() as u32;
+        self.document_length.push(document_length);
+        self.total_document_length += document_length as u64;
+        // update document count
+        self.document_count += 1;
+        // build position index, a stopword gap only takes its position
+        for (seq, tid) in term_ids.iter().enumerate() {
+            let term_offset = seq as TermOffset + 1;
+            if *tid == STOPWORD_GAP {
+                continue;
+            }
+            let postings = self.postings_lists.entry(*tid).or_default();
+            if postings.is_empty() || postings.last().unwrap().doc_id != doc_id {
+                postings.push(Posting{
+                    doc_id,
+                    term_frequency: 1,
+                    positions: vec![term_offset],
+                    fields: vec![],
+                })
+            }else{
+                let post = postings.last_mut().unwrap();
+                assert_eq!(post.doc_id, doc_id);
+                post.term_frequency += 1;
+                post.positions.push(term_offset);
+            }
+            if let Some(field_id) = fields.get(seq) {
+                postings.last_mut().unwrap().fields.push(*field_id);
+            }
+            // update term frequency
+            self.term_frequency.entry((*tid, doc_id))
+                .and_modify(|count| *count += 1)
+                .or_insert(1);
+            // update document frequency
+            if !cached_term_id.contains(tid) {
+                cached_term_id.insert(*tid);
+                self.document_frequency.entry(*tid)
+                .and_modify(|count| *count +=1)
+                .or_insert(1);
+            }
+            // update doc-term map
+            let doc_terms_entry = self.doc_terms.entry(doc_id).or_default();
+            doc_terms_entry.insert(*tid);
+        }
+        doc_id
+    }
+
+    fn update_average_document_length(&mut self) {
+        self.average_document_length = match self.document_count {
+            0 => 0.0,
+            count => self.total_document_length as f32 / count as f32,
+        };
+    }
 
 }
 
@@ -125,14 +332,124 @@ pub struct IndexStats {
     // total number of documents
     pub document_count: usize,
     pub term_freq: Vec<(TermId, String, u32)>,
+    // distinct terms / total tokens of the whole corpus
+    pub type_token_ratio: f32,
+    // distinct terms / total tokens of each document, 0 for empty ones
+    pub doc_type_token_ratio: Vec<(DocId, f32)>,
+    // terms with a postings list
+    pub unique_term_count: usize,
+    // (term, document) postings over all terms
+    pub total_posting_count: usize,
+    // documents in the longest postings list
+    pub max_posting_list_len: usize,
+}
+
+impl IndexStats {
+    // average of per document type-token ratios
+    pub fn average_doc_type_token_ratio(&self) -> f32 {
+        if self.doc_type_token_ratio.is_empty() {
+            return 0.0;
+        }
+        let sum: f32 = self.doc_type_token_ratio.iter().map(|(_, ratio)| ratio).sum();
+        sum / self.doc_type_token_ratio.len() as f32
+    }
+}
+
+// each term once, in order of first occurrence, so a repeated query term
+// does not go through its postings again
+pub fn distinct_terms(term_list: &[TermId]) -> Vec<TermId> {
+    let mut seen = HashSet::new();
+    term_list.iter().copied().filter(|term| seen.insert(*term)).collect()
+}
+
+// doc ids in all of the lists, each sorted by doc id. Every doc of the shortest
+// list is looked up in the others by galloping from where the last lookup ended,
+// so long lists are skipped through instead of read in full.
+pub fn intersect_sorted<T>(lists: &[&[T]], doc_id: fn(&T) -> DocId) -> Vec<DocId> {
+    intersect_sorted_probes(lists, doc_id, &mut 0)
+}
+
+// probes counts the elements read
+fn intersect_sorted_probes<T>(lists: &[&[T]], doc_id: fn(&T) -> DocId, probes: &mut usize) -> Vec<DocId> {
+    let mut lists: Vec<&[T]> = lists.to_vec();
+    lists.sort_by_key(|list| list.len());
+    let (shortest, others) = match lists.split_first() {
+        Some(split) => split,
+        None => return vec![],
+    };
+    let mut cursors = vec![0; others.len()];
+    let mut docs = vec![];
+    'candidates: for item in shortest.iter() {
+        let target = doc_id(item);
+        *probes += 1;
+        for (list, cursor) in others.iter().zip(cursors.iter_mut()) {
+            *cursor = gallop(list, *cursor, target, doc_id, probes);
+            match list.get(*cursor) {
+                Some(found) if doc_id(found) == target => {},
+                // the other lists have no more docs
+                None => break 'candidates,
+                _ => continue 'candidates,
+            }
+        }
+        docs.push(target);
+    }
+    docs
+}
+
+// first index from start whose doc id is not below target, steps double
+// until passing target, then a binary search within the last step
+fn gallop<T>(list: &[T], start: usize, target: DocId, doc_id: fn(&T) -> DocId, probes: &mut usize) -> usize {
+    let mut low = start;
+    let mut high = start;
+    let mut step = 1;
+    while high < list.len() {
+        *probes += 1;
+        if doc_id(&list[high]) >= target {
+            break;
+        }
+        low = high + 1;
+        high += step;
+        step *= 2;
+    }
+    let mut high = high.min(list.len());
+    while low < high {
+        let mid = (low + high) / 2;
+        *probes += 1;
+        if doc_id(&list[mid]) < target {
+            low = mid + 1;
+        }else{
+            high = mid;
+        }
+    }
+    low
+}
+
+// distinct / total, 0 if there are no tokens
+pub fn type_token_ratio(distinct: usize, total: u64) -> f32 {
+    if total == 0 {
+        return 0.0;
+    }
+    distinct as f32 / total as f32
 }
 
 pub trait SchemaDependIndex {
     fn add_document(&mut self, term_ids: &Vec<TermId>) -> DocId;
+    fn add_documents(&mut self, docs: &[Vec<TermId>]) -> Vec<DocId>;
+    // remove the document from postings and aggregates, its id stays taken,
+    // false if it is not indexed
+    fn delete_document(&mut self, doc: DocId) -> bool;
     fn next_doc_id(&mut self) -> DocId;
     // getters
+    // get: names of the fields, FieldId is the index
+    fn get_field_names(&self) -> &[String];
+    // get: tokens in each field of a document indexed with fields
+    fn get_field_lengths(&self, doc: DocId) -> Option<&[u32]>;
+    // get: tokens in each field over all documents
+    fn get_total_field_lengths(&self) -> &[u64];
+    // get: field of each position of the term in doc, None without fields
+    fn get_position_fields(&self, term: TermId, doc: DocId) -> Option<&[FieldId]>;
     // get: positition list for one term in doc
-    fn get_positions(&self, term: TermId, doc: DocId) -> Option<&Positions>;
+    fn get_positions(&self, term: TermId, doc: DocId) -> Option<&[TermOffset]>;
     // get: number of term occurences in whole collection
     fn get_term_occurences_num(&self, term: TermId) -> u32;
     // get: total number of document
@@ -149,17 +466,26 @@ pub trait SchemaDependIndex {
     // docs contain the term
     fn docs(&self, term_id: TermId) -> Option<HashSet<DocId>>; 
     // docs contain all terms
-    fn docs_contain_all(&self, term_list: &Vec<TermId>) -> Option<HashSet<DocId>>;
+    fn docs_contain_all(&self, term_list: &[TermId]) -> Option<HashSet<DocId>>;
     // docs contain any of the terms
-    fn docs_contain_any(&self, term_list: &Vec<TermId>) -> HashSet<DocId>;
+    fn docs_contain_any(&self, term_list: &[TermId]) -> HashSet<DocId>;
+    // docs do not contain the term
+    fn docs_not_containing(&self, term: TermId) -> HashSet<DocId>;
     fn is_valid_doc_id(&self, doc_id: DocId) -> bool;
     // TF-IDF related
     fn get_doc_tfidf_vector(&self, doc: DocId) -> SparseVector;
+    // dot product of document TF-IDF vector with the (query) vector
+    fn doc_tfidf_dot(&self, doc: DocId, other: &SparseVector) -> f32;
     fn get_phrase_tfidf_vector(&self, phrase: &Vec<TermId>) -> Box<SparseVector>;
+    // query TF-IDF vector with each component scaled by the term's mean weight
+    fn get_weighted_tfidf_vector(&self, terms: &[WeightedTerm]) -> Box<SparseVector>;
     // Statistics
     fn stats(&self, dict: &Dictionary) -> IndexStats;
     // Validate if index is good
     fn validate(&self) -> bool;
+    // read every posting once so it is resident before serving queries,
+    // returns number of positions visited
+    fn preload(&self) -> usize;
 }
 
 impl SchemaDependIndex for PositionList {
@@ -171,8 +497,8 @@ impl SchemaDependIndex for PositionList {
         0
     }
 
-    fn get_positions(&self, term: TermId, doc: DocId) -> Option<&Positions> {
-        let posts = self.postings_lists.get(&term).unwrap();
+    fn get_positions(&self, term: TermId, doc: DocId) -> Option<&[TermOffset]> {
+        let posts = self.postings_lists.get(&term)?;
         for post in posts {
             if post.get_doc_id() == doc {
                 let positions = post.get_positions();
@@ -184,6 +510,27 @@ impl SchemaDependIndex for PositionList {
     }
 
 
+    fn get_field_names(&self) -> &[String] {
+        &self.field_names
+    }
+
+    fn get_field_lengths(&self, doc: DocId) -> Option<&[u32]> {
+        self.doc_field_lengths.get(&doc).map(|lengths| lengths.as_slice())
+    }
+
+    fn get_total_field_lengths(&self) -> &[u64] {
+        &self.total_field_lengths
+    }
+
+    fn get_position_fields(&self, term: TermId, doc: DocId) -> Option<&[FieldId]> {
+        let postings = self.postings_lists.get(&term)?;
+        let i = postings.binary_search_by_key(&doc, |posting| posting.doc_id).ok()?;
+        match postings[i].fields.is_empty() {
+            true => None,
+            false => Some(&postings[i].fields),
+        }
+    }
+
     // get: total number of document
     fn get_document_count(&self) -> usize {
         self.document_count
@@ -217,49 +564,54 @@ impl SchemaDependIndex for PositionList {
     }
 
     fn add_document(&mut self, term_ids: &Vec<TermId>) -> DocId {
-        let doc_id = self.next_doc_id();
-        let mut cached_term_id: HashSet<TermId> = HashSet::new();
-        // update document length
-        let document_length = term_ids.len() as u32;
-        self.document_length.push(document_length);
-        self.total_document_length += document_length as u64;
-        // update document count
-        self.document_count += 1;
-        // update average document length
-        self.average_document_length = self.total_document_length as f32 / self.document_count as f32;
-        // build position index
-        for (seq, tid) in term_ids.into_iter().enumerate() {
-            let term_offset = seq as TermOffset + 1;
-            let postings = self.postings_lists.entry(*tid).or_insert_with(Vec::new);
-            if postings.len() == 0 || postings.last().unwrap().doc_id != doc_id {
-                postings.push(Posting{
-                    doc_id: doc_id,
-                    term_frequency: 1,
-                    positions: vec![term_offset],
-                })
-            }else{
-                let post = postings.last_mut().unwrap();
-                assert_eq!(post.doc_id, doc_id);
-                post.term_frequency += 1;
-                post.positions.push(term_offset);
+        let doc_id = self.index_document(term_ids);
+        self.update_average_document_length();
+        doc_id
+    }
+
+    // add documents in bulk, aggregates are recomputed once at the end
+    fn add_documents(&mut self, docs: &[Vec<TermId>]) -> Vec<DocId> {
+        let doc_ids = docs.iter().map(|term_ids| self.index_document(term_ids)).collect();
+        if !docs.is_empty() {
+            self.update_average_document_length();
+        }
+        doc_ids
+    }
+
+    fn delete_document(&mut self, doc: DocId) -> bool {
+        if !self.is_valid_doc_id(doc) {
+            return false;
+        }
+        let terms = self.doc_terms.remove(&doc).unwrap_or_default();
+        for term in terms {
+            if let Some(postings) = self.postings_lists.get_mut(&term) {
+                if let Ok(i) = postings.binary_search_by_key(&doc, |posting| posting.doc_id) {
+                    postings.remove(i);
+                }
+                if postings.is_empty() {
+                    self.postings_lists.remove(&term);
+                }
             }
-            // update term frequency
-            self.term_frequency.entry((*tid, doc_id))
-                .and_modify(|count| *count += 1)
-                .or_insert(1);
-            // update document frequency
-            if !cached_term_id.contains(tid) {
-                cached_term_id.insert(*tid);
-                self.document_frequency.entry(*tid)
-                .and_modify(|count| *count +=1)
-                .or_insert(1);
+            if let Some(df) = self.document_frequency.get_mut(&term) {
+                *df -= 1;
+                if *df == 0 {
+                    self.document_frequency.remove(&term);
+                }
             }
-            // update doc-term map
-            let doc_terms_entry = self.doc_terms.entry(doc_id)
-                .or_insert_with(HashSet::new);
-            doc_terms_entry.insert(*tid);
+            self.term_frequency.remove(&(term, doc));
         }
-        doc_id
+        if let Some(lengths) = self.doc_field_lengths.remove(&doc) {
+            for (total, length) in self.total_field_lengths.iter_mut().zip(lengths) {
+                *total -= length as u64;
+            }
+        }
+        let length = std::mem::take(&mut self.document_length[doc as usize - 1]);
+        self.total_document_length -= length as u64;
+        self.document_count -= 1;
+        self.update_average_document_length();
+        self.tfidf_cache = OnceLock::new();
+        self.deleted.insert(doc);
+        true
     }
 
     // Summary
@@ -269,7 +621,24 @@ impl SchemaDependIndex for PositionList {
             average_document_length: self.average_document_length,
             document_count: self.document_count,
             term_freq: vec![],
+            type_token_ratio: type_token_ratio(self.postings_lists.len(), self.total_document_length),
+            doc_type_token_ratio: vec![],
+            unique_term_count: self.postings_lists.len(),
+            total_posting_count: 0,
+            max_posting_list_len: 0,
         };
+        for postings in self.postings_lists.values() {
+            idx_info.total_posting_count += postings.len();
+            idx_info.max_posting_list_len = idx_info.max_posting_list_len.max(postings.len());
+        }
+        for (i, &length) in self.document_length.iter().enumerate() {
+            let doc_id = i as DocId + 1;
+            if self.deleted.contains(&doc_id) {
+                continue;
+            }
+            let distinct = self.doc_terms.get(&doc_id).map_or(0, |terms| terms.len());
+            idx_info.doc_type_token_ratio.push((doc_id, type_token_ratio(distinct, length as u64)));
+        }
         let mut term_freq_map:HashMap<TermId, u32> = HashMap::new();
         for ((tid, _), &freq) in &self.term_frequency{
             term_freq_map.entry(*tid)
@@ -297,38 +666,41 @@ impl SchemaDependIndex for PositionList {
         }
     }
 
-    fn docs_contain_all(&self, term_list: &Vec<TermId>) -> Option<HashSet<DocId>> {
-        let mut doc_set = HashSet::new();
-        let mut initialized = false;
-        for term in term_list {
-            if let Some(res_set) = self.docs(*term) {
-                if initialized {
-                    doc_set = &doc_set & &res_set;
-                }else{
-                    doc_set = res_set;
-                    initialized = true;
-                }
-            }
-        }
-        if initialized {
-            Some(doc_set)
-        }else{
-            None
+    // terms not in the index are left out, None if no term is
+    fn docs_contain_all(&self, term_list: &[TermId]) -> Option<HashSet<DocId>> {
+        let lists: Vec<&[Posting]> = distinct_terms(term_list).iter()
+            .filter_map(|term| self.postings_lists.get(term).map(Vec::as_slice))
+            .collect();
+        if lists.is_empty() {
+            return None;
         }
+        Some(intersect_sorted(&lists, |posting| posting.doc_id).into_iter().collect())
     }
 
-    fn docs_contain_any(&self, term_list: &Vec<TermId>) -> HashSet<DocId> {
+    fn docs_contain_any(&self, term_list: &[TermId]) -> HashSet<DocId> {
         let mut doc_set:HashSet<DocId> = HashSet::new();
-        for term in term_list {
-            if let Some(res_set) = self.docs(*term) {
-                    doc_set = &doc_set | &res_set;
+        for term in distinct_terms(term_list) {
+            if let Some(res_set) = self.docs(term) {
+                doc_set.extend(res_set);
+            }
+        }
+        doc_set
+    }
+
+    fn docs_not_containing(&self, term: TermId) -> HashSet<DocId> {
+        let mut doc_set: HashSet<DocId> = (1..=self.document_length.len() as DocId)
+            .filter(|doc_id| !self.deleted.contains(doc_id))
+            .collect();
+        if let Some(postings) = self.postings_lists.get(&term) {
+            for post in postings {
+                doc_set.remove(&post.doc_id);
             }
         }
         doc_set
     }
 
     fn is_valid_doc_id(&self, doc_id: DocId) -> bool {
-        doc_id >= 1 && doc_id <= self.document_count as DocId + 1 
+        doc_id >= 1 && doc_id as usize <= self.document_length.len() && !self.deleted.contains(&doc_id)
     }
 
     // TF = log(ftd) + 1 if ftd > 0, 0 otherwise
@@ -351,28 +723,58 @@ impl SchemaDependIndex for PositionList {
         return tfidf_vec;
     }
 
+    fn doc_tfidf_dot(&self, doc: DocId, other: &SparseVector) -> f32 {
+        match self.tfidf_cache.get_or_init(|| self.compute_tfidf_cache(false)) {
+            TfIdfCache::Exact(vectors) => vectors[doc as usize - 1].vec_dot(other),
+            TfIdfCache::Quantized(vectors) => vectors[doc as usize - 1].vec_dot(other),
+        }
+    }
+
     // compute query string's TF-IDF vector
     fn get_phrase_tfidf_vector(&self, terms: &Vec<TermId>) -> Box<SparseVector> {
-        let mut query_term_freq:HashMap<TermId, u32> = HashMap::new();
-        for &tid in terms {
-            query_term_freq.entry(tid)
-                .and_modify(|count| *count += 1)
-                .or_insert(1);
-        }
+        self.get_weighted_tfidf_vector(&unit_weights(terms))
+    }
+
+    fn get_weighted_tfidf_vector(&self, terms: &[WeightedTerm]) -> Box<SparseVector> {
+        let query_term_freq = query_term_weights(terms);
         let mut query_tfidf = SparseVector::new();
-        for &tid in query_term_freq.keys() {
-            let freq = *query_term_freq.get(&tid).unwrap() as f32;
+        for (&tid, &(count, weight)) in &query_term_freq {
+            // left by deleted documents, no document contains the term
+            let Some(&df) = self.document_frequency.get(&tid) else {
+                continue;
+            };
+            let freq = count as f32;
             let term_tfidf = (freq.log2() + 1f32 ) * 
-                (self.document_count as f32 / *self.document_frequency.get(&tid).unwrap() as f32).log2();
-            query_tfidf.vec_set(tid, term_tfidf);
+                (self.document_count as f32 / df as f32).log2();
+            query_tfidf.vec_set(tid, term_tfidf * weight / freq);
         }
         query_tfidf.vec_normalize();
         Box::new(query_tfidf)
     }
 
-    // Validate if index is good
+    // Validate if index is good: postings agree with term frequencies,
+    // so get_term_occurences_num matches what scorers see per document,
+    // and are sorted by doc id as intersect_sorted expects
     fn validate(&self) -> bool {
-        true
+        let mut occurrences: HashMap<TermId, u32> = HashMap::new();
+        for ((tid, _), &freq) in &self.term_frequency {
+            *occurrences.entry(*tid).or_insert(0) += freq;
+        }
+        self.postings_lists.iter().all(|(tid, postings)|
+            postings.iter().all(|posting| posting.term_frequency as usize == posting.positions.len())
+            && postings.windows(2).all(|pair| pair[0].doc_id < pair[1].doc_id)
+            && self.get_term_occurences_num(*tid) == *occurrences.get(tid).unwrap_or(&0))
+    }
+
+    fn preload(&self) -> usize {
+        let mut visited = 0;
+        for postings in self.postings_lists.values() {
+            for posting in postings {
+                visited += std::hint::black_box(posting.positions.iter()).count();
+            }
+        }
+        std::hint::black_box(&self.document_length);
+        visited
     }
 
 }
@@ -381,6 +783,7 @@ impl SchemaDependIndex for PositionList {
 mod tests {
     use super::*;
     use crate::ircore::token::dictionary::Dictionary;
+    use crate::ircore::ranking::cmp_scores;
 
     #[test]
     fn test_index_from_string(){
@@ -471,6 +874,37 @@ mod tests {
         assert_eq!(idx.get_term_occurences_num(7), 1); //明
     }
 
+    #[test]
+    fn test_add_documents() {
+        let mut dict = Dictionary::new();
+        let docs = vec![
+            dict.generate_ids(&vec!["hello", "world", "hello", "世", "界", "你", "好", "你", "好"]),
+            dict.generate_ids(&vec!["你", "好", "明", "天"]),
+            dict.generate_ids(&vec!["hello", "明", "天"]),
+        ];
+        let mut sequential = PositionList::new();
+        let mut sequential_ids = vec![];
+        for term_ids in &docs {
+            sequential_ids.push(sequential.add_document(term_ids));
+        }
+        let mut bulk = PositionList::new();
+        let bulk_ids = bulk.add_documents(&docs);
+        assert_eq!(bulk_ids, vec![1, 2, 3]);
+        assert_eq!(bulk_ids, sequential_ids);
+        assert_eq!(bulk.postings_lists, sequential.postings_lists);
+        assert_eq!(bulk.next_doc_id, sequential.next_doc_id);
+        assert_eq!(bulk.document_frequency, sequential.document_frequency);
+        assert_eq!(bulk.term_frequency, sequential.term_frequency);
+        assert_eq!(bulk.document_length, sequential.document_length);
+        assert_eq!(bulk.total_document_length, sequential.total_document_length);
+        assert_eq!(bulk.average_document_length, sequential.average_document_length);
+        assert_eq!(bulk.document_count, sequential.document_count);
+        assert_eq!(bulk.doc_terms, sequential.doc_terms);
+        // empty batch leaves the index untouched
+        assert_eq!(bulk.add_documents(&[]), Vec::<DocId>::new());
+        assert_eq!(bulk.average_document_length, sequential.average_document_length);
+    }
+
     #[test]
     fn test_docs_contain_term() {
         let mut idx = PositionList::new();
@@ -503,6 +937,66 @@ mod tests {
         term_ids = vec![7,100,7];
         let doc_set = idx.docs_contain_any(&term_ids);
         assert_eq!(doc_set, HashSet::from([2]));
+        // the repeated term is looked up once
+        assert_eq!(distinct_terms(&term_ids), vec![7, 100]);
+        assert_eq!(idx.docs_contain_any(&distinct_terms(&term_ids)), doc_set);
+        assert_eq!(idx.docs_contain_all(&[6, 1, 6]), idx.docs_contain_all(&[6, 1]));
+        // not contain
+        assert_eq!(idx.docs_not_containing(1), HashSet::from([2]));
+        assert_eq!(idx.docs_not_containing(6), HashSet::new());
+        assert_eq!(idx.docs_not_containing(100), HashSet::from([1, 2]));
+    }
+
+    #[test]
+    fn test_inconsistent_term_occurrences() {
+        use crate::ircore::ranking::lmd::LanguageModelDivergence;
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        let term_ids = dict.generate_ids(&vec!["do", "you", "quarrel", "sir"]);
+        idx.add_document(&term_ids);
+        let term_ids = dict.generate_ids(&vec!["quarrel", "sir", "no", "sir"]);
+        idx.add_document(&term_ids);
+        assert!(idx.validate());
+        // postings of quarrel pruned without updating term frequencies
+        for posting in idx.postings_lists.get_mut(&3).unwrap() {
+            posting.term_frequency = 0;
+            posting.positions.clear();
+        }
+        assert_eq!(idx.get_term_occurences_num(3), 0);
+        assert!(!idx.validate());
+        let docs = idx.rank_lmd(&vec![3, 4]);
+        assert_eq!(docs.len(), 2);
+        assert!(docs.iter().all(|doc| doc.score.is_finite()));
+    }
+
+    #[test]
+    fn test_type_token_ratio() {
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        let term_ids = dict.generate_ids(&vec!["do", "you", "quarrel", "sir"]);
+        idx.add_document(&term_ids);
+        let term_ids = dict.generate_ids(&vec!["quarrel", "sir", "no", "sir"]);
+        idx.add_document(&term_ids);
+        let stats = idx.stats(&dict);
+        assert_eq!(stats.doc_type_token_ratio, vec![(1, 1.0), (2, 0.75)]);
+        assert_eq!(stats.type_token_ratio, 5.0 / 8.0);
+        assert_eq!(stats.average_doc_type_token_ratio(), 0.875);
+    }
+
+    #[test]
+    fn test_index_size_stats() {
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        let stats = idx.stats(&dict);
+        assert_eq!((stats.unique_term_count, stats.total_posting_count, stats.max_posting_list_len), (0, 0, 0));
+        // same documents as test_index_from_string
+        idx.add_document(&dict.generate_ids(&vec!["hello", "world", "hello", "世", "界", "你", "好", "你", "好"]));
+        idx.add_document(&dict.generate_ids(&vec!["你", "好", "明", "天"]));
+        let stats = idx.stats(&dict);
+        assert_eq!(stats.unique_term_count, 8);
+        // 你 and 好 are in both documents
+        assert_eq!(stats.total_posting_count, 10);
+        assert_eq!(stats.max_posting_list_len, 2);
     }
 
     #[test]
@@ -579,4 +1073,210 @@ mod tests {
         assert_eq!(idx.get_term_occurences_num(6), 3); //好
         assert_eq!(idx.get_term_occurences_num(7), 1); //明
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_delete_document() {
+        use crate::ircore::ranking::bm25::OkapiBm25;
+        use crate::ircore::ranking::vsm::VectorSpaceModel;
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        for doc in [vec!["do", "you", "quarrel", "sir"], vec!["quarrel", "sir", "no", "sir"], vec!["well", "sir"]] {
+            let term_ids = dict.generate_ids(&doc);
+            idx.add_document(&term_ids);
+        }
+        let quarrel = dict.get_ids(&vec!["quarrel"]).0;
+        let sir = dict.get_ids(&vec!["sir"]).0[0];
+        assert_eq!(idx.rank_bm25(&quarrel).len(), 2);
+
+        assert!(idx.delete_document(2));
+        assert!(!idx.delete_document(2));
+        assert!(!idx.delete_document(4));
+        assert!(!idx.is_valid_doc_id(2));
+        let docs = idx.rank_bm25(&quarrel);
+        assert_eq!(docs.iter().map(|doc| doc.docid).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(idx.docs_contain_any(&[sir]), HashSet::from([1, 3]));
+        assert_eq!(idx.get_document_frequency(sir), Some(&2));
+        assert_eq!(idx.get_term_frequency(sir, 2), None);
+        assert_eq!(idx.document_count, 2);
+        assert_eq!(idx.total_document_length, 6);
+        assert_eq!(idx.average_document_length, 3.0);
+        // "no" only occurred in the deleted document
+        let no = dict.get_ids(&vec!["no"]).0[0];
+        assert!(!idx.postings_lists.contains_key(&no));
+        assert!(!idx.docs_not_containing(no).contains(&2));
+        assert!(idx.rank_bm25(&vec![no]).is_empty());
+        assert!(idx.rank_vsm(&vec![no]).is_empty());
+        let docs = idx.rank_vsm(&vec![no, quarrel[0]]);
+        assert_eq!(docs.iter().map(|doc| doc.docid).collect::<Vec<_>>(), vec![1]);
+        assert!(idx.validate());
+        idx.build_tfidf_cache(false);
+
+        // ids are not reused
+        let term_ids = dict.generate_ids(&vec!["no", "better"]);
+        assert_eq!(idx.add_document(&term_ids), 4);
+        assert_eq!(idx.document_count, 3);
+    }
+
+    #[test]
+    fn test_add_document_fields() {
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        let title = dict.generate_ids(&vec!["hello", "world"]);
+        let text = dict.generate_ids(&vec!["world", "peace", "hello"]);
+        let doc = idx.add_document_fields(&[("title", title), ("text", text.clone())]);
+        idx.add_document(&text);
+        assert_eq!(idx.get_field_names(), &["title".to_string(), "text".to_string()]);
+        assert_eq!(idx.get_field_id("text"), Some(1));
+        assert_eq!(idx.get_field_id("body"), None);
+        // positions run on from title to text
+        assert_eq!(idx.get_positions(2, doc), Some(&[2, 3][..]));
+        assert_eq!(idx.postings_lists[&2][0].get_fields(), &[0, 1]);
+        assert_eq!(idx.get_document_length(doc), 5);
+        assert_eq!(idx.docs_in_field(3, 0), HashSet::new());
+        assert_eq!(idx.docs_in_field(3, 1), HashSet::from([doc]));
+        assert_eq!(idx.docs_in_field(1, 0), HashSet::from([doc]));
+        // documents without fields are in no field
+        assert_eq!(idx.docs(3), Some(HashSet::from([1, 2])));
+        assert!(idx.validate());
+        // beyond MAX_FIELDS the document is indexed without fields
+        let fields: Vec<(String, Vec<TermId>)> = (0..PositionList::MAX_FIELDS)
+            .map(|i| (format!("f{}", i), vec![1])).collect();
+        let fields: Vec<(&str, Vec<TermId>)> = fields.iter().map(|(name, ids)| (name.as_str(), ids.clone())).collect();
+        let doc = idx.add_document_fields(&fields);
+        assert_eq!(idx.get_field_names().len(), PositionList::MAX_FIELDS);
+        assert!(!idx.docs_in_field(1, 0).contains(&doc));
+        assert!(idx.docs(1).unwrap().contains(&doc));
+    }
+
+    // scores recomputing every document vector per query, as before the cache
+    fn rank_vsm_uncached(idx: &PositionList, term_ids: &Vec<TermId>) -> Vec<(DocId, f32)> {
+        let query = idx.get_phrase_tfidf_vector(term_ids);
+        let mut scores: Vec<(DocId, f32)> = idx.docs_contain_any(term_ids).into_iter()
+            .map(|doc| (doc, idx.get_doc_tfidf_vector(doc).vec_dot(&query)))
+            .collect();
+        scores.sort_by(|a, b| cmp_scores(b.1, a.1).then(a.0.cmp(&b.0)));
+        scores
+    }
+
+    #[test]
+    fn test_tfidf_cache_lazy() {
+        use crate::ircore::ranking::vsm::VectorSpaceModel;
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        for doc in [vec!["do", "you", "quarrel", "sir"], vec!["quarrel", "sir", "no", "sir"],
+            vec!["no", "better"], vec!["well", "sir"]] {
+            idx.add_document(&dict.generate_ids(&doc));
+        }
+        let query = dict.generate_ids(&vec!["quarrel", "sir"]);
+        assert!(idx.tfidf_cache.get().is_none());
+        let scores = idx.rank_vsm(&query);
+        // built by the first query and reused by the next ones
+        assert!(matches!(idx.tfidf_cache.get(), Some(TfIdfCache::Exact(vectors)) if vectors.len() == 4));
+        let uncached = rank_vsm_uncached(&idx, &query);
+        assert_eq!(scores.iter().map(|doc| (doc.docid, doc.score)).collect::<Vec<_>>(), uncached);
+        assert_eq!(idx.rank_vsm(&query), scores);
+        // mutations drop it
+        idx.add_document(&dict.generate_ids(&vec!["quarrel"]));
+        assert!(idx.tfidf_cache.get().is_none());
+        let scores = idx.rank_vsm(&query);
+        assert_eq!(scores.iter().map(|doc| (doc.docid, doc.score)).collect::<Vec<_>>(), rank_vsm_uncached(&idx, &query));
+        idx.delete_document(1);
+        assert!(idx.tfidf_cache.get().is_none());
+        let scores = idx.rank_vsm(&query);
+        assert_eq!(scores.iter().map(|doc| (doc.docid, doc.score)).collect::<Vec<_>>(), rank_vsm_uncached(&idx, &query));
+    }
+
+    #[test]
+    fn test_save_positions() {
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        let words: Vec<&str> = (0..5000).map(|i| ["quarrel", "sir", "no"][i % 3]).collect();
+        idx.add_document(&dict.generate_ids(&words));
+        idx.add_document(&dict.generate_ids(&vec!["no", "better", "sir"]));
+        let path = ".rir/delta_positions";
+        let _ = std::fs::create_dir_all(path);
+        idx.save_to_with(path, Encoding::Raw).unwrap();
+        let reloaded = PositionList::load_from(path).unwrap();
+        assert_eq!(reloaded.postings_lists, idx.postings_lists);
+        assert_eq!(reloaded.get_positions(2, 1).unwrap()[..3], [2, 5, 8]);
+        assert_eq!(reloaded.get_positions(2, 2).unwrap(), &[3]);
+    }
+
+    #[test]
+    fn test_intersect_sorted() {
+        // every doc, every 7th and every 1000th of a large synthetic index
+        let all: Vec<DocId> = (1..=100_000).collect();
+        let sevens: Vec<DocId> = (1..=100_000).filter(|doc| doc % 7 == 0).collect();
+        let rare: Vec<DocId> = (1..=100_000).filter(|doc| doc % 1000 == 0).collect();
+        let lists: Vec<&[DocId]> = vec![&all, &sevens, &rare];
+        let mut probes = 0;
+        let docs = intersect_sorted_probes(&lists, |doc| *doc, &mut probes);
+        let sets: Vec<HashSet<DocId>> = lists.iter().map(|list| list.iter().copied().collect()).collect();
+        let expected: HashSet<DocId> = &(&sets[0] & &sets[1]) & &sets[2];
+        assert_eq!(docs.iter().copied().collect::<HashSet<DocId>>(), expected);
+        assert!(docs.windows(2).all(|pair| pair[0] < pair[1]));
+        // far fewer reads than the postings a set intersection goes through
+        let total: usize = lists.iter().map(|list| list.len()).sum();
+        assert!(probes * 10 < total, "{} probes of {} postings", probes, total);
+
+        assert!(intersect_sorted::<DocId>(&[], |doc| *doc).is_empty());
+        assert!(intersect_sorted(&[&[1, 3, 5][..], &[2, 4][..]], |doc| *doc).is_empty());
+        assert_eq!(intersect_sorted(&[&[1, 3, 5][..], &[5, 6][..]], |doc| *doc), vec![5]);
+    }
+
+    // cargo test test_tfidf_cache_large_corpus -- --ignored
+    #[test]
+    #[ignore]
+    fn test_tfidf_cache_large_corpus() {
+        use crate::ircore::ranking::vsm::VectorSpaceModel;
+        let words = ["quarrel", "sir", "no", "better", "well", "you", "do", "good", "man", "serve"];
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        for i in 0..5000usize {
+            let text: Vec<&str> = (0..200).map(|j| words[(i * 7 + j * j) % words.len()]).collect();
+            idx.add_document(&dict.generate_ids(&text));
+        }
+        let queries: Vec<Vec<TermId>> = [vec!["quarrel", "sir"], vec!["good", "man"], vec!["no", "better", "well"]]
+            .iter().map(|query| dict.get_ids(query).0).collect();
+        let uncached: Vec<_> = queries.iter().map(|query| rank_vsm_uncached(&idx, query)).collect();
+        let cached: Vec<_> = queries.iter().map(|query| idx.rank_vsm(query)).collect();
+        for (c, u) in cached.iter().zip(uncached) {
+            assert_eq!(c.iter().map(|doc| (doc.docid, doc.score)).collect::<Vec<_>>(), u);
+        }
+    }
+
+    // cargo test bench_load_raw -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_load_raw() {
+        use std::time::Instant;
+        let words = ["quarrel", "sir", "no", "better", "well", "you", "do", "good", "man", "serve"];
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        for i in 0..20000usize {
+            let text: Vec<&str> = (0..200).map(|j| words[(i * 7 + j * j) % words.len()]).collect();
+            idx.add_document(&dict.generate_ids(&text));
+        }
+        let gzip_path = ".rir/bench_load_gzip";
+        let raw_path = ".rir/bench_load_raw";
+        idx.save_to_with(gzip_path, Encoding::default()).unwrap();
+        idx.save_to_with(raw_path, Encoding::Raw).unwrap();
+        let file_size = |path: &str| std::fs::metadata(Path::new(path).join(PositionList::SERIALIZE_NAME)).unwrap().len();
+        // the raw file is uncompressed, so it is loaded through the mapping
+        assert!(file_size(raw_path) > file_size(gzip_path));
+        let start = Instant::now();
+        let gzip = PositionList::load_from(gzip_path).unwrap();
+        println!("gzip: {} bytes, loaded in {:?}", file_size(gzip_path), start.elapsed());
+        let start = Instant::now();
+        let raw = PositionList::load_from(raw_path).unwrap();
+        println!("raw mapped: {} bytes, loaded in {:?}", file_size(raw_path), start.elapsed());
+        // the mapped load is the same index as the gzip one, rebuilt parts included
+        assert_eq!(raw.postings_lists, gzip.postings_lists);
+        assert_eq!(raw.document_frequency, gzip.document_frequency);
+        assert_eq!(raw.term_frequency, gzip.term_frequency);
+        assert_eq!(raw.document_length, gzip.document_length);
+        assert_eq!(raw.doc_terms, gzip.doc_terms);
+        assert_eq!(raw.document_count, gzip.document_count);
+        assert_eq!(raw.document_count, 20000);
+    }
+}
@@ -8,13 +8,21 @@ pub mod ranking;
 pub type TermId = u32;
 pub type DocId = u32;
 pub type TermOffset = u32;
+pub type FieldId = u8;
 
+// term id of a removed stopword, the index skips it but keeps its position
+pub const STOPWORD_GAP: TermId = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum RankingAlgorithm {
     Default,
     ExactMatch,
     VectorSpaceModel,
     OkapiBM25,
     LMD,
+    Bim,
 }
 
 pub const CFG_NAME: &str = ".rircfg";
+// gitignore style patterns of paths left out of the corpus
+pub const IGNORE_NAME: &str = ".rirignore";
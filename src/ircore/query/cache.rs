@@ -0,0 +1,171 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::ircore::{TermId, RankingAlgorithm};
+use crate::ircore::ranking::DocScore;
+
+// (index epoch, query terms, ranking)
+type CacheKey = (u64, Vec<TermId>, RankingAlgorithm);
+
+// results and the tick of their last use, ticks to keys from least recent
+#[derive(Debug, Default)]
+struct Entries {
+    results: HashMap<CacheKey, (Vec<DocScore>, u64)>,
+    recency: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+impl Entries {
+    fn touch(&mut self, key: &CacheKey) -> Option<Vec<DocScore>> {
+        self.tick += 1;
+        let (doc_scores, last_used) = self.results.get_mut(key)?;
+        let key = self.recency.remove(last_used).unwrap();
+        *last_used = self.tick;
+        self.recency.insert(self.tick, key);
+        Some(doc_scores.clone())
+    }
+
+    fn insert(&mut self, key: CacheKey, doc_scores: Vec<DocScore>, capacity: Option<usize>) {
+        self.tick += 1;
+        if let Some((_, last_used)) = self.results.insert(key.clone(), (doc_scores, self.tick)) {
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(self.tick, key);
+        while capacity.is_some_and(|capacity| self.results.len() > capacity) {
+            let (_, least_recent) = self.recency.pop_first().unwrap();
+            self.results.remove(&least_recent);
+        }
+    }
+}
+
+// Ranked results of past queries. Keys carry the index epoch, so results
+// computed before a mutation of the index are never served. With a capacity
+// the least recently used entry is dropped to make room.
+#[derive(Debug)]
+pub struct QueryCache {
+    entries: Mutex<Entries>,
+    capacity: Option<usize>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl Default for QueryCache {
+    fn default() -> Self {
+        QueryCache::new()
+    }
+}
+
+impl QueryCache {
+    pub fn new() -> Self {
+        QueryCache {
+            entries: Mutex::new(Entries::default()),
+            capacity: None,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    // at most capacity results, 0 caches nothing
+    pub fn with_capacity(capacity: usize) -> Self {
+        QueryCache { capacity: Some(capacity), ..QueryCache::new() }
+    }
+
+    // terms are the key in query order for ExactMatch, where the phrase order
+    // matters, sorted for the other rankings, which score a bag of terms
+    pub fn get_or_insert_with<F>(&self, epoch: u64, terms: &[TermId], ranking: RankingAlgorithm, compute: F)
+        -> Vec<DocScore> where F: FnOnce() -> Vec<DocScore> {
+        let mut terms = terms.to_vec();
+        if ranking != RankingAlgorithm::ExactMatch {
+            terms.sort_unstable();
+        }
+        let key = (epoch, terms, ranking);
+        if let Some(doc_scores) = self.entries.lock().unwrap().touch(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return doc_scores;
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let doc_scores = compute();
+        self.entries.lock().unwrap().insert(key, doc_scores.clone(), self.capacity);
+        doc_scores
+    }
+
+    // drop entries computed against an older epoch
+    pub fn evict_before(&self, epoch: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.results.retain(|key, _| key.0 >= epoch);
+        entries.recency.retain(|_, key| key.0 >= epoch);
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().results.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_cache() {
+        let cache = QueryCache::new();
+        let scores = vec![DocScore{docid: 1, score: 1.5}];
+        let res = cache.get_or_insert_with(0, &[3, 4], RankingAlgorithm::OkapiBM25, || scores.clone());
+        assert_eq!(res, scores);
+        assert_eq!((cache.hits(), cache.misses()), (0, 1));
+        let res = cache.get_or_insert_with(0, &[3, 4], RankingAlgorithm::OkapiBM25, Vec::new);
+        assert_eq!(res, scores);
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+        // other ranking or epoch is another entry
+        cache.get_or_insert_with(0, &[3, 4], RankingAlgorithm::LMD, Vec::new);
+        cache.get_or_insert_with(1, &[3, 4], RankingAlgorithm::OkapiBM25, Vec::new);
+        assert_eq!((cache.hits(), cache.misses()), (1, 3));
+        assert_eq!(cache.len(), 3);
+        cache.evict_before(1);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_query_cache_lru() {
+        let cache = QueryCache::with_capacity(2);
+        let scores = |docid| vec![DocScore{docid, score: 1.0}];
+        cache.get_or_insert_with(0, &[1], RankingAlgorithm::OkapiBM25, || scores(1));
+        cache.get_or_insert_with(0, &[2], RankingAlgorithm::OkapiBM25, || scores(2));
+        // [1] is used again, so [2] is the least recent when [3] comes in
+        assert_eq!(cache.get_or_insert_with(0, &[1], RankingAlgorithm::OkapiBM25, Vec::new), scores(1));
+        cache.get_or_insert_with(0, &[3], RankingAlgorithm::OkapiBM25, || scores(3));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get_or_insert_with(0, &[1], RankingAlgorithm::OkapiBM25, Vec::new), scores(1));
+        assert_eq!(cache.get_or_insert_with(0, &[2], RankingAlgorithm::OkapiBM25, Vec::new), vec![]);
+        assert_eq!((cache.hits(), cache.misses()), (2, 4));
+
+        // term order only matters to phrases
+        let cache = QueryCache::with_capacity(10);
+        cache.get_or_insert_with(0, &[3, 4], RankingAlgorithm::OkapiBM25, || scores(1));
+        cache.get_or_insert_with(0, &[4, 3], RankingAlgorithm::OkapiBM25, Vec::new);
+        cache.get_or_insert_with(0, &[3, 4], RankingAlgorithm::ExactMatch, || scores(1));
+        cache.get_or_insert_with(0, &[4, 3], RankingAlgorithm::ExactMatch, Vec::new);
+        assert_eq!((cache.hits(), cache.misses()), (1, 3));
+        cache.evict_before(1);
+        assert!(cache.is_empty());
+
+        let cache = QueryCache::with_capacity(0);
+        cache.get_or_insert_with(0, &[1], RankingAlgorithm::OkapiBM25, || scores(1));
+        assert!(cache.is_empty());
+    }
+}
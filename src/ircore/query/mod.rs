@@ -1,18 +1,445 @@
-use crate::ircore::TermId;
+pub mod cache;
+
+use crate::ircore::{TermId, TermOffset, RankingAlgorithm};
 use crate::ircore::token::analyzer::Analyzer;
+use crate::ircore::token::sgmt::{Language, SHINGLE_SEPARATOR};
+use crate::ircore::ranking::WeightedTerm;
+use serde::Serialize;
 
 pub struct Query {
 
 }
 
+// boolean expression of a query with AND, OR and NOT operators
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryNode {
+    Term(TermId),
+    // term not in the dictionary, matches no document
+    Unknown(String),
+    And(Box<QueryNode>, Box<QueryNode>),
+    Or(Box<QueryNode>, Box<QueryNode>),
+    Not(Box<QueryNode>),
+}
+
+impl QueryNode {
+    // terms a matching document may contain, i.e. those not under a NOT
+    pub fn positive_terms(&self) -> Vec<TermId> {
+        let mut term_ids = vec![];
+        self.collect_positive_terms(&mut term_ids);
+        term_ids
+    }
+
+    fn collect_positive_terms(&self, term_ids: &mut Vec<TermId>) {
+        match self {
+            QueryNode::Term(term_id) => term_ids.push(*term_id),
+            QueryNode::Unknown(_) | QueryNode::Not(_) => {},
+            QueryNode::And(left, right) | QueryNode::Or(left, right) => {
+                left.collect_positive_terms(term_ids);
+                right.collect_positive_terms(term_ids);
+            },
+        }
+    }
+
+    fn has_unknown_terms(&self) -> bool {
+        match self {
+            QueryNode::Term(_) => false,
+            QueryNode::Unknown(_) => true,
+            QueryNode::Not(node) => node.has_unknown_terms(),
+            QueryNode::And(left, right) | QueryNode::Or(left, right) => {
+                left.has_unknown_terms() || right.has_unknown_terms()
+            },
+        }
+    }
+}
+
+const OPERATORS: [&str; 3] = ["AND", "OR", "NOT"];
+// prefix expansions beyond this many terms are cut
+const MAX_PREFIX_TERMS: usize = 1000;
+
 impl Query {
+    // a phrase with AND, OR or NOT operators yields the terms not under a NOT
     pub fn parse(phrase: &str, ignore_non_exist_term: bool, analyzer: &Analyzer) -> Vec<TermId> {
-        let (term_ids, unknown_terms) = analyzer.parse(phrase);
+        Query::parse_fuzzy(phrase, ignore_non_exist_term, analyzer, None)
+    }
+
+    // like parse, with max_distance words not in the dictionary are expanded
+    // into the terms within that many edits, boolean queries are not expanded
+    pub fn parse_fuzzy(phrase: &str, ignore_non_exist_term: bool, analyzer: &Analyzer,
+                       max_distance: Option<u8>) -> Vec<TermId> {
+        if let Some(node) = Query::parse_boolean(phrase, analyzer) {
+            if !ignore_non_exist_term && node.has_unknown_terms() {
+                return vec![];
+            }
+            return node.positive_terms();
+        }
+        let word_by_word = max_distance.is_some() || Query::has_prefixes(phrase);
+        let (term_ids, unknown_terms) = match word_by_word {
+            true => Query::parse_words(phrase, analyzer, max_distance),
+            false => analyzer.parse(phrase),
+        };
         if !ignore_non_exist_term && unknown_terms.len() > 0 {
             return vec![];
         }
         term_ids
     }
+
+    // word and weight of "word^weight", None unless weight is a non negative number
+    fn split_boost(word: &str) -> Option<(&str, f32)> {
+        let (word, weight) = word.rsplit_once('^')?;
+        let weight = weight.parse::<f32>().ok().filter(|weight| weight.is_finite() && *weight >= 0.0)?;
+        match word.is_empty() {
+            true => None,
+            false => Some((word, weight)),
+        }
+    }
+
+    pub fn has_boosts(phrase: &str) -> bool {
+        phrase.split_whitespace().any(|word| Query::split_boost(word).is_some())
+    }
+
+    // like parse_fuzzy, each term carries the weight of its word, 1.0 for words
+    // without ^weight, a shingle the largest weight of the words it joins
+    pub fn parse_weighted(phrase: &str, ignore_non_exist_term: bool, analyzer: &Analyzer,
+                          max_distance: Option<u8>) -> Vec<WeightedTerm> {
+        let words: Vec<(&str, f32)> = phrase.split_whitespace()
+            .map(|word| Query::split_boost(word).unwrap_or((word, 1.0)))
+            .collect();
+        let mut terms = vec![];
+        for token in Query::expand_tokens(&words, analyzer, max_distance) {
+            match token {
+                Ok((term_ids, weight)) => terms.extend(term_ids.into_iter().map(|term_id| (term_id, weight))),
+                Err(_) if ignore_non_exist_term => {},
+                Err(_) => return vec![],
+            }
+        }
+        terms
+    }
+
+    // word with a trailing *, matching all terms it starts
+    fn is_prefix(word: &str) -> bool {
+        word.len() > 1 && word.ends_with('*')
+    }
+
+    // ids of the terms starting with word before its *, unknown if none does
+    fn expand_prefix(word: &str, analyzer: &Analyzer) -> (Vec<TermId>, Vec<String>) {
+        let term_ids = Query::limit_prefix(word, analyzer.prefix_ids(word.trim_end_matches('*')));
+        match term_ids.is_empty() {
+            true => (term_ids, vec![word.to_string()]),
+            false => (term_ids, vec![]),
+        }
+    }
+
+    fn limit_prefix(word: &str, mut term_ids: Vec<TermId>) -> Vec<TermId> {
+        if term_ids.len() > MAX_PREFIX_TERMS {
+            log::warn!("{} expands to {} terms, only the first {} are used", word, term_ids.len(), MAX_PREFIX_TERMS);
+            term_ids.truncate(MAX_PREFIX_TERMS);
+        }
+        term_ids
+    }
+
+    // the terms each token of the words may match, in the order the analyzer yields
+    // them, with the words analyzed together so shingles span words. A prefix is one
+    // token matching the terms it starts, within a shingle the shingles with a word
+    // it starts; an unknown token matches the terms within max_distance edits if
+    // given. A token carries the largest weight of its words, Err if it matches nothing
+    fn expand_tokens(words: &[(&str, f32)], analyzer: &Analyzer,
+                     max_distance: Option<u8>) -> Vec<Result<(Vec<TermId>, f32), String>> {
+        let mut tokens = vec![];
+        for (i, &(word, _)) in words.iter().enumerate() {
+            match Query::is_prefix(word) {
+                true => tokens.push((word.trim_end_matches('*').to_string(), i)),
+                false => tokens.extend(analyzer.word_tokens(word).into_iter().map(|token| (token, i))),
+            }
+        }
+        let mut expanded = vec![];
+        for (start, end) in analyzer.token_windows(tokens.len()) {
+            let window = &tokens[start..end];
+            // a removed stopword, not a term
+            if window.iter().any(|(token, _)| token.is_empty()) {
+                continue;
+            }
+            let parts: Vec<(&str, bool)> = window.iter()
+                .map(|(token, i)| (token.as_str(), Query::is_prefix(words[*i].0)))
+                .collect();
+            let name = parts.iter()
+                .map(|&(part, prefix)| match prefix {
+                    true => format!("{}*", part),
+                    false => part.to_string(),
+                })
+                .collect::<Vec<_>>().join(SHINGLE_SEPARATOR);
+            let mut term_ids = analyzer.window_ids(&parts, max_distance);
+            if parts.iter().any(|&(_, prefix)| prefix) {
+                term_ids = Query::limit_prefix(&name, term_ids);
+            }
+            let weight = window.iter().map(|&(_, i)| words[i].1).fold(0.0, f32::max);
+            match term_ids.is_empty() {
+                true => expanded.push(Err(name)),
+                false => expanded.push(Ok((term_ids, weight))),
+            }
+        }
+        expanded
+    }
+
+    pub fn has_prefixes(phrase: &str) -> bool {
+        phrase.split_whitespace().any(Query::is_prefix)
+    }
+
+    // the terms each position of the phrase may take: the expansions of a prefix,
+    // the terms within max_distance edits of an unknown token if given, or the
+    // term of any other token, in the order the analyzer yields the tokens.
+    // None if a position matches no term
+    pub fn parse_slots(phrase: &str, analyzer: &Analyzer, max_distance: Option<u8>) -> Option<Vec<Vec<TermId>>> {
+        let words: Vec<(&str, f32)> = phrase.split_whitespace().map(|word| (word, 1.0)).collect();
+        Query::expand_tokens(&words, analyzer, max_distance).into_iter()
+            .map(|token| token.ok().map(|(term_ids, _)| term_ids))
+            .collect()
+    }
+
+    // token by token, prefixes are expanded into all the terms they start,
+    // unknown tokens into terms within max_distance edits if given
+    fn parse_words(phrase: &str, analyzer: &Analyzer, max_distance: Option<u8>) -> (Vec<TermId>, Vec<String>) {
+        let words: Vec<(&str, f32)> = phrase.split_whitespace().map(|word| (word, 1.0)).collect();
+        let mut term_ids = vec![];
+        let mut unknown_terms = vec![];
+        for token in Query::expand_tokens(&words, analyzer, max_distance) {
+            match token {
+                Ok((ids, _)) => term_ids.extend(ids),
+                Err(name) => unknown_terms.push(name),
+            }
+        }
+        (term_ids, unknown_terms)
+    }
+
+    // double quoted phrases and the loose terms around them,
+    // a quote without a closing one is kept as a literal
+    pub fn split_phrases(phrase: &str) -> (Vec<String>, String) {
+        let mut phrases = vec![];
+        let mut terms = String::new();
+        let mut rest = phrase;
+        while let Some(open) = rest.find('"') {
+            let close = match rest[open + 1..].find('"') {
+                Some(len) => open + 1 + len,
+                None => break,
+            };
+            terms.push_str(&rest[..open]);
+            terms.push(' ');
+            let quoted = rest[open + 1..close].trim();
+            if !quoted.is_empty() {
+                phrases.push(quoted.to_string());
+            }
+            rest = &rest[close + 1..];
+        }
+        terms.push_str(rest);
+        (phrases, terms.trim().to_string())
+    }
+
+    // terms and window of "w1 NEAR/n w2 ...", None unless words and NEAR/n alternate,
+    // with several operators the smallest n bounds the window of all terms
+    pub fn parse_near(phrase: &str) -> Option<(String, TermOffset)> {
+        let words: Vec<&str> = phrase.split_whitespace().collect();
+        if words.len() < 3 || words.len().is_multiple_of(2) {
+            return None;
+        }
+        let mut max_gap: Option<TermOffset> = None;
+        for operator in words.iter().skip(1).step_by(2) {
+            let gap = operator.strip_prefix("NEAR/")?.parse::<TermOffset>().ok()?;
+            max_gap = Some(max_gap.map_or(gap, |max_gap| max_gap.min(gap)));
+        }
+        let terms: Vec<&str> = words.iter().step_by(2).copied().collect();
+        if terms.iter().any(|term| term.starts_with("NEAR/")) {
+            return None;
+        }
+        Some((terms.join(" "), max_gap?))
+    }
+
+    // operators are case sensitive, so "and" is still a plain term
+    pub fn has_operators(phrase: &str) -> bool {
+        phrase.split_whitespace().any(|word| OPERATORS.contains(&word))
+    }
+
+    // None if phrase has no operators or nothing to match.
+    // NOT binds tighter than AND, AND tighter than OR,
+    // words without an operator in between are joined by AND
+    pub fn parse_boolean(phrase: &str, analyzer: &Analyzer) -> Option<QueryNode> {
+        if !Query::has_operators(phrase) {
+            return None;
+        }
+        let words: Vec<&str> = phrase.split_whitespace().collect();
+        let mut pos = 0;
+        Query::parse_or(&words, &mut pos, analyzer)
+    }
+
+    fn parse_or(words: &[&str], pos: &mut usize, analyzer: &Analyzer) -> Option<QueryNode> {
+        let mut node = Query::parse_and(words, pos, analyzer);
+        while *pos < words.len() && words[*pos] == "OR" {
+            *pos += 1;
+            let right = Query::parse_and(words, pos, analyzer);
+            node = Query::join(node, right, QueryNode::Or);
+        }
+        node
+    }
+
+    fn parse_and(words: &[&str], pos: &mut usize, analyzer: &Analyzer) -> Option<QueryNode> {
+        let mut node = None;
+        while *pos < words.len() && words[*pos] != "OR" {
+            if words[*pos] == "AND" {
+                *pos += 1;
+                continue;
+            }
+            let right = Query::parse_not(words, pos, analyzer);
+            node = Query::join(node, right, QueryNode::And);
+        }
+        node
+    }
+
+    fn parse_not(words: &[&str], pos: &mut usize, analyzer: &Analyzer) -> Option<QueryNode> {
+        let word = words[*pos];
+        *pos += 1;
+        if word == "NOT" {
+            if *pos < words.len() && !OPERATORS[..2].contains(&words[*pos]) {
+                return Query::parse_not(words, pos, analyzer).map(|node| QueryNode::Not(Box::new(node)));
+            }
+            return None;
+        }
+        // a prefix matches any of the terms it expands to
+        if Query::is_prefix(word) {
+            let (term_ids, unknown_terms) = Query::expand_prefix(word, analyzer);
+            let nodes = term_ids.into_iter().map(QueryNode::Term)
+                .chain(unknown_terms.into_iter().map(QueryNode::Unknown));
+            return nodes.fold(None, |node, right| Query::join(node, Some(right), QueryNode::Or));
+        }
+        // a word may be analyzed into several terms, all of them must match
+        let (term_ids, unknown_terms) = analyzer.parse(word);
+        let nodes = term_ids.into_iter().map(QueryNode::Term)
+            .chain(unknown_terms.into_iter().map(QueryNode::Unknown));
+        nodes.fold(None, |node, right| Query::join(node, Some(right), QueryNode::And))
+    }
+
+    fn join(left: Option<QueryNode>, right: Option<QueryNode>,
+        op: fn(Box<QueryNode>, Box<QueryNode>) -> QueryNode) -> Option<QueryNode> {
+        match (left, right) {
+            (Some(left), Some(right)) => Some(op(Box::new(left), Box::new(right))),
+            (left, None) => left,
+            (None, right) => right,
+        }
+    }
+}
+
+// which documents match, how they are scored is up to SearchRequest::ranking
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryType {
+    // documents containing any of the terms
+    Terms(String),
+    // documents containing all terms adjacent and in order
+    Phrase(String),
+    // documents containing all terms within max_gap positions,
+    // in the query order if ordered, in any order otherwise
+    Near { terms: String, max_gap: TermOffset, ordered: bool },
+    // documents containing all must terms (any should term if there is no must term)
+    // and none of the must_not terms
+    Boolean { must: String, should: String, must_not: String },
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchFilter {
+    // only documents analyzed as language
+    pub language: Option<Language>,
+    // only documents with path starting with prefix
+    pub path_prefix: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchRequest {
+    pub query: QueryType,
+    // ExactMatch orders Phrase and Near matches by number of occurrences,
+    // Terms and Boolean queries fall back to the default ranking
+    pub ranking: RankingAlgorithm,
+    // None for all results
+    pub limit: Option<usize>,
+    pub offset: usize,
+    pub filter: SearchFilter,
+    // report the number of tokens of each hit
+    pub doc_length: bool,
+}
+
+impl SearchRequest {
+    pub fn new(query: QueryType) -> Self {
+        SearchRequest {
+            query,
+            ranking: RankingAlgorithm::Default,
+            limit: None,
+            offset: 0,
+            filter: SearchFilter::default(),
+            doc_length: false,
+        }
+    }
+
+    pub fn with_ranking(mut self, ranking: RankingAlgorithm) -> Self {
+        self.ranking = ranking;
+        self
+    }
+
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn with_offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn with_filter(mut self, filter: SearchFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn with_doc_length(mut self, doc_length: bool) -> Self {
+        self.doc_length = doc_length;
+        self
+    }
+}
+
+// rank numbers of hits with equal scores
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TieRanks {
+    // 1, 2, 3, 4: ties broken by doc id
+    Sequential,
+    // 1, 2, 2, 4: ties share the best rank
+    Shared,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RankedHit<'a> {
+    // starts at 1
+    pub rank: usize,
+    pub path: &'a String,
+    pub score: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit<'a> {
+    pub path: &'a String,
+    pub score: f32,
+    // number of tokens, only if requested
+    pub doc_length: Option<u32>,
+}
+
+// positions of a query term in a document
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Highlight {
+    pub term: String,
+    pub positions: Vec<TermOffset>,
+}
+
+// a result of json output
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JsonHit<'a> {
+    pub rank: usize,
+    pub path: &'a String,
+    pub score: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub highlights: Option<Vec<Highlight>>,
 }
 
 #[cfg(test)]
@@ -29,4 +456,81 @@ mod tests {
         let terms = Query::parse(phrase_str, true, &analyzer);
         assert_eq!(terms, vec![4, 3]);
     }
+
+    #[test]
+    fn test_parse_boolean() {
+        let mut analyzer = Analyzer::new();
+        analyzer.analyze("Do you QUARREL, sir?");
+        assert_eq!(Query::parse_boolean("sir quarrel", &analyzer), None);
+        let node = Query::parse_boolean("sir AND NOT quarrel", &analyzer).unwrap();
+        assert_eq!(node, QueryNode::And(
+            Box::new(QueryNode::Term(4)),
+            Box::new(QueryNode::Not(Box::new(QueryNode::Term(3))))));
+        // AND binds tighter than OR, adjacent words are joined by AND
+        let node = Query::parse_boolean("you sir OR nobody", &analyzer).unwrap();
+        assert_eq!(node, QueryNode::Or(
+            Box::new(QueryNode::And(Box::new(QueryNode::Term(2)), Box::new(QueryNode::Term(4)))),
+            Box::new(QueryNode::Unknown("nobody".to_string()))));
+        assert_eq!(node.positive_terms(), vec![2, 4]);
+        assert_eq!(Query::parse("you sir OR nobody", true, &analyzer), vec![2, 4]);
+        assert!(Query::parse("you sir OR nobody", false, &analyzer).is_empty());
+        // dangling operators are ignored
+        assert_eq!(Query::parse_boolean("AND sir NOT", &analyzer), Some(QueryNode::Term(4)));
+    }
+
+    #[test]
+    fn test_split_phrases() {
+        assert_eq!(Query::split_phrases("sir quarrel"), (vec![], "sir quarrel".to_string()));
+        assert_eq!(Query::split_phrases("\"quarrel sir\" well"),
+            (vec!["quarrel sir".to_string()], "well".to_string()));
+        assert_eq!(Query::split_phrases("a \"b c\" d \" \"\"e\""),
+            (vec!["b c".to_string(), "e".to_string()], "a   d".to_string()));
+        // dangling quote is a literal
+        assert_eq!(Query::split_phrases("\"b c\" \"d e"),
+            (vec!["b c".to_string()], "\"d e".to_string()));
+    }
+
+    #[test]
+    fn test_parse_prefix() {
+        let mut analyzer = Analyzer::new();
+        analyzer.analyze("Do you QUARREL, sir? Quarrels, sirs!");
+        assert_eq!(Query::parse("quar*", true, &analyzer), vec![3, 5]);
+        assert_eq!(Query::parse("Do QUAR* sir", true, &analyzer), vec![1, 3, 5, 4]);
+        assert_eq!(Query::parse("x* sir", true, &analyzer), vec![4]);
+        assert!(Query::parse("x* sir", false, &analyzer).is_empty());
+        // a lone * is not a prefix
+        assert_eq!(Query::parse("* sir", true, &analyzer), vec![4]);
+        // a prefix takes one position with all its terms
+        assert_eq!(Query::parse_slots("Do QUAR* sir", &analyzer, None), Some(vec![vec![1], vec![3, 5], vec![4]]));
+        assert_eq!(Query::parse_slots("x* sir", &analyzer, None), None);
+        let node = Query::parse_boolean("quar* AND NOT do", &analyzer).unwrap();
+        assert_eq!(node, QueryNode::And(
+            Box::new(QueryNode::Or(Box::new(QueryNode::Term(3)), Box::new(QueryNode::Term(5)))),
+            Box::new(QueryNode::Not(Box::new(QueryNode::Term(1))))));
+    }
+
+    #[test]
+    fn test_parse_near() {
+        assert_eq!(Query::parse_near("quarrel NEAR/5 sir"), Some(("quarrel sir".to_string(), 5)));
+        assert_eq!(Query::parse_near("a NEAR/5 b NEAR/2 c"), Some(("a b c".to_string(), 2)));
+        assert_eq!(Query::parse_near("quarrel sir"), None);
+        assert_eq!(Query::parse_near("quarrel NEAR/5"), None);
+        assert_eq!(Query::parse_near("quarrel NEAR/x sir"), None);
+        assert_eq!(Query::parse_near("quarrel near/5 sir"), None);
+        assert_eq!(Query::parse_near("a NEAR/1 NEAR/1 NEAR/1 b"), None);
+    }
+
+    #[test]
+    fn test_parse_weighted() {
+        let mut analyzer = Analyzer::new();
+        analyzer.analyze("Do you QUARREL, sir? Quarrels, sirs!");
+        assert!(!Query::has_boosts("quarrel sir"));
+        assert!(Query::has_boosts("quarrel^2 sir"));
+        assert_eq!(Query::parse_weighted("QUARREL^2 sir", true, &analyzer, None), vec![(3, 2.0), (4, 1.0)]);
+        assert_eq!(Query::parse_weighted("quar*^0.5", true, &analyzer, None), vec![(3, 0.5), (5, 0.5)]);
+        // not a weight, the caret is left to the analyzer
+        assert_eq!(Query::parse_weighted("sir^x ^2", true, &analyzer, None), vec![(4, 1.0)]);
+        assert!(!Query::has_boosts("sir^-1 ^2"));
+        assert!(Query::parse_weighted("nobody^2 sir", false, &analyzer, None).is_empty());
+    }
 }
@@ -0,0 +1,88 @@
+use crate::ircore::index::pl::SchemaDependIndex;
+use crate::ircore::{TermId};
+use crate::ircore::ranking::{DocScore, sort_doc_scores, trace_scores};
+use std::collections::HashSet;
+
+pub trait BinaryIndependence {
+    fn rank_bim(&self, term_ids: &[TermId]) -> Vec<DocScore>;
+    // rank_bim, logging the contribution of each term if trace
+    fn rank_bim_with(&self, term_ids: &[TermId], trace: bool) -> Vec<DocScore>;
+}
+
+impl<T: SchemaDependIndex> BinaryIndependence for T {
+    fn rank_bim(&self, term_ids: &[TermId]) -> Vec<DocScore> {
+        self.rank_bim_with(term_ids, false)
+    }
+
+    // The Binary Independence Model
+    // for all distinct term t in both query and document sum log(N/Nt)
+    //   N: total count of document
+    //   Nt: total count of document that contain term t
+    // term frequency in query and document is ignored
+    fn rank_bim_with(&self, term_ids: &[TermId], trace: bool) -> Vec<DocScore> {
+        let mut scores = vec![];
+        if term_ids.is_empty() {
+            return scores;
+        }
+        let query_terms: HashSet<TermId> = term_ids.iter().cloned().collect();
+        let document_count = self.get_document_count() as f32;
+        let trace = trace_scores(trace);
+        let docs_contain_any = self.docs_contain_any(term_ids);
+        for docid in docs_contain_any {
+            assert!(self.is_valid_doc_id(docid));
+            let mut score = 0f32;
+            for &tid in &query_terms {
+                if self.get_term_frequency(tid, docid).is_some() {
+                    let nt = *self.get_document_frequency(tid).unwrap() as f32;
+                    let contribution = (document_count/nt).log2();
+                    if trace {
+                        log::trace!("bim doc {} term {}: {:.4}", docid, tid, contribution);
+                    }
+                    score += contribution;
+                }
+            }
+            scores.push(DocScore{docid, score});
+        }
+        // sort by socres
+        sort_doc_scores(&mut scores, None);
+        scores
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ircore::index::pl::{PositionList, SchemaDependIndex};
+    use crate::ircore::ranking::bm25::OkapiBm25;
+    use crate::ircore::token::dictionary::Dictionary;
+
+    #[test]
+    fn test_rank_bim(){
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        let mut term_ids = dict.generate_ids(&vec!["quarrel", "villain", "well"]);
+        idx.add_document(&term_ids);
+        term_ids = dict.generate_ids(&vec!["sir", "sir", "sir", "sir", "sir", "sir"]);
+        idx.add_document(&term_ids);
+        term_ids = dict.generate_ids(&vec!["sir", "no", "better"]);
+        idx.add_document(&term_ids);
+        term_ids = dict.generate_ids(&vec!["well", "sir"]);
+        idx.add_document(&term_ids);
+        let query = dict.generate_ids(&vec!["quarrel", "villain", "sir", "sir"]);
+        let docs = idx.rank_bim(&query);
+        assert_eq!(docs.len(), 4);
+        // two rare terms: 2*log2(4/1), sir: log2(4/3)
+        let epsilon = 0.005;
+        assert_eq!(docs[0].docid, 1);
+        assert!((docs[0].score - 4.0).abs() <= epsilon);
+        assert!((docs[1].score - 0.415).abs() <= epsilon);
+        // term frequency ignored, all docs containing only sir score the same
+        assert!(docs[1..].iter().all(|d| d.score == docs[1].score));
+        // query term frequency ignored as well
+        let query = dict.generate_ids(&vec!["quarrel", "villain", "sir"]);
+        assert_eq!(idx.rank_bim(&query)[0].score, docs[0].score);
+        // while BM25 rewards the high tf of sir
+        let bm25_docs = idx.rank_bm25(&query);
+        assert_eq!(bm25_docs[1].docid, 2);
+    }
+}
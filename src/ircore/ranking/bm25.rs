@@ -1,58 +1,112 @@
-use crate::ircore::index::pl::{PositionList, SchemaDependIndex};
+use crate::ircore::index::pl::SchemaDependIndex;
 use crate::ircore::{TermId};
-use crate::ircore::ranking::DocScore;
-use std::collections::HashMap;
+use crate::ircore::ranking::{DocScore, sort_doc_scores, cap_tf, trace_scores, WeightedTerm, unit_weights, query_term_weights};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bm25Params {
+    // weight saturation factor
+    pub k1: f32,
+    // level of normalization of document length
+    pub b: f32,
+    // slope s of the pivoted length normalization, None for standard BM25
+    pub pivot_slope: Option<f32>,
+    // count at most this many occurrences of a term in a document
+    pub tf_cap: Option<u32>,
+}
+
+impl Default for Bm25Params {
+    fn default() -> Self {
+        Bm25Params {
+            k1: 1.2,
+            b: 0.75,
+            pivot_slope: None,
+            tf_cap: None,
+        }
+    }
+}
+
+impl Bm25Params {
+    // k1 below 0 and b outside [0, 1] are clamped into range
+    pub fn set_k1_b(&mut self, k1: f32, b: f32) {
+        self.k1 = k1.max(0.0);
+        if self.k1 != k1 {
+            log::warn!("bm25 k1 {} is negative, using {}", k1, self.k1);
+        }
+        self.b = b.clamp(0.0, 1.0);
+        if self.b != b {
+            log::warn!("bm25 b {} is outside [0, 1], using {}", b, self.b);
+        }
+    }
+}
 
 pub trait OkapiBm25 {
     fn rank_bm25(&self, term_ids: &Vec<TermId>) -> Vec<DocScore>;
+    fn rank_bm25_with(&self, term_ids: &[TermId], params: &Bm25Params) -> Vec<DocScore>;
+    // trace: log per term contributions, see RankingParams::trace_scores
+    fn rank_bm25_weighted(&self, terms: &[WeightedTerm], params: &Bm25Params, trace: bool) -> Vec<DocScore>;
 }
 
-impl OkapiBm25 for PositionList {
+impl<T: SchemaDependIndex> OkapiBm25 for T {
+    fn rank_bm25(&self, term_ids: &Vec<TermId>) -> Vec<DocScore> {
+        self.rank_bm25_with(term_ids, &Bm25Params::default())
+    }
+
+    fn rank_bm25_with(&self, term_ids: &[TermId], params: &Bm25Params) -> Vec<DocScore> {
+        self.rank_bm25_weighted(&unit_weights(term_ids), params, false)
+    }
+
     // The BM25 algorithm
     // for all term t sum qt * ftd*(k1+1)/(k1*(1-b+b*(ld/lvag)) + ftd) * log(N/Nt)
-    //   qt: query term frequency
+    //   qt: query term frequency, summed weights of the term's occurrences
     //   ftd: inverted term document frequency (document_frequency[doc_id])
     //   k1: weight saturation factor, default 1.2
     //   b: level of normalization of document length, default 0.75
     //   N: total count of document (term_frequency[term_id, doc_id])
     //   Nt: total count of document that contain term t (document_count)
-    fn rank_bm25(&self, term_ids: &Vec<TermId>) -> Vec<DocScore> {
+    // With pivot_slope s, (1-b+b*(ld/lavg)) is replaced by the pivoted form
+    //   (1-s) + s*(1+ln(1+ld))/(1+ln(1+lavg))
+    // which still pivots around lavg but grows logarithmically with ld,
+    // so very long documents are penalized less than with the linear form.
+    fn rank_bm25_weighted(&self, terms: &[WeightedTerm], params: &Bm25Params, trace: bool) -> Vec<DocScore> {
         let mut scores = vec![];
-        if term_ids.len() == 0 {
+        if terms.is_empty() {
             return scores;
         }
         // find out query term frequency
-        let mut query_term_freq:HashMap<TermId, u32> = HashMap::new();
-        for &tid in term_ids {
-            query_term_freq.entry(tid)
-                .and_modify(|count| *count += 1)
-                .or_insert(1);
-        }
+        let query_term_freq = query_term_weights(terms);
+        let term_ids: Vec<TermId> = terms.iter().map(|&(tid, _)| tid).collect();
         // compute scores
-        let k1 = 1.2f32;
+        let k1 = params.k1;
         let k1plus1 = k1 + 1.0;
-        let b = 0.75f32;
+        let b = params.b;
         let document_count = self.get_document_count() as f32;
         let lavg = self.get_average_document_length();
+        let trace = trace_scores(trace);
         let docs_contain_any = self.docs_contain_any(&term_ids);
         for docid in docs_contain_any {
             assert!(self.is_valid_doc_id(docid));
             let ld = self.get_document_length(docid) as f32;
-            let k1_b_ld_lavg = k1*(1.0-b+b*(ld/lavg));
+            let k1_b_ld_lavg = match params.pivot_slope {
+                Some(s) => k1*((1.0-s)+s*(1.0+ld.ln_1p())/(1.0+lavg.ln_1p())),
+                None => k1*(1.0-b+b*(ld/lavg)),
+            };
             let mut score = 0f32;
-            for &tid in query_term_freq.keys() {
-                let qt = *query_term_freq.get(&tid).unwrap() as f32;
+            for (&tid, &(_, qt)) in &query_term_freq {
                 if let Some(ftd_ref) = self.get_term_frequency(tid, docid){
                     let nt = *self.get_document_frequency(tid).unwrap() as f32;
                     let idf = (document_count/nt).log2(); 
-                    let ftd = *ftd_ref as f32;
-                    score += qt * ftd * k1plus1 / (k1_b_ld_lavg + ftd) * idf;    
+                    let ftd = cap_tf(*ftd_ref, params.tf_cap) as f32;
+                    let contribution = qt * ftd * k1plus1 / (k1_b_ld_lavg + ftd) * idf;
+                    if trace {
+                        log::trace!("bm25 doc {} term {}: {:.4}", docid, tid, contribution);
+                    }
+                    score += contribution;
                 }
             }
             scores.push(DocScore{docid: docid, score:score});  
         }
         // sort by socres
-        scores.sort_by(|a, b| a.score.partial_cmp(&b.score).unwrap().reverse() );
+        sort_doc_scores(&mut scores, None);
         scores
     }
 }
@@ -101,4 +155,73 @@ mod tests {
         assert!( (docs[3].score - 0.18).abs() <= epsilon );
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_rank_bm25_pivoted(){
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        let mut term_ids = dict.generate_ids(&vec!["quarrel", "no", "sir"]);
+        idx.add_document(&term_ids);
+        term_ids = dict.generate_ids(&vec!["if", "you", "do", "sir"]);
+        idx.add_document(&term_ids);
+        term_ids = dict.generate_ids(&vec!["well", "sir"]);
+        idx.add_document(&term_ids);
+        // a very long document mentioning quarrel 3 times among 300 tokens
+        let mut long_doc = vec!["filler"; 297];
+        long_doc.extend(vec!["quarrel"; 3]);
+        term_ids = dict.generate_ids(&long_doc);
+        let long_doc_id = idx.add_document(&term_ids);
+        assert_eq!(long_doc_id, 4);
+        let query = dict.generate_ids(&vec!["quarrel"]);
+
+        // standard BM25 penalizes the long document linearly
+        let docs = idx.rank_bm25(&query);
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[0].docid, 1);
+        assert_eq!(docs[1].docid, long_doc_id);
+
+        // pivoted normalization dampens the penalty, the long document moves up
+        let params = Bm25Params { pivot_slope: Some(0.75), ..Bm25Params::default() };
+        let docs = idx.rank_bm25_with(&query, &params);
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[0].docid, long_doc_id);
+        assert_eq!(docs[1].docid, 1);
+    }
+
+    #[test]
+    fn test_rank_bm25_tf_cap(){
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        let term_ids = dict.generate_ids(&vec!["quarrel"; 100]);
+        let stuffed = idx.add_document(&term_ids);
+        let mut capped_doc = vec!["quarrel"; 5];
+        capped_doc.extend(vec!["filler"; 95]);
+        let term_ids = dict.generate_ids(&capped_doc);
+        let capped = idx.add_document(&term_ids);
+        let term_ids = dict.generate_ids(&vec!["well", "sir"]);
+        idx.add_document(&term_ids);
+        let query = dict.generate_ids(&vec!["quarrel"]);
+
+        let docs = idx.rank_bm25(&query);
+        assert_eq!(docs[0].docid, stuffed);
+        assert!(docs[0].score > docs[1].score);
+
+        let params = Bm25Params { tf_cap: Some(5), ..Bm25Params::default() };
+        let docs = idx.rank_bm25_with(&query, &params);
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[0].score, docs[1].score);
+        assert_eq!((docs[0].docid, docs[1].docid), (stuffed, capped));
+    }
+
+    #[test]
+    fn test_set_k1_b(){
+        let mut params = Bm25Params::default();
+        params.set_k1_b(2.0, 0.5);
+        assert_eq!((params.k1, params.b), (2.0, 0.5));
+        params.set_k1_b(-1.0, 1.5);
+        assert_eq!((params.k1, params.b), (0.0, 1.0));
+        params.set_k1_b(1.2, -0.1);
+        assert_eq!((params.k1, params.b), (1.2, 0.0));
+        assert_eq!(params.pivot_slope, None);
+    }
+}
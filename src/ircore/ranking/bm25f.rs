@@ -0,0 +1,160 @@
+use crate::ircore::index::pl::SchemaDependIndex;
+use crate::ircore::{TermId};
+use crate::ircore::ranking::{DocScore, sort_doc_scores, trace_scores, WeightedTerm, unit_weights, query_term_weights};
+use crate::ircore::ranking::bm25::Bm25Params;
+use std::collections::HashMap;
+
+// weight of each field in BM25F, fields not set weigh 1.0
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FieldWeights {
+    weights: HashMap<String, f32>,
+}
+
+impl FieldWeights {
+    pub fn new() -> Self {
+        FieldWeights::default()
+    }
+
+    // negative weights are clamped to 0
+    pub fn set(&mut self, field: &str, weight: f32) {
+        if weight < 0.0 {
+            log::warn!("weight {} of field {} is negative, using 0", weight, field);
+        }
+        self.weights.insert(field.to_string(), weight.max(0.0));
+    }
+
+    pub fn get(&self, field: &str) -> f32 {
+        *self.weights.get(field).unwrap_or(&1.0)
+    }
+}
+
+pub trait Bm25F {
+    fn rank_bm25f(&self, term_ids: &[TermId], weights: &FieldWeights, params: &Bm25Params) -> Vec<DocScore>;
+    fn rank_bm25f_weighted(&self, terms: &[WeightedTerm], weights: &FieldWeights, params: &Bm25Params, trace: bool) -> Vec<DocScore>;
+}
+
+impl<T: SchemaDependIndex> Bm25F for T {
+    fn rank_bm25f(&self, term_ids: &[TermId], weights: &FieldWeights, params: &Bm25Params) -> Vec<DocScore> {
+        self.rank_bm25f_weighted(&unit_weights(term_ids), weights, params, false)
+    }
+
+    // BM25F with weighted fields (Robertson, Zaragoza and Taylor 2004)
+    // for all term t sum qt * tfw*(k1+1)/(k1*(1-b+b*(lw/lwavg)) + tfw) * log(N/Nt)
+    //   tfw: sum over fields f of wf * ftd in f
+    //   lw: sum over fields f of wf * length of f in the document
+    //   lwavg: average lw over all documents
+    // A document indexed without fields is a single field of weight 1.0,
+    // so with all weights 1.0 the scores are the plain BM25 ones.
+    // tf_cap applies to tfw, pivot_slope to lw and lwavg.
+    fn rank_bm25f_weighted(&self, terms: &[WeightedTerm], weights: &FieldWeights, params: &Bm25Params, trace: bool) -> Vec<DocScore> {
+        let mut scores = vec![];
+        if terms.is_empty() {
+            return scores;
+        }
+        let query_term_freq = query_term_weights(terms);
+        let term_ids: Vec<TermId> = terms.iter().map(|&(tid, _)| tid).collect();
+        let field_weights: Vec<f32> = self.get_field_names().iter().map(|name| weights.get(name)).collect();
+        let k1 = params.k1;
+        let k1plus1 = k1 + 1.0;
+        let b = params.b;
+        let document_count = self.get_document_count() as f32;
+        // lwavg is lavg corrected by the extra weight of each field
+        let lavg = self.get_average_document_length();
+        let lwavg = lavg + self.get_total_field_lengths().iter().zip(&field_weights)
+            .map(|(&total, &wf)| (wf - 1.0) * total as f32 / document_count)
+            .sum::<f32>();
+        let trace = trace_scores(trace);
+        for docid in self.docs_contain_any(&term_ids) {
+            let lw = match self.get_field_lengths(docid) {
+                Some(lengths) => lengths.iter().zip(&field_weights).map(|(&l, &wf)| wf * l as f32).sum(),
+                None => self.get_document_length(docid) as f32,
+            };
+            let k1_b_lw_lwavg = match params.pivot_slope {
+                Some(s) => k1*((1.0-s)+s*(1.0+lw.ln_1p())/(1.0+lwavg.ln_1p())),
+                None => k1*(1.0-b+b*(lw/lwavg)),
+            };
+            let mut score = 0f32;
+            for (&tid, &(_, qt)) in &query_term_freq {
+                let ftd = match self.get_term_frequency(tid, docid) {
+                    Some(ftd) => *ftd,
+                    None => continue,
+                };
+                let mut tfw = match self.get_position_fields(tid, docid) {
+                    Some(fields) => fields.iter().map(|&f| field_weights[f as usize]).sum(),
+                    None => ftd as f32,
+                };
+                if let Some(cap) = params.tf_cap {
+                    tfw = tfw.min(cap as f32);
+                }
+                let nt = *self.get_document_frequency(tid).unwrap() as f32;
+                let idf = (document_count/nt).log2();
+                let contribution = qt * tfw * k1plus1 / (k1_b_lw_lwavg + tfw) * idf;
+                if trace {
+                    log::trace!("bm25f doc {} term {}: {:.4}", docid, tid, contribution);
+                }
+                score += contribution;
+            }
+            scores.push(DocScore{docid, score});
+        }
+        sort_doc_scores(&mut scores, None);
+        scores
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ircore::index::pl::{PositionList, SchemaDependIndex};
+    use crate::ircore::ranking::bm25::OkapiBm25;
+    use crate::ircore::token::dictionary::Dictionary;
+
+    fn assert_same_scores(left: &[DocScore], right: &[DocScore]) {
+        assert_eq!(left.len(), right.len());
+        for (l, r) in left.iter().zip(right) {
+            assert_eq!(l.docid, r.docid);
+            assert!((l.score - r.score).abs() < 1e-5, "{:?} {:?}", l, r);
+        }
+    }
+
+    #[test]
+    fn test_rank_bm25f() {
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        // quarrel in the title of one document and in the body of the other,
+        // otherwise the documents are alike
+        let title_hit = idx.add_document_fields(&[
+            ("title", dict.generate_ids(&vec!["quarrel", "sir"])),
+            ("text", dict.generate_ids(&vec!["do", "you", "bite", "your", "thumb"]))]);
+        let text_hit = idx.add_document_fields(&[
+            ("title", dict.generate_ids(&vec!["thumb", "sir"])),
+            ("text", dict.generate_ids(&vec!["do", "you", "quarrel", "your", "thumb"]))]);
+        idx.add_document(&dict.generate_ids(&vec!["well", "sir"]));
+        idx.add_document_fields(&[
+            ("title", dict.generate_ids(&vec!["no"])),
+            ("text", dict.generate_ids(&vec!["better", "sir"]))]);
+        let query = dict.generate_ids(&vec!["quarrel", "sir"]);
+
+        // default weights reproduce plain BM25, a tie between the two hits
+        let params = Bm25Params::default();
+        let docs = idx.rank_bm25f(&query, &FieldWeights::new(), &params);
+        assert_same_scores(&docs, &idx.rank_bm25(&query));
+        assert_eq!(docs[0].score, docs[1].score);
+        let params = Bm25Params { pivot_slope: Some(0.5), tf_cap: Some(1), ..Bm25Params::default() };
+        assert_same_scores(&idx.rank_bm25f(&query, &FieldWeights::new(), &params), &idx.rank_bm25_with(&query, &params));
+
+        // a heavier title puts the title hit first
+        let mut weights = FieldWeights::new();
+        weights.set("title", 3.0);
+        let docs = idx.rank_bm25f(&query, &weights, &Bm25Params::default());
+        assert_eq!((docs[0].docid, docs[1].docid), (title_hit, text_hit));
+        assert!(docs[0].score > docs[1].score);
+        // and a lighter one last among them
+        weights.set("title", 0.5);
+        let docs = idx.rank_bm25f(&query, &weights, &Bm25Params::default());
+        assert_eq!((docs[0].docid, docs[1].docid), (text_hit, title_hit));
+
+        weights.set("text", -1.0);
+        assert_eq!(weights.get("text"), 0.0);
+        assert_eq!(weights.get("url"), 1.0);
+    }
+}
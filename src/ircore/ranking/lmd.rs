@@ -1,54 +1,106 @@
-use crate::ircore::TermId;
-use crate::ircore::ranking::DocScore;
-use crate::ircore::index::pl::{PositionList, SchemaDependIndex};
+use crate::ircore::{DocId, TermId};
+use crate::ircore::ranking::{DocScore, sort_doc_scores, cap_tf, trace_scores, WeightedTerm, unit_weights, query_term_weights};
+use crate::ircore::index::pl::SchemaDependIndex;
 use std::collections::HashMap;
 
+// document prior probability, log2(prior_d) is added to the score
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum DocPrior {
+    // same for all documents, no effect on ranking
+    #[default]
+    Uniform,
+    // proportional to document length, ld / lavg
+    Length,
+    // supplied static scores, must be positive, 1 for missing documents
+    Static(HashMap<DocId, f32>),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LmdParams {
+    pub prior: DocPrior,
+    // Dirichlet smoothing mass, None for the average document length
+    pub mu: Option<f32>,
+    // count at most this many occurrences of a term in a document
+    pub tf_cap: Option<u32>,
+}
+
 pub trait LanguageModelDivergence {
     fn rank_lmd(&self, terms: &Vec<TermId>) -> Vec<DocScore>;
+    fn rank_lmd_with(&self, terms: &[TermId], params: &LmdParams) -> Vec<DocScore>;
+    fn rank_lmd_weighted(&self, terms: &[WeightedTerm], params: &LmdParams, trace: bool) -> Vec<DocScore>;
 }
 
-impl LanguageModelDivergence for PositionList {
+impl<T: SchemaDependIndex> LanguageModelDivergence for T {
+    fn rank_lmd(&self, terms: &Vec<TermId>) -> Vec<DocScore> {
+        self.rank_lmd_with(terms, &LmdParams::default())
+    }
+
+    fn rank_lmd_with(&self, terms: &[TermId], params: &LmdParams) -> Vec<DocScore> {
+        self.rank_lmd_weighted(&unit_weights(terms), params, false)
+    }
+
     // LMD - language modeling with Dirichlet smoothing
-    // for all term t: sum(qt * log(1 + ftd * N / lt)) - n * log(1 + ld / lavg)
-    //   qt: query term frequency
+    // for all term t: sum(qt * log(1 + ftd / (mu * lt / lc))) + n * log(mu / (mu + ld))
+    //   qt: query term frequency, summed weights of the term's occurrences
     //   ftd: inverted term document frequency, document_length[doc_id]
-    //   N: total count of document
+    //   mu: smoothing mass, defaults to lavg
     //   lt: number of times term t occurs in the collection
+    //   lc: total length of the collection, N * lavg
     //   n: equals to sum(all qt), is the number of tokens in the query
     //   ld: length of the document d, measured in tokens
+    //   N: total count of document
     //   lavg: average length of all documents in the collection
-    fn rank_lmd(&self, terms: &Vec<TermId>) -> Vec<DocScore> {
+    // plus log(prior_d) for non-uniform document priors.
+    // With the default mu = lavg this is the former fixed formula
+    //   sum(qt * log(1 + ftd * N / lt)) - n * log(1 + ld / lavg)
+    // a small mu favors documents dense in query terms, a large mu favors
+    // documents with more occurrences however long they are
+    fn rank_lmd_weighted(&self, terms: &[WeightedTerm], params: &LmdParams, trace: bool) -> Vec<DocScore> {
         let mut scores = vec![];
         if terms.len() == 0 {
             return scores;
         }
         // find out query term frequency
-        let mut query_term_freq:HashMap<TermId, u32> = HashMap::new();
-        for &tid in terms {
-            query_term_freq.entry(tid)
-                .and_modify(|count| *count += 1)
-                .or_insert(1);
-        }
+        let query_term_freq = query_term_weights(terms);
+        let term_ids: Vec<TermId> = terms.iter().map(|&(tid, _)| tid).collect();
         let document_count = self.get_document_count() as f32; // N
         let lavg = self.get_average_document_length();
-        let query_token_num = terms.len() as f32; // n
-        let docs_contain_any = self.docs_contain_any(&terms);
+        let query_token_num: f32 = terms.iter().map(|&(_, weight)| weight).sum(); // n
+        let mu = params.mu.filter(|mu| *mu > 0.0).unwrap_or(lavg);
+        let collection_length = document_count * lavg; // lc
+        let trace = trace_scores(trace);
+        let docs_contain_any = self.docs_contain_any(&term_ids);
         for docid in docs_contain_any {
             let ld = self.get_document_length(docid) as f32;
             let mut score = 0f32;
-            for &tid in query_term_freq.keys() {
-                let qt = *query_term_freq.get(&tid).unwrap() as f32;
+            for (&tid, &(_, qt)) in &query_term_freq {
                 if let Some(ftd_ref) = self.get_term_frequency(tid, docid){
-                    let ftd = *ftd_ref as f32; 
+                    let ftd = cap_tf(*ftd_ref, params.tf_cap) as f32;
                     let lt = self.get_term_occurences_num(tid) as f32;
-                    score += (1f32 + ftd * document_count / lt).log2() * qt;
+                    // postings and term frequencies out of sync, see validate()
+                    if lt == 0.0 {
+                        log::warn!("term {} has no occurrences in the collection, skipped", tid);
+                        continue;
+                    }
+                    let contribution = (1f32 + ftd * collection_length / (mu * lt)).log2() * qt;
+                    if trace {
+                        log::trace!("lmd doc {} term {}: {:.4}", docid, tid, contribution);
+                    }
+                    score += contribution;
                 }
             }
-            score -= (1f32 + ld / lavg).log2() * query_token_num;
+            if mu > 0.0 {
+                score += (mu / (mu + ld)).log2() * query_token_num;
+            }
+            match &params.prior {
+                DocPrior::Uniform => (),
+                DocPrior::Length => score += (ld / lavg).log2(),
+                DocPrior::Static(priors) => score += priors.get(&docid).unwrap_or(&1.0).log2(),
+            }
             scores.push(DocScore{docid: docid, score:score});  
         }
         // sort by socres
-        scores.sort_by(|a, b| a.score.partial_cmp(&b.score).unwrap().reverse() );
+        sort_doc_scores(&mut scores, None);
         scores
     }
 }
@@ -89,4 +141,96 @@ mod tests {
         let epsilon = 0.005;
         assert!((docs_subset[0].score - 1.25).abs() < epsilon);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_rank_lmd_prior() {
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        let mut term_ids = dict.generate_ids(&vec!["quarrel", "sir"]);
+        idx.add_document(&term_ids);
+        term_ids = dict.generate_ids(&vec!["quarrel", "sir", "no", "sir", "well", "better", "villain", "man"]);
+        idx.add_document(&term_ids);
+        term_ids = dict.generate_ids(&vec!["do", "you"]);
+        idx.add_document(&term_ids);
+        term_ids = dict.generate_ids(&vec!["quarrel", "sir"]);
+        idx.add_document(&term_ids);
+        let query = dict.generate_ids(&vec!["quarrel"]);
+        let uniform = idx.rank_lmd_with(&query, &LmdParams::default());
+        assert_eq!(uniform, idx.rank_lmd(&query));
+        // doc 1 and 4 are equally likely, the long doc 2 comes last
+        assert_eq!(uniform.iter().map(|d| d.docid).collect::<Vec<_>>(), vec![1, 4, 2]);
+        assert_eq!(uniform[0].score, uniform[1].score);
+
+        // length prior favors the long document
+        let docs = idx.rank_lmd_with(&query, &LmdParams{ prior: DocPrior::Length, ..LmdParams::default() });
+        assert_eq!(docs.iter().map(|d| d.docid).collect::<Vec<_>>(), vec![2, 1, 4]);
+
+        // static prior breaks the tie of equally likely documents
+        let priors = HashMap::from([(4, 2.0)]);
+        let docs = idx.rank_lmd_with(&query, &LmdParams{ prior: DocPrior::Static(priors), ..LmdParams::default() });
+        assert_eq!(docs.iter().map(|d| d.docid).collect::<Vec<_>>(), vec![4, 1, 2]);
+        let epsilon = 0.005;
+        assert!((docs[0].score - docs[1].score - 1.0).abs() < epsilon);
+    }
+
+    #[test]
+    fn test_rank_lmd_nan_score() {
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        for doc in [vec!["do", "you", "quarrel", "sir"], vec!["quarrel", "sir", "no", "sir"], vec!["well", "sir"]] {
+            idx.add_document(&dict.generate_ids(&doc));
+        }
+        let query = dict.generate_ids(&vec!["quarrel", "sir"]);
+        // log2 of a negative prior is NaN, ranked last instead of panicking
+        let priors = HashMap::from([(1, -1.0)]);
+        let docs = idx.rank_lmd_with(&query, &LmdParams{ prior: DocPrior::Static(priors), ..LmdParams::default() });
+        assert_eq!(docs.len(), 3);
+        assert_eq!(docs[2].docid, 1);
+        assert!(docs[2].score.is_nan());
+        assert!(docs[..2].iter().all(|doc| doc.score.is_finite()));
+    }
+
+    #[test]
+    fn test_rank_lmd_tf_cap() {
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        let term_ids = dict.generate_ids(&vec!["quarrel"; 100]);
+        idx.add_document(&term_ids);
+        let mut capped_doc = vec!["quarrel"; 5];
+        capped_doc.extend(vec!["filler"; 95]);
+        let term_ids = dict.generate_ids(&capped_doc);
+        idx.add_document(&term_ids);
+        let query = dict.generate_ids(&vec!["quarrel"]);
+        let docs = idx.rank_lmd(&query);
+        assert!(docs[0].score > docs[1].score);
+        let docs = idx.rank_lmd_with(&query, &LmdParams{ tf_cap: Some(5), ..LmdParams::default() });
+        assert_eq!(docs[0].score, docs[1].score);
+    }
+
+    #[test]
+    fn test_rank_lmd_mu() {
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        // short document, one in two tokens matches
+        let term_ids = dict.generate_ids(&vec!["quarrel", "sir"]);
+        let short = idx.add_document(&term_ids);
+        // long document, more matches but one in two hundred tokens
+        let mut long_doc = vec!["quarrel"; 5];
+        long_doc.extend(vec!["filler"; 995]);
+        let term_ids = dict.generate_ids(&long_doc);
+        let long = idx.add_document(&term_ids);
+        for _ in 0..10 {
+            let term_ids = dict.generate_ids(&vec!["other"; 100]);
+            idx.add_document(&term_ids);
+        }
+        let query = dict.generate_ids(&vec!["quarrel"]);
+        let ranking = |mu: f32| idx.rank_lmd_with(&query, &LmdParams{ mu: Some(mu), ..LmdParams::default() })
+            .iter().map(|d| d.docid).collect::<Vec<_>>();
+        assert_eq!(ranking(100.0), vec![short, long]);
+        assert_eq!(ranking(5000.0), vec![long, short]);
+        // default is the average document length, non-positive mu falls back to it
+        let lavg = idx.get_average_document_length();
+        assert_eq!(idx.rank_lmd(&query), idx.rank_lmd_with(&query, &LmdParams{ mu: Some(lavg), ..LmdParams::default() }));
+        assert_eq!(idx.rank_lmd(&query), idx.rank_lmd_with(&query, &LmdParams{ mu: Some(0.0), ..LmdParams::default() }));
+    }
+}
@@ -1,39 +1,372 @@
 pub mod vsm;
 pub mod bm25;
+pub mod bm25f;
 pub mod lmd;
 pub mod ps;
+pub mod bim;
+pub mod tf;
 use vsm::VectorSpaceModel;
-use bm25::OkapiBm25;
-use lmd::LanguageModelDivergence;
+use bm25::{OkapiBm25, Bm25Params};
+use bm25f::{Bm25F, FieldWeights};
+use lmd::{LanguageModelDivergence, LmdParams};
 use ps::PhraseMatch;
+use bim::BinaryIndependence;
+use tf::TermFrequency;
 
 use crate::ircore::DocId;
+use std::collections::HashMap;
+
+// checked once per query by scorers, contributions are only computed when
+// asked for and logged at trace level
+pub fn trace_scores(trace: bool) -> bool {
+    trace && log::log_enabled!(log::Level::Trace)
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct DocScore {
     pub docid: DocId,
     pub score: f32,
 }
 
+// total order of scores with NaN below any number, equal to each other,
+// so a NaN score from a degenerate index sorts last instead of panicking
+pub fn cmp_scores(a: f32, b: f32) -> std::cmp::Ordering {
+    a.partial_cmp(&b).unwrap_or_else(|| b.is_nan().cmp(&a.is_nan()))
+}
+
+// highest score first, scores equal after rounding to precision decimal places
+// are ordered by doc id, so ties do not reshuffle between pages, NaN scores last
+pub fn sort_doc_scores(scores: &mut [DocScore], precision: Option<u32>) {
+    let factor = precision.map(|p| 10f32.powi(p as i32));
+    let key = |score: f32| match factor {
+        Some(f) => (score * f).round(),
+        None => score,
+    };
+    scores.sort_by(|a, b| cmp_scores(key(b.score), key(a.score))
+        .then_with(|| a.docid.cmp(&b.docid)));
+}
+
+// usual k of reciprocal rank fusion, damping the weight of top ranks
+pub const RRF_K: f32 = 60.0;
+
+// reciprocal rank fusion: each document scores sum 1/(k + rank) over the rankings
+// it appears in, rank is 1-based, highest fused score first
+pub fn fuse_rankings(rankings: &[Vec<DocScore>], k: f32) -> Vec<DocScore> {
+    let mut fused: HashMap<DocId, f32> = HashMap::new();
+    for ranking in rankings {
+        for (i, doc) in ranking.iter().enumerate() {
+            *fused.entry(doc.docid).or_insert(0.0) += 1.0 / (k + (i + 1) as f32);
+        }
+    }
+    let mut scores: Vec<DocScore> = fused.into_iter()
+        .map(|(docid, score)| DocScore{ docid, score })
+        .collect();
+    sort_doc_scores(&mut scores, None);
+    scores
+}
+
+// term frequency used in scoring, at most cap
+pub fn cap_tf(tf: u32, cap: Option<u32>) -> u32 {
+    match cap {
+        Some(cap) => tf.min(cap),
+        None => tf,
+    }
+}
+
+// a query term and its weight, 1.0 unless boosted with term^weight
+pub type WeightedTerm = (TermId, f32);
+
+pub fn unit_weights(terms: &[TermId]) -> Vec<WeightedTerm> {
+    terms.iter().map(|&tid| (tid, 1.0)).collect()
+}
+
+// occurrences and summed weight of each distinct query term, the sum is the
+// query term frequency qt, equal to the occurrences when nothing is boosted
+pub fn query_term_weights(terms: &[WeightedTerm]) -> HashMap<TermId, (u32, f32)> {
+    let mut weights: HashMap<TermId, (u32, f32)> = HashMap::new();
+    for &(tid, weight) in terms {
+        let entry = weights.entry(tid).or_insert((0, 0.0));
+        entry.0 += 1;
+        entry.1 += weight;
+    }
+    weights
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RankingParams {
+    // decimal places scores are rounded to when ordering results, None for exact
+    pub score_precision: Option<u32>,
+    // below this many documents IDF based rankings fall back to term frequency,
+    // None to always use them
+    pub min_idf_docs: Option<usize>,
+    // ExactMatch scores phrase occurrences per 1000 tokens instead of raw counts,
+    // so short documents are not outranked by long ones just for their length
+    pub phrase_per_length: bool,
+    pub bm25: Bm25Params,
+    // BM25F field weights for Default and OkapiBM25, None for plain BM25
+    pub field_weights: Option<FieldWeights>,
+    pub lmd: LmdParams,
+    // log per term score contributions at trace level, off by default
+    pub trace_scores: bool,
+}
+
 use crate::ircore::{TermId, RankingAlgorithm};
-use crate::ircore::index::pl::PositionList;
+use crate::ircore::index::pl::SchemaDependIndex;
 pub trait Scorer {
     fn score(&self, terms: &Vec<TermId>, ranking: RankingAlgorithm) -> Vec<DocScore>;
+    fn score_with(&self, terms: &[TermId], ranking: RankingAlgorithm, params: &RankingParams) -> Vec<DocScore>;
+    fn score_weighted(&self, terms: &[WeightedTerm], ranking: RankingAlgorithm, params: &RankingParams) -> Vec<DocScore>;
 }
 
-impl Scorer for PositionList {
+impl<T: SchemaDependIndex> Scorer for T {
+    fn score_with(&self, terms: &[TermId], ranking: RankingAlgorithm, params: &RankingParams) -> Vec<DocScore> {
+        // no average length or idf to score with, the rankers would divide by 0
+        if self.get_document_count() == 0 {
+            return vec![];
+        }
+        let idf_based = matches!(ranking, RankingAlgorithm::Default | RankingAlgorithm::OkapiBM25
+            | RankingAlgorithm::VectorSpaceModel | RankingAlgorithm::Bim);
+        let too_small = match params.min_idf_docs {
+            Some(min_docs) => self.get_document_count() < min_docs,
+            None => false,
+        };
+        let mut doc_scores = match ranking {
+            _ if idf_based && too_small => {
+                log::debug!("{} documents, rank {:?} by term frequency", self.get_document_count(), ranking);
+                self.rank_tf_with(terms, params.trace_scores)
+            },
+            RankingAlgorithm::ExactMatch if params.phrase_per_length => {
+                let mut doc_scores = self.search_phrase(terms);
+                for doc in doc_scores.iter_mut() {
+                    doc.score *= 1000.0 / self.get_document_length(doc.docid) as f32;
+                }
+                sort_doc_scores(&mut doc_scores, None);
+                doc_scores
+            },
+            RankingAlgorithm::LMD => self.rank_lmd_weighted(&unit_weights(terms), &params.lmd, params.trace_scores),
+            RankingAlgorithm::Default | RankingAlgorithm::OkapiBM25 => match &params.field_weights {
+                Some(weights) => self.rank_bm25f_weighted(&unit_weights(terms), weights, &params.bm25, params.trace_scores),
+                None => self.rank_bm25_weighted(&unit_weights(terms), &params.bm25, params.trace_scores),
+            },
+            RankingAlgorithm::VectorSpaceModel => self.rank_vsm_weighted(&unit_weights(terms), params.trace_scores),
+            RankingAlgorithm::Bim => self.rank_bim_with(terms, params.trace_scores),
+            RankingAlgorithm::ExactMatch => self.search_phrase(terms),
+        };
+        if params.score_precision.is_some() {
+            sort_doc_scores(&mut doc_scores, params.score_precision);
+        }
+        doc_scores
+    }
+
+    // BM25, BM25F, LMD and VSM scale each term by its weight,
+    // the other rankings ignore the weights
+    fn score_weighted(&self, terms: &[WeightedTerm], ranking: RankingAlgorithm, params: &RankingParams) -> Vec<DocScore> {
+        if self.get_document_count() == 0 {
+            return vec![];
+        }
+        let term_ids: Vec<TermId> = terms.iter().map(|&(tid, _)| tid).collect();
+        let too_small = match params.min_idf_docs {
+            Some(min_docs) => self.get_document_count() < min_docs,
+            None => false,
+        };
+        if terms.iter().all(|&(_, weight)| weight == 1.0) || too_small {
+            return self.score_with(&term_ids, ranking, params);
+        }
+        let mut doc_scores = match ranking {
+            RankingAlgorithm::LMD => self.rank_lmd_weighted(terms, &params.lmd, params.trace_scores),
+            RankingAlgorithm::Default | RankingAlgorithm::OkapiBM25 => match &params.field_weights {
+                Some(weights) => self.rank_bm25f_weighted(terms, weights, &params.bm25, params.trace_scores),
+                None => self.rank_bm25_weighted(terms, &params.bm25, params.trace_scores),
+            },
+            RankingAlgorithm::VectorSpaceModel => self.rank_vsm_weighted(terms, params.trace_scores),
+            _ => return self.score_with(&term_ids, ranking, params),
+        };
+        if params.score_precision.is_some() {
+            sort_doc_scores(&mut doc_scores, params.score_precision);
+        }
+        doc_scores
+    }
+
     fn score(&self, terms: &Vec<TermId>, ranking: RankingAlgorithm) -> Vec<DocScore> {
         let docs = vec![];
-        if terms.len() == 0 {
+        if terms.is_empty() || self.get_document_count() == 0 {
             return docs;
         }
-        let scorer: fn(&PositionList, &Vec<TermId>) -> Vec<DocScore>;
         match ranking {
-            RankingAlgorithm::Default => scorer = PositionList::rank_bm25,
-            RankingAlgorithm::ExactMatch => scorer = PositionList::search_phrase,
-            RankingAlgorithm::VectorSpaceModel => scorer = PositionList::rank_vsm,
-            RankingAlgorithm::OkapiBM25 => scorer = PositionList::rank_bm25,  
-            RankingAlgorithm::LMD => scorer = PositionList::rank_lmd,       
+            RankingAlgorithm::Default => self.rank_bm25(terms),
+            RankingAlgorithm::ExactMatch => self.search_phrase(terms),
+            RankingAlgorithm::VectorSpaceModel => self.rank_vsm(terms),
+            RankingAlgorithm::OkapiBM25 => self.rank_bm25(terms),
+            RankingAlgorithm::LMD => self.rank_lmd(terms),
+            RankingAlgorithm::Bim => self.rank_bim(terms),
         }
-        let doc_scores = scorer(&self, &terms);
-        doc_scores
     }    
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sort_doc_scores() {
+        let mut scores = vec![
+            DocScore{docid: 3, score: 1.000001},
+            DocScore{docid: 1, score: 0.5},
+            DocScore{docid: 2, score: 1.0},
+        ];
+        sort_doc_scores(&mut scores, None);
+        assert_eq!(scores.iter().map(|s| s.docid).collect::<Vec<_>>(), vec![3, 2, 1]);
+        // difference below the rounding threshold, ordered by doc id
+        sort_doc_scores(&mut scores, Some(4));
+        assert_eq!(scores.iter().map(|s| s.docid).collect::<Vec<_>>(), vec![2, 3, 1]);
+        scores.reverse();
+        sort_doc_scores(&mut scores, Some(4));
+        assert_eq!(scores.iter().map(|s| s.docid).collect::<Vec<_>>(), vec![2, 3, 1]);
+    }
+
+    #[test]
+    fn test_score_empty_index() {
+        use crate::ircore::index::pl::PositionList;
+        let idx = PositionList::new();
+        for ranking in [RankingAlgorithm::Default, RankingAlgorithm::ExactMatch, RankingAlgorithm::VectorSpaceModel,
+            RankingAlgorithm::OkapiBM25, RankingAlgorithm::LMD, RankingAlgorithm::Bim] {
+            assert!(idx.score(&vec![1, 2], ranking).is_empty());
+            assert!(idx.score_with(&[1, 2], ranking, &RankingParams::default()).is_empty());
+            assert!(idx.score_weighted(&[(1, 2.0), (2, 1.0)], ranking, &RankingParams::default()).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_cmp_scores() {
+        use std::cmp::Ordering;
+        assert_eq!(cmp_scores(1.0, 0.5), Ordering::Greater);
+        assert_eq!(cmp_scores(0.5, 0.5), Ordering::Equal);
+        assert_eq!(cmp_scores(f32::NAN, f32::NEG_INFINITY), Ordering::Less);
+        assert_eq!(cmp_scores(-1.0, f32::NAN), Ordering::Greater);
+        assert_eq!(cmp_scores(f32::NAN, f32::NAN), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_sort_ties_by_doc_id() {
+        use crate::ircore::index::pl::PositionList;
+        use crate::ircore::token::dictionary::Dictionary;
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        // documents 2 and 4 are alike, so score the same in every ranking
+        for doc in [vec!["do", "you", "quarrel", "sir"], vec!["quarrel", "sir", "no"], vec!["no", "better"],
+            vec!["quarrel", "sir", "no"], vec!["well", "sir"]] {
+            idx.add_document(&dict.generate_ids(&doc));
+        }
+        let query = dict.generate_ids(&vec!["quarrel", "sir"]);
+        for ranking in [RankingAlgorithm::ExactMatch, RankingAlgorithm::VectorSpaceModel,
+            RankingAlgorithm::OkapiBM25, RankingAlgorithm::LMD] {
+            let docs = idx.score(&query, ranking);
+            let tied: Vec<DocId> = docs.iter().map(|doc| doc.docid).filter(|&doc| doc == 2 || doc == 4).collect();
+            assert_eq!(tied, vec![2, 4], "{:?}", ranking);
+            let first = docs.iter().position(|doc| doc.docid == 2).unwrap();
+            assert_eq!(docs[first].score, docs[first + 1].score);
+        }
+
+        // NaN scores go last instead of panicking
+        let mut scores = vec![
+            DocScore{docid: 1, score: f32::NAN},
+            DocScore{docid: 2, score: 0.5},
+            DocScore{docid: 3, score: f32::NAN},
+            DocScore{docid: 4, score: 1.0},
+        ];
+        sort_doc_scores(&mut scores, None);
+        assert_eq!(scores.iter().map(|s| s.docid).collect::<Vec<_>>(), vec![4, 2, 1, 3]);
+        sort_doc_scores(&mut scores, Some(2));
+        assert_eq!(scores.iter().map(|s| s.docid).collect::<Vec<_>>(), vec![4, 2, 1, 3]);
+    }
+
+    #[test]
+    fn test_min_idf_docs() {
+        use crate::ircore::index::pl::PositionList;
+        use crate::ircore::token::dictionary::Dictionary;
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        let term_ids = dict.generate_ids(&vec!["quarrel", "sir", "no", "sir"]);
+        idx.add_document(&term_ids);
+        let query = dict.generate_ids(&vec!["sir"]);
+        // log2(N/Nt) is 0 on a single document
+        let docs = idx.score_with(&query, RankingAlgorithm::OkapiBM25, &RankingParams::default());
+        assert_eq!(docs[0].score, 0.0);
+        let params = RankingParams{ min_idf_docs: Some(2), ..RankingParams::default() };
+        for ranking in [RankingAlgorithm::OkapiBM25, RankingAlgorithm::VectorSpaceModel] {
+            let docs = idx.score_with(&query, ranking, &params);
+            assert_eq!(docs, vec![DocScore{docid: 1, score: 2.0}]);
+        }
+        // enough documents, IDF rankings are used again
+        let term_ids = dict.generate_ids(&vec!["well"]);
+        idx.add_document(&term_ids);
+        let docs = idx.score_with(&query, RankingAlgorithm::OkapiBM25, &params);
+        assert_eq!(docs, idx.rank_bm25(&query));
+    }
+
+    // the logger is process-wide, lines are kept with the thread logging them,
+    // so lines of tests running in parallel are told apart
+    struct TestLogger {
+        lines: std::sync::Mutex<Vec<(std::thread::ThreadId, String)>>,
+    }
+
+    impl log::Log for TestLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+        fn log(&self, record: &log::Record) {
+            self.lines.lock().unwrap().push((std::thread::current().id(), record.args().to_string()));
+        }
+        fn flush(&self) {}
+    }
+
+    static LOGGER: TestLogger = TestLogger { lines: std::sync::Mutex::new(vec![]) };
+
+    #[test]
+    fn test_trace_scores() {
+        use crate::ircore::index::pl::PositionList;
+        use crate::ircore::token::dictionary::Dictionary;
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        // term ids no other test index has
+        let fillers: Vec<String> = (0..1000).map(|i| format!("filler{}", i)).collect();
+        dict.generate_ids(&fillers.iter().map(String::as_str).collect());
+        let term_ids = dict.generate_ids(&vec!["villain", "quarrel", "sir"]);
+        idx.add_document(&term_ids);
+        let term_ids = dict.generate_ids(&vec!["well", "sir"]);
+        idx.add_document(&term_ids);
+        let query = dict.generate_ids(&vec!["villain", "quarrel"]);
+        assert_eq!(query, [1001, 1002]);
+        let thread = std::thread::current().id();
+        let own_lines = || LOGGER.lines.lock().unwrap().iter()
+            .filter(|(id, _)| *id == thread)
+            .map(|(_, line)| line.clone())
+            .collect::<Vec<String>>();
+        let traced = |lines: &[String], prefix: &str| lines.iter().filter(|line| line.starts_with(prefix)).count();
+
+        idx.rank_bm25(&query);
+        idx.score_with(&query, RankingAlgorithm::OkapiBM25, &RankingParams::default());
+        assert_eq!(traced(&own_lines(), "bm25 doc 1 term 1001:"), 0);
+        let params = RankingParams { trace_scores: true, ..RankingParams::default() };
+        for ranking in [RankingAlgorithm::OkapiBM25, RankingAlgorithm::LMD, RankingAlgorithm::Bim,
+            RankingAlgorithm::VectorSpaceModel] {
+            idx.score_with(&query, ranking, &params);
+        }
+        // a single term query scores its contribution
+        let bm25 = idx.score_with(&[1001], RankingAlgorithm::OkapiBM25, &params);
+        // too few documents for IDF, ranked by term frequency
+        let tf_params = RankingParams { min_idf_docs: Some(10), ..params };
+        idx.score_with(&query, RankingAlgorithm::OkapiBM25, &tf_params);
+        log::set_max_level(log::LevelFilter::Off);
+        let lines = own_lines();
+        for scorer in ["bm25", "lmd", "bim", "vsm"] {
+            let single_term = if scorer == "bm25" { 1 } else { 0 };
+            assert_eq!(traced(&lines, &format!("{} doc 1 term 1001:", scorer)), 1 + single_term);
+            assert_eq!(traced(&lines, &format!("{} doc 1 term 1002:", scorer)), 1);
+        }
+        assert!(lines.contains(&format!("bm25 doc 1 term 1001: {:.4}", bm25[0].score)));
+        assert_eq!(traced(&lines, "tf doc 1 term 1001:"), 1);
+    }
+}
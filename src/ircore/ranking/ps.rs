@@ -1,18 +1,32 @@
-use crate::ircore::ranking::DocScore;
-use crate::ircore::index::pl::{PositionList, SchemaDependIndex};
+use crate::ircore::ranking::{DocScore, sort_doc_scores};
+use crate::ircore::index::pl::SchemaDependIndex;
 use crate::ircore::{DocId, TermId, TermOffset};
+use std::collections::HashSet;
 
 pub trait PhraseMatch {
-    fn search_phrase(&self, term_ids: &Vec<TermId>) -> Vec<DocScore>;   
+    fn search_phrase(&self, term_ids: &[TermId]) -> Vec<DocScore>;   
+    // overlapping: count occurrences sharing tokens ("a a" twice in "a a a")
+    fn search_phrase_with(&self, term_ids: &[TermId], overlapping: bool) -> Vec<DocScore>;
+    // (start, end) positions of all occurrences of the phrase in doc
+    fn phrase_positions(&self, doc: DocId, term_ids: &[TermId]) -> Vec<(TermOffset, TermOffset)>;
+    // documents containing all terms within max_gap positions, in the given order
+    // if ordered, any order otherwise, scored by the number of such windows
+    fn search_near(&self, term_ids: &[TermId], max_gap: TermOffset, ordered: bool) -> Vec<DocScore>;
+    // like search_phrase, each position of the phrase matches any of its terms
+    fn search_phrase_alternatives(&self, slots: &[Vec<TermId>]) -> Vec<DocScore>;
 }
 
-impl PhraseMatch for PositionList {
-    fn search_phrase(&self, term_ids: &Vec<TermId>) -> Vec<DocScore> {
+impl<T: SchemaDependIndex> PhraseMatch for T {
+    fn search_phrase(&self, term_ids: &[TermId]) -> Vec<DocScore> {
+        self.search_phrase_with(term_ids, true)
+    }
+
+    fn search_phrase_with(&self, term_ids: &[TermId], overlapping: bool) -> Vec<DocScore> {
         let mut scores = vec![];
-        if let Some(doc_set) = self.docs_contain_all(&term_ids){
+        if let Some(doc_set) = self.docs_contain_all(term_ids){
             let docs_contain_all:Vec<DocId> = doc_set.into_iter().collect();
             for doc in docs_contain_all {
-                let positions = self.all_phrase(doc, &term_ids);
+                let positions = self.all_phrase_with(doc, term_ids, overlapping);
                 if positions.len() > 0 {
                     scores.push(DocScore{
                         docid:doc,
@@ -21,7 +35,75 @@ impl PhraseMatch for PositionList {
                 }
             }
         }
-        scores.sort_by(|a, b| a.score.partial_cmp(&b.score).unwrap().reverse() );   
+        sort_doc_scores(&mut scores, None);
+        scores
+    }
+
+    fn phrase_positions(&self, doc: DocId, term_ids: &[TermId]) -> Vec<(TermOffset, TermOffset)> {
+        self.all_phrase(doc, term_ids)
+    }
+
+    fn search_near(&self, term_ids: &[TermId], max_gap: TermOffset, ordered: bool) -> Vec<DocScore> {
+        let mut scores = vec![];
+        let mut terms = term_ids.to_vec();
+        terms.sort();
+        terms.dedup();
+        if terms.is_empty() {
+            return scores;
+        }
+        if let Some(doc_set) = self.docs_contain_all(&terms) {
+            for doc in doc_set {
+                let windows = match ordered {
+                    true => self.ordered_near_windows(doc, term_ids, max_gap),
+                    false => self.near_windows(doc, &terms, max_gap),
+                };
+                if windows > 0 {
+                    scores.push(DocScore{
+                        docid: doc,
+                        score: windows as f32,
+                    });
+                }
+            }
+        }
+        sort_doc_scores(&mut scores, None);
+        scores
+    }
+
+    fn search_phrase_alternatives(&self, slots: &[Vec<TermId>]) -> Vec<DocScore> {
+        let mut scores = vec![];
+        if slots.is_empty() || slots.iter().any(|terms| terms.is_empty()) {
+            return scores;
+        }
+        let mut doc_set: Option<HashSet<DocId>> = None;
+        for terms in slots {
+            let docs = self.docs_contain_any(terms);
+            doc_set = Some(match doc_set {
+                Some(doc_set) => &doc_set & &docs,
+                None => docs,
+            });
+        }
+        for doc in doc_set.unwrap_or_default() {
+            // positions of all the terms of each slot
+            let positions: Vec<HashSet<TermOffset>> = slots.iter()
+                .map(|terms| terms.iter()
+                    .filter_map(|&term| self.get_positions(term, doc))
+                    .flatten()
+                    .copied()
+                    .collect())
+                .collect();
+            // occurrences may overlap, as with search_phrase
+            let count = positions[0].iter()
+                .filter(|&&start| positions.iter().enumerate().skip(1)
+                    .all(|(i, slot)| slot.contains(&(start + i as TermOffset))))
+                .count();
+            if count > 0 {
+                scores.push(DocScore{
+                    docid: doc,
+                    score: count as f32,
+                });
+            }
+        }
+        sort_doc_scores(&mut scores, None);
         scores
     }
 }
@@ -32,15 +114,18 @@ trait PhraseMatchHelpers {
     fn last(&self, doc:DocId, term:TermId) -> Option<TermOffset>;
     fn prev(&self, doc:DocId, term:TermId, before_position:TermOffset) -> Option<TermOffset>;
     fn next_phrase(
-        &self, doc:DocId, phrase: &Vec<TermId>, position:TermOffset) 
+        &self, doc:DocId, phrase: &[TermId], position:TermOffset) 
         -> Option<(TermOffset, TermOffset)>;
-    fn all_phrase(&self, doc: DocId, phrase: &Vec<TermId>) -> Vec<(TermOffset, TermOffset)>;
+    fn all_phrase(&self, doc: DocId, phrase: &[TermId]) -> Vec<(TermOffset, TermOffset)>;
+    fn all_phrase_with(&self, doc: DocId, phrase: &[TermId], overlapping: bool) -> Vec<(TermOffset, TermOffset)>;
+    fn near_windows(&self, doc: DocId, terms: &[TermId], max_gap: TermOffset) -> usize;
+    fn ordered_near_windows(&self, doc: DocId, terms: &[TermId], max_gap: TermOffset) -> usize;
     fn binary_search(
-        positions: &Vec<TermOffset> , low:usize, high: usize, current: TermOffset,
+        positions: &[TermOffset] , low:usize, high: usize, current: TermOffset,
         test_fn: fn(TermOffset, TermOffset) -> bool, retval_fn: fn(usize, usize) -> usize) -> usize;
 }
 
-impl PhraseMatchHelpers for PositionList {
+impl<T: SchemaDependIndex> PhraseMatchHelpers for T {
 
     fn first(&self, doc:DocId, term:TermId) -> Option<TermOffset> {
         if let Some(_term_in_doc) = self.get_document_frequency(term) {
@@ -102,7 +187,7 @@ impl PhraseMatchHelpers for PositionList {
     }
 
     fn next_phrase(
-        &self, doc:DocId, phrase: &Vec<TermId>, position:TermOffset) 
+        &self, doc:DocId, phrase: &[TermId], position:TermOffset) 
         -> Option<(TermOffset, TermOffset)>{
             if phrase.len() <= 1 {
                 return None;
@@ -130,7 +215,13 @@ impl PhraseMatchHelpers for PositionList {
             }
     }
 
-    fn all_phrase(&self, doc: DocId, phrase: &Vec<TermId>) -> Vec<(TermOffset, TermOffset)> {
+    fn all_phrase(&self, doc: DocId, phrase: &[TermId]) -> Vec<(TermOffset, TermOffset)> {
+        self.all_phrase_with(doc, phrase, true)
+    }
+
+    // overlapping: restart the search right after the start of the last match,
+    // otherwise right after its end, so no token is counted twice
+    fn all_phrase_with(&self, doc: DocId, phrase: &[TermId], overlapping: bool) -> Vec<(TermOffset, TermOffset)> {
         let mut result = Vec::new();
         // one word phrase
         if phrase.len() == 0 {
@@ -151,7 +242,11 @@ impl PhraseMatchHelpers for PositionList {
             match self.next_phrase(doc, phrase, pos) {
                 Some(r) => {
                     result.push(r);
-                    pos = r.0;
+                    if overlapping {
+                        pos = r.0;
+                    }else{
+                        pos = r.1;
+                    }
                 },
                 None => break
             }    
@@ -159,8 +254,57 @@ impl PhraseMatchHelpers for PositionList {
         result
     }
 
+    // number of minimal windows covering all (distinct) terms spanning at most max_gap
+    fn near_windows(&self, doc: DocId, terms: &[TermId], max_gap: TermOffset) -> usize {
+        let mut occurrences: Vec<(TermOffset, usize)> = vec![];
+        for (i, term) in terms.iter().enumerate() {
+            match self.get_positions(*term, doc) {
+                Some(positions) => occurrences.extend(positions.iter().map(|pos| (*pos, i))),
+                None => return 0,
+            }
+        }
+        occurrences.sort();
+        let mut counts = vec![0; terms.len()];
+        let mut covered = 0;
+        let mut left = 0;
+        let mut windows = 0;
+        for &(pos, term) in &occurrences {
+            if counts[term] == 0 {
+                covered += 1;
+            }
+            counts[term] += 1;
+            // shrink to the minimal window ending at pos
+            while counts[occurrences[left].1] > 1 {
+                counts[occurrences[left].1] -= 1;
+                left += 1;
+            }
+            if covered == terms.len() && pos - occurrences[left].0 <= max_gap {
+                windows += 1;
+            }
+        }
+        windows
+    }
+
+    // number of occurrences of the first term followed by the other terms
+    // in order, spanning at most max_gap
+    fn ordered_near_windows(&self, doc: DocId, terms: &[TermId], max_gap: TermOffset) -> usize {
+        let starts = match self.get_positions(terms[0], doc) {
+            Some(positions) => positions,
+            None => return 0,
+        };
+        starts.iter()
+            .filter(|start| {
+                let mut end = Some(**start);
+                for term in &terms[1..] {
+                    end = end.and_then(|pos| self.next(doc, *term, pos));
+                }
+                matches!(end, Some(end) if end - **start <= max_gap)
+            })
+            .count()
+    }
+
     fn binary_search(
-        positions: &Vec<TermOffset> , low:usize, high: usize, current: TermOffset,
+        positions: &[TermOffset] , low:usize, high: usize, current: TermOffset,
         test_fn: fn(TermOffset, TermOffset) -> bool, retval_fn: fn(usize, usize) -> usize) -> usize {
 
         let mut mid:usize;
@@ -246,6 +390,88 @@ mod tests {
         assert_eq!(term_ids.len(),1);
 
     }
+
+    #[test]
+    fn test_overlapping_phrase() {
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        let term_ids = dict.generate_ids(&vec!["spam", "spam", "spam", "spam", "spam", "spam"]);
+        let doc_id = idx.add_document(&term_ids);
+        assert_eq!(doc_id, 1);
+        let phrase = vec![1, 1, 1]; // vec!["spam", "spam", "spam"];
+        let overlapping = idx.all_phrase_with(1, &phrase, true);
+        assert_eq!(overlapping, vec![(1, 3), (2, 4), (3, 5), (4, 6)]);
+        assert_eq!(idx.all_phrase(1, &phrase), overlapping);
+        let non_overlapping = idx.all_phrase_with(1, &phrase, false);
+        assert_eq!(non_overlapping, vec![(1, 3), (4, 6)]);
+
+        let docs = idx.search_phrase_with(&phrase, true);
+        assert_eq!(docs[0].score, 4.0);
+        let docs = idx.search_phrase_with(&phrase, false);
+        assert_eq!(docs[0].score, 2.0);
+    }
+
+    #[test]
+    fn test_search_near() {
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        let term_ids = dict.generate_ids(&vec!["quarrel", "sir", "no", "sir"]);
+        idx.add_document(&term_ids);
+        let term_ids = dict.generate_ids(&vec!["sir", "i", "am", "for", "you", "quarrel"]);
+        idx.add_document(&term_ids);
+        let term_ids = dict.generate_ids(&vec!["quarrel", "no", "no", "no", "no", "no", "no", "sir"]);
+        idx.add_document(&term_ids);
+        let query = dict.generate_ids(&vec!["quarrel", "sir"]);
+        let docs = idx.search_near(&query, 5, false);
+        assert_eq!(docs, vec![
+            DocScore{docid: 1, score: 2.0},
+            DocScore{docid: 2, score: 1.0},
+        ]);
+        let docs = idx.search_near(&query, 1, false);
+        assert_eq!(docs, vec![DocScore{docid: 1, score: 1.0}]);
+        let docs = idx.search_near(&query, 7, false);
+        assert_eq!(docs.len(), 3);
+    }
+
+    #[test]
+    fn test_search_phrase_alternatives() {
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        let term_ids = dict.generate_ids(&vec!["quarrel", "sir", "no", "sir"]);
+        idx.add_document(&term_ids);
+        let term_ids = dict.generate_ids(&vec!["quarrels", "sir", "quarrel", "sirs"]);
+        idx.add_document(&term_ids);
+        let term_ids = dict.generate_ids(&vec!["sir", "quarrels"]);
+        idx.add_document(&term_ids);
+        let (quarrel, quarrels) = (dict.get("quarrel").unwrap(), dict.get("quarrels").unwrap());
+        let (sir, sirs) = (dict.get("sir").unwrap(), dict.get("sirs").unwrap());
+        let docs = idx.search_phrase_alternatives(&[vec![quarrel, quarrels], vec![sir, sirs]]);
+        assert_eq!(docs, vec![
+            DocScore{docid: 2, score: 2.0},
+            DocScore{docid: 1, score: 1.0},
+        ]);
+        // one term per slot is search_phrase
+        assert_eq!(idx.search_phrase_alternatives(&[vec![quarrel], vec![sir]]), idx.search_phrase(&[quarrel, sir]));
+        assert!(idx.search_phrase_alternatives(&[vec![quarrel], vec![]]).is_empty());
+        assert!(idx.search_phrase_alternatives(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_search_near_ordered() {
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        let term_ids = dict.generate_ids(&vec!["sir", "i", "quarrel"]);
+        idx.add_document(&term_ids);
+        let term_ids = dict.generate_ids(&vec!["quarrel", "i", "sir"]);
+        idx.add_document(&term_ids);
+        let query = dict.generate_ids(&vec!["sir", "quarrel"]);
+        let docs = idx.search_near(&query, 3, true);
+        assert_eq!(docs, vec![DocScore{docid: 1, score: 1.0}]);
+        let docs = idx.search_near(&query, 3, false);
+        assert_eq!(docs.len(), 2);
+        // the span limit applies to ordered matches too
+        assert!(idx.search_near(&query, 1, true).is_empty());
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,63 @@
+use crate::ircore::index::pl::SchemaDependIndex;
+use crate::ircore::{TermId};
+use crate::ircore::ranking::{DocScore, sort_doc_scores, trace_scores};
+use std::collections::HashSet;
+
+pub trait TermFrequency {
+    fn rank_tf(&self, term_ids: &[TermId]) -> Vec<DocScore>;
+    // rank_tf, logging the count of each term if trace
+    fn rank_tf_with(&self, term_ids: &[TermId], trace: bool) -> Vec<DocScore>;
+}
+
+impl<T: SchemaDependIndex> TermFrequency for T {
+    fn rank_tf(&self, term_ids: &[TermId]) -> Vec<DocScore> {
+        self.rank_tf_with(term_ids, false)
+    }
+
+    // for all distinct term t in both query and document sum ft,d
+    //   ft,d: term frequency of t in document d
+    // no collection statistics, used when the index is too small for IDF
+    fn rank_tf_with(&self, term_ids: &[TermId], trace: bool) -> Vec<DocScore> {
+        let mut scores = vec![];
+        if term_ids.is_empty() {
+            return scores;
+        }
+        let query_terms: HashSet<TermId> = term_ids.iter().cloned().collect();
+        let trace = trace_scores(trace);
+        for docid in self.docs_contain_any(term_ids) {
+            let score: u32 = query_terms.iter()
+                .filter_map(|&tid| self.get_term_frequency(tid, docid).map(|ftd| (tid, *ftd)))
+                .inspect(|(tid, ftd)| if trace {
+                    log::trace!("tf doc {} term {}: {}", docid, tid, ftd);
+                })
+                .map(|(_, ftd)| ftd)
+                .sum();
+            scores.push(DocScore{docid, score: score as f32});
+        }
+        sort_doc_scores(&mut scores, None);
+        scores
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ircore::index::pl::{PositionList, SchemaDependIndex};
+    use crate::ircore::token::dictionary::Dictionary;
+
+    #[test]
+    fn test_rank_tf(){
+        let mut idx = PositionList::new();
+        let mut dict = Dictionary::new();
+        let term_ids = dict.generate_ids(&vec!["quarrel", "sir", "no", "sir"]);
+        idx.add_document(&term_ids);
+        let term_ids = dict.generate_ids(&vec!["well", "sir"]);
+        idx.add_document(&term_ids);
+        let query = dict.generate_ids(&vec!["quarrel", "sir", "sir"]);
+        let docs = idx.rank_tf(&query);
+        assert_eq!(docs, vec![
+            DocScore{docid: 1, score: 3.0},
+            DocScore{docid: 2, score: 1.0},
+        ]);
+    }
+}
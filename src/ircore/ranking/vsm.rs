@@ -1,29 +1,44 @@
 use crate::ircore::{TermId};
-use crate::ircore::index::pl::{PositionList, SchemaDependIndex};
+use crate::ircore::index::pl::SchemaDependIndex;
+use crate::ircore::ranking::{DocScore, sort_doc_scores, trace_scores, WeightedTerm, unit_weights};
 use crate::ircore::utils::sparse_vector::SparseVectorOp;
-use crate::ircore::ranking::DocScore;
 
 pub trait VectorSpaceModel {
     fn rank_vsm(&self, term_ids: &Vec<TermId>) -> Vec<DocScore>;
+    fn rank_vsm_weighted(&self, terms: &[WeightedTerm], trace: bool) -> Vec<DocScore>;
 }
 
-impl VectorSpaceModel for PositionList {
+impl<T: SchemaDependIndex> VectorSpaceModel for T {
     fn rank_vsm(&self, term_ids: &Vec<TermId>) -> Vec<DocScore> {
+        self.rank_vsm_weighted(&unit_weights(term_ids), false)
+    }
+
+    // a boosted term weighs more in the query vector
+    fn rank_vsm_weighted(&self, terms: &[WeightedTerm], trace: bool) -> Vec<DocScore> {
         let mut scores = vec![];
-        if term_ids.len() == 0 {
+        if terms.is_empty() {
             return scores;
         }
-        let query_tfidf = self.get_phrase_tfidf_vector(term_ids);
+        let term_ids: Vec<TermId> = terms.iter().map(|&(tid, _)| tid).collect();
+        let query_tfidf = self.get_weighted_tfidf_vector(terms);
+        let trace = trace_scores(trace);
         // go through all documents that contains at least one term
         for doc_id in self.docs_contain_any(&term_ids) {
             if self.is_valid_doc_id(doc_id){
-                let doc_tfidf_vec = self.get_doc_tfidf_vector(doc_id);
-                let vec_distance = query_tfidf.vec_dot(&doc_tfidf_vec);
+                let vec_distance = self.doc_tfidf_dot(doc_id, &query_tfidf);
+                if trace {
+                    let doc_tfidf = self.get_doc_tfidf_vector(doc_id);
+                    let mut query_terms: Vec<_> = query_tfidf.iter().collect();
+                    query_terms.sort_by_key(|(tid, _)| **tid);
+                    for (tid, weight) in query_terms {
+                        log::trace!("vsm doc {} term {}: {:.4}", doc_id, tid, weight * doc_tfidf.vec_get(*tid));
+                    }
+                }
                 scores.push(DocScore{docid: doc_id, score: vec_distance});
             }
         }
         // sort by socres
-        scores.sort_by(|a, b| a.score.partial_cmp(&b.score).unwrap().reverse() );
+        sort_doc_scores(&mut scores, None);
         scores
     }
 }
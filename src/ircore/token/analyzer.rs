@@ -1,14 +1,72 @@
 use crate::ircore::token::dictionary::{Dictionary, DictionaryStats};
-use crate::ircore::token::sgmt::{Segmentator, Language};
-use crate::ircore::TermId;
+use crate::ircore::token::sgmt::{Segmentator, Language, UnicodeForm, Shingles, SHINGLE_SEPARATOR, is_cjk};
+use crate::ircore::token::filter::{Lemmatizer, TokenFilter, FilterSpec, STEMMER};
+use crate::ircore::{TermId, STOPWORD_GAP};
+use crate::ircore::utils::serialize;
 use serde::{Serialize, Deserialize};
 use whatlang::{Detector, Lang};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+pub const ENGLISH_STOPWORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into",
+    "is", "it", "no", "not", "of", "on", "or", "such", "that", "the", "their", "then",
+    "there", "these", "they", "this", "to", "was", "will", "with",
+];
+
+// runs of min_run or more identical chars are shortened to collapse_to,
+// "soooo" => "soo" by default
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct CollapseRepeats {
+    pub min_run: usize,
+    pub collapse_to: usize,
+}
+
+impl Default for CollapseRepeats {
+    fn default() -> Self {
+        CollapseRepeats {
+            min_run: 3,
+            collapse_to: 2,
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Analyzer {
     dict: Dictionary,
     seg: Segmentator,
     lang_detected: bool,
+    // stem English tokens, at index and query time
+    stemming: bool,
+    // lemmatize English tokens instead of stemming them
+    lemmatizer: Option<Lemmatizer>,
+    // words that bypass the stemmer, stored normalized
+    #[serde(serialize_with = "serialize::sorted_set")]
+    protected_words: HashSet<String>,
+    // language detection below this confidence uses fallback_language
+    min_lang_confidence: f64,
+    fallback_language: Language,
+    // collapse runs of identical chars in English tokens
+    collapse_repeats: Option<CollapseRepeats>,
+    // detect language of every document instead of the first one only,
+    // queries are then segmented as Language::Mixed
+    per_document_language: bool,
+    // tokens removed at index and query time, stored normalized
+    #[serde(default, serialize_with = "serialize::sorted_set")]
+    stopwords: HashSet<String>,
+    // English tokens of fewer chars are removed like stopwords
+    min_token_len: usize,
+    // index word n-grams, instead of or along with the words
+    shingles: Option<Shingles>,
+    // analyze texts longer than this many bytes chunk by chunk, build time only
+    #[serde(skip)]
+    chunk_bytes: Option<usize>,
+    // token filters applied in order right after segmentation
+    #[serde(default)]
+    filters: Vec<FilterSpec>,
+    // filters built from their specs, see rebuild
+    #[serde(skip)]
+    filter_chain: Vec<Box<dyn TokenFilter>>,
 }
 
 pub struct AnalyzerStats {
@@ -22,45 +80,517 @@ impl Analyzer {
             dict: Dictionary::new(),
             seg: Segmentator::new(),
             lang_detected: false,
+            stemming: false,
+            lemmatizer: None,
+            protected_words: HashSet::new(),
+            min_lang_confidence: 0.0,
+            fallback_language: Language::English,
+            collapse_repeats: None,
+            per_document_language: false,
+            stopwords: HashSet::new(),
+            min_token_len: 1,
+            shingles: None,
+            chunk_bytes: None,
+            filters: vec![],
+            filter_chain: vec![],
         }
     }
 
     pub fn detect_language(&mut self, doc_content: &str){
         if !self.lang_detected {
-            let allowlist = vec![Lang::Eng, Lang::Cmn];
-            let detector = Detector::with_allowlist(allowlist);
-            let lang = detector.detect_lang(doc_content);
-            match lang {
-                Some(Lang::Cmn) => self.set_language(Language::Chinese),
-                _ => (), // default English
+            if let Some(lang) = self.detect(doc_content) {
+                self.set_language(lang);
             }
             self.lang_detected = true;
         }
     }
 
+    // None if the text gives no hint, default English then
+    fn detect(&self, doc_content: &str) -> Option<Language> {
+        let allowlist = vec![Lang::Eng, Lang::Cmn];
+        let detector = Detector::with_allowlist(allowlist);
+        // with a one-language-per-script allowlist whatlang is always confident,
+        // weight it by how much of the text is actually written in that script
+        match detector.detect(doc_content) {
+            Some(info) if info.confidence() * Self::script_share(doc_content, info.lang()) 
+                < self.min_lang_confidence => {
+                log::info!("language detection confidence below {:.3}, fallback to {:?}",
+                    self.min_lang_confidence, self.fallback_language);
+                Some(self.fallback_language)
+            },
+            Some(info) if info.lang() == Lang::Cmn => Some(Language::Chinese),
+            _ => None,
+        }
+    }
+
+    pub fn set_per_document_language(&mut self, per_document_language: bool){
+        self.per_document_language = per_document_language;
+    }
+
+    // share of alphabetic chars written in the script of lang
+    fn script_share(text: &str, lang: Lang) -> f64 {
+        let mut total = 0usize;
+        let mut matched = 0usize;
+        for c in text.chars().filter(|c| c.is_alphabetic()) {
+            total += 1;
+            let in_script = match lang {
+                Lang::Cmn => is_cjk(c),
+                _ => c.is_ascii_alphabetic() || ('\u{00C0}'..='\u{024F}').contains(&c),
+            };
+            if in_script {
+                matched += 1;
+            }
+        }
+        if total == 0 {
+            return 0.0;
+        }
+        matched as f64 / total as f64
+    }
+
+    // detection below min_confidence (0.0 - 1.0) uses the fallback language
+    pub fn set_language_detection(&mut self, min_confidence: f64, fallback: Language){
+        self.min_lang_confidence = min_confidence;
+        self.fallback_language = fallback;
+    }
+
     pub fn set_language(&mut self, lang: Language){
         self.seg.set_language(lang)
     }
 
+    // use lang for all documents, no detection,
+    // e.g. Language::Mixed for code-switching Chinese and English text
+    pub fn set_fixed_language(&mut self, lang: Language){
+        self.set_language(lang);
+        self.lang_detected = true;
+    }
+
     pub fn get_language(&self) -> Language{
         self.seg.get_language()
     }
 
+    pub fn set_stemming(&mut self, stemming: bool){
+        self.stemming = stemming;
+    }
+
+    pub fn is_stemming(&self) -> bool {
+        self.stemming
+    }
+
+    // dictionary based alternative to stemming, takes precedence over it
+    pub fn set_lemmatizer(&mut self, lemmatizer: Option<Lemmatizer>){
+        self.lemmatizer = lemmatizer;
+    }
+
+    pub fn set_collapse_repeats(&mut self, collapse_repeats: Option<CollapseRepeats>){
+        self.collapse_repeats = collapse_repeats;
+    }
+
+    // Removed stopwords keep their position slot: analyze yields STOPWORD_GAP for them,
+    // so "quarrel sir" does not match "quarrel with sir" as a phrase. Queries drop them,
+    // a phrase query spanning a stopword therefore finds nothing. Set before indexing,
+    // ENGLISH_STOPWORDS is a default list.
+    pub fn set_stopwords(&mut self, words: &[&str]){
+        self.stopwords = words.iter().map(|word| self.seg.normalize(word)).collect();
+    }
+
+    // drop English tokens shorter than min_token_len chars, e.g. 2 for "a" and "i",
+    // Chinese single characters are words and always kept. Set before indexing.
+    pub fn set_min_token_len(&mut self, min_token_len: usize){
+        self.min_token_len = min_token_len.max(1);
+    }
+
+    // n below 2 turns shingles off, set before indexing
+    pub fn set_shingles(&mut self, shingles: Option<Shingles>){
+        self.shingles = shingles.filter(|shingles| shingles.n >= 2);
+    }
+
+    // bound the memory of analyzing very large texts, None to analyze at once
+    pub fn set_chunk_bytes(&mut self, chunk_bytes: Option<usize>){
+        self.chunk_bytes = chunk_bytes.filter(|&bytes| bytes > 0);
+    }
+
+    // full Unicode case folding at index and query time, set before indexing
+    pub fn set_case_folding(&mut self, case_folding: bool){
+        self.seg.set_case_folding(case_folding);
+    }
+
+    // keep the case of words at index and query time, overrides case folding,
+    // stopwords and protected words then match their exact case. Set before indexing.
+    pub fn set_case_sensitive(&mut self, case_sensitive: bool){
+        self.seg.set_case_sensitive(case_sensitive);
+    }
+
+    // Unicode normalization form of index and query text, set before indexing
+    pub fn set_unicode_form(&mut self, unicode_form: UnicodeForm){
+        self.seg.set_unicode_form(unicode_form);
+    }
+
+    pub fn set_protected_words(&mut self, words: &[&str]){
+        self.protected_words = words.iter()
+            .map(|word| self.seg.normalize(word))
+            .collect();
+    }
+
     pub fn get_dictionary(&self) -> &Dictionary{
         &self.dict
     }
+
+    // forget all terms, the settings and the language are kept
+    pub fn clear_dictionary(&mut self) {
+        self.dict = Dictionary::new();
+    }
+
+    // see Dictionary::take_terms
+    pub fn take_reverse_terms(&mut self) -> HashMap<TermId, String> {
+        self.dict.take_terms()
+    }
+
+    pub fn restore_reverse_terms(&mut self, terms: HashMap<TermId, String>) {
+        self.dict.restore_terms(terms);
+    }
+
+    // restore what was left out when saved
+    pub fn rebuild(&mut self) {
+        self.dict.rebuild();
+        self.seg.rebuild();
+        self.filter_chain = Self::build_filter_chain(&self.filters);
+    }
+
+    // filters run before stopwords, collapsing and stemming, set before indexing.
+    // FilterSpec::Stem turns on stemming, so language and protected words are
+    // checked and the tokens are stemmed once
+    pub fn set_filters(&mut self, filters: Vec<FilterSpec>) {
+        if filters.contains(&FilterSpec::Stem) {
+            self.stemming = true;
+        }
+        self.filter_chain = Self::build_filter_chain(&filters);
+        self.filters = filters;
+    }
+
+    fn build_filter_chain(filters: &[FilterSpec]) -> Vec<Box<dyn TokenFilter>> {
+        filters.iter()
+            .filter(|filter| **filter != FilterSpec::Stem)
+            .map(FilterSpec::build)
+            .collect()
+    }
+
+    // see Segmentator::add_user_word, set before indexing
+    pub fn add_user_word(&mut self, word: &str, freq: Option<usize>) {
+        self.seg.add_user_word(word, freq);
+    }
     pub fn analyze(&mut self, text: &str) -> Vec<TermId> {
-        self.detect_language(text);
-        let text_normalized = self.seg.normalize(&text);
-        let tokens = self.seg.parse_tokens(&text_normalized);
-        let term_ids = self.dict.generate_ids(&tokens);
-        term_ids
+        self.analyze_with_language(text).0
+    }
+
+    // term ids and the language text was analyzed as
+    pub fn analyze_with_language(&mut self, text: &str) -> (Vec<TermId>, Language) {
+        self.settle_language(text);
+        if let Some(chunk_bytes) = self.chunk_bytes.filter(|&bytes| text.len() > bytes) {
+            return self.analyze_chunked(text, chunk_bytes);
+        }
+        let (tokens, lang) = self.tokenize(text);
+        (self.merge_tokens(&tokens), lang)
+    }
+
+    // Same term ids as analyze_with_language, while only the tokens of one chunk are
+    // held at a time. Chunks end after a line break or else a whitespace, so words are
+    // not split unless a chunk has no whitespace at all. Shingles are built over the
+    // last n - 1 tokens of the previous chunk too, so none is lost at a chunk end.
+    pub fn analyze_chunked(&mut self, text: &str, chunk_bytes: usize) -> (Vec<TermId>, Language) {
+        self.settle_language(text);
+        let lang = self.text_language(text);
+        let mut term_ids = vec![];
+        let mut carry = vec![];
+        let mut token_count = 0;
+        for chunk in Self::chunks(text, chunk_bytes) {
+            let tokens = self.tokenize_words_as(chunk, lang);
+            let tokens = match self.shingles {
+                Some(shingles) => {
+                    let shingled = shingles.apply_after(&carry, &tokens);
+                    carry = shingles.carry(&carry, &tokens);
+                    token_count += tokens.len();
+                    shingled
+                },
+                None => tokens,
+            };
+            term_ids.extend(self.merge_tokens(&tokens));
+        }
+        // fewer than n words, all of them still carried
+        if let Some(shingles) = self.shingles.filter(|shingles| !shingles.unigrams && token_count < shingles.n) {
+            term_ids = self.merge_tokens(&shingles.apply(&carry));
+        }
+        (term_ids, lang)
+    }
+
+    // term ids of each field, analyzed as the language of all fields together
+    pub fn analyze_fields(&mut self, fields: &[&str]) -> (Vec<Vec<TermId>>, Language) {
+        self.settle_language(&fields.join("\n"));
+        let (tokens, lang) = self.tokenize_fields(fields);
+        let term_ids = tokens.iter()
+            .map(|field_tokens| self.merge_tokens(field_tokens))
+            .collect();
+        (term_ids, lang)
+    }
+
+    // analyze_with_language goes chunk by chunk for text, see set_chunk_bytes
+    pub fn is_chunked(&self, text: &str) -> bool {
+        self.chunk_bytes.is_some_and(|bytes| text.len() > bytes)
+    }
+
+    fn chunks(text: &str, chunk_bytes: usize) -> Vec<&str> {
+        let mut chunks = vec![];
+        let mut rest = text;
+        while rest.len() > chunk_bytes {
+            let mut end = chunk_bytes.max(1);
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            let window = &rest[..end];
+            let end = match window.rfind('\n') {
+                Some(pos) => pos + 1,
+                None => match window.rfind(char::is_whitespace) {
+                    Some(pos) => pos + window[pos..].chars().next().unwrap().len_utf8(),
+                    // first char is wider than chunk_bytes
+                    None if end == 0 => rest.chars().next().unwrap().len_utf8(),
+                    None => end,
+                },
+            };
+            chunks.push(&rest[..end]);
+            rest = &rest[end..];
+        }
+        if !rest.is_empty() {
+            chunks.push(rest);
+        }
+        chunks
+    }
+
+    // Analysis in two phases for concurrent builds: once the language is settled
+    // tokenize is read-only and can run in parallel, merge_tokens then assigns
+    // term ids serially, in document order to keep ids deterministic.
+
+    // detect the index language from the first text unless detected per document
+    pub fn settle_language(&mut self, text: &str) {
+        if !self.per_document_language {
+            self.detect_language(text);
+        }
+    }
+
+    // processed tokens of text and the language it was analyzed as
+    pub fn tokenize(&self, text: &str) -> (Vec<String>, Language) {
+        let lang = self.text_language(text);
+        (self.tokenize_as(text, lang), lang)
+    }
+
+    // read-only counterpart of analyze_fields
+    pub fn tokenize_fields(&self, fields: &[&str]) -> (Vec<Vec<String>>, Language) {
+        let lang = self.text_language(&fields.join("\n"));
+        (fields.iter().map(|field| self.tokenize_as(field, lang)).collect(), lang)
+    }
+
+    fn text_language(&self, text: &str) -> Language {
+        if self.per_document_language {
+            self.detect(text).unwrap_or(Language::English)
+        }else{
+            self.get_language()
+        }
+    }
+
+    fn tokenize_as(&self, text: &str, lang: Language) -> Vec<String> {
+        let tokens = self.tokenize_words_as(text, lang);
+        match &self.shingles {
+            Some(shingles) => shingles.apply(&tokens),
+            None => tokens,
+        }
+    }
+
+    // tokens before shingles are built
+    fn tokenize_words_as(&self, text: &str, lang: Language) -> Vec<String> {
+        let text_normalized = self.seg.normalize(text);
+        let tokens = self.seg.parse_tokens_as(&text_normalized, lang);
+        if self.filter_chain.is_empty() {
+            let tokens = self.process_tokens(tokens, lang);
+            tokens.into_iter().map(|token| token.into_owned()).collect()
+        } else {
+            let filtered = self.apply_filters(tokens);
+            let tokens = self.process_tokens(filtered.iter().map(String::as_str).collect(), lang);
+            tokens.into_iter().map(|token| token.into_owned()).collect()
+        }
+    }
+
+    fn apply_filters(&self, tokens: Vec<&str>) -> Vec<String> {
+        self.filter_chain.iter()
+            .fold(tokens.into_iter().map(str::to_string).collect(), |tokens, filter| filter.filter(tokens))
+    }
+
+    // empty tokens are removed stopwords
+    pub fn merge_tokens(&mut self, tokens: &[String]) -> Vec<TermId> {
+        let dict = &mut self.dict;
+        tokens.iter()
+            .map(|token| match token.is_empty() {
+                true => STOPWORD_GAP,
+                false => dict.get(token).unwrap_or_else(|| dict.add(token)),
+            })
+            .collect()
     }
 
     pub fn parse(&self, text: &str) -> (Vec<TermId>, Vec<String>) {
-        let text_normalized = self.seg.normalize(&text);
-        let tokens = self.seg.parse_tokens(&text_normalized);
-        self.dict.get_ids(&tokens)
+        let lang = self.query_language();
+        let tokens = self.tokenize_as(text, lang);
+        self.dict.get_ids(&tokens.iter().map(String::as_str).filter(|t| !t.is_empty()).collect())
+    }
+
+    // tokens of a query before shingles are built, stopwords are empty
+    pub fn word_tokens(&self, text: &str) -> Vec<String> {
+        self.tokenize_words_as(text, self.query_language())
+    }
+
+    // (start, end) range of the word tokens each query token joins, in order
+    pub fn token_windows(&self, len: usize) -> Vec<(usize, usize)> {
+        match &self.shingles {
+            Some(shingles) => shingles.windows(len),
+            None => (0..len).map(|i| (i, i + 1)).collect(),
+        }
+    }
+
+    // ids of indexed terms joining the word tokens of parts, a part marked as
+    // prefix matches any word it starts, a lone prefix any term. Without prefixes
+    // the term itself, or those within max_distance edits if it is not indexed
+    pub fn window_ids(&self, parts: &[(&str, bool)], max_distance: Option<u8>) -> Vec<TermId> {
+        let Some(first_prefix) = parts.iter().position(|&(_, prefix)| prefix) else {
+            let term = parts.iter().map(|&(part, _)| part).collect::<Vec<_>>().join(SHINGLE_SEPARATOR);
+            return match (self.dict.get(&term), max_distance) {
+                (Some(term_id), _) => vec![term_id],
+                (None, Some(distance)) => self.dict.fuzzy_ids(&term, distance),
+                (None, None) => vec![],
+            };
+        };
+        let parts: Vec<(String, bool)> = parts.iter()
+            .map(|&(part, prefix)| match prefix {
+                true => (self.seg.normalize(part), true),
+                false => (part.to_string(), false),
+            })
+            .collect();
+        let head = parts[..=first_prefix].iter().map(|(part, _)| part.as_str()).collect::<Vec<_>>().join(SHINGLE_SEPARATOR);
+        let term_ids = self.dict.prefix_ids(&head);
+        if parts.len() == 1 {
+            return term_ids;
+        }
+        term_ids.into_iter()
+            .filter(|&term_id| {
+                let term = self.dict.get_term_by_id(term_id);
+                let words: Vec<&str> = term.split(SHINGLE_SEPARATOR).collect();
+                words.len() == parts.len() && words.iter().zip(&parts).all(|(word, (part, prefix))| match prefix {
+                    true => word.starts_with(part.as_str()),
+                    false => word == part,
+                })
+            })
+            .collect()
+    }
+
+    // queries may target documents of any language with per document detection
+    fn query_language(&self) -> Language {
+        if self.per_document_language {
+            Language::Mixed
+        }else{
+            self.get_language()
+        }
+    }
+
+    // token processing after segmentation, shared by analyze and parse,
+    // stopwords become empty tokens
+    fn process_tokens<'a>(&self, tokens: Vec<&'a str>, lang: Language) -> Vec<Cow<'a, str>> {
+        tokens.into_iter()
+            .map(|token| match self.collapse(token, lang) {
+                _ if self.stopwords.contains(token) || self.too_short(token, lang) => Cow::Borrowed(""),
+                Cow::Borrowed(token) => self.stem(token, lang),
+                Cow::Owned(token) => Cow::Owned(self.stem(&token, lang).into_owned()),
+            })
+            .collect()
+    }
+
+    fn too_short(&self, token: &str, lang: Language) -> bool {
+        self.min_token_len > 1 && Self::is_english(token, lang) && token.chars().count() < self.min_token_len
+    }
+
+    fn is_english(token: &str, lang: Language) -> bool {
+        match lang {
+            Language::English => true,
+            Language::Chinese => false,
+            Language::Mixed => !token.chars().any(is_cjk),
+        }
+    }
+
+    fn collapse<'a>(&self, token: &'a str, lang: Language) -> Cow<'a, str> {
+        let cfg = match self.collapse_repeats {
+            Some(cfg) if Self::is_english(token, lang) => cfg,
+            _ => return Cow::Borrowed(token),
+        };
+        let mut collapsed = String::with_capacity(token.len());
+        let mut chars = token.chars().peekable();
+        while let Some(c) = chars.next() {
+            let mut run = 1;
+            while chars.next_if_eq(&c).is_some() {
+                run += 1;
+            }
+            if run >= cfg.min_run {
+                run = cfg.collapse_to;
+            }
+            collapsed.extend(std::iter::repeat_n(c, run));
+        }
+        if collapsed.len() == token.len() {
+            Cow::Borrowed(token)
+        }else{
+            Cow::Owned(collapsed)
+        }
+    }
+
+    fn stem<'a>(&self, token: &'a str, lang: Language) -> Cow<'a, str> {
+        if !Self::is_english(token, lang) || self.protected_words.contains(token) {
+            return Cow::Borrowed(token);
+        }
+        if let Some(lemmatizer) = &self.lemmatizer {
+            return match lemmatizer.lemma(token) {
+                Some(lemma) => Cow::Owned(lemma.to_string()),
+                None => Cow::Borrowed(token),
+            };
+        }
+        if self.stemming {
+            STEMMER.stem(token)
+        }else{
+            Cow::Borrowed(token)
+        }
+    }
+
+    // byte range in the original text of each token, token at index i
+    // has position i + 1 in the index, a shingle spans the words it joins
+    pub fn token_spans(&self, text: &str) -> Vec<(usize, usize)> {
+        let spans = self.word_spans(text);
+        match &self.shingles {
+            Some(shingles) => shingles.windows(spans.len()).into_iter()
+                .map(|(start, end)| (spans[start].0, spans[end - 1].1))
+                .collect(),
+            None => spans,
+        }
+    }
+
+    // byte range in the original text of each word, the same as token_spans without shingles
+    pub fn word_spans(&self, text: &str) -> Vec<(usize, usize)> {
+        // normalize char by char, remembering where each normalized byte came from
+        let mut normalized = String::with_capacity(text.len());
+        let mut origin = Vec::with_capacity(text.len() + 1);
+        let mut buf = [0u8; 4];
+        for (i, c) in text.char_indices() {
+            normalized.push_str(&self.seg.normalize(c.encode_utf8(&mut buf)));
+            origin.resize(normalized.len(), i);
+        }
+        origin.push(text.len());
+        let base = normalized.as_ptr() as usize;
+        self.seg.parse_tokens_as(&normalized, self.query_language()).into_iter()
+            .map(|token| {
+                let start = token.as_ptr() as usize - base;
+                (origin[start], origin[start + token.len()])
+            })
+            .collect()
     }
 
     pub fn stats(&self) -> AnalyzerStats {
@@ -68,6 +598,7 @@ impl Analyzer {
         match self.get_language() {
             Language::English => lang_str = String::from("English"),
             Language::Chinese => lang_str = String::from("Chinese"),
+            Language::Mixed => lang_str = String::from("Mixed"),
         }
         AnalyzerStats{
             dict: self.dict.stats(),
@@ -78,6 +609,16 @@ impl Analyzer {
     pub fn get_term_by_id(&self, tid: TermId) -> String {
         self.dict.get_term_by_id(tid)
     }
+
+    // ids of indexed terms within max_distance edits of an analyzed token
+    pub fn fuzzy_ids(&self, token: &str, max_distance: u8) -> Vec<TermId> {
+        self.dict.fuzzy_ids(token, max_distance)
+    }
+
+    // ids of indexed terms starting with prefix, normalized but not stemmed
+    pub fn prefix_ids(&self, prefix: &str) -> Vec<TermId> {
+        self.dict.prefix_ids(&self.seg.normalize(prefix))
+    }
 }
 
 #[cfg(test)]
@@ -102,6 +643,43 @@ mod tests {
         assert_eq!(unknown_terms, vec!["cool"]);        
     }
 
+    #[test]
+    fn test_stemming_protected_words() {
+        let mut analyzer = Analyzer::new();
+        analyzer.set_stemming(true);
+        analyzer.set_protected_words(&["Business"]);
+        let term_ids = analyzer.analyze("Business quarrels");
+        assert_eq!(term_ids, vec![1, 2]);
+        assert_eq!(analyzer.get_term_by_id(1), "business");
+        assert_eq!(analyzer.get_term_by_id(2), "quarrel");
+        let (term_known, unknown_terms) = analyzer.parse("business quarrel quarrelling");
+        assert_eq!(term_known, vec![1, 2, 2]);
+        assert_eq!(unknown_terms, Vec::<String>::new());
+
+        // without protection business is over-collapsed
+        let mut analyzer = Analyzer::new();
+        analyzer.set_stemming(true);
+        analyzer.analyze("Business quarrels");
+        assert_eq!(analyzer.get_term_by_id(1), "busi");
+    }
+
+    #[test]
+    fn test_stemming() {
+        let mut analyzer = Analyzer::new();
+        analyzer.set_stemming(true);
+        let term_ids = analyzer.analyze("Do you quarrel, sir? Quarrels, sirs!");
+        assert_eq!(term_ids, vec![1, 2, 3, 4, 3, 4]);
+        let (term_ids, _) = analyzer.parse("quarrels");
+        assert_eq!(term_ids, vec![3]);
+
+        // Chinese tokens are not stemmed
+        let mut analyzer = Analyzer::new();
+        analyzer.set_stemming(true);
+        let term_ids = analyzer.analyze("滚滚长江东逝水");
+        let terms: Vec<String> = term_ids.iter().map(|&term_id| analyzer.get_term_by_id(term_id)).collect();
+        assert_eq!(terms.concat(), "滚滚长江东逝水");
+    }
+
     #[test]
     fn test_analyze_english() {
         let mut analyzer = Analyzer::new();
@@ -119,6 +697,104 @@ mod tests {
         assert_eq!(term_ids, vec![1, 2, 3, 4, 5, 6, 7, 8]);
     }
 
+    #[test]
+    fn test_token_spans() {
+        let analyzer = Analyzer::new();
+        let text = "Quarrel SIR! no, sir!";
+        let spans = analyzer.token_spans(text);
+        let tokens: Vec<&str> = spans.iter().map(|&(start, end)| &text[start..end]).collect();
+        assert_eq!(tokens, vec!["Quarrel", "SIR", "no", "sir"]);
+        // lowercase of İ is longer than İ itself
+        let text = "İstanbul is big";
+        let spans = analyzer.token_spans(text);
+        assert_eq!(&text[spans[1].0..spans[1].1], "is");
+    }
+
+    #[test]
+    fn test_per_document_language() {
+        let mut analyzer = Analyzer::new();
+        analyzer.set_per_document_language(true);
+        let (_, lang) = analyzer.analyze_with_language("Do you quarrel, sir?");
+        assert_eq!(lang, Language::English);
+        let (term_ids, lang) = analyzer.analyze_with_language("滚滚长江东逝水");
+        assert_eq!(lang, Language::Chinese);
+        assert_eq!(term_ids, vec![5, 6, 7, 8]);
+        let (term_ids, _) = analyzer.analyze_with_language("quarrels, sir");
+        assert_eq!(term_ids, vec![9, 4]);
+        let (term_known, _) = analyzer.parse("sir 长江");
+        assert_eq!(term_known, vec![4, 6]);
+    }
+
+    #[test]
+    fn test_collapse_repeats() {
+        let mut analyzer = Analyzer::new();
+        analyzer.set_collapse_repeats(Some(CollapseRepeats::default()));
+        let term_ids = analyzer.analyze("cooool, soooo cool");
+        assert_eq!(term_ids, vec![1, 2, 1]);
+        assert_eq!(analyzer.get_term_by_id(1), "cool");
+        assert_eq!(analyzer.get_term_by_id(2), "soo");
+        let (term_known, _) = analyzer.parse("coool");
+        assert_eq!(term_known, vec![1]);
+
+        let mut analyzer = Analyzer::new();
+        analyzer.set_collapse_repeats(Some(CollapseRepeats{ min_run: 3, collapse_to: 1 }));
+        let term_ids = analyzer.analyze("soooo so cool");
+        assert_eq!(term_ids, vec![1, 1, 2]);
+        assert_eq!(analyzer.get_term_by_id(1), "so");
+        assert_eq!(analyzer.get_term_by_id(2), "cool");
+
+        // Chinese text is left alone
+        let mut analyzer = Analyzer::new();
+        analyzer.set_collapse_repeats(Some(CollapseRepeats{ min_run: 2, collapse_to: 1 }));
+        analyzer.analyze("滚滚长江东逝水");
+        assert_eq!(analyzer.get_term_by_id(1), "滚滚");
+    }
+
+    #[test]
+    fn test_analyze_mixed() {
+        let mut analyzer = Analyzer::new();
+        analyzer.set_fixed_language(Language::Mixed);
+        let term_ids = analyzer.analyze("我爱 Rust programming 语言");
+        assert_eq!(analyzer.get_language(), Language::Mixed);
+        assert_eq!(term_ids, vec![1, 2, 3, 4, 5]);
+        assert_eq!(analyzer.get_term_by_id(3), "rust");
+        assert_eq!(analyzer.get_term_by_id(5), "语言");
+        let (term_known, unknown_terms) = analyzer.parse("rust 语言");
+        assert_eq!(term_known, vec![3, 5]);
+        assert_eq!(unknown_terms, Vec::<String>::new());
+        // English runs are stemmed, Chinese ones kept
+        let mut analyzer = Analyzer::new();
+        analyzer.set_fixed_language(Language::Mixed);
+        analyzer.set_stemming(true);
+        analyzer.analyze("编程 languages");
+        assert_eq!(analyzer.get_term_by_id(1), "编程");
+        assert_eq!(analyzer.get_term_by_id(2), "languag");
+    }
+
+    #[test]
+    fn test_language_detection_fallback() {
+        let text = "ok 好的";
+        assert_eq!(Analyzer::script_share(text, Lang::Eng), 0.5);
+        assert_eq!(Analyzer::script_share(text, Lang::Cmn), 0.5);
+        assert_eq!(Analyzer::script_share("Do you quarrel, sir?", Lang::Eng), 1.0);
+
+        let mut analyzer = Analyzer::new();
+        analyzer.set_language_detection(0.6, Language::Chinese);
+        analyzer.detect_language(text);
+        assert_eq!(analyzer.get_language(), Language::Chinese);
+
+        let mut analyzer = Analyzer::new();
+        analyzer.set_language_detection(0.6, Language::English);
+        analyzer.detect_language(text);
+        assert_eq!(analyzer.get_language(), Language::English);
+
+        // confident detection is kept
+        let mut analyzer = Analyzer::new();
+        analyzer.set_language_detection(0.6, Language::English);
+        analyzer.detect_language("宴桃园豪杰三结义　斩黄巾英雄首立功");
+        assert_eq!(analyzer.get_language(), Language::Chinese);
+    }
+
     #[test]
     fn test_unicode_functions() {
         assert!(' '.is_whitespace());
@@ -140,4 +816,223 @@ mod tests {
         assert_eq!(lang, Some(Lang::Cmn));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_case_folding() {
+        let mut analyzer = Analyzer::new();
+        analyzer.set_case_folding(true);
+        let term_ids = analyzer.analyze("STRASSE");
+        let (known, unknown) = analyzer.parse("straße");
+        assert_eq!(known, term_ids);
+        assert!(unknown.is_empty());
+        assert_eq!(analyzer.token_spans("Straße 1"), vec![(0, 7), (8, 9)]);
+    }
+
+    #[test]
+    fn test_case_sensitive() {
+        let mut analyzer = Analyzer::new();
+        analyzer.set_case_sensitive(true);
+        let term_ids = analyzer.analyze("Sir, sir");
+        assert_ne!(term_ids[0], term_ids[1]);
+        assert_eq!(analyzer.get_term_by_id(term_ids[0]), "Sir");
+        assert_eq!(analyzer.parse("sir").0, vec![term_ids[1]]);
+        assert_eq!(analyzer.parse("SIR").1, vec!["SIR".to_string()]);
+    }
+
+    #[test]
+    fn test_filters() {
+        let mut analyzer = Analyzer::new();
+        analyzer.set_filters(vec![FilterSpec::MinLength(3), FilterSpec::Stopwords(vec!["you".to_string()])]);
+        let term_ids = analyzer.analyze("Do you quarrel, sir?");
+        // removed tokens keep their positions
+        assert_eq!(term_ids[..2], [STOPWORD_GAP, STOPWORD_GAP]);
+        assert_eq!(analyzer.get_term_by_id(term_ids[2]), "quarrel");
+        assert_eq!(analyzer.parse("you quarrel").0, vec![term_ids[2]]);
+        // the chain is built again from the saved specs
+        let bytes = bincode::serialize(&analyzer).unwrap();
+        let mut loaded: Analyzer = bincode::deserialize(&bytes).unwrap();
+        loaded.rebuild();
+        assert_eq!(loaded.parse("do you sir").0, vec![term_ids[3]]);
+    }
+
+    #[test]
+    fn test_stem_filter_with_stemming() {
+        let mut analyzer = Analyzer::new();
+        analyzer.set_stemming(true);
+        analyzer.set_protected_words(&["running"]);
+        analyzer.set_filters(vec![FilterSpec::Stem]);
+        // stemming "agre" again would give "agr"
+        let term_ids = analyzer.analyze("agreed running");
+        assert_eq!(analyzer.get_term_by_id(term_ids[0]), "agre");
+        assert_eq!(analyzer.get_term_by_id(term_ids[1]), "running");
+        assert_eq!(analyzer.parse("agreed running").0, term_ids);
+
+        // the filter alone stems as well, with the same checks
+        let mut analyzer = Analyzer::new();
+        analyzer.set_protected_words(&["running"]);
+        analyzer.set_filters(vec![FilterSpec::Stem]);
+        assert!(analyzer.is_stemming());
+        let term_ids = analyzer.analyze("agreed running");
+        assert_eq!(analyzer.get_term_by_id(term_ids[0]), "agre");
+        assert_eq!(analyzer.get_term_by_id(term_ids[1]), "running");
+    }
+
+    #[test]
+    fn test_min_token_len() {
+        let mut analyzer = Analyzer::new();
+        analyzer.set_min_token_len(2);
+        let term_ids = analyzer.analyze("I serve as good a man as you");
+        assert_eq!(term_ids.iter().filter(|&&id| id == STOPWORD_GAP).count(), 2);
+        let (known, unknown) = analyzer.parse("a man i");
+        assert_eq!(known.len(), 1);
+        assert!(unknown.is_empty());
+        assert_eq!(analyzer.stats().dict.term_count, 5);
+        // single Chinese characters are kept
+        let mut analyzer = Analyzer::new();
+        analyzer.set_min_token_len(2);
+        analyzer.set_fixed_language(Language::Chinese);
+        assert!(!analyzer.analyze("滚滚长江东逝水").contains(&STOPWORD_GAP));
+    }
+
+    #[test]
+    fn test_shingles() {
+        let mut analyzer = Analyzer::new();
+        analyzer.set_shingles(Some(Shingles { n: 2, unigrams: true }));
+        let term_ids = analyzer.analyze("Do you quarrel, sir?");
+        assert_eq!(term_ids.len(), 7);
+        assert_eq!(analyzer.get_term_by_id(term_ids[5]), "quarrel_sir");
+        // a phrase analyzes to the same sequence as in the text
+        assert_eq!(analyzer.parse("you quarrel").0, term_ids[2..5]);
+        let text = "Do you quarrel, sir?";
+        let spans = analyzer.token_spans(text);
+        assert_eq!(spans.len(), term_ids.len());
+        assert_eq!(&text[spans[5].0..spans[5].1], "quarrel, sir");
+
+        analyzer.set_shingles(Some(Shingles { n: 2, unigrams: false }));
+        let (known, _) = analyzer.parse("quarrel sir");
+        assert_eq!(known, vec![term_ids[5]]);
+        assert_eq!(analyzer.parse("quarrel").0, vec![term_ids[4]]);
+        analyzer.set_shingles(Some(Shingles { n: 1, unigrams: false }));
+        assert_eq!(analyzer.parse("quarrel sir").0, vec![term_ids[4], term_ids[6]]);
+    }
+
+    #[test]
+    fn test_lemmatization() {
+        let mut analyzer = Analyzer::new();
+        analyzer.set_stemming(true);
+        analyzer.set_lemmatizer(Some(Lemmatizer::new()));
+        let term_ids = analyzer.analyze("He ran, he is running; no better.");
+        assert_eq!(analyzer.get_term_by_id(term_ids[1]), "run");
+        assert_eq!(term_ids[1], term_ids[4]);
+        assert_eq!(analyzer.get_term_by_id(term_ids[3]), "be");
+        assert_eq!(analyzer.get_term_by_id(term_ids[6]), "good");
+        let (term_known, unknown_terms) = analyzer.parse("runs good");
+        assert_eq!(term_known, vec![term_ids[1], term_ids[6]]);
+        assert!(unknown_terms.is_empty());
+        // the stemmer would not unify these
+        assert_ne!(STEMMER.stem("ran"), STEMMER.stem("running"));
+    }
+
+    #[test]
+    fn test_two_phase_analysis() {
+        use rayon::prelude::*;
+        let texts = [
+            "Do you quarrel, sir?",
+            "Quarrel sir! no, sir!",
+            "If you do, sir, I am for you: I serve as good a man as you.",
+            "No better.",
+            "Well, sir.",
+        ];
+        let mut sequential = Analyzer::new();
+        sequential.set_stemming(true);
+        let sequential_ids: Vec<Vec<TermId>> = texts.iter().map(|text| sequential.analyze(text)).collect();
+
+        let mut two_phase = Analyzer::new();
+        two_phase.set_stemming(true);
+        two_phase.settle_language(texts[0]);
+        let tokens: Vec<(Vec<String>, Language)> = texts.par_iter()
+            .map(|text| two_phase.tokenize(text))
+            .collect();
+        let two_phase_ids: Vec<Vec<TermId>> = tokens.iter()
+            .map(|(tokens, _)| two_phase.merge_tokens(tokens))
+            .collect();
+        assert_eq!(two_phase_ids, sequential_ids);
+        assert_eq!(two_phase.dict, sequential.dict);
+    }
+
+    #[test]
+    fn test_chunked_analysis() {
+        use crate::ircore::index::pl::{PositionList, SchemaDependIndex};
+        let lines = [
+            "Do you quarrel, sir?",
+            "Quarrel sir! no, sir!",
+            "If you do, sir, I am for you: I serve as good a man as you.",
+            "滚滚长江东逝水，浪花淘尽英雄。",
+        ];
+        let text: String = (0..500).map(|i| format!("{} {}\n", lines[i % lines.len()], i)).collect();
+        let mut whole = Analyzer::new();
+        whole.set_per_document_language(true);
+        let (whole_ids, whole_lang) = whole.analyze_with_language(&text);
+
+        for chunk_bytes in [7, 64, 1000] {
+            let mut chunked = Analyzer::new();
+            chunked.set_per_document_language(true);
+            chunked.set_chunk_bytes(Some(chunk_bytes));
+            let (chunked_ids, chunked_lang) = chunked.analyze_with_language(&text);
+            assert_eq!(chunked_lang, whole_lang);
+            assert_eq!(chunked_ids, whole_ids);
+            assert_eq!(chunked.dict, whole.dict);
+        }
+
+        // shingles spanning a chunk end, and texts of fewer than n words
+        for shingles in [Shingles { n: 2, unigrams: false }, Shingles { n: 2, unigrams: true },
+                         Shingles { n: 3, unigrams: false }] {
+            for text in [text.as_str(), "quarrel sir", "sir"] {
+                let mut whole = Analyzer::new();
+                whole.set_shingles(Some(shingles));
+                let whole_ids = whole.analyze(text);
+                for chunk_bytes in [7, 64, 1000] {
+                    let mut chunked = Analyzer::new();
+                    chunked.set_shingles(Some(shingles));
+                    let (chunked_ids, _) = chunked.analyze_chunked(text, chunk_bytes);
+                    assert_eq!(chunked_ids, whole_ids);
+                    assert_eq!(chunked.dict, whole.dict);
+                }
+            }
+        }
+
+        // positions follow from the id sequence
+        let mut chunked = Analyzer::new();
+        let (chunked_ids, _) = chunked.analyze_chunked(&text, 64);
+        let mut whole_index = PositionList::new();
+        let mut chunked_index = PositionList::new();
+        let doc = whole_index.add_document(&whole_ids);
+        chunked_index.add_document(&chunked_ids);
+        let quarrel = chunked.parse("quarrel").0[0];
+        assert_eq!(chunked_index.get_positions(quarrel, doc), whole_index.get_positions(quarrel, doc));
+    }
+
+    #[test]
+    fn test_chunks() {
+        assert_eq!(Analyzer::chunks("ab cd\nef gh", 7), vec!["ab cd\n", "ef gh"]);
+        assert_eq!(Analyzer::chunks("ab cd ef", 4), vec!["ab ", "cd ", "ef"]);
+        // no whitespace to cut at
+        assert_eq!(Analyzer::chunks("abcdef", 4), vec!["abcd", "ef"]);
+        assert_eq!(Analyzer::chunks("长江", 2), vec!["长", "江"]);
+        assert_eq!(Analyzer::chunks("", 2), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn test_stopwords() {
+        let mut analyzer = Analyzer::new();
+        analyzer.set_stopwords(ENGLISH_STOPWORDS);
+        // removed stopwords keep their position
+        let term_ids = analyzer.analyze("Quarrel with the sir");
+        assert_eq!(term_ids, vec![1, STOPWORD_GAP, STOPWORD_GAP, 2]);
+        assert_eq!(analyzer.get_dictionary().get_term_count(), 2);
+        let (term_ids, unknown_terms) = analyzer.parse("the quarrel WITH sir");
+        assert_eq!(term_ids, vec![1, 2]);
+        assert!(unknown_terms.is_empty());
+        let (term_ids, unknown_terms) = analyzer.parse("the");
+        assert!(term_ids.is_empty() && unknown_terms.is_empty());
+    }
+}
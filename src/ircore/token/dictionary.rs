@@ -1,10 +1,13 @@
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 use crate::ircore::TermId;
+use crate::ircore::utils::serialize;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct Dictionary {
+    #[serde(serialize_with = "serialize::sorted_map")]
     term_ids: HashMap<String, TermId>,
+    #[serde(serialize_with = "serialize::sorted_map")]
     terms: HashMap<TermId, String>,
     next_id: TermId,
 }
@@ -64,6 +67,30 @@ impl Dictionary {
         term_ids
     }
 
+    // ids of all terms starting with prefix, in id order
+    pub fn prefix_ids(&self, prefix: &str) -> Vec<TermId> {
+        let mut term_ids: Vec<TermId> = self.term_ids.iter()
+            .filter(|(term, _)| term.starts_with(prefix))
+            .map(|(_, id)| *id)
+            .collect();
+        term_ids.sort_unstable();
+        term_ids
+    }
+
+    // ids of all terms within max_distance edits of term, in id order,
+    // terms differing in length by more than max_distance are not compared
+    pub fn fuzzy_ids(&self, term: &str, max_distance: u8) -> Vec<TermId> {
+        let chars: Vec<char> = term.chars().collect();
+        let max_distance = max_distance as usize;
+        let mut term_ids: Vec<TermId> = self.term_ids.iter()
+            .filter(|(other, _)| other.chars().count().abs_diff(chars.len()) <= max_distance)
+            .filter(|(other, _)| edit_distance(&chars, other) <= max_distance)
+            .map(|(_, id)| *id)
+            .collect();
+        term_ids.sort_unstable();
+        term_ids
+    }
+
     pub fn get_term_by_id(&self, tid: TermId) -> String {
         if let Some(term_str) = self.terms.get(&tid) {
             return term_str.clone();
@@ -72,6 +99,22 @@ impl Dictionary {
         }
     }
 
+    // the id to term map is derived from term_ids, it can be left out
+    // of a saved dictionary and rebuilt on load
+    pub fn take_terms(&mut self) -> HashMap<TermId, String> {
+        std::mem::take(&mut self.terms)
+    }
+
+    pub fn restore_terms(&mut self, terms: HashMap<TermId, String>) {
+        self.terms = terms;
+    }
+
+    pub fn rebuild(&mut self) {
+        if self.terms.is_empty() {
+            self.terms = self.term_ids.iter().map(|(term, id)| (*id, term.clone())).collect();
+        }
+    }
+
     pub fn get_term_count(&self) -> usize {
         self.term_ids.len()
     }
@@ -83,6 +126,28 @@ impl Dictionary {
     }
 }
 
+// Levenshtein distance in chars, swapping two adjacent chars counts as one edit
+fn edit_distance(a: &[char], b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // rows for b[..j-2], b[..j-1] and b[..j]
+    let mut prev2: Vec<usize> = vec![0; a.len() + 1];
+    let mut prev: Vec<usize> = (0..=a.len()).collect();
+    let mut cur = vec![0; a.len() + 1];
+    for j in 1..=b.len() {
+        cur[0] = j;
+        for i in 1..=a.len() {
+            let substitution = prev[i - 1] + (a[i - 1] != b[j - 1]) as usize;
+            cur[i] = substitution.min(prev[i] + 1).min(cur[i - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                cur[i] = cur[i].min(prev2[i - 2] + 1);
+            }
+        }
+        std::mem::swap(&mut prev2, &mut prev);
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    prev[a.len()]
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -123,4 +188,30 @@ mod tests {
         assert_eq!(dict.get_term_count(), 5);
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_prefix_ids() {
+        let mut dict = Dictionary::new();
+        dict.generate_ids(&vec!["quarrel", "sir", "quarrels", "quart", "squarrel"]);
+        assert_eq!(dict.prefix_ids("quar"), vec![1, 3, 4]);
+        assert_eq!(dict.prefix_ids("quarrel"), vec![1, 3]);
+        assert_eq!(dict.prefix_ids("x"), Vec::<TermId>::new());
+        assert_eq!(dict.prefix_ids("").len(), 5);
+    }
+
+    #[test]
+    fn test_fuzzy_ids() {
+        let chars = |s: &str| s.chars().collect::<Vec<char>>();
+        assert_eq!(edit_distance(&chars("quarrle"), "quarrel"), 1);
+        assert_eq!(edit_distance(&chars("quarlre"), "quarrel"), 2);
+        assert_eq!(edit_distance(&chars("quarel"), "quarrel"), 1);
+        assert_eq!(edit_distance(&chars(""), "sir"), 3);
+        assert_eq!(edit_distance(&chars("长江"), "长河"), 1);
+        let mut dict = Dictionary::new();
+        dict.generate_ids(&vec!["quarrel", "sir", "quarrels", "squarrel", "stir"]);
+        assert_eq!(dict.fuzzy_ids("quarel", 1), vec![1]);
+        assert_eq!(dict.fuzzy_ids("quarel", 2), vec![1, 3, 4]);
+        assert_eq!(dict.fuzzy_ids("sir", 0), vec![2]);
+        assert_eq!(dict.fuzzy_ids("stir", 1), vec![2, 5]);
+    }
+}
@@ -0,0 +1,219 @@
+use serde::{Serialize, Deserialize};
+use rust_stemmers::{Algorithm, Stemmer};
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub static STEMMER: Lazy<Stemmer> = Lazy::new(|| Stemmer::create(Algorithm::English));
+
+// a step of token processing, applied the same way at index and query time,
+// a removed token is left empty so it keeps its position like a stopword
+pub trait TokenFilter: fmt::Debug + Send + Sync {
+    fn filter(&self, tokens: Vec<String>) -> Vec<String>;
+}
+
+#[derive(Debug)]
+pub struct LowercaseFilter;
+
+impl TokenFilter for LowercaseFilter {
+    fn filter(&self, tokens: Vec<String>) -> Vec<String> {
+        tokens.into_iter().map(|token| token.to_lowercase()).collect()
+    }
+}
+
+// matches words exactly, case included
+#[derive(Debug)]
+pub struct StopwordFilter {
+    words: HashSet<String>,
+}
+
+impl StopwordFilter {
+    pub fn new(words: &[String]) -> Self {
+        StopwordFilter { words: words.iter().cloned().collect() }
+    }
+}
+
+impl TokenFilter for StopwordFilter {
+    fn filter(&self, tokens: Vec<String>) -> Vec<String> {
+        tokens.into_iter()
+            .map(|token| if self.words.contains(&token) { String::new() } else { token })
+            .collect()
+    }
+}
+
+// removes tokens of fewer than min_chars chars
+#[derive(Debug)]
+pub struct MinLengthFilter {
+    min_chars: usize,
+}
+
+impl MinLengthFilter {
+    pub fn new(min_chars: usize) -> Self {
+        MinLengthFilter { min_chars }
+    }
+}
+
+impl TokenFilter for MinLengthFilter {
+    fn filter(&self, tokens: Vec<String>) -> Vec<String> {
+        tokens.into_iter()
+            .map(|token| if token.chars().count() < self.min_chars { String::new() } else { token })
+            .collect()
+    }
+}
+
+// English Snowball stemmer
+#[derive(Debug)]
+pub struct StemFilter;
+
+impl TokenFilter for StemFilter {
+    fn filter(&self, tokens: Vec<String>) -> Vec<String> {
+        tokens.into_iter().map(|token| STEMMER.stem(&token).into_owned()).collect()
+    }
+}
+
+// serializable description of a built-in filter, trait objects are not saved
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum FilterSpec {
+    Lowercase,
+    Stopwords(Vec<String>),
+    MinLength(usize),
+    // in an Analyzer this turns on its stemming instead, see Analyzer::set_filters
+    Stem,
+}
+
+impl FilterSpec {
+    pub fn build(&self) -> Box<dyn TokenFilter> {
+        match self {
+            FilterSpec::Lowercase => Box::new(LowercaseFilter),
+            FilterSpec::Stopwords(words) => Box::new(StopwordFilter::new(words)),
+            FilterSpec::MinLength(min_chars) => Box::new(MinLengthFilter::new(*min_chars)),
+            FilterSpec::Stem => Box::new(StemFilter),
+        }
+    }
+}
+
+// built-in English lemmas, irregular forms mostly, (form, lemma)
+const LEMMAS: &[(&str, &str)] = &[
+    ("am", "be"), ("are", "be"), ("is", "be"), ("was", "be"), ("were", "be"), ("been", "be"), ("being", "be"),
+    ("has", "have"), ("had", "have"), ("having", "have"),
+    ("does", "do"), ("did", "do"), ("done", "do"), ("doing", "do"),
+    ("goes", "go"), ("went", "go"), ("gone", "go"), ("going", "go"),
+    ("runs", "run"), ("ran", "run"), ("running", "run"),
+    ("better", "good"), ("best", "good"), ("worse", "bad"), ("worst", "bad"),
+    ("came", "come"), ("comes", "come"), ("coming", "come"),
+    ("made", "make"), ("makes", "make"), ("making", "make"),
+    ("said", "say"), ("says", "say"), ("saying", "say"),
+    ("saw", "see"), ("seen", "see"), ("sees", "see"), ("seeing", "see"),
+    ("took", "take"), ("taken", "take"), ("takes", "take"), ("taking", "take"),
+    ("gave", "give"), ("given", "give"), ("gives", "give"), ("giving", "give"),
+    ("knew", "know"), ("known", "know"), ("knows", "know"), ("knowing", "know"),
+    ("thought", "think"), ("thinks", "think"), ("thinking", "think"),
+    ("wrote", "write"), ("written", "write"), ("writes", "write"), ("writing", "write"),
+    ("spoke", "speak"), ("spoken", "speak"), ("speaks", "speak"), ("speaking", "speak"),
+    ("ate", "eat"), ("eaten", "eat"), ("eats", "eat"), ("eating", "eat"),
+    ("brought", "bring"), ("bought", "buy"), ("found", "find"), ("told", "tell"),
+    ("men", "man"), ("women", "woman"), ("children", "child"), ("feet", "foot"),
+    ("teeth", "tooth"), ("mice", "mouse"), ("geese", "goose"), ("people", "person"),
+];
+
+// dictionary based lemmatization, tokens without a lemma are kept as they are
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Lemmatizer {
+    lemmas: HashMap<String, String>,
+}
+
+impl Lemmatizer {
+    pub fn new() -> Self {
+        Lemmatizer {
+            lemmas: LEMMAS.iter()
+                .map(|(form, lemma)| (form.to_string(), lemma.to_string()))
+                .collect(),
+        }
+    }
+
+    // built-in lemmas plus those in file, one "form lemma" pair per line,
+    // lines starting with # are comments
+    pub fn load_from(path: &Path) -> io::Result<Self> {
+        let mut lemmatizer = Self::new();
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [form, lemma] => {
+                    lemmatizer.lemmas.insert(form.to_lowercase(), lemma.to_lowercase());
+                },
+                _ => log::warn!("invalid lemma line in {}: {}", path.to_string_lossy(), line),
+            }
+        }
+        Ok(lemmatizer)
+    }
+
+    pub fn lemma(&self, token: &str) -> Option<&str> {
+        self.lemmas.get(token).map(|lemma| lemma.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.lemmas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lemmas.is_empty()
+    }
+}
+
+impl Default for Lemmatizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TokenFilter for Lemmatizer {
+    fn filter(&self, tokens: Vec<String>) -> Vec<String> {
+        tokens.into_iter()
+            .map(|token| match self.lemma(&token) {
+                Some(lemma) => lemma.to_string(),
+                None => token,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lemmatizer() {
+        let lemmatizer = Lemmatizer::new();
+        let tokens = ["ran", "running", "run", "better", "quarrel"];
+        let lemmas = lemmatizer.filter(tokens.iter().map(|t| t.to_string()).collect());
+        assert_eq!(lemmas, vec!["run", "run", "run", "good", "quarrel"]);
+
+        let path = Path::new(".rir/lemma/lemmas.txt");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "# extra lemmas\nquarrelled quarrel\nQuarrels quarrel\nbroken line here\n").unwrap();
+        let lemmatizer = Lemmatizer::load_from(path).unwrap();
+        assert_eq!(lemmatizer.len(), LEMMAS.len() + 2);
+        assert_eq!(lemmatizer.lemma("quarrels"), Some("quarrel"));
+        assert_eq!(lemmatizer.lemma("ran"), Some("run"));
+    }
+
+    #[test]
+    fn test_filter_chain() {
+        let chain: Vec<Box<dyn TokenFilter>> = [
+            FilterSpec::Lowercase,
+            FilterSpec::MinLength(3),
+            FilterSpec::Stopwords(vec!["you".to_string()]),
+            FilterSpec::Stem,
+        ].iter().map(FilterSpec::build).collect();
+        let tokens = ["Do", "YOU", "quarrelling", "sir", "a", "über"];
+        let tokens = chain.iter().fold(tokens.iter().map(|t| t.to_string()).collect(),
+            |tokens, filter| filter.filter(tokens));
+        assert_eq!(tokens, vec!["", "", "quarrel", "sir", "", "über"]);
+    }
+}
@@ -1,3 +1,4 @@
 pub mod analyzer;
 pub mod dictionary;
-pub mod sgmt;
\ No newline at end of file
+pub mod sgmt;
+pub mod filter;
\ No newline at end of file
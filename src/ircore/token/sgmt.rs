@@ -2,24 +2,124 @@ use unicode_segmentation::UnicodeSegmentation;
 use serde::{Serialize, Deserialize};
 use jieba_rs::Jieba;
 use once_cell::sync::Lazy;
+use unicode_normalization::{UnicodeNormalization, IsNormalized, is_nfc_quick, is_nfkc_quick};
+use std::borrow::Cow;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum Language {
     English,
     Chinese,
+    // Chinese text with inline English (or other Latin script) words
+    Mixed,
+}
+
+// Unicode normalization form applied before case handling and segmentation
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum UnicodeForm {
+    // canonical composition, e + combining acute is é
+    #[default]
+    Nfc,
+    // compatibility composition, also full-width ａ is a and ① is 1
+    Nfkc,
+}
+
+pub const SHINGLE_SEPARATOR: &str = "_";
+
+// Word n-grams of n words joined by '_', "quarrel sir" => "quarrel_sir" for n = 2.
+// Texts of fewer than n words keep their words, so short queries still match.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Shingles {
+    pub n: usize,
+    // keep the words too, each shingle right before the word it ends with,
+    // with n = 2 a phrase then analyzes to the same sequence as in the documents
+    // containing it, larger n break phrase queries
+    #[serde(default)]
+    pub unigrams: bool,
+}
+
+impl Shingles {
+    // (start, end) token range of each output token, in order
+    pub fn windows(&self, len: usize) -> Vec<(usize, usize)> {
+        if self.unigrams {
+            let mut windows = vec![];
+            for i in 0..len {
+                if i + 1 >= self.n {
+                    windows.push((i + 1 - self.n, i + 1));
+                }
+                windows.push((i, i + 1));
+            }
+            return windows;
+        }
+        if len < self.n {
+            return (0..len).map(|i| (i, i + 1)).collect();
+        }
+        (0..=len - self.n).map(|i| (i, i + self.n)).collect()
+    }
+
+    // tokens and shingles, a shingle over an empty (removed) token is empty
+    pub fn apply(&self, tokens: &[String]) -> Vec<String> {
+        Self::join(tokens, self.windows(tokens.len()))
+    }
+
+    // Output for tokens that follow carry, the last n - 1 tokens before them, so
+    // shingles spanning the two are kept. Fewer than n words in all are not kept
+    // as bare words here, the caller falls back to them once the text is done.
+    pub fn apply_after(&self, carry: &[String], tokens: &[String]) -> Vec<String> {
+        let all: Vec<String> = carry.iter().chain(tokens).cloned().collect();
+        let windows = self.windows(all.len()).into_iter()
+            .filter(|&(start, end)| end > carry.len() && (self.unigrams || end - start == self.n))
+            .collect();
+        Self::join(&all, windows)
+    }
+
+    // the last n - 1 tokens of carry followed by tokens, see apply_after
+    pub fn carry(&self, carry: &[String], tokens: &[String]) -> Vec<String> {
+        let all: Vec<&String> = carry.iter().chain(tokens).collect();
+        let keep = self.n.saturating_sub(1).min(all.len());
+        all[all.len() - keep..].iter().map(|token| token.to_string()).collect()
+    }
+
+    fn join(tokens: &[String], windows: Vec<(usize, usize)>) -> Vec<String> {
+        windows.into_iter()
+            .map(|(start, end)| match tokens[start..end].iter().any(String::is_empty) {
+                true => String::new(),
+                false => tokens[start..end].join(SHINGLE_SEPARATOR),
+            })
+            .collect()
+    }
 }
 
 static JIEBA: Lazy<Jieba> = Lazy::new(Jieba::new);
 
+// CJK unified ideographs, including extension A
+pub fn is_cjk(c: char) -> bool {
+    ('\u{4E00}'..='\u{9FFF}').contains(&c) || ('\u{3400}'..='\u{4DBF}').contains(&c)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Segmentator {
     lang: Language,
+    // full Unicode case folding (ß -> ss) instead of lowercasing
+    case_folding: bool,
+    // keep the case of words, "Apple" and "apple" are different terms
+    case_sensitive: bool,
+    unicode_form: UnicodeForm,
+    // words added to the Chinese segmenter, with their frequency if given
+    user_words: Vec<(String, Option<usize>)>,
+    // default segmenter with user_words, None to use JIEBA
+    #[serde(skip)]
+    user_jieba: Option<Jieba>,
 }
 
 impl Segmentator {
     pub fn new() -> Self {
         Segmentator{
             lang: Language::English,
+            case_folding: false,
+            case_sensitive: false,
+            unicode_form: UnicodeForm::default(),
+            user_words: vec![],
+            user_jieba: None,
         }
     }
 
@@ -27,44 +127,156 @@ impl Segmentator {
         self.lang = lang;
     }
 
+    pub fn set_case_folding(&mut self, case_folding: bool){
+        self.case_folding = case_folding;
+    }
+
+    pub fn set_case_sensitive(&mut self, case_sensitive: bool){
+        self.case_sensitive = case_sensitive;
+    }
+
+    pub fn set_unicode_form(&mut self, unicode_form: UnicodeForm){
+        self.unicode_form = unicode_form;
+    }
+
+    // keep word whole when cutting Chinese text, freq None lets jieba
+    // pick one high enough for that
+    pub fn add_user_word(&mut self, word: &str, freq: Option<usize>) {
+        match self.user_words.iter_mut().find(|(w, _)| w == word) {
+            Some(entry) => entry.1 = freq,
+            None => self.user_words.push((word.to_string(), freq)),
+        }
+        self.user_jieba.get_or_insert_with(|| JIEBA.clone()).add_word(word, freq, None);
+    }
+
+    // the segmenter is not saved, add user_words again after loading
+    pub fn rebuild(&mut self) {
+        self.user_jieba = None;
+        if self.user_words.is_empty() {
+            return;
+        }
+        let mut jieba = JIEBA.clone();
+        for (word, freq) in &self.user_words {
+            jieba.add_word(word, *freq, None);
+        }
+        self.user_jieba = Some(jieba);
+    }
+
     pub fn get_language(&self) -> Language {
         return self.lang;
     }
     pub fn parse_tokens<'a>(&self, text: &'a str) -> Vec<&'a str>{
-        match self.lang {
-            Language::English => return text.unicode_words().collect(),
-            Language::Chinese => {
-                let raw_word_list = JIEBA.cut(text, false);
+        self.parse_tokens_as(text, self.lang)
+    }
+
+    // segment text as lang, regardless of the language set
+    pub fn parse_tokens_as<'a>(&self, text: &'a str, lang: Language) -> Vec<&'a str>{
+        match lang {
+            Language::English => text.unicode_words().collect(),
+            Language::Chinese => self.cut_chinese(text),
+            Language::Mixed => {
                 let mut words = vec![];
-                for raw_word in raw_word_list {
-                    let mut chars = raw_word.chars();
-                    match chars.next() {
-                        Some(c) => {
-                            if c.is_alphabetic() {
-                                words.push(raw_word);
-                            }
-                        }
-                        _ => (),
+                for (cjk, run) in Self::script_runs(text) {
+                    if cjk {
+                        words.extend(self.cut_chinese(run));
+                    }else{
+                        words.extend(run.unicode_words());
+                    }
+                }
+                words
+            }
+        }
+    }
+
+    // words containing a letter or a digit, pure punctuation is dropped
+    fn cut_chinese<'a>(&self, text: &'a str) -> Vec<&'a str>{
+        let jieba = self.user_jieba.as_ref().unwrap_or(&JIEBA);
+        let base = text.as_ptr() as usize;
+        let mut spans: Vec<(usize, usize)> = vec![];
+        for raw_word in jieba.cut(text, false) {
+            let start = raw_word.as_ptr() as usize - base;
+            let end = start + raw_word.len();
+            // jieba splits decimals, join "3" "." "14" back into "3.14"
+            if let [.., (num_start, num_end), (dot_start, dot_end)] = spans[..] {
+                if &text[dot_start..dot_end] == "." && num_end == dot_start && dot_end == start
+                    && text[num_start..num_end].ends_with(|c: char| c.is_ascii_digit())
+                    && raw_word.starts_with(|c: char| c.is_ascii_digit()) {
+                    spans.pop();
+                    if let Some(span) = spans.last_mut() {
+                        span.1 = end;
                     }
-                    
+                    continue;
                 }
-                return words;
             }
+            spans.push((start, end));
         }
-        
+        spans.into_iter()
+            .map(|(start, end)| &text[start..end])
+            .filter(|word| word.chars().any(char::is_alphanumeric))
+            .collect()
+    }
+
+    // split text into CJK and non-CJK runs, only letters switch runs,
+    // punctuation and spaces stay in the current run
+    fn script_runs(text: &str) -> Vec<(bool, &str)> {
+        let mut runs = vec![];
+        let mut start = 0;
+        let mut current: Option<bool> = None;
+        for (i, c) in text.char_indices() {
+            if !c.is_alphabetic() {
+                continue;
+            }
+            let cjk = is_cjk(c);
+            match current {
+                Some(run_cjk) if run_cjk != cjk => {
+                    runs.push((run_cjk, &text[start..i]));
+                    start = i;
+                    current = Some(cjk);
+                },
+                None => current = Some(cjk),
+                _ => (),
+            }
+        }
+        if let Some(run_cjk) = current {
+            runs.push((run_cjk, &text[start..]));
+        }
+        runs
     }
     
     pub fn normalize(&self, text: &str) -> String {
+        let text = self.normalize_unicode(text);
+        let text = text.as_ref();
+        if self.case_sensitive {
+            return text.to_string();
+        }
+        if self.case_folding {
+            return caseless::default_case_fold_str(text);
+        }
         match self.lang {
             Language::English => {
                 return text.to_lowercase();
             },
-            Language::Chinese => {
+            Language::Chinese | Language::Mixed => {
                 return text.to_lowercase(); // do nothing at this moment, 
             },
         }
     }    
 
+    // text in unicode_form, borrowed when already in it
+    fn normalize_unicode<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let quick_check = match self.unicode_form {
+            UnicodeForm::Nfc => is_nfc_quick(text.chars()),
+            UnicodeForm::Nfkc => is_nfkc_quick(text.chars()),
+        };
+        if quick_check == IsNormalized::Yes {
+            return Cow::Borrowed(text);
+        }
+        match self.unicode_form {
+            UnicodeForm::Nfc => Cow::Owned(text.nfc().collect()),
+            UnicodeForm::Nfkc => Cow::Owned(text.nfkc().collect()),
+        }
+    }
+
 }
 
 
@@ -92,6 +304,16 @@ mod tests {
         assert_eq!(tokens, vec!["滚滚", "长江", "东", "逝水", "浪花", "淘", "尽", "英雄"]);
     }
 
+    #[test]
+    fn test_parse_mixed() {
+        let mut seg = Segmentator::new();
+        seg.set_language(Language::Mixed);
+        assert_eq!(Segmentator::script_runs("我爱 Rust programming 语言"),
+            vec![(true, "我爱 "), (false, "Rust programming "), (true, "语言")]);
+        let tokens = seg.parse_tokens("我爱 rust programming 语言, don't panic");
+        assert_eq!(tokens, vec!["我", "爱", "rust", "programming", "语言", "don't", "panic"]);
+    }
+
     #[test]
     fn test_jieba() {
         use jieba_rs::Token;
@@ -112,4 +334,77 @@ mod tests {
             Token { word: "。", start: 14, end: 15 }]
         );
     }
+
+    #[test]
+    fn test_case_folding() {
+        let mut seg = Segmentator::new();
+        assert_ne!(seg.normalize("STRASSE"), seg.normalize("straße"));
+        seg.set_case_folding(true);
+        assert_eq!(seg.normalize("STRASSE"), "strasse");
+        assert_eq!(seg.normalize("straße"), "strasse");
+        assert_eq!(seg.normalize("ΣΊΣΥΦΟΣ"), seg.normalize("σίσυφος"));
+    }
+
+    #[test]
+    fn test_parse_chinese_alphanumeric() {
+        let mut seg = Segmentator::new();
+        seg.set_language(Language::Chinese);
+        let tokens = seg.parse_tokens("新冠病毒COVID19，圆周率约3.14。");
+        assert!(tokens.contains(&"COVID19"));
+        assert!(tokens.contains(&"3.14"));
+        assert!(!tokens.contains(&"，"));
+        assert!(tokens.iter().all(|token| token.chars().any(char::is_alphanumeric)));
+        // a full stop between numbers does not join them across a space
+        assert_eq!(seg.parse_tokens("第3. 14"), vec!["第", "3", "14"]);
+    }
+
+    #[test]
+    fn test_case_sensitive() {
+        let mut seg = Segmentator::new();
+        seg.set_case_sensitive(true);
+        assert_eq!(seg.parse_tokens(&seg.normalize("Sir, sir")), vec!["Sir", "sir"]);
+        // no folding either
+        seg.set_case_folding(true);
+        assert_eq!(seg.normalize("STRASSE"), "STRASSE");
+    }
+
+    #[test]
+    fn test_unicode_form() {
+        let mut seg = Segmentator::new();
+        // e and a combining acute compose into é
+        assert_eq!(seg.normalize("Cafe\u{301}"), "caf\u{e9}");
+        assert_eq!(seg.normalize("ｆｕｌｌ１２３"), "ｆｕｌｌ１２３");
+        seg.set_unicode_form(UnicodeForm::Nfkc);
+        assert_eq!(seg.parse_tokens(&seg.normalize("ｆｕｌｌ １２３")), vec!["full", "123"]);
+        assert_eq!(seg.normalize("１２３"), seg.normalize("123"));
+    }
+
+    #[test]
+    fn test_user_words() {
+        let mut seg = Segmentator::new();
+        seg.set_language(Language::Chinese);
+        assert_eq!(seg.parse_tokens("滚滚长江东逝水"), vec!["滚滚", "长江", "东", "逝水"]);
+        seg.add_user_word("东逝水", None);
+        assert_eq!(seg.parse_tokens("滚滚长江东逝水"), vec!["滚滚", "长江", "东逝水"]);
+        // the default segmenter is left as it was
+        assert_eq!(Segmentator::new().parse_tokens_as("滚滚长江东逝水", Language::Chinese).len(), 4);
+        // words are added again to a segmenter loaded without them
+        let bytes = bincode::serialize(&seg).unwrap();
+        let mut loaded: Segmentator = bincode::deserialize(&bytes).unwrap();
+        assert!(loaded.user_jieba.is_none());
+        loaded.rebuild();
+        assert_eq!(loaded.parse_tokens("滚滚长江东逝水"), vec!["滚滚", "长江", "东逝水"]);
+    }
+
+    #[test]
+    fn test_shingles() {
+        let tokens: Vec<String> = ["do", "you", "", "quarrel"].iter().map(|t| t.to_string()).collect();
+        let shingles = Shingles { n: 2, unigrams: false };
+        // shingles over the removed token are removed too
+        assert_eq!(shingles.apply(&tokens), vec!["do_you", "", ""]);
+        assert_eq!(shingles.apply(&tokens[..1]), vec!["do"]);
+        let shingles = Shingles { n: 2, unigrams: true };
+        assert_eq!(shingles.apply(&tokens[..2]), vec!["do", "do_you", "you"]);
+        assert_eq!(Shingles { n: 3, unigrams: false }.windows(4), vec![(0, 3), (1, 4)]);
+    }
 }
@@ -1,34 +1,302 @@
 use std::path::Path;
 use std::fs::{self, File};
-use std::io::{self, Write, Read};
-use serde::{Serialize, Deserialize};
+use std::io::{self, Write, Read, Seek, SeekFrom};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use bincode::Options;
 use flate2::write::GzEncoder;
 use flate2::read::GzDecoder;
 use flate2::Compression;
+use memmap2::Mmap;
+
+// every file starts with MAGIC and SCHEMA_VERSION as u16 little endian,
+// then RAW_MAGIC and raw bincode, or a gzip stream (0x1f 0x8b) of bincode
+const MAGIC: &[u8; 4] = b"RIR1";
+const RAW_MAGIC: &[u8; 4] = b"RIRB";
+const HEADER_LEN: usize = MAGIC.len() + 2;
+// bump on any incompatible change of a serialized struct,
+// files of another version are rejected instead of misread
+pub const SCHEMA_VERSION: u16 = 8;
+
+// file format of write_file_with, read_file detects either
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Gzip(CompressionLevel),
+    // uncompressed bincode, faster to load, larger on disk
+    Raw,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding::Gzip(CompressionLevel::Default)
+    }
+}
+
+// gzip level, Fast saves sooner, Best saves smaller files, loading is alike
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CompressionLevel {
+    Fast,
+    #[default]
+    Default,
+    Best,
+}
+
+impl From<CompressionLevel> for Compression {
+    fn from(level: CompressionLevel) -> Self {
+        match level {
+            CompressionLevel::Fast => Compression::fast(),
+            CompressionLevel::Default => Compression::default(),
+            CompressionLevel::Best => Compression::best(),
+        }
+    }
+}
 
 pub fn write_file<T: Serialize>(filepath: &Path, obj: &T) -> io::Result<()>{
+    write_file_with(filepath, obj, Encoding::default())
+}
+
+pub fn write_file_with<T: Serialize>(filepath: &Path, obj: &T, encoding: Encoding) -> io::Result<()>{
     if let Some(dir) = filepath.parent() {
         fs::create_dir_all(dir)?;
     }
     let bincode_options = bincode::DefaultOptions::new().with_varint_encoding().allow_trailing_bytes();
     let encoded: Vec<u8> = bincode_options.serialize(obj).unwrap();
-    let f = File::create(filepath)?;
-    let mut writer = GzEncoder::new(f, Compression::default());
-    writer.write_all(&encoded)?;
+    let mut f = File::create(filepath)?;
+    f.write_all(MAGIC)?;
+    f.write_all(&SCHEMA_VERSION.to_le_bytes())?;
+    match encoding {
+        Encoding::Gzip(level) => {
+            let mut writer = GzEncoder::new(f, level.into());
+            writer.write_all(&encoded)?;
+        },
+        Encoding::Raw => {
+            f.write_all(RAW_MAGIC)?;
+            f.write_all(&encoded)?;
+        },
+    }
+    Ok(())
+}
+
+// HashMap and HashSet iterate in a random order, serialize them sorted by key
+// so the same index is saved to the same bytes
+pub fn sorted_map<K: Ord + Serialize, V: Serialize, S: Serializer>(map: &HashMap<K, V>, serializer: S)
+    -> Result<S::Ok, S::Error> {
+    serializer.collect_map(sorted(map))
+}
+
+pub fn sorted_set<T: Ord + Serialize, S: Serializer>(set: &HashSet<T>, serializer: S)
+    -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(set.iter().collect::<BTreeSet<_>>())
+}
+
+// increasing offsets saved as gaps from the previous one, small numbers that
+// varint encoding stores in a byte or two, see from_deltas for loading
+pub fn deltas<S: Serializer>(offsets: &[u32], serializer: S) -> Result<S::Ok, S::Error> {
+    let mut previous = 0u32;
+    serializer.collect_seq(offsets.iter().map(|&offset| {
+        let gap = offset.wrapping_sub(previous);
+        previous = offset;
+        gap
+    }))
+}
+
+pub fn from_deltas<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u32>, D::Error> {
+    let mut gaps = Vec::<u32>::deserialize(deserializer)?;
+    let mut previous = 0u32;
+    for gap in gaps.iter_mut() {
+        previous = previous.wrapping_add(*gap);
+        *gap = previous;
+    }
+    Ok(gaps)
+}
+
+// view of map to serialize in key order
+pub fn sorted<K: Ord, V>(map: &HashMap<K, V>) -> BTreeMap<&K, &V> {
+    map.iter().collect()
+}
+
+fn check_header(f: &mut File) -> io::Result<()> {
+    let mut header = [0u8; HEADER_LEN];
+    if f.read_exact(&mut header).is_err() || &header[..4] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            "not a rir index file, or saved by a version older than the version header"));
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version != SCHEMA_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("index schema version {} is not supported, expected {}, rebuild the index",
+                version, SCHEMA_VERSION)));
+    }
     Ok(())
 }
 
+// read the bincode payload of either encoding into encoded,
+// returns the offset it starts at
+fn read_payload(mut f: File, encoded: &mut Vec<u8>) -> io::Result<usize> {
+    check_header(&mut f)?;
+    let start = encoded.len();
+    f.read_to_end(encoded)?;
+    if encoded[start..].starts_with(RAW_MAGIC) {
+        return Ok(start + RAW_MAGIC.len());
+    }
+    let compressed = encoded.split_off(start);
+    GzDecoder::new(&compressed[..]).read_to_end(encoded)?;
+    Ok(start)
+}
+
+// a missing file is ErrorKind::NotFound, a corrupt one ErrorKind::InvalidData
 pub fn read_file<'a, T>(filepath: &Path, encoded: &'a mut Vec<u8>) -> io::Result<T>
     where T: Deserialize<'a> {
-    let f = File::open(filepath).expect("cannot open file.");
-    let mut reader = GzDecoder::new(f);
-    match reader.read_to_end(encoded){
-        Ok(_) => {
-            let bincode_options = bincode::DefaultOptions::new().with_varint_encoding().allow_trailing_bytes();
-            let reloaded_obj: T = bincode_options.deserialize(&encoded[..]).unwrap();
-            return Ok(reloaded_obj);    
-        },
-        Err(e) => return Err(e),
+    let f = File::open(filepath)?;
+    let offset = read_payload(f, encoded)?;
+    deserialize(&encoded[offset..])
+}
+
+// same as read_file, without an external buffer the result borrows from.
+// A raw file is memory mapped and deserialized from the mapping, instead of
+// being read into memory first, a gzip one is inflated into a buffer.
+pub fn read_file_owned<T>(filepath: &Path) -> io::Result<T>
+    where T: DeserializeOwned {
+    let mut f = File::open(filepath)?;
+    check_header(&mut f)?;
+    let mut magic = [0u8; 4];
+    if f.read_exact(&mut magic).is_ok() && &magic == RAW_MAGIC {
+        // safety: the mapping is dropped once deserialized, the file must not
+        // be saved over by another process in the meantime
+        let mapped = unsafe { Mmap::map(&f)? };
+        return deserialize(&mapped[HEADER_LEN + RAW_MAGIC.len()..]);
+    }
+    f.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+    let mut encoded = vec![];
+    GzDecoder::new(f).read_to_end(&mut encoded)?;
+    deserialize(&encoded)
+}
+
+fn deserialize<'a, T: Deserialize<'a>>(encoded: &'a [u8]) -> io::Result<T> {
+    let bincode_options = bincode::DefaultOptions::new().with_varint_encoding().allow_trailing_bytes();
+    bincode_options.deserialize(encoded)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_file_owned() {
+        let path = Path::new(".rir/serialize/owned.bin");
+        let obj = HashMap::from([(1u32, "a".to_string()), (2u32, "b".to_string())]);
+        assert!(write_file(path, &obj).is_ok());
+        let reloaded: HashMap<u32, String> = read_file_owned(path).unwrap();
+        assert_eq!(reloaded, obj);
+        // missing file is an error instead of a panic
+        assert!(read_file_owned::<HashMap<u32, String>>(Path::new(".rir/serialize/none.bin")).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_read_raw_and_gzip() {
+        let obj: HashMap<u32, String> = (0..100).map(|i| (i, "quarrel sir".to_string())).collect();
+        let gzip_path = Path::new(".rir/serialize/gzip.bin");
+        let raw_path = Path::new(".rir/serialize/raw.bin");
+        assert!(write_file_with(gzip_path, &obj, Encoding::default()).is_ok());
+        assert!(write_file_with(raw_path, &obj, Encoding::Raw).is_ok());
+        assert!(fs::metadata(raw_path).unwrap().len() > fs::metadata(gzip_path).unwrap().len());
+        for path in [gzip_path, raw_path] {
+            let mut encoded = vec![];
+            let reloaded: HashMap<u32, String> = read_file(path, &mut encoded).unwrap();
+            assert_eq!(reloaded, obj);
+            let reloaded: HashMap<u32, String> = read_file_owned(path).unwrap();
+            assert_eq!(reloaded, obj);
+        }
+    }
+
+    #[derive(Serialize)]
+    struct Sorted {
+        #[serde(serialize_with = "sorted_map")]
+        map: HashMap<u32, String>,
+        #[serde(serialize_with = "sorted_set")]
+        set: HashSet<u32>,
+    }
+
+    #[test]
+    fn test_sorted_bytes() {
+        // separately built maps iterate in different orders
+        let build = || Sorted {
+            map: (0..100).map(|i| (i, i.to_string())).collect(),
+            set: (0..100).collect(),
+        };
+        let left = Path::new(".rir/serialize/sorted_left.bin");
+        let right = Path::new(".rir/serialize/sorted_right.bin");
+        assert!(write_file(left, &build()).is_ok());
+        assert!(write_file(right, &build()).is_ok());
+        assert_eq!(fs::read(left).unwrap(), fs::read(right).unwrap());
+        let reloaded: HashMap<u32, String> = read_file_owned(left).unwrap();
+        assert_eq!(reloaded, build().map);
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Offsets {
+        #[serde(serialize_with = "deltas", deserialize_with = "from_deltas")]
+        offsets: Vec<u32>,
+    }
+
+    #[test]
+    fn test_deltas() {
+        let path = Path::new(".rir/serialize/deltas.bin");
+        let obj = Offsets { offsets: vec![3, 7, 7, 1000, 70000, 5] };
+        assert!(write_file(path, &obj).is_ok());
+        assert_eq!(read_file_owned::<Offsets>(path).unwrap(), obj);
+        // a term at every position of a long document
+        let obj = Offsets { offsets: (1..=10000).collect() };
+        assert!(write_file_with(path, &obj, Encoding::Raw).is_ok());
+        let delta_size = fs::metadata(path).unwrap().len();
+        assert_eq!(read_file_owned::<Offsets>(path).unwrap(), obj);
+        assert!(write_file_with(path, &obj.offsets, Encoding::Raw).is_ok());
+        let absolute_size = fs::metadata(path).unwrap().len();
+        assert!(delta_size * 2 < absolute_size, "{} vs {} bytes", delta_size, absolute_size);
+    }
+
+    #[test]
+    fn test_read_file_errors() {
+        let mut encoded = vec![];
+        let missing = read_file::<HashMap<u32, String>>(Path::new(".rir/serialize/none.bin"), &mut encoded);
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+        // truncated payload of a valid raw file
+        let path = Path::new(".rir/serialize/corrupt.bin");
+        let obj: HashMap<u32, String> = (0..10).map(|i| (i, "quarrel sir".to_string())).collect();
+        assert!(write_file_with(path, &obj, Encoding::Raw).is_ok());
+        let bytes = fs::read(path).unwrap();
+        fs::write(path, &bytes[..bytes.len() / 2]).unwrap();
+        let mut encoded = vec![];
+        let corrupt = read_file::<HashMap<u32, String>>(path, &mut encoded);
+        assert_eq!(corrupt.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let corrupt = read_file_owned::<HashMap<u32, String>>(path);
+        assert_eq!(corrupt.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // neither raw nor gzip
+        fs::write(path, b"not an index").unwrap();
+        assert!(read_file_owned::<HashMap<u32, String>>(path).is_err());
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        let path = Path::new(".rir/serialize/version.bin");
+        let obj: HashMap<u32, String> = HashMap::from([(1, "quarrel".to_string())]);
+        for encoding in [Encoding::default(), Encoding::Raw] {
+            assert!(write_file_with(path, &obj, encoding).is_ok());
+            let mut bytes = fs::read(path).unwrap();
+            assert!(bytes.starts_with(MAGIC));
+            bytes[MAGIC.len()] = bytes[MAGIC.len()].wrapping_add(1);
+            fs::write(path, &bytes).unwrap();
+            let e = read_file_owned::<HashMap<u32, String>>(path).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert!(e.to_string().contains(&format!("schema version {}", SCHEMA_VERSION + 1)), "{}", e);
+            let mut encoded = vec![];
+            assert!(read_file::<HashMap<u32, String>>(path, &mut encoded).is_err());
+        }
+        // no header at all
+        fs::write(path, b"RIRB").unwrap();
+        let e = read_file_owned::<HashMap<u32, String>>(path).unwrap_err();
+        assert!(e.to_string().contains("not a rir index file"), "{}", e);
+    }
+}
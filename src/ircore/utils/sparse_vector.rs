@@ -58,6 +58,45 @@ impl SparseVectorOp for SparseVector {
     }
 }
 
+// Sparse vector of weights in [0, 1] quantized to u16, sorted by id.
+// Each weight is off by at most 1/(2*65535) ~ 7.6e-6, so a dot product with a
+// normalized vector is off by at most 7.6e-6 * sqrt(number of entries).
+#[derive(Debug, Clone)]
+pub struct QuantizedVector {
+    ids: Vec<TermId>,
+    weights: Vec<u16>,
+}
+
+impl QuantizedVector {
+    const SCALE: f32 = u16::MAX as f32;
+
+    pub fn from_sparse(sv: &SparseVector) -> Self {
+        let mut entries: Vec<(TermId, f32)> = sv.iter().map(|(id, value)| (*id, *value)).collect();
+        entries.sort_by_key(|entry| entry.0);
+        QuantizedVector {
+            ids: entries.iter().map(|entry| entry.0).collect(),
+            weights: entries.iter()
+                .map(|entry| (entry.1.clamp(0.0, 1.0) * Self::SCALE).round() as u16)
+                .collect(),
+        }
+    }
+
+    pub fn vec_get(&self, id: TermId) -> f32 {
+        match self.ids.binary_search(&id) {
+            Ok(i) => self.weights[i] as f32 / Self::SCALE,
+            Err(_) => 0.0,
+        }
+    }
+
+    pub fn vec_dot(&self, other: &SparseVector) -> f32 {
+        let mut result = 0.0f32;
+        for (id, value) in other.iter() {
+            result += self.vec_get(*id) * value;
+        }
+        result
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -116,4 +155,23 @@ mod tests {
         assert!((res - 0.59).abs() <= 0.005);
     }
 
+    #[test]
+    fn test_quantized_vector() {
+        let mut sv1 = SparseVector::new();
+        sv1.vec_set(5, 1.32);
+        sv1.vec_set(12, 1.32);
+        sv1.vec_set(14, 0.32);
+        sv1.vec_set(16, 1.32);
+        sv1.vec_normalize();
+        let qv = QuantizedVector::from_sparse(&sv1);
+        let bound = 0.5 / u16::MAX as f32;
+        for id in [5, 12, 14, 16, 100] {
+            assert!((qv.vec_get(id) - sv1.vec_get(id)).abs() <= bound);
+        }
+        let mut sv2 = SparseVector::new();
+        sv2.vec_set(12, 0.97);
+        sv2.vec_set(14, 0.24);
+        assert!((qv.vec_dot(&sv2) - sv1.vec_dot(&sv2)).abs() <= bound * 2f32.sqrt());
+    }
+
 }
\ No newline at end of file
@@ -1,8 +1,16 @@
 pub mod ircore;
 use ircore::index::engine::Engine;
 use clap::{Parser, Subcommand, ValueEnum};
+use indicatif::ProgressBar;
 use std::io::{self, BufRead};
+use std::process;
 use ircore::RankingAlgorithm;
+use ircore::ranking::bm25::Bm25Params;
+use ircore::token::analyzer::ENGLISH_STOPWORDS;
+use ircore::utils::serialize::{Encoding, CompressionLevel};
+
+// most frequent terms listed by stats
+const DEFAULT_TOP_TERMS: usize = 100;
 
 #[derive(Parser)]
 #[derive(Debug)]
@@ -25,6 +33,24 @@ enum Commands {
         #[clap(short, long, value_parser)]
         /// Corpus directory
         corpus_dir: String,
+        /// no progress bar
+        #[clap(short, long, value_parser)]
+        quiet: bool,
+        /// stem English words, queries are stemmed the same way
+        #[clap(short, long, value_parser)]
+        stemming: bool,
+        /// remove common English words, at index and query time
+        #[clap(long, value_parser)]
+        stopwords: bool,
+        /// skip files larger than this many bytes
+        #[clap(long, value_parser)]
+        max_file_size: Option<u64>,
+        /// save the index uncompressed, larger on disk but faster to load
+        #[clap(long, value_parser)]
+        raw: bool,
+        /// gzip level of the saved index, fast to save sooner, best for smaller files
+        #[clap(long, value_enum, default_value_t = SelectCompression::Default, conflicts_with = "raw")]
+        compression: SelectCompression,
     },
     /// Search
     Search {
@@ -34,18 +60,125 @@ enum Commands {
         /// ranking algorithm
         #[clap(short, long, value_enum)]
         ranking: Option<SelectRankingAlgorithm>,
-
+        /// show document titles along with paths
+        #[clap(short, long, value_parser)]
+        titles: bool,
+        /// BM25 weight saturation factor, default 1.2
+        #[clap(long, value_parser)]
+        k1: Option<f32>,
+        /// BM25 document length normalization (0 to 1), default 0.75
+        #[clap(long, value_parser)]
+        b: Option<f32>,
+        /// LMD Dirichlet smoothing mass, default average document length
+        #[clap(long, value_parser)]
+        mu: Option<f32>,
+        /// match unknown query terms within this many edits, 1 or 2
+        #[clap(long, value_parser)]
+        fuzzy: Option<u8>,
+        /// number of results to skip
+        #[clap(long, value_parser, default_value_t = 0)]
+        offset: usize,
+        /// number of results to show
+        #[clap(long, value_parser, default_value_t = 10)]
+        limit: usize,
+        /// output format, json prints one array of {rank, path, score} per query
+        #[clap(short, long, visible_alias = "format", value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
+        /// list query term positions of each result, json output only
+        #[clap(long, value_parser)]
+        highlight: bool,
+        /// show this many words of context around the first match, text output only
+        #[clap(long, value_parser)]
+        snippet: Option<usize>,
+        /// drop results scoring below this, on the scale of the ranking (LMD scores can be negative)
+        #[clap(long, value_parser, allow_hyphen_values = true)]
+        min_score: Option<f32>,
+    },
+    /// Print statistics of the index
+    Stats {
+        /// number of most frequent terms to list
+        #[clap(long, value_parser, default_value_t = DEFAULT_TOP_TERMS)]
+        top: usize,
     },
     /// Playgound for try sth new
     SandBox,
 }
 
-#[derive(Debug, Clone, ValueEnum)]
+// engine settings of a search, None keeps the default
+struct SearchTuning {
+    k1: Option<f32>,
+    b: Option<f32>,
+    mu: Option<f32>,
+    fuzzy: Option<u8>,
+}
+
+// which results of a query are shown and how
+#[derive(Clone)]
+struct ResultOptions {
+    titles: bool,
+    // (offset, limit) of the results to show
+    page: (usize, usize),
+    output: OutputFormat,
+    highlight: bool,
+    snippet: Option<usize>,
+    min_score: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum SelectCompression {
+    Fast,
+    Default,
+    Best,
+}
+
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
 enum SelectRankingAlgorithm {
+    #[value(alias = "exact")]
     ExactMatch,
+    #[value(alias = "vsm")]
     VectorSpaceModel,
+    #[value(alias = "bm25")]
     OkapiBM25,
     LMD,
+    #[value(alias = "bim")]
+    Bim,
+}
+
+// lines starting with ':' in interactive search change the session
+#[derive(Debug, PartialEq)]
+enum MetaCommand {
+    // None goes back to the default ranking
+    Algo(Option<SelectRankingAlgorithm>),
+    Limit(usize),
+    Quit,
+}
+
+const META_USAGE: &str = "meta commands: :algo [exact|vsm|bm25|lmd|bim], :limit N, :quit";
+
+// the command of a line starting with ':', Err with what is wrong otherwise
+fn parse_meta_command(line: &str) -> Result<MetaCommand, String> {
+    let mut words = line.trim().trim_start_matches(':').split_whitespace();
+    let command = words.next().unwrap_or("");
+    let arg = words.next();
+    if words.next().is_some() {
+        return Err(format!("too many arguments to :{}", command));
+    }
+    match (command, arg) {
+        ("algo", None) => Ok(MetaCommand::Algo(None)),
+        ("algo", Some(name)) => SelectRankingAlgorithm::from_str(name, true)
+            .map(|algo| MetaCommand::Algo(Some(algo)))
+            .map_err(|_| format!("unknown ranking algorithm {}", name)),
+        ("limit", Some(n)) => n.parse().map(MetaCommand::Limit)
+            .map_err(|_| format!("limit {} is not a number", n)),
+        ("quit" | "q", None) => Ok(MetaCommand::Quit),
+        _ => Err(format!("unknown meta command :{}", command)),
+    }
 }
 
 fn main() {
@@ -55,59 +188,140 @@ fn main() {
     // You can check for the existence of subcommands, and if found use their
     // matches just as you would the top level cmd
     match &cli.command {
-        Some(Commands::Build { corpus_dir}) => 
-            match command_build_index(corpus_dir, &cli.index_dir){
+        Some(Commands::Build { corpus_dir, quiet, stemming, stopwords, max_file_size, raw, compression}) => {
+            let encoding = match (raw, compression) {
+                (true, _) => Encoding::Raw,
+                (false, SelectCompression::Fast) => Encoding::Gzip(CompressionLevel::Fast),
+                (false, SelectCompression::Default) => Encoding::Gzip(CompressionLevel::Default),
+                (false, SelectCompression::Best) => Encoding::Gzip(CompressionLevel::Best),
+            };
+            match command_build_index(corpus_dir, &cli.index_dir, *quiet, *stemming, *stopwords, *max_file_size, encoding){
                 Ok(count) => log::info!("{} documents indexed", count),
-                Err(_) => log::error!("error in processing")
-            },
-        Some(Commands::Search {phrase, ranking}) => 
-            command_search(&cli.index_dir, phrase, ranking)
-        ,
+                Err(e) => {
+                    eprintln!("cannot build index: {}", e);
+                    process::exit(1);
+                },
+            }
+        },
+        Some(Commands::Search {phrase, ranking, titles, k1, b, mu, fuzzy, offset, limit, output, highlight, snippet,
+            min_score}) => {
+            let tuning = SearchTuning { k1: *k1, b: *b, mu: *mu, fuzzy: *fuzzy };
+            let options = ResultOptions {
+                titles: *titles,
+                page: (*offset, *limit),
+                output: *output,
+                highlight: *highlight,
+                snippet: *snippet,
+                min_score: *min_score,
+            };
+            command_search(&cli.index_dir, phrase, ranking, &tuning, &options)
+        },
+        Some(Commands::Stats { top }) => {
+            command_load_index(&cli.index_dir, *top);
+        },
         Some(Commands::SandBox) => {
             command_sand_box();
         }
         None => {
-            command_load_index(&cli.index_dir);
+            command_load_index(&cli.index_dir, DEFAULT_TOP_TERMS);
         }
     }
 }
 
-fn command_search(index_dir: &str, phrase_option: &Option<String>, ranking_option: &Option<SelectRankingAlgorithm>){
-    let engine = Engine::load_from(index_dir);
-    println!("index of {} documents loaded",engine.doc_count());
+fn command_search(index_dir: &str, phrase_option: &Option<String>, ranking_option: &Option<SelectRankingAlgorithm>,
+    tuning: &SearchTuning, options: &ResultOptions){
+    let mut engine = load_index(index_dir);
+    if tuning.k1.is_some() || tuning.b.is_some() {
+        let defaults = Bm25Params::default();
+        engine.set_bm25_k1_b(tuning.k1.unwrap_or(defaults.k1), tuning.b.unwrap_or(defaults.b));
+    }
+    if tuning.mu.is_some() {
+        engine.set_lmd_mu(tuning.mu);
+    }
+    if tuning.fuzzy.is_some() {
+        engine.set_fuzzy(tuning.fuzzy);
+    }
+    // json output is one array per query on stdout, anything else goes to stderr
+    match options.output {
+        OutputFormat::Text => println!("index of {} documents loaded", engine.doc_count()),
+        OutputFormat::Json => eprintln!("index of {} documents loaded", engine.doc_count()),
+    }
     match phrase_option {
-        Some(phrase_str) => exec_query(&engine, &phrase_str, ranking_option),
+        Some(phrase_str) => exec_query(&engine, phrase_str, ranking_option, options),
         None => {
-            println!("input phrase");
+            let visited = engine.preload();
+            log::debug!("{} positions preloaded", visited);
+            if options.output == OutputFormat::Text {
+                println!("input phrase");
+            }
+            let mut ranking = ranking_option.clone();
+            let mut options = options.clone();
             let stdin = io::stdin();
             for line_result in stdin.lock().lines() {
                 let line = line_result.unwrap();
-                exec_query(&engine, &line, ranking_option);
-            }    
+                if !line.trim_start().starts_with(':') {
+                    exec_query(&engine, &line, &ranking, &options);
+                    continue;
+                }
+                match parse_meta_command(&line) {
+                    Ok(MetaCommand::Algo(algo)) => ranking = algo,
+                    Ok(MetaCommand::Limit(limit)) => options.page.1 = limit,
+                    Ok(MetaCommand::Quit) => break,
+                    Err(e) => eprintln!("{}\n{}", e, META_USAGE),
+                }
+            }
         }
     }
 }
 
-fn exec_query(engine: &Engine, phrase: &str, ranking_option: &Option<SelectRankingAlgorithm>){
+fn exec_query(engine: &Engine, phrase: &str, ranking_option: &Option<SelectRankingAlgorithm>, options: &ResultOptions){
     let ranking;
     match ranking_option {
         Some(SelectRankingAlgorithm::ExactMatch) => ranking = RankingAlgorithm::ExactMatch,
         Some(SelectRankingAlgorithm::VectorSpaceModel) => ranking = RankingAlgorithm::VectorSpaceModel,
         Some(SelectRankingAlgorithm::OkapiBM25) => ranking = RankingAlgorithm::OkapiBM25,
         Some(SelectRankingAlgorithm::LMD) => ranking = RankingAlgorithm::LMD,
+        Some(SelectRankingAlgorithm::Bim) => ranking = RankingAlgorithm::Bim,
         None => ranking = RankingAlgorithm::Default,
     }
-    let result = engine.exec_query(phrase, ranking);
-    let result_len = result.len();
+    if options.output == OutputFormat::Json {
+        let (_, unknown_terms) = engine.analyze_query(phrase);
+        if !unknown_terms.is_empty() {
+            eprintln!("unknown terms: {}", unknown_terms.join(", "));
+        }
+        let (offset, limit) = options.page;
+        match engine.exec_query_json(phrase, ranking, options.highlight, options.min_score, offset, limit) {
+            Ok(json) => println!("{}", json),
+            Err(e) => log::error!("json output failed: {}", e),
+        }
+        return;
+    }
+    let (_, unknown_terms) = engine.analyze_query(phrase);
+    if !unknown_terms.is_empty() {
+        println!("unknown terms: {}", unknown_terms.join(", "));
+    }
+    let (offset, limit) = options.page;
+    let (result_len, page) = engine.exec_query_page(phrase, ranking, options.min_score, offset, limit);
     if result_len > 0 {
         println!("{} results", result_len);
-        let mut display = result_len;
-        if result_len > 10 {
-            println!("top 10:");
-            display = 10;
+        let end = result_len.min(offset.saturating_add(limit));
+        if offset >= end {
+            println!("nothing to show from {}", offset + 1);
+        }else if end - offset < result_len {
+            println!("{} to {}:", offset + 1, end);
         }
-        for (i,doc) in result.into_iter().enumerate().take(display){
-            println!("{}:{}", i+1, doc);
+        let terms = engine.query_term_ids(phrase);
+        for (i,(doc_id, doc, score)) in page.into_iter().enumerate(){
+            let i = offset + i;
+            match engine.get_title(doc_id) {
+                Some(title) if options.titles => println!("{}:{} ({}) (score {:.2})", i+1, title, doc, score),
+                _ => println!("{}:{} (score {:.2})", i+1, doc, score),
+            }
+            if let Some(window) = options.snippet {
+                if let Some(text) = engine.snippet(doc_id, &terms, window) {
+                    println!("    {}", text);
+                }
+            }
         }
     }else{
         println!("no result");
@@ -115,31 +329,59 @@ fn exec_query(engine: &Engine, phrase: &str, ranking_option: &Option<SelectRanki
 
 }
 
-fn command_build_index(corpus_dir: &str, index_dir: &str) -> io::Result<usize>{
+fn command_build_index(corpus_dir: &str, index_dir: &str, quiet: bool, stemming: bool, stopwords: bool,
+    max_file_size: Option<u64>, encoding: Encoding) -> io::Result<usize>{
     let mut engine = Engine::new();
-    let mut count = 0;
-    if let Ok(count_res) = engine.build_index_from(corpus_dir){
-        count = count_res;
-        engine.save_to(index_dir)?;
-        stats(&engine);
+    engine.set_stemming(stemming);
+    engine.set_max_file_bytes(max_file_size);
+    if stopwords {
+        engine.set_stopwords(ENGLISH_STOPWORDS);
     }
+    let progress_bar = if quiet { ProgressBar::hidden() } else { ProgressBar::new(0) };
+    let mut progress = |done: usize, total: usize| {
+        progress_bar.set_length(total as u64);
+        progress_bar.set_position(done as u64);
+    };
+    let build_res = engine.build_index_with_progress(corpus_dir, &mut progress);
+    progress_bar.finish_and_clear();
+    let count = build_res.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    engine.save_to_with(index_dir, encoding)?;
+    stats(&engine, DEFAULT_TOP_TERMS);
     Ok(count)
 }
 
-fn command_load_index(index_dir: &str){
-    let engine = Engine::load_from(index_dir);
-    stats(&engine);
+fn command_load_index(index_dir: &str, top: usize){
+    let engine = load_index(index_dir);
+    stats(&engine, top);
 }
 
-fn stats(engine: &Engine) {
+// the index, or exit with status 1 after telling why it cannot be loaded
+fn load_index(index_dir: &str) -> Engine {
+    match Engine::load_from(index_dir) {
+        Ok(engine) => engine,
+        Err(e) => {
+            eprintln!("cannot load index from {}: {}", index_dir, e);
+            if e.kind() == io::ErrorKind::NotFound {
+                eprintln!("build it first with `rir build <corpus_dir>`");
+            }
+            process::exit(1);
+        },
+    }
+}
+
+fn stats(engine: &Engine, display_num: usize) {
     let summary = engine.stats();
     println!("===Index===");
     println!("total document: {}", summary.index.document_count);
     println!("total length: {}", summary.index.total_document_length);
     println!("average length: {}", summary.index.average_document_length);
+    println!("type-token ratio: {:.3}", summary.index.type_token_ratio);
+    println!("average document type-token ratio: {:.3}", summary.index.average_doc_type_token_ratio());
     println!("total term count: {}", summary.analyzer.dict.term_count);
+    println!("indexed terms: {}", summary.index.unique_term_count);
+    println!("total postings: {}", summary.index.total_posting_count);
+    println!("longest postings list: {}", summary.index.max_posting_list_len);
     println!("language: {}", summary.analyzer.lang);
-    let display_num = 100;
     println!("===Top {} terms===", display_num);
     let mut sum_so_far:f32 = 0.0;
     for (i, (_, term, count)) in summary.index.term_freq.into_iter().enumerate().take(display_num){
@@ -156,4 +398,28 @@ fn command_sand_box() {
     info!("{:?}", "And every where that Mary went");
     debug!("Mary has a little lamb");
     log::error!("--");
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_meta_command() {
+        assert_eq!(parse_meta_command(":algo bm25"), Ok(MetaCommand::Algo(Some(SelectRankingAlgorithm::OkapiBM25))));
+        assert_eq!(parse_meta_command(":algo LMD"), Ok(MetaCommand::Algo(Some(SelectRankingAlgorithm::LMD))));
+        assert_eq!(parse_meta_command(":algo BIM"), Ok(MetaCommand::Algo(Some(SelectRankingAlgorithm::Bim))));
+        assert_eq!(parse_meta_command(":algo vector-space-model"),
+            Ok(MetaCommand::Algo(Some(SelectRankingAlgorithm::VectorSpaceModel))));
+        assert_eq!(parse_meta_command(":algo"), Ok(MetaCommand::Algo(None)));
+        assert_eq!(parse_meta_command("  :limit 20 "), Ok(MetaCommand::Limit(20)));
+        assert_eq!(parse_meta_command(":quit"), Ok(MetaCommand::Quit));
+        assert_eq!(parse_meta_command(":q"), Ok(MetaCommand::Quit));
+
+        assert!(parse_meta_command(":algo pagerank").unwrap_err().contains("unknown ranking algorithm"));
+        assert!(parse_meta_command(":limit ten").is_err());
+        assert!(parse_meta_command(":limit").is_err());
+        assert!(parse_meta_command(":quit now").is_err());
+        assert!(parse_meta_command(":sort").unwrap_err().contains("unknown meta command"));
+        assert!(parse_meta_command(":").is_err());
+    }
+}
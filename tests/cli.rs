@@ -0,0 +1,71 @@
+use std::process::Command;
+
+fn rir(args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_rir")).args(args).output().unwrap()
+}
+
+#[test]
+fn test_search_json_output() {
+    let index_dir = ".rir/cli_json.idx";
+    let build = rir(&["-i", index_dir, "build", "-c", "./sample_corpus/romeo_juliet", "-q"]);
+    assert!(build.status.success());
+
+    let search = rir(&["-i", index_dir, "search", "quarrel sir", "--format", "json", "-r", "okapi-bm25"]);
+    assert!(search.status.success());
+    // stdout is nothing but the json array, so it can be piped
+    let hits: serde_json::Value = serde_json::from_slice(&search.stdout).unwrap();
+    let hits = hits.as_array().unwrap();
+    assert!(!hits.is_empty());
+    for (i, hit) in hits.iter().enumerate() {
+        assert_eq!(hit["rank"], i + 1);
+        assert!(hit["path"].as_str().unwrap().contains("romeo_juliet"));
+        assert!(hit["score"].is_number());
+    }
+    let stderr = String::from_utf8_lossy(&search.stderr);
+    assert!(stderr.contains("index of 5 documents loaded"), "{}", stderr);
+
+    // paged like the text output
+    let paged = rir(&["-i", index_dir, "search", "quarrel sir", "--format", "json", "-r", "okapi-bm25",
+        "--offset", "1", "--limit", "1"]);
+    let paged: serde_json::Value = serde_json::from_slice(&paged.stdout).unwrap();
+    assert_eq!(paged.as_array().unwrap().len(), 1);
+    assert_eq!(paged[0], hits[1]);
+
+    let search = rir(&["-i", index_dir, "search", "quarrel xyzzy", "--output", "json"]);
+    assert!(serde_json::from_slice::<serde_json::Value>(&search.stdout).is_ok());
+    assert!(String::from_utf8_lossy(&search.stderr).contains("unknown terms: xyzzy"));
+}
+
+#[test]
+fn test_stats() {
+    let index_dir = ".rir/cli_stats.idx";
+    assert!(rir(&["-i", index_dir, "build", "-c", "./sample_corpus/romeo_juliet", "-q"]).status.success());
+
+    let stats = rir(&["-i", index_dir, "stats", "--top", "3"]);
+    assert!(stats.status.success());
+    let stdout = String::from_utf8_lossy(&stats.stdout);
+    assert!(stdout.contains("total document: 5"), "{}", stdout);
+    assert!(stdout.contains("total postings: "), "{}", stdout);
+    assert!(stdout.contains("===Top 3 terms==="), "{}", stdout);
+    let terms: Vec<&str> = stdout.lines().skip_while(|line| !line.starts_with("===Top")).skip(1).collect();
+    assert_eq!(terms.len(), 3);
+    assert!(terms[0].trim_start().starts_with("1: "), "{}", terms[0]);
+
+    // no index to load
+    let stats = rir(&["-i", ".rir/cli_none.idx", "stats"]);
+    assert!(String::from_utf8_lossy(&stats.stderr).contains("cannot load index"));
+    assert_eq!(stats.status.code(), Some(1));
+    let search = rir(&["-i", ".rir/cli_none.idx", "search", "quarrel"]);
+    assert_eq!(search.status.code(), Some(1));
+}
+
+#[test]
+fn test_build_empty_corpus() {
+    let corpus_dir = ".rir/cli_empty_corpus";
+    let _ = std::fs::create_dir_all(corpus_dir);
+    let build = rir(&["-i", ".rir/cli_empty.idx", "build", "-c", corpus_dir, "-q"]);
+    assert_eq!(build.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&build.stderr).contains("no documents found"));
+    let build = rir(&["-i", ".rir/cli_empty.idx", "build", "-c", ".rir/cli_no_corpus", "-q"]);
+    assert_eq!(build.status.code(), Some(1));
+}